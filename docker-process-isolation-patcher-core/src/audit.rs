@@ -0,0 +1,497 @@
+// Audit trail of every change the patcher makes to services
+// Kept apart from the debug log in `audit.jsonl` beside the exe, one entry per line and only ever
+// appended to. Each entry holds the hash of the previous one, so editing or removing an entry
+// breaks the chain from that point on. Cutting entries off the end or rewriting the whole log
+// leaves a valid chain, so the last entry is also kept in `audit.head.json` and logged as an
+// `audit.appended` event, which a log shipper can keep off the machine.
+
+use std::fmt;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use log::Level;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use super::backend::Target;
+use super::event;
+use super::event::{Event, EventId};
+use super::permissions;
+
+pub const AUDIT_FILE_NAME: &str = "audit.jsonl";
+pub const AUDIT_HEAD_FILE_NAME: &str = "audit.head.json";
+/// previous hash of the first entry
+const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Action {
+    ChangeConfig,
+    Start,
+    Stop,
+    /// the original command line was put back on request
+    Unpatch,
+    Install,
+    Uninstall,
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let action = match self {
+            Action::ChangeConfig => "change-config",
+            Action::Start => "start",
+            Action::Stop => "stop",
+            Action::Unpatch => "unpatch",
+            Action::Install => "install",
+            Action::Uninstall => "uninstall",
+        };
+
+        f.write_str(action)
+    }
+}
+
+/// What made the patcher act
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Trigger {
+    Watcher,
+    Cli,
+    /// a CLI command handled by the service, e.g. patch-now
+    ControlChannel,
+}
+
+impl fmt::Display for Trigger {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Trigger::Watcher => f.write_str("watcher"),
+            Trigger::Cli => f.write_str("cli"),
+            Trigger::ControlChannel => f.write_str("control-channel"),
+        }
+    }
+}
+
+/// An action about to be written, `AuditLog::append` fills in the rest
+#[derive(Debug, Clone)]
+pub struct Record {
+    pub action: Action,
+    pub service: String,
+    /// None for this computer
+    pub computer: Option<String>,
+    pub trigger: Trigger,
+    /// ImagePath before and after the action, for actions which change it
+    pub before: Option<String>,
+    pub after: Option<String>,
+    pub correlation_id: Option<String>,
+    /// why the action failed, None if it succeeded
+    pub error: Option<String>,
+}
+
+impl Record {
+    pub fn new(action: Action, service: &str, trigger: Trigger) -> Self {
+        Record { action, service: service.to_string(), computer: None, trigger, before: None, after: None, correlation_id: None, error: None }
+    }
+
+    /// Where the service is, the log always stays on this computer
+    pub fn target(mut self, target: &Target) -> Self {
+        self.computer = target.computer().map(ToString::to_string);
+        self
+    }
+
+    pub fn image_paths(mut self, before: &str, after: &str) -> Self {
+        self.before = Some(before.to_string());
+        self.after = Some(after.to_string());
+        self
+    }
+
+    pub fn correlation(mut self, id: &str) -> Self {
+        self.correlation_id = Some(id.to_string());
+        self
+    }
+
+    pub fn result<T, E: fmt::Display>(mut self, res: &Result<T, E>) -> Self {
+        self.error = res.as_ref().err().map(ToString::to_string);
+        self
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Entry {
+    /// position in the log, starting at 1
+    pub seq: u64,
+    pub timestamp: String,
+    pub action: Action,
+    pub service: String,
+    /// remote computer the service is on, left out for this computer so older entries hash the same
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub computer: Option<String>,
+    pub trigger: Trigger,
+    /// `DOMAIN\user` who ran the CLI command, None for the watcher
+    pub user: Option<String>,
+    pub before: Option<String>,
+    pub after: Option<String>,
+    pub correlation_id: Option<String>,
+    pub error: Option<String>,
+    pub prev_hash: String,
+    /// sha-256 of the entry serialized with an empty hash
+    pub hash: String,
+}
+
+impl Entry {
+    fn compute_hash(&self) -> String {
+        let unhashed = Entry { hash: String::new(), ..self.clone() };
+        format!("{:x}", Sha256::digest(serde_json::to_string(&unhashed).unwrap().as_bytes()))
+    }
+}
+
+/// The last entry written, kept apart from the log
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct Head {
+    seq: u64,
+    hash: String,
+    /// size of the log right after the entry was written. A log of another size was written to
+    /// by someone else, its last entry is read from the log then
+    len: u64,
+}
+
+#[derive(Debug)]
+pub enum AuditError {
+    Io(PathBuf, std::io::Error),
+    /// line number and why it couldn't be parsed
+    Parse(PathBuf, usize, serde_json::Error),
+}
+
+impl fmt::Display for AuditError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuditError::Io(path, e) => write!(f, "failed to access {}: {}", path.display(), e),
+            AuditError::Parse(path, line, e) => write!(f, "corrupt audit log {} line {}: {}", path.display(), line, e),
+        }
+    }
+}
+
+impl std::error::Error for AuditError {}
+
+/// First problem found by `verify`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Broken {
+    /// entry was edited after it was written
+    Hash { line: usize, seq: u64 },
+    /// an entry before this one was removed, edited or reordered
+    Chain { line: usize, seq: u64 },
+    Sequence { line: usize, expected: u64, found: u64 },
+    /// the log ends before the last entry written
+    Truncated { expected: u64, found: u64 },
+    /// the entry doesn't match the one written, the log was rewritten
+    Rewritten { seq: u64 },
+    /// the log has entries but no head to check them against
+    NoHead,
+}
+
+impl fmt::Display for Broken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Broken::Hash { line, seq } => write!(f, "line {} (entry {}) was modified", line, seq),
+            Broken::Chain { line, seq } => {
+                write!(f, "line {} (entry {}) doesn't follow the previous entry, entries were removed or modified", line, seq)
+            }
+            Broken::Sequence { line, expected, found } => {
+                write!(f, "line {} is entry {} but entry {} was expected, entries were removed", line, found, expected)
+            }
+            Broken::Truncated { expected, found } => {
+                write!(f, "the log ends at entry {} but entry {} was written, entries were removed from the end", found, expected)
+            }
+            Broken::Rewritten { seq } => write!(f, "entry {} isn't the one written, the log was rewritten", seq),
+            Broken::NoHead => write!(f, "{} is missing, the end of the log can't be checked", AUDIT_HEAD_FILE_NAME),
+        }
+    }
+}
+
+pub struct AuditLog {
+    path: PathBuf,
+}
+
+impl AuditLog {
+    /// `audit.jsonl` in the same directory as the exe
+    pub fn default_path() -> PathBuf {
+        std::env::current_exe().unwrap().with_file_name(AUDIT_FILE_NAME)
+    }
+
+    pub fn open() -> Self {
+        AuditLog::at(&AuditLog::default_path())
+    }
+
+    pub fn at(path: &Path) -> Self {
+        AuditLog { path: path.to_path_buf() }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// `audit.head.json` beside the log
+    pub fn head_path(&self) -> PathBuf {
+        self.path.with_file_name(AUDIT_HEAD_FILE_NAME)
+    }
+
+    /// None if there's no head yet, or it can't be read
+    fn head(&self) -> Option<Head> {
+        let contents = std::fs::read_to_string(self.head_path()).ok()?;
+        serde_json::from_str(&contents).ok()
+    }
+
+    /// Written to a temporary file first, like the state
+    fn save_head(&self, head: &Head) -> Result<(), AuditError> {
+        let path = self.head_path();
+        let tmp = path.with_extension("json.tmp");
+
+        std::fs::write(&tmp, serde_json::to_string(head).unwrap()).map_err(|e| AuditError::Io(tmp.clone(), e))?;
+        std::fs::rename(&tmp, &path).map_err(|e| AuditError::Io(path, e))
+    }
+
+    /// All entries, oldest first. A missing file is an empty log
+    pub fn entries(&self) -> Result<Vec<Entry>, AuditError> {
+        let contents = match std::fs::read_to_string(&self.path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(AuditError::Io(self.path.clone(), e)),
+        };
+
+        contents
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty())
+            .map(|(i, line)| serde_json::from_str(line).map_err(|e| AuditError::Parse(self.path.clone(), i + 1, e)))
+            .collect()
+    }
+
+    pub fn append(&self, record: Record, user: Option<String>) -> Result<Entry, AuditError> {
+        let io_error = |e| AuditError::Io(self.path.clone(), e);
+
+        // read for the lock, windows only locks handles opened for reading or writing
+        let mut file = OpenOptions::new().create(true).read(true).append(true).open(&self.path).map_err(io_error)?;
+
+        // processes appending at once would take the same seq and previous hash otherwise
+        file.lock().map_err(io_error)?;
+
+        let len = file.metadata().map_err(io_error)?.len();
+        let last = match self.head() {
+            Some(head) if head.len == len => Some((head.seq, head.hash)),
+            _ => self.entries()?.pop().map(|e| (e.seq, e.hash)),
+        };
+
+        let mut entry = Entry {
+            seq: last.as_ref().map_or(1, |(seq, _)| seq + 1),
+            timestamp: chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+            action: record.action,
+            service: record.service,
+            computer: record.computer,
+            trigger: record.trigger,
+            user,
+            before: record.before,
+            after: record.after,
+            correlation_id: record.correlation_id,
+            error: record.error,
+            prev_hash: last.map_or(GENESIS_HASH.to_string(), |(_, hash)| hash),
+            hash: String::new(),
+        };
+        entry.hash = entry.compute_hash();
+
+        writeln!(file, "{}", serde_json::to_string(&entry).unwrap()).map_err(io_error)?;
+
+        let len = file.metadata().map_err(io_error)?.len();
+        self.save_head(&Head { seq: entry.seq, hash: entry.hash.clone(), len })?;
+
+        event!(Level::Info, Event::new(EventId::AuditAppended), "audit entry {} written, hash {}", entry.seq, entry.hash);
+
+        // the lock goes with the file
+        Ok(entry)
+    }
+
+    /// Check every hash and link of the chain, and that it ends with the last entry written.
+    /// Ok(None) means the log is intact
+    pub fn verify(&self) -> Result<Option<Broken>, AuditError> {
+        let mut prev_hash = GENESIS_HASH.to_string();
+        let entries = self.entries()?;
+
+        for (i, entry) in entries.iter().enumerate() {
+            let line = i + 1;
+            let expected = i as u64 + 1;
+
+            if entry.hash != entry.compute_hash() {
+                return Ok(Some(Broken::Hash { line, seq: entry.seq }));
+            }
+
+            if entry.seq != expected {
+                return Ok(Some(Broken::Sequence { line, expected, found: entry.seq }));
+            }
+
+            if entry.prev_hash != prev_hash {
+                return Ok(Some(Broken::Chain { line, seq: entry.seq }));
+            }
+
+            prev_hash = entry.hash.clone();
+        }
+
+        let head = match self.head() {
+            Some(head) => head,
+            None if entries.is_empty() => return Ok(None),
+            None => return Ok(Some(Broken::NoHead)),
+        };
+
+        // entries after the head are from an append which didn't get to save it
+        match head.seq.checked_sub(1).and_then(|i| entries.get(i as usize)) {
+            None => Ok(Some(Broken::Truncated { expected: head.seq, found: entries.len() as u64 })),
+            Some(entry) if entry.hash != head.hash => Ok(Some(Broken::Rewritten { seq: head.seq })),
+            Some(_) => Ok(None),
+        }
+    }
+}
+
+/// `DOMAIN\user` running this process, from its token. USERNAME and USERDOMAIN are whatever the
+/// caller set them to
+pub fn current_user() -> Option<String> {
+    permissions::current_user().ok()
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::fs;
+    use std::thread;
+
+    use super::{Action, AuditLog, Broken, Record, Trigger};
+    use crate::testing::ScratchDir;
+
+    fn log(dir: &ScratchDir, entries: usize) -> AuditLog {
+        let log = AuditLog::at(&dir.join("audit.jsonl"));
+        for _ in 0..entries {
+            log.append(Record::new(Action::ChangeConfig, "docker", Trigger::Watcher).image_paths("dockerd", "dockerd --patched"), None).unwrap();
+        }
+        log
+    }
+
+    /// Replace the lines of the log with what `edit` makes of them
+    fn edit(log: &AuditLog, edit: impl FnOnce(&mut Vec<String>)) {
+        let mut lines: Vec<String> = fs::read_to_string(log.path()).unwrap().lines().map(str::to_string).collect();
+        edit(&mut lines);
+        fs::write(log.path(), lines.iter().map(|l| format!("{}\n", l)).collect::<String>()).unwrap();
+    }
+
+    #[test]
+    fn appended_entries_verify() {
+        let dir = ScratchDir::new("audit-intact");
+        let log = log(&dir, 3);
+
+        let entries = log.entries().unwrap();
+        assert_eq!(entries.iter().map(|e| e.seq).collect::<Vec<_>>(), vec![1, 2, 3]);
+        assert_eq!(entries[2].prev_hash, entries[1].hash);
+        assert_eq!(log.verify().unwrap(), None);
+    }
+
+    #[test]
+    fn empty_log_verifies() {
+        let dir = ScratchDir::new("audit-empty");
+
+        assert_eq!(log(&dir, 0).verify().unwrap(), None);
+    }
+
+    #[test]
+    fn edited_entry() {
+        let dir = ScratchDir::new("audit-edited");
+        let log = log(&dir, 3);
+
+        edit(&log, |lines| lines[1] = lines[1].replace("--patched", "--other"));
+
+        assert_eq!(log.verify().unwrap(), Some(Broken::Hash { line: 2, seq: 2 }));
+    }
+
+    #[test]
+    fn edited_entry_with_its_hash_recomputed() {
+        let dir = ScratchDir::new("audit-rehashed");
+        let log = log(&dir, 3);
+
+        let mut entry = log.entries().unwrap().remove(1);
+        entry.after = Some("dockerd --other".to_string());
+        entry.hash = entry.compute_hash();
+        edit(&log, |lines| lines[1] = serde_json::to_string(&entry).unwrap());
+
+        assert_eq!(log.verify().unwrap(), Some(Broken::Chain { line: 3, seq: 3 }));
+    }
+
+    #[test]
+    fn deleted_entry() {
+        let dir = ScratchDir::new("audit-deleted");
+        let log = log(&dir, 3);
+
+        edit(&log, |lines| {
+            lines.remove(1);
+        });
+
+        assert_eq!(log.verify().unwrap(), Some(Broken::Sequence { line: 2, expected: 2, found: 3 }));
+    }
+
+    #[test]
+    fn truncated_tail() {
+        let dir = ScratchDir::new("audit-truncated");
+        let log = log(&dir, 3);
+
+        // the chain left is valid, only the head knows about entry 3
+        edit(&log, |lines| {
+            lines.pop();
+        });
+
+        assert_eq!(log.verify().unwrap(), Some(Broken::Truncated { expected: 3, found: 2 }));
+    }
+
+    #[test]
+    fn rewritten_log() {
+        let dir = ScratchDir::new("audit-rewritten");
+        let log = log(&dir, 2);
+
+        // a valid chain of other entries
+        let other = ScratchDir::new("audit-rewritten-other");
+        let forged = AuditLog::at(&other.join("audit.jsonl"));
+        for _ in 0..2 {
+            forged.append(Record::new(Action::Stop, "docker", Trigger::Cli), None).unwrap();
+        }
+        fs::copy(forged.path(), log.path()).unwrap();
+
+        assert_eq!(log.verify().unwrap(), Some(Broken::Rewritten { seq: 2 }));
+    }
+
+    #[test]
+    fn missing_head() {
+        let dir = ScratchDir::new("audit-no-head");
+        let log = log(&dir, 1);
+
+        fs::remove_file(log.head_path()).unwrap();
+
+        assert_eq!(log.verify().unwrap(), Some(Broken::NoHead));
+    }
+
+    #[test]
+    fn concurrent_appends_keep_the_chain() {
+        let dir = ScratchDir::new("audit-concurrent");
+        let path = dir.join("audit.jsonl");
+
+        let writers: Vec<_> = (0..8)
+            .map(|_| {
+                let path = path.clone();
+                thread::spawn(move || {
+                    let log = AuditLog::at(&path);
+                    for _ in 0..10 {
+                        log.append(Record::new(Action::Start, "docker", Trigger::Cli), Some("root".to_string())).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+
+        let log = AuditLog::at(&path);
+        assert_eq!(log.entries().unwrap().len(), 80);
+        assert_eq!(log.verify().unwrap(), None);
+    }
+}
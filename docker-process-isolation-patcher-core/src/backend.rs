@@ -0,0 +1,180 @@
+// Service backend abstraction
+// The watcher, doctor and CLI talk to services through this trait instead of the SCM directly,
+// so they can run against fakes. A backend is connected to one computer, its `Target`.
+
+use std::fmt;
+use std::path::PathBuf;
+
+use serde::Serialize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ServiceState {
+    Stopped,
+    StartPending,
+    StopPending,
+    Running,
+    ContinuePending,
+    PausePending,
+    Paused,
+}
+
+impl fmt::Display for ServiceState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = match self {
+            ServiceState::Stopped => "stopped",
+            ServiceState::StartPending => "start pending",
+            ServiceState::StopPending => "stop pending",
+            ServiceState::Running => "running",
+            ServiceState::ContinuePending => "continue pending",
+            ServiceState::PausePending => "pause pending",
+            ServiceState::Paused => "paused",
+        };
+
+        f.write_str(state)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ServiceConfig {
+    pub display_name: String,
+    /// full command line, executable included (the ImagePath registry value)
+    pub command_line: String,
+    /// services and groups (prefixed with `+`) this service depends on
+    pub dependencies: Vec<String>,
+    pub account_name: Option<String>,
+}
+
+/// A service to create, it's created as its own process, started automatically and run as System
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NewService {
+    pub name: String,
+    pub display_name: String,
+    pub description: String,
+    /// path on the target computer
+    pub executable: PathBuf,
+    pub arguments: Vec<String>,
+    /// services it has to start ahead of at boot. systemd orders it before them, the SCM can't
+    /// name services so it's put in a load order group started before ungrouped services
+    pub start_before: Vec<String>,
+}
+
+/// Computer whose services a backend manages
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Target {
+    Local,
+    Remote(String),
+}
+
+impl Target {
+    /// `--computer`, where no name, `.` and `localhost` mean this computer
+    pub fn from_computer(computer: Option<&str>) -> Self {
+        match computer.map(|c| c.trim().trim_start_matches('\\')) {
+            None => Target::Local,
+            Some(c) if c.is_empty() || c == "." || c.eq_ignore_ascii_case("localhost") => Target::Local,
+            Some(c) => Target::Remote(c.to_string()),
+        }
+    }
+
+    /// None for this computer
+    pub fn computer(&self) -> Option<&str> {
+        match self {
+            Target::Local => None,
+            Target::Remote(computer) => Some(computer),
+        }
+    }
+
+    pub fn is_local(&self) -> bool {
+        *self == Target::Local
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Target::Local => f.write_str("this computer"),
+            Target::Remote(computer) => f.write_str(computer),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct BackendError {
+    /// os error code, if the error came from the os
+    pub code: Option<i32>,
+    pub message: String,
+    /// the watcher refused or gave up on a change, e.g. a pre-stop hook vetoed or dockerd failed
+    /// the integrity check. Nothing the service manager did wrong
+    pub refused: bool,
+}
+
+impl BackendError {
+    pub fn new(message: impl Into<String>) -> Self {
+        BackendError { code: None, message: message.into(), refused: false }
+    }
+
+    pub fn refused(message: impl Into<String>) -> Self {
+        BackendError { code: None, message: message.into(), refused: true }
+    }
+
+    /// Whether trying the service manager call again later may succeed. Errors the patcher can't
+    /// get past by waiting, missing rights or a broken call, are fatal, everything else, like the
+    /// service manager being busy, starting up or unreachable, is transient
+    pub fn is_transient(&self) -> bool {
+        self.code.is_none_or(|code| !FATAL_OS_ERRORS.contains(&code))
+    }
+}
+
+/// ERROR_ACCESS_DENIED, ERROR_INVALID_HANDLE, ERROR_INVALID_PARAMETER and ERROR_INVALID_NAME
+#[cfg(windows)]
+const FATAL_OS_ERRORS: &[i32] = &[5, 6, 87, 123];
+/// EPERM, EACCES and EROFS
+#[cfg(not(windows))]
+const FATAL_OS_ERRORS: &[i32] = &[1, 13, 30];
+
+impl fmt::Display for BackendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.code {
+            Some(code) => write!(f, "{} (os error {})", self.message, code),
+            None => f.write_str(&self.message),
+        }
+    }
+}
+
+impl std::error::Error for BackendError {}
+
+impl From<std::io::Error> for BackendError {
+    fn from(e: std::io::Error) -> Self {
+        BackendError { code: e.raw_os_error(), message: e.to_string(), refused: false }
+    }
+}
+
+pub type BackendResult<T> = Result<T, BackendError>;
+
+/// Queries and controls services by name. Queries return None if the service doesn't exist
+pub trait ServiceBackend {
+    /// computer the services are on
+    fn target(&self) -> &Target;
+
+    fn query_state(&self, name: &str) -> BackendResult<Option<ServiceState>>;
+    fn query_config(&self, name: &str) -> BackendResult<Option<ServiceConfig>>;
+    /// Write the display name, command line and dependencies, the rest of the config is kept
+    fn change_config(&self, name: &str, config: &ServiceConfig) -> BackendResult<()>;
+    fn set_description(&self, name: &str, description: &str) -> BackendResult<()>;
+    /// Ask the service to start, without waiting for it
+    fn start(&self, name: &str) -> BackendResult<()>;
+    /// Ask the service to stop, without waiting for it
+    fn stop(&self, name: &str) -> BackendResult<()>;
+    fn create(&self, service: &NewService) -> BackendResult<()>;
+    /// Mark the service for deletion, it's removed once it's stopped and nothing holds it open
+    fn delete(&self, name: &str) -> BackendResult<()>;
+
+    /// Process id of the service, None if no process was launched yet or the backend can't tell
+    fn query_process_id(&self, _name: &str) -> BackendResult<Option<u32>> {
+        Ok(None)
+    }
+
+    fn exists(&self, name: &str) -> BackendResult<bool> {
+        Ok(self.query_state(name)?.is_some())
+    }
+}
@@ -0,0 +1,73 @@
+// Exponential backoff
+// The service waits longer after each failure in a row, so a service manager that's down isn't
+// asked every second, and goes back to its normal pace after the first success.
+
+use std::time::Duration;
+
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    /// failures in a row
+    failures: u32,
+}
+
+impl Backoff {
+    pub fn new(initial: Duration, max: Duration) -> Self {
+        Backoff { initial, max, failures: 0 }
+    }
+
+    /// Count a failure, and how long to wait before trying again: `initial`, doubled for every
+    /// failure before it, up to `max`
+    pub fn fail(&mut self) -> Duration {
+        let delay = self.initial.saturating_mul(2u32.saturating_pow(self.failures)).min(self.max);
+        self.failures = self.failures.saturating_add(1);
+        delay
+    }
+
+    pub fn failures(&self) -> u32 {
+        self.failures
+    }
+
+    pub fn reset(&mut self) {
+        self.failures = 0;
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::time::Duration;
+
+    use super::Backoff;
+
+    #[test]
+    fn delay_doubles_up_to_max() {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(10));
+
+        let delays: Vec<u64> = (0..6).map(|_| backoff.fail().as_secs()).collect();
+        assert_eq!(delays, vec![1, 2, 4, 8, 10, 10]);
+        assert_eq!(backoff.failures(), 6);
+    }
+
+    #[test]
+    fn reset_starts_over() {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(10));
+        backoff.fail();
+        backoff.fail();
+
+        backoff.reset();
+
+        assert_eq!(backoff.failures(), 0);
+        assert_eq!(backoff.fail(), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn many_failures_dont_overflow() {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(300));
+        for _ in 0..100 {
+            backoff.fail();
+        }
+
+        assert_eq!(backoff.fail(), Duration::from_secs(300));
+    }
+}
@@ -0,0 +1,307 @@
+// Batch mode
+// Runs one command against every computer of an inventory file, one after the other, and keeps
+// a result per computer. A computer which can't be reached or fails doesn't stop the others.
+// The inventory has one computer name per line, blank lines and lines starting with `#` are
+// skipped.
+
+use std::io;
+use std::path::Path;
+
+use log::{error, info};
+use serde::Serialize;
+
+use super::audit::{AuditLog, Trigger};
+use super::backend::{BackendResult, ServiceBackend, Target};
+use super::config::Config;
+use super::lock::PatchLock;
+use super::manage::{Outcome, PatcherService};
+use super::plan;
+use super::state::State;
+use super::status;
+use super::watcher::Watcher;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+    Install,
+    Uninstall,
+    Start,
+    Stop,
+    Status,
+    Plan,
+    PatchNow,
+}
+
+impl Command {
+    pub const NAMES: [&'static str; 7] = ["install", "uninstall", "start", "stop", "status", "plan", "patch-now"];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "install" => Some(Command::Install),
+            "uninstall" => Some(Command::Uninstall),
+            "start" => Some(Command::Start),
+            "stop" => Some(Command::Stop),
+            "status" => Some(Command::Status),
+            "plan" => Some(Command::Plan),
+            "patch-now" => Some(Command::PatchNow),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct HostResult {
+    pub computer: String,
+    pub ok: bool,
+    pub message: String,
+}
+
+pub fn parse_inventory(contents: &str) -> Vec<Target> {
+    let mut targets = vec![];
+
+    for line in contents.lines().map(str::trim).filter(|l| !l.is_empty() && !l.starts_with('#')) {
+        let target = Target::from_computer(Some(line));
+        if !targets.contains(&target) {
+            targets.push(target);
+        }
+    }
+
+    targets
+}
+
+pub fn read_inventory(path: &Path) -> io::Result<Vec<Target>> {
+    Ok(parse_inventory(&std::fs::read_to_string(path)?))
+}
+
+pub struct Batch<'a> {
+    pub command: Command,
+    pub config: &'a Config,
+    /// exe path on the target computers, for install
+    pub binary: &'a Path,
+    /// on this computer, shared by all targets
+    pub audit_log: &'a AuditLog,
+    pub user: Option<String>,
+}
+
+impl<'a> Batch<'a> {
+    /// Run the command on every target, connecting with `connect`
+    pub fn run<B, C>(&self, targets: &[Target], connect: C) -> Vec<HostResult>
+    where
+        B: ServiceBackend,
+        C: Fn(&Target) -> BackendResult<B>,
+    {
+        targets
+            .iter()
+            .map(|target| {
+                info!("running {:?} on {}", self.command, target);
+
+                let res = connect(target)
+                    .map_err(|e| format!("couldn't connect: {}", e))
+                    .and_then(|backend| self.run_on(backend));
+
+                if let Err(e) = &res {
+                    error!("{:?} failed on {}: {}", self.command, target, e);
+                }
+
+                HostResult {
+                    computer: target.to_string(),
+                    ok: res.is_ok(),
+                    message: res.unwrap_or_else(|e| e),
+                }
+            })
+            .collect()
+    }
+
+    fn run_on<B: ServiceBackend>(&self, backend: B) -> Result<String, String> {
+        let service = PatcherService::new(&backend, self.audit_log, self.user.clone());
+        let state_path = State::path_for(backend.target());
+
+        match self.command {
+            Command::Install => outcome(service.install(self.binary)),
+            Command::Uninstall => outcome(service.uninstall()),
+            Command::Start => outcome(service.start()),
+            Command::Stop => outcome(service.stop(|| ())),
+
+            Command::Status => {
+                let report = status::collect(&backend, &state_path, self.config);
+
+                if report.errors.is_empty() {
+                    Ok(status::summary(&report))
+                } else {
+                    Err(report.errors.join("; "))
+                }
+            }
+
+            Command::Plan => plan::collect(&backend, &state_path, self.config).map(|p| plan::summary(&p)).map_err(|e| e.to_string()),
+
+            Command::PatchNow => {
+                // hosts being patched by someone else are reported, not waited for
+                let _lock = PatchLock::try_acquire(backend.target(), "batch patch-now").map_err(|e| e.to_string())?;
+                let audit_log = AuditLog::at(self.audit_log.path());
+                let config = self.config.clone();
+                let mut watcher = Watcher::new(backend, config.watcher, config.hooks, &config.proxy.upstream, state_path, audit_log).desktop(config.desktop)
+                    .integrity(config.integrity);
+
+                watcher.patch_now(Trigger::Cli, self.user.clone()).map_err(|e| e.to_string())
+            }
+        }
+    }
+}
+
+fn outcome(res: BackendResult<Outcome>) -> Result<String, String> {
+    match res {
+        Ok(outcome) if outcome.failed() => Err(outcome.to_string()),
+        Ok(outcome) => Ok(outcome.to_string()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+pub fn render_table(results: &[HostResult]) -> String {
+    let width = results.iter().map(|r| r.computer.len()).chain(Some("COMPUTER".len())).max().unwrap_or(0);
+
+    let mut out = format!("{:width$}  {:6}  {}\n", "COMPUTER", "RESULT", "MESSAGE", width = width);
+    for result in results {
+        let ok = if result.ok { "ok" } else { "failed" };
+        out.push_str(&format!("{:width$}  {:6}  {}\n", result.computer, ok, result.message, width = width));
+    }
+
+    let failed = results.iter().filter(|r| !r.ok).count();
+    out.push_str(&format!("{} computers, {} failed\n", results.len(), failed));
+
+    out
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::collections::HashMap;
+    use std::path::Path;
+
+    use super::{parse_inventory, render_table, Batch, Command, HostResult};
+    use crate::audit::{Action, AuditLog};
+    use crate::backend::{BackendError, BackendResult, ServiceState, Target};
+    use crate::config::Config;
+    use crate::patch::{self, Flag};
+    use crate::shared::*;
+    use crate::testing::{FakeBackend, ScratchDir};
+
+    const DOCKERD: &str = "/usr/bin/dockerd -H fd://";
+
+    fn config() -> Config {
+        let mut config = Config::default();
+        config.watcher.flags = vec![Flag::new("--exec-opt", Some("isolation=process"))];
+        config
+    }
+
+    fn remote(computer: &str) -> Target {
+        Target::Remote(computer.to_string())
+    }
+
+    /// Backends by computer, the ones missing can't be reached
+    fn run(command: Command, config: &Config, audit_log: &AuditLog, backends: &HashMap<Target, FakeBackend>, targets: &[Target]) -> Vec<HostResult> {
+        let batch = Batch { command, config, binary: Path::new("/opt/patcher/patcher"), audit_log, user: Some("admin".to_string()) };
+
+        batch.run(targets, |target| -> BackendResult<FakeBackend> {
+            backends.get(target).cloned().ok_or_else(|| BackendError::new("no route to host"))
+        })
+    }
+
+    #[test]
+    fn inventory() {
+        let targets = parse_inventory("# web servers\nweb1\n  web2  \n\nweb1\n\\\\web3\nlocalhost\n");
+        assert_eq!(targets, vec![remote("web1"), remote("web2"), remote("web3"), Target::Local]);
+    }
+
+    #[test]
+    fn plan() {
+        let dir = ScratchDir::new("batch-plan");
+        let audit_log = AuditLog::at(&dir.join("audit.log"));
+        let config = config();
+
+        let patched = patch::join_command_line(&patch::patch_args(&patch::split_command_line(DOCKERD), &config.watcher.flags));
+        let backends: HashMap<Target, FakeBackend> = vec![
+            (remote("web1"), FakeBackend::new(remote("web1")).with(DOCKER_SERVICE_NAME, ServiceState::Running, DOCKERD)),
+            (remote("web2"), FakeBackend::new(remote("web2")).with(DOCKER_SERVICE_NAME, ServiceState::Running, &patched)),
+            (remote("web4"), FakeBackend::new(remote("web4"))),
+        ]
+        .into_iter()
+        .collect();
+
+        let targets = [remote("web1"), remote("web2"), remote("web3"), remote("web4")];
+        let results = run(Command::Plan, &config, &audit_log, &backends, &targets);
+
+        assert_eq!(results.iter().map(|r| (r.computer.as_str(), r.ok)).collect::<Vec<_>>(), [("web1", true), ("web2", true), ("web3", false), ("web4", true)]);
+        assert!(results[0].message.starts_with("patch-now would take 4 steps: stop docker; change the docker command line to /usr/bin/dockerd --exec-opt isolation=process -H fd://;"), "{}", results[0].message);
+        assert_eq!(results[1].message, "nothing to do, docker is patched");
+        assert_eq!(results[2].message, "couldn't connect: no route to host");
+        assert_eq!(results[3].message, "docker isn't installed");
+
+        // a plan only reads
+        assert_eq!(backends[&remote("web1")].config(DOCKER_SERVICE_NAME).unwrap().command_line, DOCKERD);
+        assert_eq!(backends[&remote("web1")].state(DOCKER_SERVICE_NAME), Some(ServiceState::Running));
+        assert!(audit_log.entries().unwrap().is_empty());
+
+        let table = render_table(&results);
+        assert!(table.starts_with("COMPUTER  RESULT  MESSAGE\nweb1      ok      patch-now would take 4 steps"), "{}", table);
+        assert!(table.contains("\nweb3      failed  couldn't connect: no route to host\n"));
+        assert!(table.ends_with("4 computers, 1 failed\n"));
+    }
+
+    #[test]
+    fn execute() {
+        let dir = ScratchDir::new("batch-execute");
+        let audit_log = AuditLog::at(&dir.join("audit.log"));
+        let config = config();
+
+        let backends: HashMap<Target, FakeBackend> = vec![
+            (remote("web1"), FakeBackend::new(remote("web1"))),
+            (remote("web2"), FakeBackend::new(remote("web2")).with(SERVICE_NAME, ServiceState::Running, "/opt/patcher/patcher run-service")),
+        ]
+        .into_iter()
+        .collect();
+        let targets = [remote("web1"), remote("web2"), remote("web3")];
+        let messages = |results: Vec<HostResult>| results.into_iter().map(|r| (r.ok, r.message)).collect::<Vec<_>>();
+
+        let results = run(Command::Install, &config, &audit_log, &backends, &targets);
+        assert_eq!(
+            messages(results)[..2],
+            [(true, "Installed service".to_string()), (true, "Service already installed. Try the uninstall command".to_string())]
+        );
+        let installed = backends[&remote("web1")].config(SERVICE_NAME).unwrap();
+        assert_eq!(installed.command_line, "/opt/patcher/patcher run-service");
+
+        // one unreachable computer doesn't stop the others
+        let results = run(Command::Start, &config, &audit_log, &backends, &targets);
+        assert_eq!(
+            messages(results),
+            [
+                (true, "Started service".to_string()),
+                (true, "Service already running".to_string()),
+                (false, "couldn't connect: no route to host".to_string())
+            ]
+        );
+        assert_eq!(backends[&remote("web1")].state(SERVICE_NAME), Some(ServiceState::Running));
+
+        let results = run(Command::Stop, &config, &audit_log, &backends, &targets[..2]);
+        assert!(results.iter().all(|r| r.ok && r.message == "Stopped service"));
+        assert_eq!(backends[&remote("web2")].state(SERVICE_NAME), Some(ServiceState::Stopped));
+
+        let results = run(Command::Uninstall, &config, &audit_log, &backends, &targets[..1]);
+        assert_eq!(results[0].message, "Uninstalled service");
+        assert!(backends[&remote("web1")].config(SERVICE_NAME).is_none());
+
+        // every change on every computer, by whoever ran the batch
+        let entries = audit_log.entries().unwrap();
+        let actions: Vec<(Action, Option<&str>)> = entries.iter().map(|e| (e.action, e.computer.as_deref())).collect();
+        assert_eq!(
+            actions,
+            [
+                (Action::Install, Some("web1")),
+                (Action::Start, Some("web1")),
+                (Action::Stop, Some("web1")),
+                (Action::Stop, Some("web2")),
+                (Action::Uninstall, Some("web1"))
+            ]
+        );
+        assert!(entries.iter().all(|e| e.user.as_deref() == Some("admin") && e.error.is_none()));
+        assert!(audit_log.verify().unwrap().is_none());
+    }
+}
@@ -0,0 +1,386 @@
+// Host/image Windows build compatibility
+// Process isolation shares the host kernel, so it only works when the image was built for the
+// same Windows build as the host. Hyper-V isolation has no such restriction.
+
+use std::fmt;
+use std::str::FromStr;
+
+use serde_json::Value;
+
+use super::config::HostConfig;
+
+// Windows 11 hosts can run Windows Server 2022 images with process isolation
+const SERVER_2022_BUILD: u32 = 20348;
+const WINDOWS_11_BUILD: u32 = 22000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OsVersion {
+    pub major: u32,
+    pub minor: u32,
+    pub build: u32,
+    /// update build revision, e.g. 2114 in 10.0.17763.2114
+    pub revision: Option<u32>,
+}
+
+impl FromStr for OsVersion {
+    type Err = CompatError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = s.trim().split('.').collect();
+        let number = |i: usize| -> Result<u32, CompatError> {
+            parts[i].parse().map_err(|_| CompatError::InvalidVersion(s.to_string()))
+        };
+
+        match parts.len() {
+            // a bare build number, as the registry stores it
+            1 => Ok(OsVersion { major: 10, minor: 0, build: number(0)?, revision: None }),
+            3 => Ok(OsVersion { major: number(0)?, minor: number(1)?, build: number(2)?, revision: None }),
+            4 => Ok(OsVersion { major: number(0)?, minor: number(1)?, build: number(2)?, revision: Some(number(3)?) }),
+            _ => Err(CompatError::InvalidVersion(s.to_string())),
+        }
+    }
+}
+
+impl fmt::Display for OsVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.build)?;
+
+        if let Some(revision) = self.revision {
+            write!(f, ".{}", revision)?;
+        }
+
+        if let Some(name) = release_name(self.build) {
+            write!(f, " ({})", name)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Marketing name of a Windows build, as used in image tags
+pub fn release_name(build: u32) -> Option<&'static str> {
+    Some(match build {
+        14393 => "ltsc2016",
+        16299 => "1709",
+        17134 => "1803",
+        17763 => "ltsc2019",
+        18362 => "1903",
+        18363 => "1909",
+        19041 => "2004",
+        19042 => "20H2",
+        19043 => "21H1",
+        19044 => "21H2",
+        20348 => "ltsc2022",
+        22000 => "Windows 11",
+        _ => return None,
+    })
+}
+
+/// One platform an image is available for
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImagePlatform {
+    pub os: String,
+    pub os_version: Option<OsVersion>,
+    pub architecture: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Compatibility {
+    Compatible,
+    /// process isolation works, but isn't the recommended setup
+    Warning(String),
+    Incompatible(String),
+    /// not enough information to tell
+    Unknown(String),
+}
+
+impl Compatibility {
+    /// Unknown is given the benefit of the doubt, same as without the check
+    pub fn allows_process(&self) -> bool {
+        !matches!(self, Compatibility::Incompatible(_))
+    }
+}
+
+impl fmt::Display for Compatibility {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Compatibility::Compatible => write!(f, "compatible with process isolation"),
+            Compatibility::Warning(reason) => write!(f, "compatible with process isolation, but {}", reason),
+            Compatibility::Incompatible(reason) => write!(f, "not compatible with process isolation: {}", reason),
+            Compatibility::Unknown(reason) => write!(f, "unknown: {}", reason),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum CompatError {
+    InvalidVersion(String),
+    /// json isn't a manifest, manifest list, image config or image inspect output
+    UnknownFormat,
+    #[cfg(windows)]
+    Registry(std::io::Error),
+}
+
+impl fmt::Display for CompatError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CompatError::InvalidVersion(v) => write!(f, "invalid os version {:?}", v),
+            CompatError::UnknownFormat => write!(f, "not an image manifest, manifest list or image config"),
+            #[cfg(windows)]
+            CompatError::Registry(e) => write!(f, "failed to read host build from registry: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for CompatError {}
+
+/// Check whether an image built for `image` can run with process isolation on `host`
+pub fn check(host: &OsVersion, image: &ImagePlatform) -> Compatibility {
+    if !image.os.eq_ignore_ascii_case("windows") {
+        return Compatibility::Incompatible(format!("{} image can't run in a windows container", image.os));
+    }
+
+    let version = match &image.os_version {
+        Some(version) => version,
+        None => return Compatibility::Unknown("image doesn't declare its os version".to_string()),
+    };
+
+    if version.build == host.build {
+        return match (version.revision, host.revision) {
+            (Some(image_rev), Some(host_rev)) if image_rev != host_rev => Compatibility::Warning(format!(
+                "image revision {} differs from host revision {}, matching updates are recommended",
+                image_rev, host_rev
+            )),
+            _ => Compatibility::Compatible,
+        };
+    }
+
+    if version.build == SERVER_2022_BUILD && host.build >= WINDOWS_11_BUILD {
+        return Compatibility::Compatible;
+    }
+
+    Compatibility::Incompatible(format!("image build {} doesn't match host build {}", version, host))
+}
+
+/// Best result over every windows platform of an image. A manifest list is compatible if any
+/// of its entries is, since docker pulls the matching one
+pub fn check_any(host: &OsVersion, platforms: &[ImagePlatform]) -> Compatibility {
+    let results: Vec<Compatibility> = platforms.iter().map(|p| check(host, p)).collect();
+
+    let rank = |c: &Compatibility| match c {
+        Compatibility::Compatible => 0,
+        Compatibility::Warning(_) => 1,
+        Compatibility::Unknown(_) => 2,
+        Compatibility::Incompatible(_) => 3,
+    };
+
+    results
+        .into_iter()
+        .min_by_key(rank)
+        .unwrap_or_else(|| Compatibility::Unknown("image has no platforms".to_string()))
+}
+
+/// Platforms of an image, from a manifest list/OCI index (`docker manifest inspect`), an image
+/// config blob, or `docker image inspect` output
+pub fn image_platforms(json: &Value) -> Result<Vec<ImagePlatform>, CompatError> {
+    // docker image inspect wraps its result in an array
+    if let Value::Array(images) = json {
+        let mut platforms = vec![];
+        for image in images {
+            platforms.extend(image_platforms(image)?);
+        }
+        return Ok(platforms);
+    }
+
+    // manifest list or OCI index
+    if let Some(manifests) = json.get("manifests").and_then(Value::as_array) {
+        return Ok(manifests
+            .iter()
+            .filter_map(|m| m.get("platform"))
+            .map(|p| platform_from(p, "os", "os.version", "architecture"))
+            .collect());
+    }
+
+    // image inspect output
+    if json.get("Os").is_some() {
+        return Ok(vec![platform_from(json, "Os", "OsVersion", "Architecture")]);
+    }
+
+    // image config blob
+    if json.get("os").is_some() {
+        return Ok(vec![platform_from(json, "os", "os.version", "architecture")]);
+    }
+
+    Err(CompatError::UnknownFormat)
+}
+
+fn platform_from(json: &Value, os: &str, os_version: &str, architecture: &str) -> ImagePlatform {
+    let field = |name: &str| json.get(name).and_then(Value::as_str).filter(|s| !s.is_empty());
+
+    ImagePlatform {
+        os: field(os).unwrap_or("unknown").to_string(),
+        os_version: field(os_version).and_then(|v| v.parse().ok()),
+        architecture: field(architecture).map(str::to_string),
+    }
+}
+
+/// Host build from config, falling back to the registry on Windows. None if it can't be known
+pub fn host_os_version(config: &HostConfig) -> Result<Option<OsVersion>, CompatError> {
+    if let Some(version) = &config.os_version {
+        return version.parse().map(Some);
+    }
+
+    registry_os_version()
+}
+
+#[cfg(windows)]
+fn registry_os_version() -> Result<Option<OsVersion>, CompatError> {
+    use winreg::enums::HKEY_LOCAL_MACHINE;
+    use winreg::RegKey;
+
+    let key = RegKey::predef(HKEY_LOCAL_MACHINE)
+        .open_subkey(r"SOFTWARE\Microsoft\Windows NT\CurrentVersion")
+        .map_err(CompatError::Registry)?;
+
+    let build: String = key.get_value("CurrentBuildNumber").map_err(CompatError::Registry)?;
+    let revision: Option<u32> = key.get_value("UBR").ok();
+
+    let mut version: OsVersion = build.parse()?;
+    version.revision = revision;
+
+    Ok(Some(version))
+}
+
+#[cfg(not(windows))]
+fn registry_os_version() -> Result<Option<OsVersion>, CompatError> {
+    Ok(None)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use serde_json::json;
+
+    use super::{check, check_any, host_os_version, image_platforms, Compatibility, ImagePlatform, OsVersion};
+    use crate::config::HostConfig;
+
+    fn version(s: &str) -> OsVersion {
+        s.parse().unwrap()
+    }
+
+    fn windows(os_version: Option<&str>) -> ImagePlatform {
+        ImagePlatform { os: "windows".to_string(), os_version: os_version.map(version), architecture: Some("amd64".to_string()) }
+    }
+
+    /// Compatibility without its reason
+    fn kind(c: &Compatibility) -> &'static str {
+        match c {
+            Compatibility::Compatible => "compatible",
+            Compatibility::Warning(_) => "warning",
+            Compatibility::Incompatible(_) => "incompatible",
+            Compatibility::Unknown(_) => "unknown",
+        }
+    }
+
+    #[test]
+    fn versions() {
+        assert_eq!(version("10.0.17763.2114"), OsVersion { major: 10, minor: 0, build: 17763, revision: Some(2114) });
+        assert_eq!(version("17763"), OsVersion { major: 10, minor: 0, build: 17763, revision: None });
+        assert_eq!(version("10.0.20348").to_string(), "10.0.20348 (ltsc2022)");
+
+        for invalid in ["", "10.0", "10.0.x", "10.0.17763.1.2"] {
+            assert!(invalid.parse::<OsVersion>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn check_table() {
+        let cases = [
+            // host, image, result
+            ("10.0.17763.2114", Some("10.0.17763.2114"), "compatible"),
+            ("10.0.17763", Some("10.0.17763.2114"), "compatible"),
+            ("10.0.17763.2114", Some("10.0.17763"), "compatible"),
+            ("10.0.17763.2114", Some("10.0.17763.1999"), "warning"),
+            ("10.0.17763.2114", Some("10.0.20348.100"), "incompatible"),
+            ("10.0.20348.100", Some("10.0.17763.2114"), "incompatible"),
+            // Windows 11 runs Server 2022 images, not the other way around
+            ("10.0.22000.100", Some("10.0.20348.100"), "compatible"),
+            ("10.0.22621.100", Some("10.0.20348.100"), "compatible"),
+            ("10.0.20348.100", Some("10.0.22000.100"), "incompatible"),
+            ("10.0.19044.100", Some("10.0.20348.100"), "incompatible"),
+            ("10.0.17763.2114", None, "unknown"),
+        ];
+
+        for (host, image, expected) in cases {
+            let result = check(&version(host), &windows(image));
+            assert_eq!(kind(&result), expected, "host {} image {:?}: {}", host, image, result);
+        }
+
+        let linux = ImagePlatform { os: "linux".to_string(), os_version: None, architecture: None };
+        assert_eq!(kind(&check(&version("10.0.17763"), &linux)), "incompatible");
+    }
+
+    #[test]
+    fn check_any_takes_the_best_platform() {
+        let host = version("10.0.17763.2114");
+        let cases: [(&[Option<&str>], &str); 5] = [
+            (&[Some("10.0.20348.100"), Some("10.0.17763.2114")], "compatible"),
+            (&[Some("10.0.17763.1999"), Some("10.0.20348.100")], "warning"),
+            (&[None, Some("10.0.20348.100")], "unknown"),
+            (&[Some("10.0.20348.100"), Some("10.0.14393.100")], "incompatible"),
+            (&[], "unknown"),
+        ];
+
+        for (images, expected) in cases {
+            let platforms: Vec<ImagePlatform> = images.iter().map(|v| windows(*v)).collect();
+            assert_eq!(kind(&check_any(&host, &platforms)), expected, "{:?}", images);
+        }
+    }
+
+    #[test]
+    fn platforms_of_a_manifest_list() {
+        let list = json!({
+            "schemaVersion": 2,
+            "mediaType": "application/vnd.docker.distribution.manifest.list.v2+json",
+            "manifests": [
+                { "digest": "sha256:1", "platform": { "architecture": "amd64", "os": "windows", "os.version": "10.0.17763.2114" } },
+                { "digest": "sha256:2", "platform": { "architecture": "amd64", "os": "windows", "os.version": "10.0.20348.169" } },
+                { "digest": "sha256:3", "platform": { "architecture": "arm64", "os": "linux" } },
+                { "digest": "sha256:4" }
+            ]
+        });
+
+        let platforms = image_platforms(&list).unwrap();
+
+        assert_eq!(
+            platforms.iter().map(|p| (p.os.as_str(), p.os_version.map(|v| v.build))).collect::<Vec<_>>(),
+            vec![("windows", Some(17763)), ("windows", Some(20348)), ("linux", None)]
+        );
+        // the host's build is pulled, the others don't matter
+        assert_eq!(check_any(&version("10.0.20348.169"), &platforms), Compatibility::Compatible);
+        assert_eq!(kind(&check_any(&version("10.0.19044.100"), &platforms)), "incompatible");
+    }
+
+    #[test]
+    fn platforms_of_an_image() {
+        let config = json!({ "architecture": "amd64", "os": "windows", "os.version": "10.0.17763.2114", "config": {} });
+        assert_eq!(image_platforms(&config).unwrap(), vec![windows(Some("10.0.17763.2114"))]);
+
+        let inspect = json!([{ "Id": "sha256:1", "Architecture": "amd64", "Os": "windows", "OsVersion": "10.0.17763.2114" }]);
+        assert_eq!(image_platforms(&inspect).unwrap(), vec![windows(Some("10.0.17763.2114"))]);
+
+        // an empty version is no version
+        let unversioned = json!({ "architecture": "amd64", "os": "windows", "os.version": "" });
+        assert_eq!(image_platforms(&unversioned).unwrap(), vec![windows(None)]);
+
+        assert!(image_platforms(&json!({ "name": "nginx" })).is_err());
+    }
+
+    #[test]
+    fn host_version_from_the_config() {
+        let config = HostConfig { os_version: Some("10.0.17763.2114".to_string()) };
+        assert_eq!(host_os_version(&config).unwrap(), Some(version("10.0.17763.2114")));
+
+        assert!(host_os_version(&HostConfig { os_version: Some("ltsc2019".to_string()) }).is_err());
+        assert_eq!(host_os_version(&HostConfig::default()).unwrap(), None);
+    }
+}
@@ -0,0 +1,470 @@
+// Compose file rewriting
+// Sets `isolation` of every service in a docker-compose file according to the isolation policy,
+// so a compose file asks for the right isolation on machines without the patcher or the proxy
+// too. The file is edited line by line instead of being parsed and written out again, which keeps
+// comments, ordering, quoting and line endings as they were. That works for services written in
+// block style, the way compose files are written, anything else is reported instead.
+
+use std::fmt;
+
+use serde::Serialize;
+
+use super::policy::{Decision, IsolationPolicy, PROCESS_ISOLATION};
+
+const BOM: char = '\u{feff}';
+
+/// The isolation of one service, set or changed
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Change {
+    pub service: String,
+    /// line of the isolation key in the rewritten file, counting from 1
+    pub line: usize,
+    /// None if the service didn't set isolation
+    pub from: Option<String>,
+    pub to: String,
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: service {}: isolation {} -> {}", self.line, self.service, self.from.as_deref().unwrap_or("unset"), self.to)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Rewrite {
+    /// the file with the changes, the same as before without any
+    pub contents: String,
+    pub changes: Vec<Change>,
+}
+
+#[derive(Debug)]
+pub enum ComposeError {
+    /// no top level `services`, like compose files of format version 1
+    NoServices,
+    /// a service which isn't a block mapping, e.g. `web: {image: nginx}` or an alias
+    NotBlockStyle { service: String, line: usize },
+}
+
+impl fmt::Display for ComposeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ComposeError::NoServices => f.write_str("no top level services key, compose file format version 1 isn't supported"),
+            ComposeError::NotBlockStyle { service, line } => {
+                write!(f, "line {}: {} isn't written in block style, set its isolation by hand", line, service)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ComposeError {}
+
+/// How a service's isolation was set, by line index in the original file
+enum Edit {
+    Replaced(usize),
+    /// a line was added after this one
+    Inserted(usize),
+}
+
+/// A line without its line ending, which is kept as it was
+struct Line<'a> {
+    text: &'a str,
+    ending: &'a str,
+}
+
+impl Line<'_> {
+    fn indent(&self) -> usize {
+        self.text.len() - self.text.trim_start_matches(' ').len()
+    }
+
+    /// blank and comment lines don't end blocks
+    fn is_filler(&self) -> bool {
+        let text = self.text.trim();
+        text.is_empty() || text.starts_with('#')
+    }
+}
+
+/// A `key: value` line split up, `comment` keeps the whitespace before it
+struct Entry<'a> {
+    key: String,
+    /// everything up to and including the colon
+    head: &'a str,
+    /// whitespace between the colon and the value
+    gap: &'a str,
+    value: &'a str,
+    comment: &'a str,
+}
+
+impl Entry<'_> {
+    fn parse(text: &str) -> Option<Entry<'_>> {
+        let trimmed = text.trim_start_matches(' ');
+
+        let (key, colon) = match trimmed.chars().next()? {
+            quote @ ('"' | '\'') => {
+                let end = trimmed[1..].find(quote)? + 1;
+                (trimmed[1..end].to_string(), end + 1 + trimmed[end + 1..].find(':')?)
+            }
+            '-' | '#' => return None,
+            _ => {
+                let colon = trimmed.find(": ").or_else(|| trimmed.strip_suffix(':').map(str::len))?;
+                (trimmed[..colon].trim_end().to_string(), colon)
+            }
+        };
+
+        let colon = text.len() - trimmed.len() + colon;
+        let rest = &text[colon + 1..];
+        let value_start = rest.len() - rest.trim_start().len();
+        let comment_start = comment_start(rest).unwrap_or(rest.len());
+        let value_end = rest[..comment_start].trim_end().len().max(value_start);
+
+        Some(Entry {
+            key,
+            head: &text[..=colon],
+            gap: &rest[..value_start],
+            value: &rest[value_start..value_end],
+            comment: &rest[value_end..],
+        })
+    }
+
+    /// the value without quotes, None if the value is on the following lines
+    fn scalar(&self) -> Option<&str> {
+        let value = self.value;
+        let unquoted = ['"', '\'']
+            .iter()
+            .find_map(|&q| value.strip_prefix(q).and_then(|v| v.strip_suffix(q)))
+            .unwrap_or(value);
+
+        Some(unquoted).filter(|v| !v.is_empty())
+    }
+
+    /// The line with `value` instead, quoted like the old one
+    fn with_value(&self, value: &str) -> String {
+        if self.value.is_empty() {
+            let comment = if self.comment.is_empty() { String::new() } else { format!(" {}", self.comment) };
+            return format!("{} {}{}", self.head, value, comment);
+        }
+
+        let quote = self.value.chars().next().filter(|c| *c == '"' || *c == '\'');
+        let gap = if self.gap.is_empty() { " " } else { self.gap };
+
+        match quote {
+            Some(q) => format!("{}{}{}{}{}{}", self.head, gap, q, value, q, self.comment),
+            None => format!("{}{}{}{}", self.head, gap, value, self.comment),
+        }
+    }
+}
+
+/// Where a ` #` comment starts, outside of quotes
+fn comment_start(text: &str) -> Option<usize> {
+    let mut quote = None;
+    let mut previous = ' ';
+
+    for (i, c) in text.char_indices() {
+        match quote {
+            Some(q) if c == q => quote = None,
+            Some(_) => (),
+            None if c == '"' || c == '\'' => quote = Some(c),
+            None if c == '#' && previous.is_whitespace() => return Some(i),
+            None => (),
+        }
+        previous = c;
+    }
+
+    None
+}
+
+fn split_lines(contents: &str) -> Vec<Line<'_>> {
+    contents
+        .split_inclusive('\n')
+        .map(|line| {
+            let text = line.trim_end_matches('\n').trim_end_matches('\r');
+            Line { text, ending: &line[text.len()..] }
+        })
+        .collect()
+}
+
+/// Set the isolation of every service in `contents` to what `policy` wants for it. The image
+/// compatibility with the host isn't known here, so images are treated as compatible
+pub fn rewrite(contents: &str, policy: &IsolationPolicy) -> Result<Rewrite, ComposeError> {
+    let (bom, body) = match contents.strip_prefix(BOM) {
+        Some(body) => (BOM.to_string(), body),
+        None => (String::new(), contents),
+    };
+    let lines = split_lines(body);
+
+    let services = lines
+        .iter()
+        .position(|l| l.indent() == 0 && Entry::parse(l.text).is_some_and(|e| e.key == "services"))
+        .ok_or(ComposeError::NoServices)?;
+
+    let header = Entry::parse(lines[services].text).unwrap();
+    if !header.value.is_empty() {
+        return Err(ComposeError::NotBlockStyle { service: "services".to_string(), line: services + 1 });
+    }
+
+    let end = block_end(&lines, services, 0);
+    let service_indent = match lines[services + 1..end].iter().find(|l| !l.is_filler()) {
+        Some(line) => line.indent(),
+        None => return Ok(Rewrite { contents: contents.to_string(), changes: vec![] }),
+    };
+
+    // new text of changed lines, and lines to insert after a line
+    let mut replaced: Vec<Option<String>> = vec![None; lines.len()];
+    let mut inserted: Vec<Option<String>> = vec![None; lines.len()];
+    let mut changes = vec![];
+
+    let mut i = services + 1;
+    while i < end {
+        let line = &lines[i];
+        if line.is_filler() || line.indent() != service_indent {
+            i += 1;
+            continue;
+        }
+
+        let entry = match Entry::parse(line.text) {
+            Some(entry) => entry,
+            None => {
+                i += 1;
+                continue;
+            }
+        };
+
+        if !entry.value.is_empty() {
+            return Err(ComposeError::NotBlockStyle { service: entry.key, line: i + 1 });
+        }
+
+        let service_end = block_end(&lines, i, service_indent);
+        let key_indent = lines[i + 1..service_end]
+            .iter()
+            .find(|l| !l.is_filler())
+            .map_or(service_indent + 2, Line::indent);
+
+        let mut image = None;
+        let mut isolation = None;
+        for (j, line) in lines.iter().enumerate().take(service_end).skip(i + 1) {
+            if line.is_filler() || line.indent() != key_indent {
+                continue;
+            }
+
+            match Entry::parse(line.text) {
+                Some(e) if e.key == "image" => image = Some((j, e.scalar().map(str::to_string))),
+                Some(e) if e.key == "isolation" => isolation = Some((j, e)),
+                _ => (),
+            }
+        }
+
+        let requested = isolation.as_ref().and_then(|(_, e)| e.scalar());
+        let to = match policy.decide(image.as_ref().and_then(|(_, i)| i.as_deref()), requested, None) {
+            // kept as it is, only written the way docker spells it
+            Decision::Keep => requested.map(str::to_lowercase).unwrap_or_else(|| PROCESS_ISOLATION.to_string()),
+            Decision::Rewrite { to, .. } => to.to_string(),
+            // the proxy would refuse it, process isolation is what it accepts
+            Decision::Reject(_) => PROCESS_ISOLATION.to_string(),
+        };
+
+        if requested != Some(to.as_str()) {
+            let from = requested.map(str::to_string);
+
+            let at = match &isolation {
+                Some((j, e)) => {
+                    replaced[*j] = Some(e.with_value(&to));
+                    Edit::Replaced(*j)
+                }
+                None => {
+                    // right below the image, or first in the service if there's none
+                    let after = image.as_ref().map_or(i, |(j, _)| *j);
+                    inserted[after] = Some(format!("{}isolation: {}", " ".repeat(key_indent), to));
+                    Edit::Inserted(after)
+                }
+            };
+
+            changes.push((entry.key, at, from, to));
+        }
+
+        i = service_end;
+    }
+
+    let mut out = bom;
+    // where each line of `contents` ended up, counting from 0
+    let mut moved_to = Vec::with_capacity(lines.len());
+
+    for (i, line) in lines.iter().enumerate() {
+        moved_to.push(out.matches('\n').count());
+        out.push_str(replaced[i].as_deref().unwrap_or(line.text));
+
+        match &inserted[i] {
+            // the last line may not have a line ending
+            Some(new) if line.ending.is_empty() => {
+                out.push('\n');
+                out.push_str(new);
+            }
+            Some(new) => {
+                out.push_str(line.ending);
+                out.push_str(new);
+                out.push_str(line.ending);
+            }
+            None => out.push_str(line.ending),
+        }
+    }
+
+    let changes = changes
+        .into_iter()
+        .map(|(service, at, from, to)| {
+            let line = match at {
+                Edit::Replaced(i) => moved_to[i] + 1,
+                Edit::Inserted(after) => moved_to[after] + 2,
+            };
+            Change { service, line, from, to }
+        })
+        .collect();
+
+    Ok(Rewrite { contents: out, changes })
+}
+
+/// Index after the last line belonging to the block started by `start`, whose key is at `indent`
+fn block_end(lines: &[Line], start: usize, indent: usize) -> usize {
+    let mut end = start + 1;
+
+    for (i, line) in lines.iter().enumerate().skip(start + 1) {
+        if line.is_filler() {
+            continue;
+        }
+
+        if line.indent() <= indent {
+            break;
+        }
+
+        end = i + 1;
+    }
+
+    end
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::{rewrite, Change, ComposeError};
+    use crate::policy::IsolationPolicy;
+
+    fn change(service: &str, line: usize, from: Option<&str>) -> Change {
+        Change { service: service.to_string(), line, from: from.map(str::to_string), to: "process".to_string() }
+    }
+
+    #[test]
+    fn comments_and_ordering_are_kept() {
+        let before = "\
+# the app
+version: \"3.8\"
+
+services:
+  # the frontend
+  web:
+    image: nginx   # pinned later
+    ports:
+      - \"80:80\"
+  db:
+    isolation: hyperv  # needs it for now
+    image: mssql
+volumes:
+  data: {}
+";
+        let after = "\
+# the app
+version: \"3.8\"
+
+services:
+  # the frontend
+  web:
+    image: nginx   # pinned later
+    isolation: process
+    ports:
+      - \"80:80\"
+  db:
+    isolation: process  # needs it for now
+    image: mssql
+volumes:
+  data: {}
+";
+
+        let rewrite = rewrite(before, &IsolationPolicy::default()).unwrap();
+
+        assert_eq!(rewrite.contents, after);
+        assert_eq!(rewrite.changes, vec![change("web", 8, None), change("db", 12, Some("hyperv"))]);
+    }
+
+    #[test]
+    fn nothing_changes_when_isolation_matches() {
+        let before = "services:\n  web:\n    image: nginx\n    isolation: process\n";
+
+        let rewrite = rewrite(before, &IsolationPolicy::default()).unwrap();
+
+        assert_eq!(rewrite.contents, before);
+        assert!(rewrite.changes.is_empty());
+    }
+
+    #[test]
+    fn quoting_is_kept() {
+        let before = "services:\n  web:\n    isolation: \"hyperv\"\n  db:\n    isolation: 'default'\n";
+
+        let rewrite = rewrite(before, &IsolationPolicy::default()).unwrap();
+
+        assert_eq!(rewrite.contents, "services:\n  web:\n    isolation: \"process\"\n  db:\n    isolation: 'process'\n");
+    }
+
+    #[test]
+    fn bom_and_crlf_are_kept() {
+        let before = "\u{feff}services:\r\n  web:\r\n    image: nginx\r\n";
+
+        let rewrite = rewrite(before, &IsolationPolicy::default()).unwrap();
+
+        assert_eq!(rewrite.contents, "\u{feff}services:\r\n  web:\r\n    image: nginx\r\n    isolation: process\r\n");
+        assert_eq!(rewrite.changes, vec![change("web", 4, None)]);
+    }
+
+    #[test]
+    fn last_line_without_line_ending() {
+        let rewrite = rewrite("services:\n  web:\n    image: nginx", &IsolationPolicy::default()).unwrap();
+
+        assert_eq!(rewrite.contents, "services:\n  web:\n    image: nginx\n    isolation: process");
+    }
+
+    #[test]
+    fn flow_style_is_refused() {
+        let service = rewrite("services:\n  web: {image: nginx}\n", &IsolationPolicy::default()).unwrap_err();
+        assert!(matches!(service, ComposeError::NotBlockStyle { service, line: 2 } if service == "web"));
+
+        let services = rewrite("services: {web: {image: nginx}}\n", &IsolationPolicy::default()).unwrap_err();
+        assert!(matches!(services, ComposeError::NotBlockStyle { service, line: 1 } if service == "services"));
+
+        let version_1 = rewrite("web:\n  image: nginx\n", &IsolationPolicy::default()).unwrap_err();
+        assert!(matches!(version_1, ComposeError::NoServices));
+    }
+
+    #[test]
+    fn quoted_keys() {
+        let before = "\"services\":\n  \"web\":\n    \"isolation\": hyperv\n";
+
+        let rewrite = rewrite(before, &IsolationPolicy::default()).unwrap();
+
+        assert_eq!(rewrite.contents, "\"services\":\n  \"web\":\n    \"isolation\": process\n");
+        assert_eq!(rewrite.changes, vec![change("web", 3, Some("hyperv"))]);
+    }
+
+    #[test]
+    fn hash_inside_quotes_isnt_a_comment() {
+        let before = "services:\n  web:\n    command: \"echo #1\"\n    isolation: \"hyperv #2\"  # keep this\n";
+
+        let rewrite = rewrite(before, &IsolationPolicy::default()).unwrap();
+
+        assert_eq!(rewrite.contents, "services:\n  web:\n    command: \"echo #1\"\n    isolation: \"process\"  # keep this\n");
+        assert_eq!(rewrite.changes, vec![change("web", 4, Some("hyperv #2"))]);
+    }
+
+    #[test]
+    fn empty_isolation_with_a_comment() {
+        let before = "services:\n  web:\n    isolation:   # set by ci\n    image: nginx\n";
+
+        let rewrite = rewrite(before, &IsolationPolicy::default()).unwrap();
+
+        assert_eq!(rewrite.contents, "services:\n  web:\n    isolation: process # set by ci\n    image: nginx\n");
+        assert_eq!(rewrite.changes, vec![change("web", 3, None)]);
+    }
+}
@@ -0,0 +1,292 @@
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+use super::desktop::DesktopConfig;
+use super::hooks::HooksConfig;
+use super::integrity::IntegrityConfig;
+use super::logging::LoggingConfig;
+use super::patch::{self, Flag};
+use super::permissions::{self, PermissionError};
+use super::policy::IsolationPolicy;
+use super::schedule::MaintenanceWindow;
+
+pub const CONFIG_FILE_NAME: &str = "config.toml";
+
+#[cfg(windows)]
+const DEFAULT_PROXY_LISTEN: &str = r"\\.\pipe\docker_process_isolation_proxy";
+#[cfg(windows)]
+const DEFAULT_PROXY_UPSTREAM: &str = r"\\.\pipe\docker_engine";
+
+#[cfg(not(windows))]
+const DEFAULT_PROXY_LISTEN: &str = "/var/run/docker-process-isolation-proxy.sock";
+#[cfg(not(windows))]
+const DEFAULT_PROXY_UPSTREAM: &str = "/var/run/docker.sock";
+
+/// Patcher configuration. Every section is optional, a missing config file is the same as an
+/// empty one.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub desktop: DesktopConfig,
+    pub host: HostConfig,
+    pub hooks: HooksConfig,
+    pub integrity: IntegrityConfig,
+    pub logging: LoggingConfig,
+    pub proxy: ProxyConfig,
+    pub watcher: WatcherConfig,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HostConfig {
+    /// host windows version, e.g. "10.0.17763.2114". Read from the registry if not set
+    pub os_version: Option<String>,
+}
+
+/// Docker Engine API proxy which enforces the isolation policy per container
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProxyConfig {
+    pub enabled: bool,
+    /// pipe (Windows) or unix socket path the proxy listens on
+    pub listen: String,
+    /// pipe (Windows) or unix socket path of dockerd
+    pub upstream: String,
+    pub policy: IsolationPolicy,
+}
+
+impl Default for ProxyConfig {
+    fn default() -> Self {
+        ProxyConfig {
+            enabled: false,
+            listen: DEFAULT_PROXY_LISTEN.to_string(),
+            upstream: DEFAULT_PROXY_UPSTREAM.to_string(),
+            policy: IsolationPolicy::default(),
+        }
+    }
+}
+
+/// What the watcher does when docker's command line changes while docker is running
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DriftPolicy {
+    /// patch right away, docker is restarted according to the restart policy
+    Immediate,
+    /// write the patch, docker picks it up whenever it restarts
+    NextRestart,
+    /// only log it and show it in status
+    Report,
+}
+
+impl fmt::Display for DriftPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DriftPolicy::Immediate => f.write_str("immediate"),
+            DriftPolicy::NextRestart => f.write_str("next-restart"),
+            DriftPolicy::Report => f.write_str("report"),
+        }
+    }
+}
+
+/// When docker is restarted to pick up a patch
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RestartPolicy {
+    Immediate,
+    /// the patch is written right away, the restart waits for a maintenance window, for no
+    /// containers running, or for the patch-now command
+    Deferred,
+}
+
+impl fmt::Display for RestartPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RestartPolicy::Immediate => f.write_str("immediate"),
+            RestartPolicy::Deferred => f.write_str("deferred"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WatcherConfig {
+    /// dockerd flags the patch enforces, e.g. "--exec-opt isolation=process"
+    pub flags: Vec<Flag>,
+    /// how often a patched, running docker is checked for drift. 0 disables it
+    pub reconcile_interval_secs: u64,
+    pub drift_policy: DriftPolicy,
+    pub restart_policy: RestartPolicy,
+    /// deferred restarts happen inside any of these
+    pub maintenance_windows: Vec<MaintenanceWindow>,
+    /// deferred restarts happen once no containers are running
+    pub restart_when_idle: bool,
+    /// stop the running containers through the engine api before docker is restarted, and start
+    /// them again afterwards
+    pub graceful_restart: bool,
+    /// how long each container gets to exit before it's killed, like docker stop -t
+    pub container_stop_timeout_secs: u64,
+}
+
+impl Default for WatcherConfig {
+    fn default() -> Self {
+        WatcherConfig {
+            flags: patch::default_flags(),
+            reconcile_interval_secs: 60,
+            drift_policy: DriftPolicy::Immediate,
+            restart_policy: RestartPolicy::Immediate,
+            maintenance_windows: vec![],
+            restart_when_idle: false,
+            graceful_restart: false,
+            container_stop_timeout_secs: 30,
+        }
+    }
+}
+
+impl WatcherConfig {
+    /// Without flags every command line counts as patched and the watcher never changes
+    /// anything, which is the default on Linux
+    pub fn check_flags(&self) -> Result<(), ConfigError> {
+        if self.flags.is_empty() {
+            return Err(ConfigError::NoFlags);
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(PathBuf, std::io::Error),
+    Parse(PathBuf, toml::de::Error),
+    /// non-administrators could change it, see permissions
+    Insecure(PermissionError),
+    /// watcher.flags is empty, there's nothing to patch
+    NoFlags,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io(path, e) => write!(f, "failed to read {}: {}", path.display(), e),
+            ConfigError::Parse(path, e) => write!(f, "invalid config {}: {}", path.display(), e),
+            ConfigError::Insecure(e) => write!(f, "refusing to load the config: {}", e),
+            ConfigError::NoFlags => f.write_str("watcher.flags is empty, set the dockerd flags to enforce in the config"),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ConfigError::Io(_, e) => Some(e),
+            ConfigError::Parse(_, e) => Some(e),
+            ConfigError::Insecure(e) => Some(e),
+            ConfigError::NoFlags => None,
+        }
+    }
+}
+
+impl Config {
+    /// `config.toml` in the same directory as the exe
+    pub fn default_path() -> PathBuf {
+        std::env::current_exe().unwrap().with_file_name(CONFIG_FILE_NAME)
+    }
+
+    pub fn load() -> Result<Config, ConfigError> {
+        Config::load_from(&Config::default_path())
+    }
+
+    /// The config for the service and the commands changing docker, refused unless only
+    /// administrators can change it, see check_permissions
+    pub fn load_from(path: &Path) -> Result<Config, ConfigError> {
+        Config::load_checked(path, true)
+    }
+
+    /// The config for commands that only read, whoever can change it. main warns about an
+    /// insecure config
+    pub fn read_from(path: &Path) -> Result<Config, ConfigError> {
+        Config::load_checked(path, false)
+    }
+
+    /// Whether only administrators can change the config and the directories holding it, see
+    /// permissions. There's nothing to check without a config
+    pub fn check_permissions(path: &Path) -> Result<(), PermissionError> {
+        if !path.exists() {
+            return Ok(());
+        }
+
+        permissions::check_file(path)
+    }
+
+    fn load_checked(path: &Path, check_permissions: bool) -> Result<Config, ConfigError> {
+        let contents = match std::fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Config::default()),
+            Err(e) => return Err(ConfigError::Io(path.to_path_buf(), e)),
+        };
+
+        if check_permissions {
+            Config::check_permissions(path).map_err(ConfigError::Insecure)?;
+        }
+
+        Config::parse(&contents).map_err(|e| ConfigError::Parse(path.to_path_buf(), e))
+    }
+
+    pub fn parse(contents: &str) -> Result<Config, toml::de::Error> {
+        toml::from_str(contents)
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::fs;
+
+    use super::{Config, ConfigError};
+    use crate::permissions::PermissionError;
+    use crate::testing::{set_mode, ScratchDir};
+
+    #[test]
+    fn insecure_config() {
+        let dir = ScratchDir::new("config-insecure");
+        let path = dir.join("config.toml");
+        fs::write(&path, "[proxy]\nlisten = \"127.0.0.1:2376\"\n").unwrap();
+
+        set_mode(&path, 0o644);
+        assert!(Config::check_permissions(&path).is_ok());
+        assert_eq!(Config::load_from(&path).unwrap().proxy.listen, "127.0.0.1:2376");
+
+        // refused for changing docker, read for looking
+        set_mode(&path, 0o646);
+        assert!(matches!(Config::check_permissions(&path), Err(PermissionError::Writable { .. })));
+        match Config::load_from(&path) {
+            Err(ConfigError::Insecure(e)) => assert!(e.to_string().contains("can be changed by everyone"), "{}", e),
+            res => panic!("{:?}", res),
+        }
+        assert_eq!(Config::read_from(&path).unwrap().proxy.listen, "127.0.0.1:2376");
+    }
+
+    #[test]
+    fn missing_config() {
+        let dir = ScratchDir::new("config-missing");
+        set_mode(&dir, 0o777);
+        let path = dir.join("config.toml");
+
+        assert!(Config::check_permissions(&path).is_ok());
+        assert!(Config::load_from(&path).unwrap().watcher.flags.is_empty());
+        assert!(Config::read_from(&path).unwrap().watcher.flags.is_empty());
+    }
+
+    #[test]
+    fn invalid_config() {
+        let dir = ScratchDir::new("config-invalid");
+        let path = dir.join("config.toml");
+        fs::write(&path, "[proxy\n").unwrap();
+        set_mode(&path, 0o666);
+
+        // a broken config is reported either way
+        assert!(matches!(Config::read_from(&path), Err(ConfigError::Parse(..))));
+        assert!(matches!(Config::load_from(&path), Err(ConfigError::Insecure(_))));
+    }
+}
@@ -0,0 +1,142 @@
+// Control channel between the CLI and the running service
+// The service serves a pipe (unix socket elsewhere). The CLI writes one json request line and
+// reads one json reply line back. Requests are handed to the watcher loop, which answers them
+// between ticks. Only administrators and SYSTEM may connect (root elsewhere), the pipe gets an
+// explicit DACL rather than relying on the default one.
+
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::sync::mpsc;
+use std::thread;
+
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use super::proxy::{Connector, Listener, Stream};
+
+#[cfg(windows)]
+pub const CONTROL_PIPE: &str = r"\\.\pipe\docker_process_isolation_patcher";
+#[cfg(unix)]
+pub const CONTROL_PIPE: &str = "/var/run/docker-process-isolation-patcher.sock";
+
+/// full control for SYSTEM and Administrators, nothing for anyone else, not inherited
+#[cfg(windows)]
+const CONTROL_PIPE_SDDL: &str = "D:P(A;;GA;;;SY)(A;;GA;;;BA)";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Command {
+    /// patch now and restart docker, even if the restart is deferred
+    Repatch,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Request {
+    pub command: Command,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Reply {
+    pub ok: bool,
+    pub message: String,
+}
+
+impl Reply {
+    pub fn ok(message: impl Into<String>) -> Self {
+        Reply { ok: true, message: message.into() }
+    }
+
+    pub fn error(message: impl Into<String>) -> Self {
+        Reply { ok: false, message: message.into() }
+    }
+}
+
+/// A request waiting for the watcher loop
+pub struct Pending {
+    pub request: Request,
+    /// `DOMAIN\user` of the client as the OS knows it, for the audit log
+    pub user: Option<String>,
+    pub reply: mpsc::Sender<Reply>,
+}
+
+/// Serve the control pipe on a background thread, passing requests to `requests`
+pub fn spawn(requests: mpsc::Sender<Pending>) -> io::Result<thread::JoinHandle<()>> {
+    #[cfg(windows)]
+    let listener = super::proxy::pipe::PipeListener::bind_with_sddl(CONTROL_PIPE, CONTROL_PIPE_SDDL)?;
+    #[cfg(unix)]
+    let listener = {
+        use std::os::unix::fs::PermissionsExt;
+
+        let listener = super::proxy::unix::UnixSocketListener::bind(CONTROL_PIPE)?;
+        std::fs::set_permissions(CONTROL_PIPE, std::fs::Permissions::from_mode(0o600))?;
+        listener
+    };
+
+    info!("listening on {}", CONTROL_PIPE);
+    Ok(thread::spawn(move || serve(listener, requests)))
+}
+
+pub fn serve<L: Listener>(mut listener: L, requests: mpsc::Sender<Pending>) {
+    loop {
+        let client = match listener.accept() {
+            Ok(client) => client,
+            Err(e) => {
+                warn!("failed to accept connection: {}", e);
+                thread::sleep(std::time::Duration::from_millis(100));
+                continue;
+            }
+        };
+
+        let requests = requests.clone();
+        thread::spawn(move || {
+            if let Err(e) = handle(client, &requests) {
+                info!("connection closed: {}", e);
+            }
+        });
+    }
+}
+
+fn handle<S: Stream>(client: S, requests: &mpsc::Sender<Pending>) -> io::Result<()> {
+    let user = client.peer_user().unwrap_or_else(|e| {
+        warn!("couldn't tell who connected: {}", e);
+        None
+    });
+
+    let mut writer = client.try_clone()?;
+    let mut line = String::new();
+    BufReader::new(client).take(64 * 1024).read_line(&mut line)?;
+
+    let reply = match serde_json::from_str::<Request>(&line) {
+        Ok(request) => {
+            info!("{:?} requested by {}", request.command, user.as_deref().unwrap_or("unknown user"));
+
+            let (reply_tx, reply_rx) = mpsc::channel();
+            if requests.send(Pending { request, user, reply: reply_tx }).is_err() {
+                Reply::error("the service is shutting down")
+            } else {
+                reply_rx.recv().unwrap_or_else(|_| Reply::error("the service dropped the request"))
+            }
+        }
+
+        Err(e) => Reply::error(format!("invalid request: {}", e)),
+    };
+
+    writeln!(writer, "{}", serde_json::to_string(&reply).unwrap())?;
+    writer.flush()
+}
+
+/// Send a request to the running service and wait for its reply
+pub fn send(request: &Request) -> io::Result<Reply> {
+    #[cfg(windows)]
+    let stream = super::proxy::pipe::PipeConnector::new(CONTROL_PIPE).connect()?;
+    #[cfg(unix)]
+    let stream = super::proxy::unix::UnixSocketConnector::new(CONTROL_PIPE).connect()?;
+
+    let mut writer = stream.try_clone()?;
+    writeln!(writer, "{}", serde_json::to_string(request).unwrap())?;
+    writer.flush()?;
+
+    let mut line = String::new();
+    BufReader::new(stream).read_line(&mut line)?;
+
+    serde_json::from_str(&line).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
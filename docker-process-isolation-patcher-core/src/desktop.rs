@@ -0,0 +1,128 @@
+// Docker Desktop coordination
+// Docker Desktop's backend service creates, deletes and restarts the docker service itself when
+// the user switches between Linux and Windows containers. Stopping docker in the middle of that
+// breaks the switch, so the watcher asks here which engine is active and only touches docker once
+// Desktop has brought the Windows engine up and it has stayed up for a while.
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use super::backend::{BackendResult, ServiceBackend, ServiceState};
+use super::shared::*;
+
+/// pipe Desktop's Windows engine listens on, Desktop points docker_engine at the active engine
+pub const WINDOWS_ENGINE_PIPE: &str = r"\\.\pipe\docker_engine_windows";
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DesktopConfig {
+    /// false treats docker as a plain engine install even if Desktop is installed
+    pub enabled: bool,
+    /// how long the Windows engine has to be running after a switch before it's patched
+    pub settle_secs: u64,
+    /// Desktop settings files to look for, every user profile's if empty
+    pub settings_paths: Vec<PathBuf>,
+    /// pipe of Desktop's Windows engine, it has to answer before docker is patched
+    pub windows_engine: String,
+}
+
+impl Default for DesktopConfig {
+    fn default() -> Self {
+        DesktopConfig {
+            enabled: true,
+            settle_secs: 30,
+            settings_paths: vec![],
+            windows_engine: WINDOWS_ENGINE_PIPE.to_string(),
+        }
+    }
+}
+
+/// Which engine Desktop runs
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum EngineMode {
+    /// the docker service runs, it's the one the patcher patches
+    Windows,
+    /// the docker service is stopped or deleted, the Linux VM runs the containers
+    Linux,
+    /// Desktop or the docker service is starting or stopping
+    Switching,
+}
+
+impl fmt::Display for EngineMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EngineMode::Windows => f.write_str("running windows containers"),
+            EngineMode::Linux => f.write_str("running linux containers"),
+            EngineMode::Switching => f.write_str("switching engines"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Desktop {
+    /// state of Desktop's backend service
+    pub backend: ServiceState,
+    /// settings file found, None if there's none or the computer is remote
+    pub settings: Option<PathBuf>,
+    pub mode: EngineMode,
+}
+
+/// Docker Desktop on the backend's computer, None if it isn't installed or detection is disabled.
+/// Desktop counts as installed when its backend service is, settings files stay behind after an
+/// uninstall
+pub fn detect<B: ServiceBackend>(backend: &B, config: &DesktopConfig) -> BackendResult<Option<Desktop>> {
+    if !config.enabled {
+        return Ok(None);
+    }
+
+    let desktop = match backend.query_state(DOCKER_DESKTOP_SERVICE_NAME)? {
+        Some(state) => state,
+        None => return Ok(None),
+    };
+
+    let docker = backend.query_state(DOCKER_SERVICE_NAME)?;
+    let settings = if backend.target().is_local() { settings_file(config) } else { None };
+
+    Ok(Some(Desktop { backend: desktop, settings, mode: mode(desktop, docker) }))
+}
+
+/// Engine mode from the state of Desktop's backend service and the docker service
+pub fn mode(desktop: ServiceState, docker: Option<ServiceState>) -> EngineMode {
+    let pending = |state| matches!(state, ServiceState::StartPending | ServiceState::StopPending);
+
+    if pending(desktop) || docker.is_some_and(pending) {
+        return EngineMode::Switching;
+    }
+
+    match docker {
+        Some(ServiceState::Running) => EngineMode::Windows,
+        _ => EngineMode::Linux,
+    }
+}
+
+fn settings_file(config: &DesktopConfig) -> Option<PathBuf> {
+    let candidates = if config.settings_paths.is_empty() { default_settings_paths() } else { config.settings_paths.clone() };
+    candidates.into_iter().find(|p| p.is_file())
+}
+
+/// Desktop keeps its settings per user, `settings-store.json` in newer versions
+fn default_settings_paths() -> Vec<PathBuf> {
+    if !cfg!(windows) {
+        return vec![];
+    }
+
+    let drive = std::env::var("SystemDrive").unwrap_or_else(|_| "C:".to_string());
+    let profiles = match std::fs::read_dir(Path::new(&format!(r"{}\", drive)).join("Users")) {
+        Ok(profiles) => profiles,
+        Err(_) => return vec![],
+    };
+
+    profiles
+        .filter_map(Result::ok)
+        .map(|profile| profile.path().join("AppData").join("Roaming").join("Docker"))
+        .flat_map(|dir| vec![dir.join("settings-store.json"), dir.join("settings.json")])
+        .collect()
+}
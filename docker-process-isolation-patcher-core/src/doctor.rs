@@ -0,0 +1,702 @@
+// `doctor` diagnostics
+// Every check reports pass/warn/fail with a remediation hint. Checks only read, and take the
+// services and file locations from outside so they can run against a fake environment.
+
+use std::fmt;
+use std::fs::OpenOptions;
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+
+use super::backend::{ServiceBackend, ServiceConfig, ServiceState};
+use super::compat::OsVersion;
+use super::config::CONFIG_FILE_NAME;
+use super::exit::{self, ServiceExit};
+use super::hooks::{self, HooksConfig, Stage};
+use super::integrity::{self, IntegrityConfig, IntegrityError};
+use super::patch::{self, Flag};
+use super::permissions;
+use super::shared::*;
+use super::state::{self, State};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Pass,
+    Warn,
+    Fail,
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Status::Pass => f.write_str("PASS"),
+            Status::Warn => f.write_str("WARN"),
+            Status::Fail => f.write_str("FAIL"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Check {
+    pub name: &'static str,
+    pub status: Status,
+    pub message: String,
+    pub remediation: Option<String>,
+}
+
+impl Check {
+    fn pass(name: &'static str, message: impl Into<String>) -> Self {
+        Check { name, status: Status::Pass, message: message.into(), remediation: None }
+    }
+
+    fn warn(name: &'static str, message: impl Into<String>, remediation: impl Into<String>) -> Self {
+        Check { name, status: Status::Warn, message: message.into(), remediation: Some(remediation.into()) }
+    }
+
+    fn fail(name: &'static str, message: impl Into<String>, remediation: impl Into<String>) -> Self {
+        Check { name, status: Status::Fail, message: message.into(), remediation: Some(remediation.into()) }
+    }
+}
+
+/// Everything besides services the checks look at
+pub struct Environment {
+    pub elevated: bool,
+    pub config_path: PathBuf,
+    pub state_path: PathBuf,
+    pub log_dir: PathBuf,
+    /// daemon.json dockerd reads when its command line has no --config-file
+    pub default_daemon_json: PathBuf,
+    /// host windows version, or why it couldn't be determined
+    pub host_version: Result<Option<OsVersion>, String>,
+    /// dockerd flags the patch enforces
+    pub flags: Vec<Flag>,
+    pub integrity: IntegrityConfig,
+    pub hooks: HooksConfig,
+}
+
+pub fn default_daemon_json() -> PathBuf {
+    let program_data = std::env::var_os("ProgramData").unwrap_or_else(|| r"C:\ProgramData".into());
+    Path::new(&program_data).join("docker").join("config").join("daemon.json")
+}
+
+pub fn run<B: ServiceBackend>(backend: &B, env: &Environment) -> Vec<Check> {
+    let docker_config = backend.query_config(DOCKER_SERVICE_NAME);
+
+    let mut checks = vec![
+        check_elevation(env),
+        check_patcher(backend, env),
+        check_service(backend, "docker service", DOCKER_SERVICE_NAME, "start docker, or switch Docker Desktop to Windows containers"),
+    ];
+
+    match &docker_config {
+        Ok(config) => {
+            checks.push(check_windows_containers(backend, config.as_ref()));
+            checks.push(check_image_path(config.as_ref(), env));
+            checks.push(check_integrity(backend, config.as_ref(), env));
+            checks.push(check_daemon_json(config.as_ref(), env));
+            checks.push(check_state(config.as_ref(), env));
+        }
+
+        Err(e) => checks.push(Check::fail(
+            "docker config",
+            format!("couldn't query the docker service config: {}", e),
+            "run doctor as administrator",
+        )),
+    }
+
+    checks.push(check_log_dir(env));
+    checks.push(check_permissions(env));
+    checks.push(check_host_version(env));
+
+    checks
+}
+
+/// Worst status over all checks
+pub fn overall(checks: &[Check]) -> Status {
+    checks.iter().map(|c| c.status).max().unwrap_or(Status::Pass)
+}
+
+pub fn render_text(checks: &[Check]) -> String {
+    let mut out = String::new();
+
+    for check in checks {
+        out.push_str(&format!("[{}] {}: {}\n", check.status, check.name, check.message));
+
+        if let Some(remediation) = &check.remediation {
+            out.push_str(&format!("       fix: {}\n", remediation));
+        }
+    }
+
+    out
+}
+
+fn check_elevation(env: &Environment) -> Check {
+    if env.elevated {
+        Check::pass("elevation", "running as administrator")
+    } else {
+        Check::warn(
+            "elevation",
+            "not running as administrator, some services may not be queryable",
+            "run doctor from an elevated prompt for complete results",
+        )
+    }
+}
+
+/// The patcher service, with why it stopped if it stopped with an error
+fn check_patcher<B: ServiceBackend>(backend: &B, env: &Environment) -> Check {
+    const NAME: &str = "patcher service";
+
+    let failure = match backend.query_state(SERVICE_NAME) {
+        Ok(Some(ServiceState::Stopped)) => State::load_from(&env.state_path).ok().and_then(|s| s.service_failure),
+        _ => None,
+    };
+
+    let failure = match failure {
+        Some(failure) => failure,
+        None => return check_service(backend, NAME, SERVICE_NAME, "run the install command"),
+    };
+
+    let fix = match ServiceExit::from_code(failure.exit_code) {
+        Some(ServiceExit::ServiceManager) => "make sure the service runs as SYSTEM (root on Linux) and the service manager works, then run the start command",
+        Some(ServiceExit::Lock) => "make sure the service runs as SYSTEM (root on Linux), which can create the patch lock, then run the start command",
+        Some(ServiceExit::Panic) => "attach the panic report from the log directory to a bug report, then run the start command",
+        Some(ServiceExit::Internal) | None => "look up the error in the log and run the start command",
+    };
+
+    Check::fail(
+        NAME,
+        format!(
+            "{} stopped {} seconds ago with exit code {}: {}",
+            SERVICE_NAME,
+            state::unix_time().saturating_sub(failure.at),
+            exit::describe(failure.exit_code),
+            failure.message
+        ),
+        fix,
+    )
+}
+
+fn check_service<B: ServiceBackend>(backend: &B, check: &'static str, name: &str, missing_fix: &str) -> Check {
+    match backend.query_state(name) {
+        Ok(Some(ServiceState::Running)) => Check::pass(check, format!("{} is running", name)),
+
+        Ok(Some(ServiceState::Stopped)) => Check::warn(
+            check,
+            format!("{} is installed but stopped", name),
+            if name == SERVICE_NAME { "run the start command" } else { missing_fix },
+        ),
+
+        Ok(Some(state)) => Check::warn(
+            check,
+            format!("{} is {}", name, state),
+            "wait for the service to settle and run doctor again",
+        ),
+
+        Ok(None) => Check::fail(check, format!("{} is not installed", name), missing_fix),
+
+        Err(e) => Check::fail(check, format!("couldn't query {}: {}", name, e), "run doctor as administrator"),
+    }
+}
+
+fn check_windows_containers<B: ServiceBackend>(backend: &B, docker: Option<&ServiceConfig>) -> Check {
+    const NAME: &str = "windows containers";
+
+    match docker {
+        Some(config) => {
+            let args = patch::split_command_line(&config.command_line);
+            let exe = args.first().map(|a| a.to_ascii_lowercase()).unwrap_or_default();
+
+            if exe.ends_with("dockerd.exe") || exe.ends_with("dockerd") {
+                Check::pass(NAME, "docker service runs the windows engine")
+            } else {
+                Check::warn(
+                    NAME,
+                    format!("docker service runs {}, not dockerd", exe),
+                    "make sure the docker service is the windows container engine",
+                )
+            }
+        }
+
+        None => {
+            if backend.exists(DOCKER_DESKTOP_SERVICE_NAME).unwrap_or(false) {
+                Check::fail(
+                    NAME,
+                    "Docker Desktop is installed, but the windows engine isn't registered (Linux containers mode)",
+                    "switch to Windows containers from the Docker Desktop tray menu",
+                )
+            } else {
+                Check::fail(NAME, "no docker engine is installed", "install docker with Windows containers support")
+            }
+        }
+    }
+}
+
+fn check_image_path(docker: Option<&ServiceConfig>, env: &Environment) -> Check {
+    const NAME: &str = "image path";
+
+    let config = match docker {
+        Some(config) => config,
+        None => return Check::warn(NAME, "docker service not found, nothing to check", "install docker"),
+    };
+
+    if config.command_line.matches('"').count() % 2 != 0 {
+        return Check::fail(
+            NAME,
+            format!("unbalanced quotes in {}", config.command_line),
+            "reinstall docker or fix the ImagePath of the docker service",
+        );
+    }
+
+    let args = patch::split_command_line(&config.command_line);
+    let exe = match args.first() {
+        Some(exe) => exe,
+        None => return Check::fail(NAME, "docker service has an empty command line", "reinstall docker"),
+    };
+
+    if !Path::new(exe).exists() {
+        return Check::fail(NAME, format!("{} does not exist", exe), "reinstall docker");
+    }
+
+    let isolation_opts = patch::exec_opts(&args).into_iter().filter(|o| o.starts_with("isolation=")).count();
+    if isolation_opts > 1 {
+        return Check::warn(
+            NAME,
+            format!("{} sets isolation more than once", config.command_line),
+            "remove the extra --exec-opt isolation flags, the patcher adds its own",
+        );
+    }
+
+    if patch::is_patched(&args, &env.flags) {
+        Check::pass(NAME, format!("patched: {}", config.command_line))
+    } else {
+        Check::warn(
+            NAME,
+            format!("not patched: {}", config.command_line),
+            "start the patcher service, it patches docker once docker is running",
+        )
+    }
+}
+
+fn check_integrity<B: ServiceBackend>(backend: &B, docker: Option<&ServiceConfig>, env: &Environment) -> Check {
+    const NAME: &str = "dockerd integrity";
+
+    let config = match docker {
+        Some(config) => config,
+        None => return Check::pass(NAME, "docker service not found, nothing to check"),
+    };
+
+    match integrity::verify(&env.integrity, backend.target(), &config.command_line) {
+        Ok(None) => Check::pass(NAME, "integrity check disabled"),
+        Ok(Some(verified)) => Check::pass(NAME, format!("{} is allowed, sha256 {}", verified.path.display(), verified.sha256)),
+        Err(IntegrityError::Mismatch(found)) => Check::fail(
+            NAME,
+            format!("{} isn't allowed, the patcher won't patch or restart docker", found.path.display()),
+            format!("if this dockerd is trusted, add its sha256 {} to integrity.sha256 in {}", found.sha256, CONFIG_FILE_NAME),
+        ),
+        Err(e @ IntegrityError::OutsideAllowedDirs(_)) | Err(e @ IntegrityError::NothingAllowed) => {
+            Check::fail(NAME, e.to_string(), format!("fix integrity.allowed_dirs in {}", CONFIG_FILE_NAME))
+        }
+        Err(e) => Check::fail(NAME, e.to_string(), "run doctor as administrator"),
+    }
+}
+
+fn check_daemon_json(docker: Option<&ServiceConfig>, env: &Environment) -> Check {
+    const NAME: &str = "daemon.json";
+
+    let args = docker.map(|c| patch::split_command_line(&c.command_line)).unwrap_or_default();
+    let path = patch::config_file_arg(&args).map(PathBuf::from).unwrap_or_else(|| env.default_daemon_json.clone());
+
+    let contents = match std::fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            return Check::pass(NAME, format!("{} doesn't exist", path.display()));
+        }
+        Err(e) => return Check::warn(NAME, format!("couldn't read {}: {}", path.display(), e), "run doctor as administrator"),
+    };
+
+    let json: serde_json::Value = match serde_json::from_str(&contents) {
+        Ok(json) => json,
+        Err(e) => {
+            return Check::fail(
+                NAME,
+                format!("{} is not valid json, dockerd won't start: {}", path.display(), e),
+                "fix the syntax error in daemon.json",
+            )
+        }
+    };
+
+    // dockerd refuses to start if an option is given both as a flag and in daemon.json
+    if let Some(opts) = json.get("exec-opts") {
+        return Check::fail(
+            NAME,
+            format!("{} sets exec-opts {}, which conflicts with the --exec-opt flag the patcher adds", path.display(), opts),
+            "remove exec-opts from daemon.json",
+        );
+    }
+
+    Check::pass(NAME, format!("{} doesn't conflict with the patch", path.display()))
+}
+
+fn check_state(docker: Option<&ServiceConfig>, env: &Environment) -> Check {
+    const NAME: &str = "state file";
+
+    let state = match State::load_from(&env.state_path) {
+        Ok(state) => state,
+        Err(e) => {
+            return Check::fail(
+                NAME,
+                e.to_string(),
+                format!("delete {}, it's recreated on the next patch", env.state_path.display()),
+            )
+        }
+    };
+
+    let patched = match &state.patched_command_line {
+        Some(patched) => patched,
+        None => return Check::pass(NAME, "no patch recorded yet"),
+    };
+
+    if state.original_command_line.as_deref().is_some_and(|o| patch::is_patched(&patch::split_command_line(o), &env.flags)) {
+        return Check::warn(
+            NAME,
+            "recorded original command line already contains the patch",
+            format!("delete {} so the next patch records the real original", env.state_path.display()),
+        );
+    }
+
+    let ago = state.patched_at.map(|t| state::unix_time().saturating_sub(t));
+    let when = ago.map_or("at an unknown time".to_string(), |s| format!("{} seconds ago", s));
+
+    match docker {
+        Some(config) if !patch::same_command_line(&config.command_line, patched) => Check::warn(
+            NAME,
+            format!("docker's command line changed since it was patched {}", when),
+            "restart docker so the patcher applies the patch again",
+        ),
+
+        _ => Check::pass(NAME, format!("last patched {}", when)),
+    }
+}
+
+fn check_log_dir(env: &Environment) -> Check {
+    const NAME: &str = "log directory";
+
+    let probe = env.log_dir.join(".doctor-write-test");
+    let res = OpenOptions::new().create(true).append(true).open(&probe);
+
+    match res {
+        Ok(_) => {
+            std::fs::remove_file(&probe).ok();
+            Check::pass(NAME, format!("{} is writable", env.log_dir.display()))
+        }
+
+        Err(e) => Check::fail(
+            NAME,
+            format!("{} is not writable: {}", env.log_dir.display(), e),
+            format!("set logging.directory in {} to a directory the SYSTEM account can write to", CONFIG_FILE_NAME),
+        ),
+    }
+}
+
+fn check_permissions(env: &Environment) -> Check {
+    const NAME: &str = "permissions";
+
+    let mut problems: Vec<String> = vec![
+        permissions::check_file(&env.config_path),
+        permissions::check_file(&env.state_path),
+        permissions::check_dir(&env.log_dir),
+    ]
+    .into_iter()
+    .filter_map(|res| res.err().map(|e| e.to_string()))
+    .collect();
+
+    for stage in Stage::ALL {
+        if let Some(Err(e)) = env.hooks.command(stage).map(hooks::check_permissions) {
+            problems.push(format!("{} hook: {}", stage, e));
+        }
+    }
+
+    if problems.is_empty() {
+        Check::pass(NAME, "config, state, log directory and hooks can only be changed by administrators")
+    } else {
+        Check::fail(
+            NAME,
+            problems.join("; "),
+            "run install again as administrator, it locks down the exe directory. Hook scripts elsewhere have to be locked down by hand",
+        )
+    }
+}
+
+fn check_host_version(env: &Environment) -> Check {
+    const NAME: &str = "host build";
+
+    match &env.host_version {
+        Ok(Some(version)) => Check::pass(NAME, format!("host is {}", version)),
+        Ok(None) => Check::warn(
+            NAME,
+            "host windows build is unknown, image compatibility can't be checked",
+            format!("set host.os_version in {}", CONFIG_FILE_NAME),
+        ),
+        Err(e) => Check::warn(NAME, e.clone(), format!("set host.os_version in {}", CONFIG_FILE_NAME)),
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::fs;
+
+    use super::{overall, render_text, run, Check, Environment, Status};
+    use crate::backend::{ServiceState, Target};
+    use crate::exit::ServiceExit;
+    use crate::patch::{self, Flag};
+    use crate::shared::*;
+    use crate::state::{self, ServiceFailure, State};
+    use crate::testing::{set_mode, FakeBackend, ScratchDir};
+
+    fn flags() -> Vec<Flag> {
+        vec![Flag::new("--exec-opt", Some("isolation=process"))]
+    }
+
+    /// Everything in `dir`, the host build known
+    fn env(dir: &ScratchDir) -> Environment {
+        Environment {
+            elevated: true,
+            config_path: dir.join("config.toml"),
+            state_path: dir.join("state.json"),
+            log_dir: dir.to_path_buf(),
+            default_daemon_json: dir.join("daemon.json"),
+            host_version: Ok(Some("10.0.17763.2114".parse().unwrap())),
+            flags: flags(),
+            integrity: Default::default(),
+            hooks: Default::default(),
+        }
+    }
+
+    /// Command line of a dockerd in `dir`
+    fn dockerd(dir: &ScratchDir) -> String {
+        let exe = dir.join("dockerd");
+        fs::write(&exe, "").unwrap();
+        format!("{} --run-service", exe.display())
+    }
+
+    fn patched(command_line: &str) -> String {
+        patch::join_command_line(&patch::patch_args(&patch::split_command_line(command_line), &flags()))
+    }
+
+    /// Patcher running, docker running patched
+    fn healthy(dir: &ScratchDir) -> FakeBackend {
+        FakeBackend::new(Target::Local)
+            .with(SERVICE_NAME, ServiceState::Running, "patcher run-service")
+            .with(DOCKER_SERVICE_NAME, ServiceState::Running, &patched(&dockerd(dir)))
+    }
+
+    fn check<'a>(checks: &'a [Check], name: &str) -> &'a Check {
+        checks.iter().find(|c| c.name == name).unwrap_or_else(|| panic!("no {} check", name))
+    }
+
+    fn assert_status(check: &Check, status: Status, message: &str) {
+        assert_eq!(check.status, status, "{}: {}", check.name, check.message);
+        assert!(check.message.contains(message), "{}: {}", check.name, check.message);
+    }
+
+    #[test]
+    fn healthy_host_passes() {
+        let dir = ScratchDir::new("doctor-healthy");
+        let checks = run(&healthy(&dir), &env(&dir));
+
+        for check in &checks {
+            assert_eq!(check.status, Status::Pass, "{}: {}", check.name, check.message);
+            assert!(check.remediation.is_none());
+        }
+        assert_eq!(checks.len(), 11);
+        assert_eq!(overall(&checks), Status::Pass);
+        assert!(render_text(&checks).starts_with("[PASS] elevation: running as administrator\n"));
+    }
+
+    #[test]
+    fn elevation_and_host_build() {
+        let dir = ScratchDir::new("doctor-elevation");
+        let mut env = env(&dir);
+        env.elevated = false;
+        env.host_version = Ok(None);
+
+        let checks = run(&healthy(&dir), &env);
+        assert_status(check(&checks, "elevation"), Status::Warn, "not running as administrator");
+        assert_status(check(&checks, "host build"), Status::Warn, "host windows build is unknown");
+        assert_eq!(overall(&checks), Status::Warn);
+        assert!(render_text(&checks).contains("       fix: set host.os_version in config.toml\n"));
+
+        env.host_version = Err("the registry said no".to_string());
+        let checks = run(&healthy(&dir), &env);
+        assert_status(check(&checks, "host build"), Status::Warn, "the registry said no");
+    }
+
+    #[test]
+    fn services() {
+        let dir = ScratchDir::new("doctor-services");
+        let env = env(&dir);
+
+        let backend = FakeBackend::new(Target::Local).with(DOCKER_SERVICE_NAME, ServiceState::Stopped, &patched(&dockerd(&dir)));
+        let checks = run(&backend, &env);
+        assert_status(check(&checks, "patcher service"), Status::Fail, "is not installed");
+        assert_eq!(check(&checks, "patcher service").remediation.as_deref(), Some("run the install command"));
+        assert_status(check(&checks, "docker service"), Status::Warn, "docker is installed but stopped");
+
+        let backend = backend.with(SERVICE_NAME, ServiceState::StopPending, "patcher run-service");
+        let checks = run(&backend, &env);
+        assert_status(check(&checks, "patcher service"), Status::Warn, "is stop pending");
+    }
+
+    #[test]
+    fn patcher_failure() {
+        let dir = ScratchDir::new("doctor-failure");
+        let env = env(&dir);
+
+        let backend = healthy(&dir).with(SERVICE_NAME, ServiceState::Stopped, "patcher run-service");
+        assert_status(check(&run(&backend, &env), "patcher service"), Status::Warn, "installed but stopped");
+
+        let failure = ServiceFailure { exit_code: ServiceExit::Lock.code(), message: "lock is taken".to_string(), at: state::unix_time() };
+        State { service_failure: Some(failure), ..Default::default() }.save_to(&env.state_path).unwrap();
+
+        let checks = run(&backend, &env);
+        let patcher = check(&checks, "patcher service");
+        assert_status(patcher, Status::Fail, "with exit code 3 (patch lock unavailable): lock is taken");
+        assert!(patcher.remediation.as_deref().unwrap().contains("create the patch lock"));
+
+        // only a stopped service is explained by its last failure
+        let backend = backend.with(SERVICE_NAME, ServiceState::Running, "patcher run-service");
+        assert_status(check(&run(&backend, &env), "patcher service"), Status::Pass, "is running");
+    }
+
+    #[test]
+    fn windows_containers() {
+        let dir = ScratchDir::new("doctor-engine");
+        let env = env(&dir);
+
+        let backend = FakeBackend::new(Target::Local).with(SERVICE_NAME, ServiceState::Running, "patcher run-service");
+        let checks = run(&backend, &env);
+        assert_status(check(&checks, "windows containers"), Status::Fail, "no docker engine is installed");
+        assert_status(check(&checks, "docker service"), Status::Fail, "docker is not installed");
+        assert_status(check(&checks, "image path"), Status::Warn, "docker service not found");
+
+        let backend = backend.with(DOCKER_DESKTOP_SERVICE_NAME, ServiceState::Running, "desktop");
+        assert_status(check(&run(&backend, &env), "windows containers"), Status::Fail, "Linux containers mode");
+
+        let backend = backend.with(DOCKER_SERVICE_NAME, ServiceState::Running, "/usr/bin/containerd");
+        assert_status(check(&run(&backend, &env), "windows containers"), Status::Warn, "runs /usr/bin/containerd, not dockerd");
+    }
+
+    #[test]
+    fn image_path() {
+        let dir = ScratchDir::new("doctor-image-path");
+        let env = env(&dir);
+        let dockerd = dockerd(&dir);
+
+        let cases = [
+            (format!("\"{}", dockerd), Status::Fail, "unbalanced quotes"),
+            ("/nowhere/dockerd --run-service".to_string(), Status::Fail, "/nowhere/dockerd does not exist"),
+            (dockerd.clone(), Status::Warn, "not patched"),
+            (format!("{} --exec-opt isolation=hyperv --exec-opt isolation=process", dockerd), Status::Warn, "sets isolation more than once"),
+            (patched(&dockerd), Status::Pass, "patched: "),
+        ];
+
+        for (command_line, status, message) in &cases {
+            let backend = healthy(&dir).with(DOCKER_SERVICE_NAME, ServiceState::Running, command_line);
+            assert_status(check(&run(&backend, &env), "image path"), *status, message);
+        }
+    }
+
+    #[test]
+    fn integrity() {
+        let dir = ScratchDir::new("doctor-integrity");
+        let mut env = env(&dir);
+        env.integrity.enabled = true;
+
+        let integrity = |env: &Environment| check(&run(&healthy(&dir), env), "dockerd integrity").clone();
+        assert_status(&integrity(&env), Status::Fail, "allowed_dirs, sha256 and file_versions are all empty");
+
+        env.integrity.allowed_dirs = vec![dir.to_path_buf()];
+        assert_status(&integrity(&env), Status::Pass, "is allowed, sha256 e3b0c44298fc1c149afbf4c8996fb924");
+
+        env.integrity.sha256 = vec!["00".to_string()];
+        let check = integrity(&env);
+        assert_status(&check, Status::Fail, "isn't allowed, the patcher won't patch or restart docker");
+        assert!(check.remediation.unwrap().contains("add its sha256 e3b0c44298fc1c149afbf4c8996fb924"));
+
+        env.integrity.allowed_dirs = vec!["/nowhere".into()];
+        assert_status(&integrity(&env), Status::Fail, "isn't in any of the allowed directories");
+    }
+
+    #[test]
+    fn daemon_json() {
+        let dir = ScratchDir::new("doctor-daemon-json");
+        let env = env(&dir);
+        let daemon_json = |backend: &FakeBackend| check(&run(backend, &env), "daemon.json").clone();
+
+        assert_status(&daemon_json(&healthy(&dir)), Status::Pass, "doesn't exist");
+
+        fs::write(&env.default_daemon_json, r#"{"debug": true}"#).unwrap();
+        assert_status(&daemon_json(&healthy(&dir)), Status::Pass, "doesn't conflict with the patch");
+
+        fs::write(&env.default_daemon_json, r#"{"exec-opts": ["isolation=hyperv"]}"#).unwrap();
+        assert_status(&daemon_json(&healthy(&dir)), Status::Fail, r#"sets exec-opts ["isolation=hyperv"]"#);
+
+        // --config-file replaces the default one
+        let other = dir.join("other.json");
+        fs::write(&other, "{").unwrap();
+        let command_line = format!("{} --config-file {}", patched(&dockerd(&dir)), other.display());
+        let backend = healthy(&dir).with(DOCKER_SERVICE_NAME, ServiceState::Running, &command_line);
+        let check = daemon_json(&backend);
+        assert_status(&check, Status::Fail, "other.json is not valid json");
+    }
+
+    #[test]
+    fn state_file() {
+        let dir = ScratchDir::new("doctor-state");
+        let env = env(&dir);
+        let dockerd = dockerd(&dir);
+        let state = |backend: &FakeBackend| check(&run(backend, &env), "state file").clone();
+
+        assert_status(&state(&healthy(&dir)), Status::Pass, "no patch recorded yet");
+
+        let recorded = State {
+            original_command_line: Some(dockerd.clone()),
+            patched_command_line: Some(patched(&dockerd)),
+            patched_at: Some(state::unix_time()),
+            ..Default::default()
+        };
+        recorded.save_to(&env.state_path).unwrap();
+        assert_status(&state(&healthy(&dir)), Status::Pass, "last patched 0 seconds ago");
+
+        let changed = healthy(&dir).with(DOCKER_SERVICE_NAME, ServiceState::Running, &dockerd);
+        assert_status(&state(&changed), Status::Warn, "docker's command line changed since it was patched");
+
+        State { original_command_line: Some(patched(&dockerd)), ..recorded }.save_to(&env.state_path).unwrap();
+        assert_status(&state(&healthy(&dir)), Status::Warn, "already contains the patch");
+
+        fs::write(&env.state_path, "{").unwrap();
+        let check = state(&healthy(&dir));
+        assert_eq!(check.status, Status::Fail);
+        assert!(check.remediation.unwrap().starts_with(&format!("delete {}", env.state_path.display())));
+    }
+
+    #[test]
+    fn log_dir_and_permissions() {
+        let dir = ScratchDir::new("doctor-permissions");
+        let mut env = env(&dir);
+
+        fs::write(&env.config_path, "").unwrap();
+        set_mode(&env.config_path, 0o646);
+        let checks = run(&healthy(&dir), &env);
+        assert_status(check(&checks, "permissions"), Status::Fail, "config.toml can be changed by everyone");
+        assert_status(check(&checks, "log directory"), Status::Pass, "is writable");
+        // the probe is gone again
+        assert!(!dir.join(".doctor-write-test").exists());
+
+        set_mode(&env.config_path, 0o644);
+        env.log_dir = dir.join("missing");
+        let checks = run(&healthy(&dir), &env);
+        assert_status(check(&checks, "log directory"), Status::Fail, "missing is not writable");
+    }
+}
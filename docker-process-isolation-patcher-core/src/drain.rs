@@ -0,0 +1,105 @@
+// Graceful docker restarts
+// Stopping docker kills every running container. With graceful restarts the watcher stops the
+// running containers through the engine api first, giving each the configured time to exit, and
+// starts them again once the patched docker answers. Containers with restart policy always are
+// left to docker, it starts them itself when it comes back.
+
+use std::io;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::warn;
+
+use super::engine;
+use super::proxy::Connector;
+use super::state::NotRestarted;
+
+/// how long the restarted docker gets to answer
+const ENGINE_START_TIMEOUT: Duration = Duration::from_secs(60);
+/// how long the containers get to run once they were started
+const CONTAINER_START_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A container stopped before docker was
+#[derive(Debug, Clone)]
+pub struct Drained {
+    pub id: String,
+    pub name: String,
+    pub restart_policy: String,
+    pub auto_remove: bool,
+}
+
+impl Drained {
+    fn not_restarted(&self, reason: impl Into<String>) -> NotRestarted {
+        NotRestarted { container: self.name.clone(), reason: reason.into() }
+    }
+}
+
+/// Stop every running container, all at once so the slowest one decides how long it takes.
+/// A container which fails to stop is still returned, docker kills it when it stops
+pub fn stop_running<C: Connector>(connector: &C, timeout_secs: u64) -> io::Result<Vec<Drained>> {
+    let containers = engine::running_containers(connector)?;
+
+    let drained = thread::scope(|scope| {
+        let stops: Vec<_> = containers
+            .iter()
+            .map(|container| {
+                scope.spawn(move || {
+                    let details = engine::inspect_container(connector, &container.id).unwrap_or_default();
+
+                    if let Err(e) = engine::stop_container(connector, &container.id, timeout_secs) {
+                        warn!("failed to stop container {}: {}", container.name(), e);
+                    }
+
+                    Drained {
+                        id: container.id.clone(),
+                        name: container.name().to_string(),
+                        restart_policy: details.host_config.restart_policy.name,
+                        auto_remove: details.host_config.auto_remove,
+                    }
+                })
+            })
+            .collect();
+
+        stops.into_iter().map(|stop| stop.join().unwrap()).collect()
+    });
+
+    Ok(drained)
+}
+
+/// Start the drained containers again once docker answers and wait for them to run. Returns the
+/// ones which didn't come back
+pub fn restore<C: Connector>(connector: &C, drained: &[Drained]) -> Vec<NotRestarted> {
+    if let Err(e) = engine::wait_until_up(connector, ENGINE_START_TIMEOUT) {
+        return drained.iter().map(|c| c.not_restarted(format!("docker doesn't answer: {}", e))).collect();
+    }
+
+    let mut not_restarted = vec![];
+    let mut starting = vec![];
+
+    for container in drained {
+        if container.auto_remove {
+            not_restarted.push(container.not_restarted("it ran with --rm and was removed when it stopped"));
+        } else if container.restart_policy == "always" {
+            starting.push(container);
+        } else {
+            match engine::start_container(connector, &container.id) {
+                Ok(()) => starting.push(container),
+                Err(e) => not_restarted.push(container.not_restarted(e.to_string())),
+            }
+        }
+    }
+
+    let deadline = Instant::now() + CONTAINER_START_TIMEOUT;
+    while !starting.is_empty() && Instant::now() < deadline {
+        if let Ok(running) = engine::running_containers(connector) {
+            starting.retain(|c| !running.iter().any(|r| r.id == c.id));
+        }
+
+        if !starting.is_empty() {
+            thread::sleep(Duration::from_secs(1));
+        }
+    }
+
+    not_restarted.extend(starting.into_iter().map(|c| c.not_restarted("it isn't running")));
+    not_restarted
+}
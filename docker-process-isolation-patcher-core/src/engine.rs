@@ -0,0 +1,160 @@
+// Docker Engine API client
+// The few calls the watcher needs, over the same pipe/socket connectors the proxy uses.
+
+use std::io::{self, BufReader, Write};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+
+use super::proxy::{self, Connector, Head, Stream};
+
+#[cfg(windows)]
+pub type EngineConnector = proxy::pipe::PipeConnector;
+#[cfg(windows)]
+pub fn connector(endpoint: &str) -> EngineConnector {
+    proxy::pipe::PipeConnector::new(endpoint)
+}
+
+#[cfg(unix)]
+pub type EngineConnector = proxy::unix::UnixSocketConnector;
+#[cfg(unix)]
+pub fn connector(endpoint: &str) -> EngineConnector {
+    proxy::unix::UnixSocketConnector::new(endpoint)
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Container {
+    #[serde(rename = "Id")]
+    pub id: String,
+    #[serde(rename = "Names", default)]
+    pub names: Vec<String>,
+    #[serde(rename = "Image", default)]
+    pub image: String,
+}
+
+impl Container {
+    /// First name without the leading slash, or the short id
+    pub fn name(&self) -> &str {
+        match self.names.first() {
+            Some(name) => name.trim_start_matches('/'),
+            None => &self.id[..self.id.len().min(12)],
+        }
+    }
+}
+
+/// The parts of `/containers/{id}/json` the patcher looks at
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct ContainerDetails {
+    #[serde(rename = "State")]
+    pub state: ContainerState,
+    #[serde(rename = "HostConfig")]
+    pub host_config: HostConfig,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct ContainerState {
+    #[serde(rename = "Running")]
+    pub running: bool,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct HostConfig {
+    #[serde(rename = "RestartPolicy")]
+    pub restart_policy: RestartPolicy,
+    /// started with --rm, it's removed once it stops
+    #[serde(rename = "AutoRemove")]
+    pub auto_remove: bool,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct RestartPolicy {
+    /// no, always, unless-stopped or on-failure, empty means no
+    #[serde(rename = "Name")]
+    pub name: String,
+}
+
+/// Send a request without a body and return the status and body
+pub fn request<C: Connector>(connector: &C, method: &str, path: &str) -> io::Result<(u16, Vec<u8>)> {
+    let stream = connector.connect()?;
+    let mut writer = stream.try_clone()?;
+    let mut reader = BufReader::new(stream);
+
+    write!(writer, "{} {} HTTP/1.1\r\nHost: docker\r\nContent-Length: 0\r\nConnection: close\r\n\r\n", method, path)?;
+    writer.flush()?;
+
+    let response = Head::read(&mut reader)?
+        .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "docker closed the connection"))?;
+
+    let status = response.status()?;
+    let body = proxy::read_body(&response.body_length(true), &mut reader)?;
+
+    Ok((status, body))
+}
+
+/// GET a json document, anything but 200 is an error
+pub fn get_json<C: Connector, T: DeserializeOwned>(connector: &C, path: &str) -> io::Result<T> {
+    let (status, body) = request(connector, "GET", path)?;
+
+    if status != 200 {
+        return Err(io::Error::new(io::ErrorKind::NotFound, format!("docker returned {} for {}", status, path)));
+    }
+
+    serde_json::from_slice(&body).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Whether the engine is up, it answers `/_ping` with 200 once it is
+pub fn ping<C: Connector>(connector: &C) -> io::Result<()> {
+    let (status, _) = request(connector, "GET", "/_ping")?;
+
+    if status != 200 {
+        return Err(io::Error::other(format!("docker returned {} for /_ping", status)));
+    }
+
+    Ok(())
+}
+
+/// Wait for the engine to answer, e.g. after docker was started
+pub fn wait_until_up<C: Connector>(connector: &C, timeout: Duration) -> io::Result<()> {
+    let deadline = Instant::now() + timeout;
+
+    loop {
+        match ping(connector) {
+            Ok(()) => return Ok(()),
+            Err(e) if Instant::now() >= deadline => return Err(e),
+            Err(_) => thread::sleep(Duration::from_secs(1)),
+        }
+    }
+}
+
+pub fn running_containers<C: Connector>(connector: &C) -> io::Result<Vec<Container>> {
+    get_json(connector, "/containers/json")
+}
+
+pub fn inspect_container<C: Connector>(connector: &C, id: &str) -> io::Result<ContainerDetails> {
+    get_json(connector, &format!("/containers/{}/json", id))
+}
+
+/// Stop a container, killing it if it's still running after `timeout_secs`
+pub fn stop_container<C: Connector>(connector: &C, id: &str, timeout_secs: u64) -> io::Result<()> {
+    post(connector, &format!("/containers/{}/stop?t={}", id, timeout_secs))
+}
+
+pub fn start_container<C: Connector>(connector: &C, id: &str) -> io::Result<()> {
+    post(connector, &format!("/containers/{}/start", id))
+}
+
+/// POST without a body, 304 means there was nothing to do
+fn post<C: Connector>(connector: &C, path: &str) -> io::Result<()> {
+    let (status, body) = request(connector, "POST", path)?;
+
+    match status {
+        204 | 304 => Ok(()),
+        _ => Err(io::Error::other(format!("docker returned {} for {}: {}", status, path, String::from_utf8_lossy(&body).trim()))),
+    }
+}
@@ -0,0 +1,175 @@
+// Structured log events
+// Watcher and CLI log lines carry an event with a stable id, which the json log format writes as
+// separate fields so log shippers can alert on them. Ids are part of the log format, never rename
+// or reuse one.
+
+use std::cell::RefCell;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+
+use super::backend::Target;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum EventId {
+    #[serde(rename = "service.started")]
+    ServiceStarted,
+    #[serde(rename = "service.stopped")]
+    ServiceStopped,
+    #[serde(rename = "service.retrying")]
+    ServiceRetrying,
+    #[serde(rename = "service.failed")]
+    ServiceFailed,
+    #[serde(rename = "config.invalid")]
+    ConfigInvalid,
+    #[serde(rename = "proxy.started")]
+    ProxyStarted,
+
+    #[serde(rename = "docker.state-changed")]
+    DockerStateChanged,
+    #[serde(rename = "docker.deleted")]
+    DockerDeleted,
+    #[serde(rename = "desktop.mode-changed")]
+    DesktopModeChanged,
+
+    #[serde(rename = "patch.already-patched")]
+    PatchAlreadyApplied,
+    #[serde(rename = "patch.started")]
+    PatchStarted,
+    #[serde(rename = "patch.docker-stopped")]
+    PatchDockerStopped,
+    #[serde(rename = "patch.applied")]
+    PatchApplied,
+    #[serde(rename = "patch.docker-started")]
+    PatchDockerStarted,
+    #[serde(rename = "patch.failed")]
+    PatchFailed,
+    #[serde(rename = "patch.removed")]
+    PatchRemoved,
+    #[serde(rename = "drift.detected")]
+    DriftDetected,
+    #[serde(rename = "drift.corrected")]
+    DriftCorrected,
+    #[serde(rename = "drift.pending-restart")]
+    DriftPendingRestart,
+    #[serde(rename = "restart.deferred")]
+    RestartDeferred,
+    #[serde(rename = "restart.performed")]
+    RestartPerformed,
+    #[serde(rename = "containers.stopped")]
+    ContainersStopped,
+    #[serde(rename = "containers.restarted")]
+    ContainersRestarted,
+    #[serde(rename = "containers.not-restarted")]
+    ContainersNotRestarted,
+    #[serde(rename = "integrity.failed")]
+    IntegrityFailed,
+    #[serde(rename = "hook.vetoed")]
+    HookVetoed,
+    #[serde(rename = "hook.failed")]
+    HookFailed,
+    #[serde(rename = "control.request")]
+    ControlRequest,
+    #[serde(rename = "audit.appended")]
+    AuditAppended,
+    #[serde(rename = "state.load-failed")]
+    StateLoadFailed,
+    #[serde(rename = "state.save-failed")]
+    StateSaveFailed,
+
+    #[serde(rename = "cli.service-installed")]
+    CliServiceInstalled,
+    #[serde(rename = "cli.service-uninstalled")]
+    CliServiceUninstalled,
+    #[serde(rename = "cli.service-started")]
+    CliServiceStarted,
+    #[serde(rename = "cli.service-stopped")]
+    CliServiceStopped,
+    #[serde(rename = "cli.error")]
+    CliError,
+
+    #[serde(rename = "process.panic")]
+    Panic,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Event {
+    pub id: EventId,
+    /// service the event is about
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub service: Option<String>,
+    /// remote computer the service is on
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub computer: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub old_state: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub new_state: Option<String>,
+    /// shared by every event of one patch attempt
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub correlation_id: Option<String>,
+}
+
+impl Event {
+    pub fn new(id: EventId) -> Self {
+        Event { id, service: None, computer: None, old_state: None, new_state: None, correlation_id: None }
+    }
+
+    pub fn service(mut self, service: &str) -> Self {
+        self.service = Some(service.to_string());
+        self
+    }
+
+    pub fn target(mut self, target: &Target) -> Self {
+        self.computer = target.computer().map(ToString::to_string);
+        self
+    }
+
+    pub fn states(mut self, old: impl ToString, new: impl ToString) -> Self {
+        self.old_state = Some(old.to_string());
+        self.new_state = Some(new.to_string());
+        self
+    }
+
+    pub fn correlation(mut self, id: &str) -> Self {
+        self.correlation_id = Some(id.to_string());
+        self
+    }
+}
+
+thread_local! {
+    static CURRENT: RefCell<Option<Event>> = const { RefCell::new(None) };
+}
+
+/// Run `f` with `event` attached to every record it logs on this thread. Used by `event!`
+pub fn with_event<R>(event: Event, f: impl FnOnce() -> R) -> R {
+    let previous = CURRENT.with(|current| current.replace(Some(event)));
+    let res = f();
+    CURRENT.with(|current| *current.borrow_mut() = previous);
+
+    res
+}
+
+/// Event attached to the record being logged right now, if any
+pub fn current() -> Option<Event> {
+    CURRENT.with(|current| current.borrow().clone())
+}
+
+/// Unique per process run and attempt, e.g. `17c3a5f2e4b-1a2c-3`
+pub fn correlation_id() -> String {
+    static COUNTER: AtomicU32 = AtomicU32::new(0);
+
+    let millis = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis()).unwrap_or(0);
+    let n = COUNTER.fetch_add(1, Ordering::Relaxed);
+
+    format!("{:x}-{:x}-{:x}", millis, std::process::id(), n)
+}
+
+/// `log!` with an event attached, e.g. `event!(Level::Info, Event::new(EventId::PatchStarted), "...")`
+#[macro_export]
+macro_rules! event {
+    ($level:expr, $event:expr, $($arg:tt)+) => {
+        $crate::event::with_event($event, || log::log!($level, $($arg)+))
+    };
+}
@@ -0,0 +1,89 @@
+// Exit codes of the patcher service
+// A failed service reports why it stopped as a service specific exit code in its last status, so
+// SCM recovery actions run, and exits with the same code on Linux for systemd's Restart. The
+// failure is kept in the state file for `status` and `doctor`. Codes are part of the interface,
+// never renumber or reuse one.
+
+use std::error::Error;
+use std::fmt;
+
+use super::backend::BackendError;
+use super::crash::Panicked;
+use super::lock::LockError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ServiceExit {
+    /// an error without a code of its own
+    Internal,
+    /// a service manager error retrying can't fix
+    ServiceManager,
+    /// the patch lock couldn't be created
+    Lock,
+    /// the service worker panicked, see its panic report
+    Panic,
+}
+
+impl ServiceExit {
+    pub const ALL: [ServiceExit; 4] = [ServiceExit::Internal, ServiceExit::ServiceManager, ServiceExit::Lock, ServiceExit::Panic];
+
+    /// Why the service stopped with `e`
+    pub fn of(e: &(dyn Error + 'static)) -> Self {
+        if e.is::<BackendError>() {
+            ServiceExit::ServiceManager
+        } else if e.is::<LockError>() {
+            ServiceExit::Lock
+        } else if e.is::<Panicked>() {
+            ServiceExit::Panic
+        } else {
+            ServiceExit::Internal
+        }
+    }
+
+    pub fn code(self) -> u32 {
+        match self {
+            ServiceExit::Internal => 1,
+            ServiceExit::ServiceManager => 2,
+            ServiceExit::Lock => 3,
+            ServiceExit::Panic => 4,
+        }
+    }
+
+    pub fn from_code(code: u32) -> Option<Self> {
+        ServiceExit::ALL.iter().copied().find(|e| e.code() == code)
+    }
+}
+
+impl fmt::Display for ServiceExit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ServiceExit::Internal => f.write_str("internal error"),
+            ServiceExit::ServiceManager => f.write_str("service manager error"),
+            ServiceExit::Lock => f.write_str("patch lock unavailable"),
+            ServiceExit::Panic => f.write_str("panic"),
+        }
+    }
+}
+
+/// `code` with what it means, e.g. "2 (service manager error)"
+pub fn describe(code: u32) -> String {
+    match ServiceExit::from_code(code) {
+        Some(exit) => format!("{} ({})", code, exit),
+        None => format!("{} (unknown)", code),
+    }
+}
+
+/// `e` followed by the errors that caused it, unless its message ends with them already
+pub fn error_chain(e: &(dyn Error + 'static)) -> String {
+    let mut out = e.to_string();
+    let mut source = e.source();
+
+    while let Some(cause) = source {
+        let cause_message = cause.to_string();
+        if !out.ends_with(&cause_message) {
+            out.push_str(&format!(": {}", cause_message));
+        }
+        source = cause.source();
+    }
+
+    out
+}
@@ -0,0 +1,500 @@
+// Hook commands run around a patch
+// Each hook is a command line, run with cmd /C (sh -c elsewhere) from the exe directory. The
+// attempt is described in DOCKER_PATCHER_* environment variables, and the hook's output goes to
+// the log. A pre-stop hook which fails, times out or can't be started vetoes the patch, the other
+// hooks can only report failures. A hook that times out is killed with everything it started, it
+// runs in a job object (a process group elsewhere). Hooks run as SYSTEM, so one whose directory or script
+// non-administrators can change isn't run at all, see permissions.
+
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::info;
+use serde::Deserialize;
+
+use super::patch;
+use super::permissions::{self, PermissionError};
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HooksConfig {
+    /// before docker is stopped, exiting with anything but 0 vetoes the patch
+    pub pre_stop: Option<String>,
+    /// after the patched command line was written
+    pub post_patch: Option<String>,
+    /// after the patched docker started
+    pub post_start: Option<String>,
+    /// after the patch couldn't be written or the patched docker didn't start
+    pub on_failure: Option<String>,
+    /// hooks still running after this are killed
+    pub timeout_secs: u64,
+    /// how long the watcher waits after a veto before trying again
+    pub veto_retry_secs: u64,
+}
+
+impl Default for HooksConfig {
+    fn default() -> Self {
+        HooksConfig {
+            pre_stop: None,
+            post_patch: None,
+            post_start: None,
+            on_failure: None,
+            timeout_secs: 300,
+            veto_retry_secs: 300,
+        }
+    }
+}
+
+impl HooksConfig {
+    pub fn command(&self, stage: Stage) -> Option<&str> {
+        let command = match stage {
+            Stage::PreStop => &self.pre_stop,
+            Stage::PostPatch => &self.post_patch,
+            Stage::PostStart => &self.post_start,
+            Stage::OnFailure => &self.on_failure,
+        };
+
+        command.as_deref().filter(|c| !c.trim().is_empty())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    PreStop,
+    PostPatch,
+    PostStart,
+    OnFailure,
+}
+
+impl Stage {
+    pub const ALL: [Stage; 4] = [Stage::PreStop, Stage::PostPatch, Stage::PostStart, Stage::OnFailure];
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let stage = match self {
+            Stage::PreStop => "pre-stop",
+            Stage::PostPatch => "post-patch",
+            Stage::PostStart => "post-start",
+            Stage::OnFailure => "on-failure",
+        };
+
+        f.write_str(stage)
+    }
+}
+
+/// What the hook is told about the attempt
+#[derive(Debug, Clone)]
+pub struct Context {
+    pub service: String,
+    /// remote computer the service is on, None for this one. Hooks always run here
+    pub computer: Option<String>,
+    pub old_command_line: String,
+    pub new_command_line: String,
+    pub attempt_id: String,
+    pub trigger: String,
+    /// why the attempt failed, only for on-failure
+    pub error: Option<String>,
+}
+
+impl Context {
+    fn env(&self, stage: Stage) -> Vec<(&'static str, String)> {
+        let mut env = vec![
+            ("DOCKER_PATCHER_HOOK", stage.to_string()),
+            ("DOCKER_PATCHER_SERVICE", self.service.clone()),
+            ("DOCKER_PATCHER_OLD_COMMAND_LINE", self.old_command_line.clone()),
+            ("DOCKER_PATCHER_NEW_COMMAND_LINE", self.new_command_line.clone()),
+            ("DOCKER_PATCHER_ATTEMPT_ID", self.attempt_id.clone()),
+            ("DOCKER_PATCHER_TRIGGER", self.trigger.clone()),
+        ];
+
+        if let Some(computer) = &self.computer {
+            env.push(("DOCKER_PATCHER_COMPUTER", computer.clone()));
+        }
+
+        if let Some(error) = &self.error {
+            env.push(("DOCKER_PATCHER_ERROR", error.clone()));
+        }
+
+        env
+    }
+}
+
+#[derive(Debug)]
+pub enum HookError {
+    Spawn(io::Error),
+    /// the hook's directory or a file it names can be changed by non-administrators
+    Insecure(PermissionError),
+    Timeout(Duration),
+    /// exit code, None if it was terminated
+    Failed(Option<i32>),
+}
+
+impl fmt::Display for HookError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HookError::Spawn(e) => write!(f, "failed to run: {}", e),
+            HookError::Insecure(e) => write!(f, "wasn't run: {}", e),
+            HookError::Timeout(timeout) => write!(f, "killed after {} seconds", timeout.as_secs()),
+            HookError::Failed(Some(code)) => write!(f, "exited with {}", code),
+            HookError::Failed(None) => f.write_str("was terminated"),
+        }
+    }
+}
+
+impl std::error::Error for HookError {}
+
+/// Run the hook for `stage` if one is configured and wait for it
+pub fn run(config: &HooksConfig, stage: Stage, context: &Context) -> Result<(), HookError> {
+    if config.command(stage).is_none() {
+        return Ok(());
+    }
+
+    let exe = std::env::current_exe().map_err(HookError::Spawn)?;
+    permissions::check_file(&exe).map_err(HookError::Insecure)?;
+
+    run_in(config, stage, context, exe.parent().unwrap())
+}
+
+/// Run the hook for `stage` with `dir` as its working directory
+fn run_in(config: &HooksConfig, stage: Stage, context: &Context, dir: &Path) -> Result<(), HookError> {
+    let command = match config.command(stage) {
+        Some(command) => command,
+        None => return Ok(()),
+    };
+
+    check_command(command, dir).map_err(HookError::Insecure)?;
+
+    info!("running {} hook: {}", stage, command);
+
+    let mut cmd = shell(command);
+    cmd.envs(context.env(stage)).current_dir(dir).stdin(Stdio::null()).stdout(Stdio::piped()).stderr(Stdio::piped());
+    let (mut child, tree) = platform::ProcessTree::spawn(&mut cmd).map_err(HookError::Spawn)?;
+
+    // not joined, a process started by the hook may keep the pipes open after the hook exits
+    log_output(stage, child.stdout.take());
+    log_output(stage, child.stderr.take());
+
+    let timeout = Duration::from_secs(config.timeout_secs);
+    let status = wait(&mut child, timeout).map_err(HookError::Spawn)?;
+
+    match status {
+        Some(status) if status.success() => {
+            info!("{} hook finished", stage);
+            Ok(())
+        }
+
+        Some(status) => Err(HookError::Failed(status.code())),
+
+        None => {
+            // cmd or sh only started the hook, its script and whatever that started have to go too
+            let _ = tree.kill().or_else(|_| child.kill());
+            let _ = child.wait();
+            Err(HookError::Timeout(timeout))
+        }
+    }
+}
+
+/// Check the exe, the exe directory the hook runs in, where cmd looks for programs first, and
+/// every file the command names, like its script
+pub fn check_permissions(command: &str) -> Result<(), PermissionError> {
+    let exe = std::env::current_exe().map_err(|e| PermissionError::Io(PathBuf::from("the patcher executable"), e))?;
+    permissions::check_file(&exe)?;
+
+    check_command(command, exe.parent().unwrap())
+}
+
+/// Check `dir` and every file `command` names. Relative paths are taken from `dir`, the hook's
+/// working directory, and each file's directories are checked up to the root
+fn check_command(command: &str, dir: &Path) -> Result<(), PermissionError> {
+    permissions::check_dir(dir)?;
+
+    for arg in patch::split_command_line(command) {
+        // joining an absolute path gives the path itself
+        let path = dir.join(&arg);
+        if path.exists() {
+            permissions::check_file(&path)?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(windows)]
+fn shell(command: &str) -> Command {
+    use std::os::windows::process::CommandExt;
+
+    // passed as is, cmd doesn't understand the quoting of regular args
+    let mut cmd = Command::new("cmd");
+    cmd.arg("/C").raw_arg(command);
+    cmd
+}
+
+#[cfg(not(windows))]
+fn shell(command: &str) -> Command {
+    let mut cmd = Command::new("sh");
+    cmd.arg("-c").arg(command);
+    cmd
+}
+
+/// Wait for the child to exit, None if it's still running after `timeout`
+fn wait(child: &mut Child, timeout: Duration) -> io::Result<Option<ExitStatus>> {
+    let deadline = Instant::now() + timeout;
+
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(Some(status));
+        }
+
+        if Instant::now() >= deadline {
+            return Ok(None);
+        }
+
+        thread::sleep(Duration::from_millis(100));
+    }
+}
+
+fn log_output(stage: Stage, output: Option<impl Read + Send + 'static>) {
+    if let Some(output) = output {
+        thread::spawn(move || {
+            for line in BufReader::new(output).lines().map_while(Result::ok) {
+                info!("{} hook: {}", stage, line);
+            }
+        });
+    }
+}
+
+#[cfg(not(windows))]
+mod platform {
+    use std::io;
+    use std::os::unix::process::CommandExt;
+    use std::process::{Child, Command};
+
+    /// The hook's process group, which what it starts stays in unless it leaves on purpose
+    pub struct ProcessTree {
+        group: libc::pid_t,
+    }
+
+    impl ProcessTree {
+        pub fn spawn(cmd: &mut Command) -> io::Result<(Child, ProcessTree)> {
+            let child = cmd.process_group(0).spawn()?;
+            let group = child.id() as libc::pid_t;
+            Ok((child, ProcessTree { group }))
+        }
+
+        pub fn kill(&self) -> io::Result<()> {
+            match unsafe { libc::kill(-self.group, libc::SIGKILL) } {
+                0 => Ok(()),
+                _ => Err(io::Error::last_os_error()),
+            }
+        }
+    }
+}
+
+#[cfg(windows)]
+mod platform {
+    use std::io;
+    use std::mem;
+    use std::os::windows::io::AsRawHandle;
+    use std::os::windows::process::CommandExt;
+    use std::process::{Child, Command};
+    use std::ptr;
+
+    use winapi::shared::minwindef::{DWORD, FALSE};
+    use winapi::um::handleapi::{CloseHandle, INVALID_HANDLE_VALUE};
+    use winapi::um::jobapi2::{AssignProcessToJobObject, CreateJobObjectW, TerminateJobObject};
+    use winapi::um::processthreadsapi::{OpenThread, ResumeThread};
+    use winapi::um::tlhelp32::{CreateToolhelp32Snapshot, Thread32First, Thread32Next, TH32CS_SNAPTHREAD, THREADENTRY32};
+    use winapi::um::winbase::CREATE_SUSPENDED;
+    use winapi::um::winnt::{HANDLE, THREAD_SUSPEND_RESUME};
+
+    /// A job object holding the hook, processes it starts join the job too
+    pub struct ProcessTree {
+        job: HANDLE,
+    }
+
+    impl ProcessTree {
+        pub fn spawn(cmd: &mut Command) -> io::Result<(Child, ProcessTree)> {
+            let job = unsafe { CreateJobObjectW(ptr::null_mut(), ptr::null()) };
+            if job.is_null() {
+                return Err(io::Error::last_os_error());
+            }
+            let tree = ProcessTree { job };
+
+            // cmd only runs once it's in the job, so nothing it starts can get outside of it
+            let mut child = cmd.creation_flags(CREATE_SUSPENDED).spawn()?;
+            let res = match unsafe { AssignProcessToJobObject(job, child.as_raw_handle() as HANDLE) } {
+                0 => Err(io::Error::last_os_error()),
+                _ => resume(child.id()),
+            };
+
+            if let Err(e) = res {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(e);
+            }
+
+            Ok((child, tree))
+        }
+
+        pub fn kill(&self) -> io::Result<()> {
+            match unsafe { TerminateJobObject(self.job, 1) } {
+                0 => Err(io::Error::last_os_error()),
+                _ => Ok(()),
+            }
+        }
+    }
+
+    impl Drop for ProcessTree {
+        fn drop(&mut self) {
+            // closing the job leaves what's still in it running
+            unsafe { CloseHandle(self.job) };
+        }
+    }
+
+    /// Resume the main thread of a process created suspended, std doesn't hand out its handle so
+    /// it's looked up among the threads of the process
+    fn resume(process_id: u32) -> io::Result<()> {
+        let snapshot = unsafe { CreateToolhelp32Snapshot(TH32CS_SNAPTHREAD, 0) };
+        if snapshot == INVALID_HANDLE_VALUE {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut entry: THREADENTRY32 = unsafe { mem::zeroed() };
+        entry.dwSize = mem::size_of::<THREADENTRY32>() as DWORD;
+
+        let mut res = Err(io::Error::new(io::ErrorKind::NotFound, "the hook's thread wasn't found"));
+        let mut more = unsafe { Thread32First(snapshot, &mut entry) } != 0;
+        while more {
+            if entry.th32OwnerProcessID == process_id {
+                let thread = unsafe { OpenThread(THREAD_SUSPEND_RESUME, FALSE, entry.th32ThreadID) };
+                if thread.is_null() || unsafe { ResumeThread(thread) } == DWORD::MAX {
+                    res = Err(io::Error::last_os_error());
+                } else {
+                    res = Ok(());
+                }
+
+                if !thread.is_null() {
+                    unsafe { CloseHandle(thread) };
+                }
+                break;
+            }
+
+            more = unsafe { Thread32Next(snapshot, &mut entry) } != 0;
+        }
+
+        unsafe { CloseHandle(snapshot) };
+        res
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::fs;
+    use std::path::Path;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use super::{run_in, Context, HookError, HooksConfig, Stage};
+    use crate::permissions::PermissionError;
+    use crate::testing::{set_mode, ScratchDir};
+
+    fn context() -> Context {
+        Context {
+            service: "docker".to_string(),
+            computer: None,
+            old_command_line: "dockerd".to_string(),
+            new_command_line: "dockerd --exec-opt isolation=process".to_string(),
+            attempt_id: "attempt".to_string(),
+            trigger: "watcher".to_string(),
+            error: None,
+        }
+    }
+
+    fn pre_stop(command: &str) -> HooksConfig {
+        HooksConfig { pre_stop: Some(command.to_string()), ..Default::default() }
+    }
+
+    fn script(path: &Path, contents: &str, mode: u32) {
+        fs::write(path, contents).unwrap();
+        set_mode(path, mode);
+    }
+
+    /// Whether the process is gone, a zombie nobody reaped counts as gone
+    fn gone(pid: &str) -> bool {
+        fs::read_to_string(format!("/proc/{}/stat", pid)).map_or(true, |stat| stat.rsplit(')').next().unwrap().trim_start().starts_with('Z'))
+    }
+
+    #[test]
+    fn hook_gets_the_attempt() {
+        let dir = ScratchDir::new("hook-env");
+
+        run_in(&pre_stop("echo $DOCKER_PATCHER_HOOK $DOCKER_PATCHER_ATTEMPT_ID > out"), Stage::PreStop, &context(), &dir).unwrap();
+
+        assert_eq!(fs::read_to_string(dir.join("out")).unwrap(), "pre-stop attempt\n");
+    }
+
+    #[test]
+    fn failing_hook() {
+        let dir = ScratchDir::new("hook-fail");
+
+        let res = run_in(&pre_stop("exit 3"), Stage::PreStop, &context(), &dir);
+
+        assert!(matches!(res, Err(HookError::Failed(Some(3)))));
+    }
+
+    #[test]
+    fn timeout_kills_the_process_group() {
+        let dir = ScratchDir::new("hook-timeout");
+        let config = HooksConfig { timeout_secs: 1, ..pre_stop("sleep 30 & echo $! > pid; wait") };
+
+        let res = run_in(&config, Stage::PreStop, &context(), &dir);
+        assert!(matches!(res, Err(HookError::Timeout(_))));
+
+        // the sleep the hook started went with it
+        let pid = fs::read_to_string(dir.join("pid")).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !gone(pid.trim()) {
+            assert!(Instant::now() < deadline, "{} is still running", pid.trim());
+            thread::sleep(Duration::from_millis(50));
+        }
+    }
+
+    #[test]
+    fn world_writable_hook_isnt_run() {
+        let dir = ScratchDir::new("hook-writable");
+        let hook = dir.join("hook.sh");
+        script(&hook, "touch ran\n", 0o757);
+
+        // named relative to the working directory or absolute
+        for command in ["sh hook.sh".to_string(), format!("sh {}", hook.display())] {
+            match run_in(&pre_stop(&command), Stage::PreStop, &context(), &dir) {
+                Err(HookError::Insecure(PermissionError::Writable { path, .. })) => assert_eq!(path, hook),
+                res => panic!("{}: {:?}", command, res),
+            }
+        }
+        assert!(!dir.join("ran").exists());
+
+        set_mode(&hook, 0o755);
+        run_in(&pre_stop("sh hook.sh"), Stage::PreStop, &context(), &dir).unwrap();
+        assert!(dir.join("ran").exists());
+    }
+
+    #[test]
+    fn hook_in_a_writable_directory_isnt_run() {
+        let dir = ScratchDir::new("hook-writable-dir");
+        let scripts = dir.join("scripts");
+        fs::create_dir(&scripts).unwrap();
+        script(&scripts.join("hook.sh"), "touch ran\n", 0o755);
+        set_mode(&scripts, 0o777);
+
+        match run_in(&pre_stop("sh scripts/hook.sh"), Stage::PreStop, &context(), &dir) {
+            Err(HookError::Insecure(PermissionError::Writable { path, .. })) => assert_eq!(path, scripts),
+            res => panic!("{:?}", res),
+        }
+    }
+}
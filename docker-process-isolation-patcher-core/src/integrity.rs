@@ -0,0 +1,221 @@
+// dockerd integrity check
+// The patcher runs as SYSTEM (root on Linux) and starts whatever executable the docker service
+// points to. With the check enabled, that executable has to be inside one of the allowed
+// directories and match an allowed SHA-256 or file version, otherwise the patcher refuses to write
+// docker's config or start it.
+
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use super::backend::Target;
+use super::patch;
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct IntegrityConfig {
+    pub enabled: bool,
+    /// directories dockerd has to be in, any directory if empty
+    pub allowed_dirs: Vec<PathBuf>,
+    /// SHA-256 of allowed dockerd executables, in hex
+    pub sha256: Vec<String>,
+    /// allowed file versions, "20.10" allows every 20.10.x.x. Windows only, Linux executables
+    /// have none
+    pub file_versions: Vec<String>,
+}
+
+/// What the checked dockerd looked like
+#[derive(Debug, Clone, Serialize)]
+pub struct Verified {
+    pub path: PathBuf,
+    pub sha256: String,
+    pub file_version: Option<String>,
+}
+
+#[derive(Debug)]
+pub enum IntegrityError {
+    /// enabled without allowed directories, hashes or versions
+    NothingAllowed,
+    /// the executable is on another computer, only this one's can be read
+    Remote(Target),
+    NoExecutable,
+    Io(PathBuf, io::Error),
+    OutsideAllowedDirs(PathBuf),
+    Mismatch(Verified),
+}
+
+impl fmt::Display for IntegrityError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IntegrityError::NothingAllowed => {
+                f.write_str("the integrity check is enabled, but allowed_dirs, sha256 and file_versions are all empty")
+            }
+            IntegrityError::Remote(target) => write!(f, "the dockerd of {} can't be checked, only this computer's", target),
+            IntegrityError::NoExecutable => f.write_str("the docker service has an empty command line"),
+            IntegrityError::Io(path, e) => write!(f, "failed to read {}: {}", path.display(), e),
+            IntegrityError::OutsideAllowedDirs(path) => write!(f, "{} isn't in any of the allowed directories", path.display()),
+            IntegrityError::Mismatch(found) => write!(
+                f,
+                "{} isn't allowed, its sha256 is {} and its file version {}",
+                found.path.display(),
+                found.sha256,
+                found.file_version.as_deref().unwrap_or("unknown")
+            ),
+        }
+    }
+}
+
+impl std::error::Error for IntegrityError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            IntegrityError::Io(_, e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// Check the executable of docker's `command_line`. None if the check is disabled
+pub fn verify(config: &IntegrityConfig, target: &Target, command_line: &str) -> Result<Option<Verified>, IntegrityError> {
+    if !config.enabled {
+        return Ok(None);
+    }
+
+    if config.allowed_dirs.is_empty() && config.sha256.is_empty() && config.file_versions.is_empty() {
+        return Err(IntegrityError::NothingAllowed);
+    }
+
+    if !target.is_local() {
+        return Err(IntegrityError::Remote(target.clone()));
+    }
+
+    let args = patch::split_command_line(command_line);
+    let executable = Path::new(args.first().ok_or(IntegrityError::NoExecutable)?);
+
+    // symlinks and .. are resolved, so the real file is checked
+    let path = executable.canonicalize().map_err(|e| IntegrityError::Io(executable.to_path_buf(), e))?;
+
+    if !config.allowed_dirs.is_empty() {
+        let allowed = config.allowed_dirs.iter().filter_map(|dir| dir.canonicalize().ok()).any(|dir| path.starts_with(dir));
+
+        if !allowed {
+            return Err(IntegrityError::OutsideAllowedDirs(path));
+        }
+    }
+
+    let found = Verified {
+        sha256: sha256_file(&path).map_err(|e| IntegrityError::Io(path.clone(), e))?,
+        file_version: file_version(&path),
+        path,
+    };
+
+    if config.sha256.is_empty() && config.file_versions.is_empty() {
+        return Ok(Some(found));
+    }
+
+    let hash_allowed = config.sha256.iter().any(|h| h.trim().eq_ignore_ascii_case(&found.sha256));
+    let version_allowed = found
+        .file_version
+        .as_deref()
+        .is_some_and(|version| config.file_versions.iter().any(|allowed| version_matches(allowed, version)));
+
+    if hash_allowed || version_allowed {
+        Ok(Some(found))
+    } else {
+        Err(IntegrityError::Mismatch(found))
+    }
+}
+
+pub fn sha256_file(path: &Path) -> io::Result<String> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; 64 * 1024];
+
+    loop {
+        match file.read(&mut buf)? {
+            0 => break,
+            n => hasher.update(&buf[..n]),
+        }
+    }
+
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// `allowed` matches versions starting with the same numbers, 20.10 matches 20.10.9.0
+fn version_matches(allowed: &str, version: &str) -> bool {
+    let allowed: Vec<&str> = allowed.trim().split('.').collect();
+    let version: Vec<&str> = version.split('.').collect();
+
+    allowed.len() <= version.len() && allowed.iter().zip(&version).all(|(a, v)| a.parse::<u32>().ok() == v.parse::<u32>().ok())
+}
+
+/// File version from the version resource, e.g. 20.10.9.0
+#[cfg(windows)]
+fn file_version(path: &Path) -> Option<String> {
+    use std::os::windows::ffi::OsStrExt;
+    use std::ptr;
+
+    use winapi::ctypes::c_void;
+    use winapi::um::winver::{GetFileVersionInfoSizeW, GetFileVersionInfoW, VerQueryValueW};
+
+    /// VS_FIXEDFILEINFO, which winapi 0.3 doesn't declare. Most fields are only there for the layout
+    #[repr(C)]
+    #[allow(dead_code)]
+    struct FixedFileInfo {
+        signature: u32,
+        struc_version: u32,
+        file_version_ms: u32,
+        file_version_ls: u32,
+        product_version_ms: u32,
+        product_version_ls: u32,
+        file_flags_mask: u32,
+        file_flags: u32,
+        file_os: u32,
+        file_type: u32,
+        file_subtype: u32,
+        file_date_ms: u32,
+        file_date_ls: u32,
+    }
+
+    let path: Vec<u16> = path.as_os_str().encode_wide().chain(Some(0)).collect();
+    let root: Vec<u16> = "\\".encode_utf16().chain(Some(0)).collect();
+
+    unsafe {
+        let size = GetFileVersionInfoSizeW(path.as_ptr(), ptr::null_mut());
+        if size == 0 {
+            return None;
+        }
+
+        let mut data = vec![0u8; size as usize];
+        if GetFileVersionInfoW(path.as_ptr(), 0, size, data.as_mut_ptr() as *mut c_void) == 0 {
+            return None;
+        }
+
+        let mut info: *mut c_void = ptr::null_mut();
+        let mut len = 0;
+        if VerQueryValueW(data.as_ptr() as *const c_void, root.as_ptr(), &mut info, &mut len) == 0 || info.is_null() {
+            return None;
+        }
+
+        if (len as usize) < std::mem::size_of::<FixedFileInfo>() {
+            return None;
+        }
+
+        let info = &*(info as *const FixedFileInfo);
+        Some(format!(
+            "{}.{}.{}.{}",
+            info.file_version_ms >> 16,
+            info.file_version_ms & 0xffff,
+            info.file_version_ls >> 16,
+            info.file_version_ls & 0xffff
+        ))
+    }
+}
+
+#[cfg(not(windows))]
+fn file_version(_path: &Path) -> Option<String> {
+    None
+}
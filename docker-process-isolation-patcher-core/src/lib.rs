@@ -0,0 +1,82 @@
+//! Patching engine of docker-process-isolation-patcher
+//!
+//! Everything the patcher does to the docker service, without the command line around it:
+//!
+//! - [`patch::split_command_line`] and [`patch::join_command_line`] parse and build a service
+//!   command line (the ImagePath)
+//! - [`patch::patch_config`] computes the patched config, [`patch::unpatch_config`] removes the
+//!   patch again
+//! - [`patch::is_patched`] and [`patch::has_drifted`] detect whether a command line is patched.
+//!   The patch is a list of dockerd [`patch::Flag`]s, [`patch::default_flags`] unless the config
+//!   sets `watcher.flags`
+//! - [`patch::apply`] and [`patch::restore`] write the patch, or the config from before it,
+//!   through a [`ServiceBackend`]. [`PlatformBackend`] is `scm::ScmBackend`, the Windows service
+//!   manager of this or another computer, or `systemd::SystemdBackend` on Linux. Tests can pass a
+//!   fake
+//! - [`watcher::Watcher`] is what the service runs: it patches, restarts docker by the restart
+//!   policy, runs hooks, rolls back and writes the state file and audit log
+//!
+//! ```no_run
+//! use docker_process_isolation_patcher_core::{patch, PlatformBackend};
+//!
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! let flags = patch::default_flags();
+//! let args = patch::split_command_line(r#""C:\Program Files\docker\dockerd.exe" --run-service"#);
+//! assert_eq!(patch::has_drifted(&args, &flags), !flags.is_empty());
+//!
+//! let backend = PlatformBackend::local()?;
+//! if let Some(original) = patch::apply(&backend, &flags)? {
+//!     println!("patched docker, restart it to apply. It was {}", original.command_line);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! The other modules are the pieces the CLI is built from and follow its needs, expect them to
+//! change more often.
+
+pub mod audit;
+pub mod backend;
+pub mod backoff;
+pub mod batch;
+pub mod compat;
+pub mod compose;
+pub mod config;
+pub mod control;
+pub mod crash;
+pub mod desktop;
+pub mod diagnostics;
+pub mod doctor;
+pub mod drain;
+pub mod engine;
+pub mod event;
+pub mod exit;
+pub mod hooks;
+pub mod integrity;
+pub mod lock;
+pub mod logging;
+pub mod manage;
+pub mod patch;
+pub mod permissions;
+pub mod plan;
+pub mod policy;
+pub mod proxy;
+pub mod schedule;
+#[cfg(windows)]
+pub mod scm;
+pub mod shared;
+pub mod state;
+pub mod status;
+#[cfg(unix)]
+pub mod systemd;
+#[cfg(all(test, unix))]
+mod testing;
+pub mod watcher;
+
+pub use backend::{BackendError, BackendResult, ServiceBackend, ServiceConfig, ServiceState, Target};
+
+/// Service manager of the platform, with `local()` and `connect(target)`
+#[cfg(windows)]
+pub use scm::ScmBackend as PlatformBackend;
+#[cfg(unix)]
+pub use systemd::SystemdBackend as PlatformBackend;
@@ -0,0 +1,186 @@
+// Lock against patching docker from two processes at once
+// The service, a CLI patch-now or unpatch, and batch would otherwise stop and start docker over
+// each other. Whoever changes docker on a target holds its lock first: a global named mutex on
+// Windows, a lock file in /run on Linux. Both are only creatable by administrators, so a user
+// can't hold the lock to keep docker from being patched. The service holds the lock of this
+// computer while it runs, CLI commands ask it over the control channel or wait for the lock.
+
+use std::fmt;
+use std::io;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::backend::Target;
+use super::shared::APP_NAME;
+
+/// how often a waiting process tries again
+const RETRY_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Held until dropped. On Windows the mutex belongs to the thread which took it, so it has to be
+/// dropped there too
+pub struct PatchLock {
+    _inner: platform::Lock,
+}
+
+#[derive(Debug)]
+pub enum LockError {
+    Io(io::Error),
+    /// another process holds the lock, with what it is if known
+    Busy { target: Target, holder: Option<String> },
+}
+
+impl fmt::Display for LockError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LockError::Io(e) => write!(f, "failed to take the patch lock: {}", e),
+            LockError::Busy { target, holder: Some(holder) } => {
+                write!(f, "another patcher process is changing docker on {}: {}", target, holder)
+            }
+            LockError::Busy { target, holder: None } => write!(
+                f,
+                "another patcher process is changing docker on {}, the patcher service or a patch-now or unpatch command",
+                target
+            ),
+        }
+    }
+}
+
+impl std::error::Error for LockError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            LockError::Io(e) => Some(e),
+            LockError::Busy { .. } => None,
+        }
+    }
+}
+
+impl PatchLock {
+    /// Take the lock of `target` if it's free. `holder` says who holds it, e.g. "unpatch", to
+    /// processes waiting for it
+    pub fn try_acquire(target: &Target, holder: &str) -> Result<PatchLock, LockError> {
+        let holder = format!("{} (pid {})", holder, std::process::id());
+
+        match platform::try_lock(&lock_name(target), &holder).map_err(LockError::Io)? {
+            Ok(inner) => Ok(PatchLock { _inner: inner }),
+            Err(holder) => Err(LockError::Busy { target: target.clone(), holder }),
+        }
+    }
+
+    /// Take the lock of `target`, waiting up to `timeout` for whoever holds it
+    pub fn acquire(target: &Target, holder: &str, timeout: Duration) -> Result<PatchLock, LockError> {
+        let deadline = Instant::now() + timeout;
+
+        loop {
+            match PatchLock::try_acquire(target, holder) {
+                Err(LockError::Busy { .. }) if Instant::now() < deadline => thread::sleep(RETRY_INTERVAL),
+                res => return res,
+            }
+        }
+    }
+}
+
+/// One lock per computer, remote ones by name
+fn lock_name(target: &Target) -> String {
+    let target = match target.computer() {
+        None => "local".to_string(),
+        Some(computer) => {
+            let computer: String = computer.chars().map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '.' { c } else { '_' }).collect();
+            format!("remote-{}", computer.to_lowercase())
+        }
+    };
+
+    format!("{}-{}", APP_NAME, target)
+}
+
+#[cfg(not(windows))]
+mod platform {
+    use std::fs::{self, File, OpenOptions, TryLockError};
+    use std::io::{self, Write};
+    use std::path::PathBuf;
+
+    pub struct Lock {
+        // the lock goes away with the file handle, when the process exits at the latest
+        _file: File,
+    }
+
+    fn path(name: &str) -> PathBuf {
+        PathBuf::from("/run").join(format!("{}.lock", name))
+    }
+
+    /// The lock, or who holds it if it's taken
+    pub fn try_lock(name: &str, holder: &str) -> io::Result<Result<Lock, Option<String>>> {
+        let path = path(name);
+        let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(&path)?;
+
+        match file.try_lock() {
+            Ok(()) => {
+                file.set_len(0)?;
+                file.write_all(holder.as_bytes())?;
+                Ok(Ok(Lock { _file: file }))
+            }
+
+            Err(TryLockError::WouldBlock) => {
+                let holder = fs::read_to_string(&path).ok().filter(|h| !h.is_empty());
+                Ok(Err(holder))
+            }
+
+            Err(TryLockError::Error(e)) => Err(e),
+        }
+    }
+}
+
+#[cfg(windows)]
+mod platform {
+    use std::ffi::OsStr;
+    use std::io;
+    use std::os::windows::ffi::OsStrExt;
+    use std::ptr;
+
+    use winapi::shared::winerror::WAIT_TIMEOUT;
+    use winapi::um::handleapi::CloseHandle;
+    use winapi::um::synchapi::{CreateMutexW, ReleaseMutex, WaitForSingleObject};
+    use winapi::um::winbase::{WAIT_ABANDONED, WAIT_OBJECT_0};
+    use winapi::um::winnt::HANDLE;
+
+    pub struct Lock {
+        mutex: HANDLE,
+    }
+
+    impl Drop for Lock {
+        fn drop(&mut self) {
+            unsafe {
+                ReleaseMutex(self.mutex);
+                CloseHandle(self.mutex);
+            }
+        }
+    }
+
+    /// The lock, or None as the holder if it's taken. A mutex can't tell who owns it
+    pub fn try_lock(name: &str, _holder: &str) -> io::Result<Result<Lock, Option<String>>> {
+        // Global\ so sessions share it, creating global objects needs administrator rights
+        let name: Vec<u16> = OsStr::new(&format!(r"Global\{}", name)).encode_wide().chain(Some(0)).collect();
+
+        unsafe {
+            let mutex = CreateMutexW(ptr::null_mut(), 0, name.as_ptr());
+            if mutex.is_null() {
+                return Err(io::Error::last_os_error());
+            }
+
+            match WaitForSingleObject(mutex, 0) {
+                // abandoned means its holder died, it's ours now
+                WAIT_OBJECT_0 | WAIT_ABANDONED => Ok(Ok(Lock { mutex })),
+
+                WAIT_TIMEOUT => {
+                    CloseHandle(mutex);
+                    Ok(Err(None))
+                }
+
+                _ => {
+                    let e = io::Error::last_os_error();
+                    CloseHandle(mutex);
+                    Err(e)
+                }
+            }
+        }
+    }
+}
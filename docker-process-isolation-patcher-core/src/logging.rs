@@ -0,0 +1,502 @@
+// File logging with size/age based rotation
+// app.log used to sit beside the exe, where it grew without bound and isn't writable when the exe
+// lives under Program Files. human-panic-logger writes panics into the same directory, the last
+// log lines are kept in memory for those reports, see crash.
+// Directories non-administrators can write to are skipped, the service logs there as SYSTEM.
+// Every process appends to the same app.log, only its owner rotates it: the service while it
+// runs, otherwise whoever gets the owner lock for the moment. Others would move the log out from
+// under the service, which would carry on writing into app.log.1.
+
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use log::{LevelFilter, Log, Metadata, Record};
+use serde::Deserialize;
+
+use super::permissions;
+use super::shared::APP_NAME;
+
+pub const LOG_FILE_NAME: &str = "app.log";
+/// held by the process owning app.log
+const OWNER_LOCK_FILE_NAME: &str = "app.lock";
+/// overrides the configured level, e.g. `DOCKER_PATCHER_LOG_LEVEL=debug`
+pub const LOG_LEVEL_ENV: &str = "DOCKER_PATCHER_LOG_LEVEL";
+/// log lines kept for panic reports
+const RECENT_LINES: usize = 50;
+
+static RECENT: Mutex<VecDeque<String>> = Mutex::new(VecDeque::new());
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Text,
+    /// one json object per line, see `event`
+    Json,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingConfig {
+    /// defaults to the exe directory
+    pub directory: Option<PathBuf>,
+    /// off, error, warn, info, debug or trace
+    pub level: String,
+    pub format: LogFormat,
+    /// rotate once the log reaches this size
+    pub max_size_mb: u64,
+    /// rotate once the log is older than this, 0 disables age based rotation
+    pub max_age_days: u64,
+    /// number of rotated logs kept, app.log.1 being the newest
+    pub retention: usize,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        LoggingConfig {
+            directory: None,
+            level: "info".to_string(),
+            format: LogFormat::Text,
+            max_size_mb: 10,
+            max_age_days: 30,
+            retention: 5,
+        }
+    }
+}
+
+impl LoggingConfig {
+    /// Environment variable first, then config. Invalid values fall back to info
+    pub fn level(&self) -> LevelFilter {
+        std::env::var(LOG_LEVEL_ENV)
+            .ok()
+            .and_then(|level| LevelFilter::from_str(&level).ok())
+            .or_else(|| LevelFilter::from_str(&self.level).ok())
+            .unwrap_or(LevelFilter::Info)
+    }
+}
+
+/// Directories tried in order until one is usable
+fn candidate_dirs(config: &LoggingConfig) -> Vec<PathBuf> {
+    let mut dirs = vec![];
+
+    match &config.directory {
+        Some(dir) => dirs.push(dir.clone()),
+        None => dirs.push(std::env::current_exe().unwrap().parent().unwrap().to_path_buf()),
+    }
+
+    if let Some(program_data) = std::env::var_os("ProgramData") {
+        dirs.push(Path::new(&program_data).join(APP_NAME).join("logs"));
+    }
+
+    dirs.push(std::env::temp_dir().join(APP_NAME));
+    dirs
+}
+
+/// Writable, and only by administrators, the service writes there as SYSTEM. Probed with a file
+/// of its own, directories which aren't picked are left as they were
+fn is_usable(dir: &Path) -> bool {
+    if fs::create_dir_all(dir).is_err() || permissions::check_dir(dir).is_err() {
+        return false;
+    }
+
+    let probe = dir.join(format!(".write-test-{}", std::process::id()));
+    match OpenOptions::new().write(true).create_new(true).open(&probe) {
+        Ok(_) => {
+            fs::remove_file(&probe).ok();
+            true
+        }
+        Err(_) => false,
+    }
+}
+
+/// First usable log directory, without setting up logging
+pub fn log_dir(config: &LoggingConfig) -> PathBuf {
+    let dirs = candidate_dirs(config);
+
+    dirs.iter()
+        .find(|dir| is_usable(dir))
+        .cloned()
+        .unwrap_or_else(|| dirs[0].clone())
+}
+
+/// Install the file logger. The `owner` of the log, the service, rotates it. Returns the directory
+/// logs are written to
+pub fn init(config: &LoggingConfig, owner: bool) -> io::Result<PathBuf> {
+    let preferred = candidate_dirs(config).remove(0);
+    let dir = log_dir(config);
+    permissions::check_dir(&dir).map_err(|e| io::Error::new(io::ErrorKind::PermissionDenied, e.to_string()))?;
+
+    let file = RotatingFile::open(
+        &dir,
+        owner,
+        Duration::from_secs(config.max_age_days * 24 * 60 * 60),
+        config.max_size_mb * 1024 * 1024,
+        config.retention,
+    )?;
+
+    let level = config.level();
+    let logger = FileLogger { level, format: config.format, file: Mutex::new(file) };
+
+    log::set_boxed_logger(Box::new(logger)).map_err(|e| io::Error::other(e.to_string()))?;
+    log::set_max_level(level);
+
+    if dir != preferred {
+        log::warn!("{} is not writable or not secure, logging to {} instead", preferred.display(), dir.display());
+    }
+
+    Ok(dir)
+}
+
+/// The last log lines, each with its line ending
+pub fn recent_lines() -> Vec<String> {
+    match RECENT.try_lock() {
+        Ok(recent) => recent.iter().cloned().collect(),
+        Err(_) => vec![],
+    }
+}
+
+struct FileLogger {
+    level: LevelFilter,
+    format: LogFormat,
+    file: Mutex<RotatingFile>,
+}
+
+impl Log for FileLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let line = match self.format {
+            LogFormat::Text => format!(
+                "{} [{}] {}: {}\n",
+                chrono::Local::now().format("%Y-%m-%d %H:%M:%S%.3f"),
+                record.level(),
+                module(record.target()),
+                record.args()
+            ),
+
+            LogFormat::Json => json_line(record),
+        };
+
+        // not waited for, the panic hook may be running on this thread
+        if let Ok(mut recent) = RECENT.try_lock() {
+            if recent.len() == RECENT_LINES {
+                recent.pop_front();
+            }
+            recent.push_back(line.clone());
+        }
+
+        // a poisoned lock only means another thread panicked mid write, keep logging
+        let mut file = self.file.lock().unwrap_or_else(|e| e.into_inner());
+        file.write_line(line.as_bytes()).ok();
+    }
+
+    fn flush(&self) {
+        let mut file = self.file.lock().unwrap_or_else(|e| e.into_inner());
+        file.file.flush().ok();
+    }
+}
+
+/// The module a record was logged from without the crate, e.g. `watcher`
+fn module(target: &str) -> &str {
+    target.split_once("::").map_or("main", |(_, module)| module)
+}
+
+/// `{"timestamp", "level", "target", "message", "event", "service", "computer", "old_state", "new_state", "correlation_id"}`,
+/// the event fields are left out for records without an event
+fn json_line(record: &Record) -> String {
+    let mut line = serde_json::json!({
+        "timestamp": chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+        "level": record.level().as_str(),
+        "target": record.target(),
+        "message": record.args().to_string(),
+    });
+
+    if let Some(event) = super::event::current() {
+        if let (Some(line), serde_json::Value::Object(fields)) = (line.as_object_mut(), serde_json::to_value(event).unwrap()) {
+            for (key, value) in fields {
+                // the id is written as "event"
+                line.insert(if key == "id" { "event".to_string() } else { key }, value);
+            }
+        }
+    }
+
+    format!("{}\n", line)
+}
+
+struct RotatingFile {
+    dir: PathBuf,
+    file: File,
+    size: u64,
+    /// when the log was started, from its first line. File times don't do: NTFS hands the
+    /// creation time of a deleted file to a new one created under its name right after
+    started: SystemTime,
+    /// the owner lock, held for as long as the process runs by the owner of the log
+    owner: Option<File>,
+    max_age: Duration,
+    max_size: u64,
+    retention: usize,
+}
+
+impl RotatingFile {
+    fn open(dir: &Path, owner: bool, max_age: Duration, max_size: u64, retention: usize) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+
+        // a process rotating for the moment holds it briefly
+        let owner = if owner {
+            let lock = open_owner_lock(dir)?;
+            lock.lock()?;
+            Some(lock)
+        } else {
+            None
+        };
+
+        let (file, size, started) = open_log(&dir.join(LOG_FILE_NAME))?;
+        let mut rotating = RotatingFile { dir: dir.to_path_buf(), file, size, started, owner, max_age, max_size, retention };
+
+        // a log left over from a long time ago is rotated right away
+        if rotating.too_old() {
+            rotating.rotate_if_owner()?;
+        }
+
+        Ok(rotating)
+    }
+
+    fn too_old(&self) -> bool {
+        !self.max_age.is_zero() && self.started.elapsed().is_ok_and(|age| age > self.max_age)
+    }
+
+    fn write_line(&mut self, line: &[u8]) -> io::Result<()> {
+        if self.size > 0 && (self.size + line.len() as u64 > self.max_size || self.too_old()) {
+            self.rotate_if_owner()?;
+        }
+
+        self.file.write_all(line)?;
+        self.size += line.len() as u64;
+
+        Ok(())
+    }
+
+    /// Rotate if this process owns the log, or can own it for the moment because nobody does.
+    /// Otherwise the owner rotates it, the log is opened again to follow it
+    fn rotate_if_owner(&mut self) -> io::Result<()> {
+        if self.owner.is_some() {
+            return self.rotate();
+        }
+
+        let lock = open_owner_lock(&self.dir)?;
+        match lock.try_lock() {
+            // unlocked when dropped
+            Ok(()) => self.rotate(),
+
+            Err(TryLockError::WouldBlock) => {
+                let (file, size, started) = open_log(&self.dir.join(LOG_FILE_NAME))?;
+                self.file = file;
+                self.size = size;
+                self.started = started;
+                Ok(())
+            }
+
+            Err(TryLockError::Error(e)) => Err(e),
+        }
+    }
+
+    /// app.log -> app.log.1 -> app.log.2 ..., dropping whatever is past the retention count
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+
+        let rotated = |n: usize| self.dir.join(format!("{}.{}", LOG_FILE_NAME, n));
+
+        fs::remove_file(rotated(self.retention.max(1))).ok();
+        for n in (1..self.retention.max(1)).rev() {
+            fs::rename(rotated(n), rotated(n + 1)).ok();
+        }
+
+        let current = self.dir.join(LOG_FILE_NAME);
+        if self.retention == 0 {
+            fs::remove_file(&current)?;
+        } else {
+            fs::rename(&current, rotated(1))?;
+        }
+
+        let (file, size, started) = open_log(&current)?;
+        self.file = file;
+        self.size = size;
+        self.started = started;
+
+        Ok(())
+    }
+}
+
+fn open_owner_lock(dir: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).truncate(false).write(true).open(dir.join(OWNER_LOCK_FILE_NAME))
+}
+
+fn open_log(path: &Path) -> io::Result<(File, u64, SystemTime)> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let size = file.metadata()?.len();
+
+    // a log nothing was written to yet starts now
+    let started = first_line_time(path).unwrap_or_else(SystemTime::now);
+
+    Ok((file, size, started))
+}
+
+/// Time of the first line of the log, in either format
+fn first_line_time(path: &Path) -> Option<SystemTime> {
+    let mut line = String::new();
+    BufReader::new(File::open(path).ok()?).read_line(&mut line).ok()?;
+
+    if line.starts_with('{') {
+        let line: serde_json::Value = serde_json::from_str(&line).ok()?;
+        let time = chrono::DateTime::parse_from_rfc3339(line.get("timestamp")?.as_str()?).ok()?;
+        return Some(time.into());
+    }
+
+    let time = chrono::NaiveDateTime::parse_from_str(line.get(..23)?, "%Y-%m-%d %H:%M:%S%.3f").ok()?;
+    let time = time.and_local_timezone(chrono::Local).earliest()?;
+    Some(time.into())
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::fs;
+    use std::path::Path;
+    use std::time::{Duration, SystemTime};
+
+    use super::{first_line_time, RotatingFile, LOG_FILE_NAME};
+    use crate::testing::ScratchDir;
+
+    const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+    /// Contents of app.log and the rotated logs after it, a missing one is None
+    fn logs(dir: &Path) -> Vec<Option<String>> {
+        let current = fs::read_to_string(dir.join(LOG_FILE_NAME)).ok();
+        let rotated = (1..=3).map(|n| fs::read_to_string(dir.join(format!("{}.{}", LOG_FILE_NAME, n))).ok());
+        std::iter::once(current).chain(rotated).collect()
+    }
+
+    fn some(s: &str) -> Option<String> {
+        Some(s.to_string())
+    }
+
+    fn text_line(time: chrono::DateTime<chrono::Local>) -> String {
+        format!("{} [INFO] watcher: started\n", time.format("%Y-%m-%d %H:%M:%S%.3f"))
+    }
+
+    #[test]
+    fn rotates_by_size() {
+        let dir = ScratchDir::new("log-size");
+        let mut log = RotatingFile::open(&dir, true, Duration::ZERO, 10, 2).unwrap();
+
+        for line in ["one\n", "two\n", "three\n", "four\n", "five\n"] {
+            log.write_line(line.as_bytes()).unwrap();
+        }
+        assert_eq!(logs(&dir), vec![some("four\nfive\n"), some("three\n"), some("one\ntwo\n"), None]);
+
+        // past the retention count the oldest log goes
+        log.write_line(b"six\n").unwrap();
+        assert_eq!(logs(&dir), vec![some("six\n"), some("four\nfive\n"), some("three\n"), None]);
+    }
+
+    #[test]
+    fn long_line_goes_into_an_empty_log() {
+        let dir = ScratchDir::new("log-long-line");
+        let mut log = RotatingFile::open(&dir, true, Duration::ZERO, 4, 2).unwrap();
+
+        log.write_line(b"longer than max\n").unwrap();
+
+        assert_eq!(logs(&dir), vec![some("longer than max\n"), None, None, None]);
+    }
+
+    #[test]
+    fn no_retention_drops_the_log() {
+        let dir = ScratchDir::new("log-no-retention");
+        let mut log = RotatingFile::open(&dir, true, Duration::ZERO, 10, 0).unwrap();
+
+        for line in ["one\n", "two\n", "three\n", "four\n"] {
+            log.write_line(line.as_bytes()).unwrap();
+        }
+
+        assert_eq!(logs(&dir), vec![some("four\n"), None, None, None]);
+    }
+
+    #[test]
+    fn old_log_is_rotated_when_opened() {
+        let dir = ScratchDir::new("log-age");
+        let old = text_line(chrono::Local::now() - chrono::Duration::days(2));
+        fs::write(dir.join(LOG_FILE_NAME), &old).unwrap();
+
+        let mut log = RotatingFile::open(&dir, true, DAY, 1024, 2).unwrap();
+        log.write_line(b"new\n").unwrap();
+
+        assert_eq!(logs(&dir), vec![some("new\n"), Some(old), None, None]);
+    }
+
+    #[test]
+    fn recent_log_is_kept() {
+        let dir = ScratchDir::new("log-age-recent");
+        let recent = text_line(chrono::Local::now() - chrono::Duration::hours(1));
+        fs::write(dir.join(LOG_FILE_NAME), &recent).unwrap();
+
+        let mut log = RotatingFile::open(&dir, true, DAY, 1024, 2).unwrap();
+        log.write_line(b"new\n").unwrap();
+
+        assert_eq!(logs(&dir), vec![Some(format!("{}new\n", recent)), None, None, None]);
+    }
+
+    #[test]
+    fn first_line_time_of_both_formats() {
+        let dir = ScratchDir::new("log-first-line");
+        let path = dir.join(LOG_FILE_NAME);
+        let time = chrono::Local::now() - chrono::Duration::days(3);
+        let close = |found: Option<SystemTime>| {
+            let found: chrono::DateTime<chrono::Local> = found.unwrap().into();
+            (found - time).num_milliseconds().abs() < 1
+        };
+
+        fs::write(&path, format!("{}second line\n", text_line(time))).unwrap();
+        assert!(close(first_line_time(&path)));
+
+        let json = serde_json::json!({
+            "timestamp": time.with_timezone(&chrono::Utc).to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+            "level": "INFO",
+            "message": "started",
+        });
+        fs::write(&path, format!("{}\n", json)).unwrap();
+        assert!(close(first_line_time(&path)));
+
+        for unreadable in ["", "garbage\n", "{\"level\": \"INFO\"}\n"] {
+            fs::write(&path, unreadable).unwrap();
+            assert_eq!(first_line_time(&path), None, "{:?}", unreadable);
+        }
+        assert_eq!(first_line_time(&dir.join("missing.log")), None);
+    }
+
+    #[test]
+    fn only_the_owner_rotates() {
+        let dir = ScratchDir::new("log-owner");
+        let owner = RotatingFile::open(&dir, true, Duration::ZERO, 1024, 2).unwrap();
+        let mut other = RotatingFile::open(&dir, false, Duration::ZERO, 10, 2).unwrap();
+
+        // the owner rotates, the other process keeps appending
+        for line in ["one\n", "two\n", "three\n"] {
+            other.write_line(line.as_bytes()).unwrap();
+        }
+        assert_eq!(logs(&dir), vec![some("one\ntwo\nthree\n"), None, None, None]);
+
+        // once the owner is gone, whoever writes takes over for the moment
+        drop(owner);
+        other.write_line(b"four\n").unwrap();
+        assert_eq!(logs(&dir), vec![some("four\n"), some("one\ntwo\nthree\n"), None, None]);
+    }
+}
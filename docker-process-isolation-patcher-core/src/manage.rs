@@ -0,0 +1,205 @@
+// Installing and controlling the patcher service
+// The install, uninstall, start and stop commands go through the backend, so they work the same
+// on this computer, on a remote one and in batch mode.
+
+use std::fmt;
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::{error, info, Level};
+use serde::Serialize;
+
+use super::audit::{Action, AuditLog, Record, Trigger};
+use super::backend::{BackendResult, NewService, ServiceBackend, ServiceState};
+use super::event;
+use super::event::{Event, EventId};
+use super::shared::*;
+
+pub const DISPLAY_NAME: &str = "Docker Process Isolation Patcher";
+const DESCRIPTION: &str = "Docker Process Isolation Manager will automatically set Windows docker service to run in process isolation mode";
+/// really, should be long enough..
+const STOP_TIMEOUT: Duration = Duration::from_secs(10);
+// ERROR_SERVICE_MARKED_FOR_DELETE
+const ERROR_SERVICE_MARKED_FOR_DELETE: i32 = 1072;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Outcome {
+    Installed,
+    AlreadyInstalled,
+    Uninstalled,
+    /// deleted before, it goes away once nothing holds it open
+    MarkedForDelete,
+    Started,
+    AlreadyRunning,
+    Stopped,
+    AlreadyStopped,
+    NotInstalled,
+    /// neither stopped nor running, e.g. still starting
+    Busy,
+    /// still running STOP_TIMEOUT after it was asked to stop
+    StopTimedOut,
+}
+
+impl Outcome {
+    /// Whether the command didn't get the service where it was asked to
+    pub fn failed(&self) -> bool {
+        matches!(self, Outcome::NotInstalled | Outcome::Busy | Outcome::StopTimedOut)
+    }
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let message = match self {
+            Outcome::Installed => "Installed service",
+            Outcome::AlreadyInstalled => "Service already installed. Try the uninstall command",
+            Outcome::Uninstalled => "Uninstalled service",
+            Outcome::MarkedForDelete => {
+                "Service already marked for delete. If Windows services manager is open, please close it to let the service delete"
+            }
+            Outcome::Started => "Started service",
+            Outcome::AlreadyRunning => "Service already running",
+            Outcome::Stopped => "Stopped service",
+            Outcome::AlreadyStopped => "Service already stopped",
+            Outcome::NotInstalled => "Service not found. Is it installed?",
+            Outcome::Busy => "Service neither stopped nor running. Please try again",
+            Outcome::StopTimedOut => "Service didn't stop in time",
+        };
+
+        f.write_str(message)
+    }
+}
+
+/// The patcher service on the backend's computer. Changes are written to the audit log on this
+/// computer
+pub struct PatcherService<'a, B: ServiceBackend> {
+    backend: &'a B,
+    audit_log: &'a AuditLog,
+    user: Option<String>,
+}
+
+impl<'a, B: ServiceBackend> PatcherService<'a, B> {
+    pub fn new(backend: &'a B, audit_log: &'a AuditLog, user: Option<String>) -> Self {
+        PatcherService { backend, audit_log, user }
+    }
+
+    /// Install the service running `executable`, a path on the backend's computer
+    pub fn install(&self, executable: &Path) -> BackendResult<Outcome> {
+        if self.backend.exists(SERVICE_NAME)? {
+            info!("service already installed on {}", self.backend.target());
+            return Ok(Outcome::AlreadyInstalled);
+        }
+
+        let service = NewService {
+            name: SERVICE_NAME.to_string(),
+            display_name: DISPLAY_NAME.to_string(),
+            description: DESCRIPTION.to_string(),
+            executable: executable.to_path_buf(),
+            arguments: vec!["run-service".to_string()],
+            // so a stopped docker is patched before it's launched at boot
+            start_before: vec![DOCKER_SERVICE_NAME.to_string()],
+        };
+
+        let res = self.backend.create(&service);
+        self.audit(Action::Install, &res);
+        res?;
+
+        event!(Level::Info, self.event(EventId::CliServiceInstalled), "installed service on {}", self.backend.target());
+        Ok(Outcome::Installed)
+    }
+
+    /// Stop and delete the service
+    pub fn uninstall(&self) -> BackendResult<Outcome> {
+        match self.stop(|| ())? {
+            Outcome::NotInstalled => return Ok(Outcome::NotInstalled),
+            // deleting works anyway, the service is removed once it stops
+            Outcome::StopTimedOut => error!("service didn't stop on {}, deleting it anyway", self.backend.target()),
+            _ => (),
+        }
+
+        let res = self.backend.delete(SERVICE_NAME);
+        self.audit(Action::Uninstall, &res);
+
+        match res {
+            Ok(()) => {
+                event!(Level::Info, self.event(EventId::CliServiceUninstalled), "uninstalled service on {}", self.backend.target());
+                Ok(Outcome::Uninstalled)
+            }
+
+            Err(e) if e.code == Some(ERROR_SERVICE_MARKED_FOR_DELETE) => {
+                info!("service on {} is already marked for delete", self.backend.target());
+                Ok(Outcome::MarkedForDelete)
+            }
+
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn start(&self) -> BackendResult<Outcome> {
+        match self.backend.query_state(SERVICE_NAME)? {
+            None => Ok(Outcome::NotInstalled),
+
+            Some(ServiceState::Stopped) => {
+                let res = self.backend.start(SERVICE_NAME);
+                self.audit(Action::Start, &res);
+                res?;
+
+                event!(Level::Info, self.event(EventId::CliServiceStarted).states("stopped", "running"), "started service on {}", self.backend.target());
+                Ok(Outcome::Started)
+            }
+
+            Some(ServiceState::Running) => Ok(Outcome::AlreadyRunning),
+
+            Some(state) => {
+                info!("service on {} is neither running nor stopped: {}", self.backend.target(), state);
+                Ok(Outcome::Busy)
+            }
+        }
+    }
+
+    /// Stop the service and wait for it, calling `waiting` every poll while it's still stopping
+    pub fn stop(&self, mut waiting: impl FnMut()) -> BackendResult<Outcome> {
+        match self.backend.query_state(SERVICE_NAME)? {
+            None => return Ok(Outcome::NotInstalled),
+            Some(ServiceState::Stopped) => return Ok(Outcome::AlreadyStopped),
+            Some(_) => (),
+        }
+
+        info!("stopping service on {}", self.backend.target());
+        let res = self.backend.stop(SERVICE_NAME);
+        self.audit(Action::Stop, &res);
+        res?;
+
+        let deadline = Instant::now() + STOP_TIMEOUT;
+        loop {
+            match self.backend.query_state(SERVICE_NAME)? {
+                Some(ServiceState::Stopped) | None => break,
+                Some(_) if Instant::now() >= deadline => {
+                    error!("service on {} timed out", self.backend.target());
+                    return Ok(Outcome::StopTimedOut);
+                }
+                Some(_) => {
+                    waiting();
+                    thread::sleep(Duration::from_millis(250));
+                }
+            }
+        }
+
+        event!(Level::Info, self.event(EventId::CliServiceStopped), "stopped service on {}", self.backend.target());
+        Ok(Outcome::Stopped)
+    }
+
+    fn event(&self, id: EventId) -> Event {
+        Event::new(id).service(SERVICE_NAME).target(self.backend.target())
+    }
+
+    /// Failing to write the audit log doesn't fail the command
+    fn audit<T, E: fmt::Display>(&self, action: Action, res: &Result<T, E>) {
+        let record = Record::new(action, SERVICE_NAME, Trigger::Cli).target(self.backend.target()).result(res);
+
+        if let Err(e) = self.audit_log.append(record, self.user.clone()) {
+            error!("failed to write audit log: {}", e);
+        }
+    }
+}
@@ -0,0 +1,383 @@
+// Docker service command line patching
+// The patch is a list of dockerd flags. Each one replaces whatever the command line already sets
+// for the same option, `--exec-opt isolation=process` by default on Windows.
+
+use std::convert::TryFrom;
+use std::fmt;
+
+use serde::Deserialize;
+use splitty::*;
+
+use super::backend::{BackendError, BackendResult, ServiceBackend, ServiceConfig};
+use super::shared::DOCKER_SERVICE_NAME;
+
+#[cfg(windows)]
+pub const PATCHED_DISPLAY_NAME: &str = "Docker Engine - Patched Process Isolation";
+/// display name the docker installer uses
+#[cfg(windows)]
+pub const UNPATCHED_DISPLAY_NAME: &str = "Docker Engine";
+
+#[cfg(not(windows))]
+pub const PATCHED_DISPLAY_NAME: &str = "Docker Application Container Engine - Patched";
+/// Description of the docker.service unit the docker packages install
+#[cfg(not(windows))]
+pub const UNPATCHED_DISPLAY_NAME: &str = "Docker Application Container Engine";
+
+pub const PATCHED_DESCRIPTION: &str = "Patched docker process isolated service";
+
+pub const EXEC_OPT: &str = "--exec-opt";
+pub const ISOLATION_PROCESS: &str = "isolation=process";
+
+/// A dockerd flag the patch enforces, `--option`, `--option value` or `--option=value`
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Flag {
+    pub option: String,
+    pub value: Option<String>,
+    /// written as `--option=value`, which boolean options like `--iptables=false` need
+    pub joined: bool,
+}
+
+impl Flag {
+    pub fn new(option: &str, value: Option<&str>) -> Self {
+        Flag { option: option.to_string(), value: value.map(str::to_string), joined: false }
+    }
+
+    /// Arguments the flag adds to the command line
+    pub fn args(&self) -> Vec<String> {
+        match &self.value {
+            Some(value) if self.joined => vec![format!("{}={}", self.option, value)],
+            Some(value) => vec![self.option.clone(), value.clone()],
+            None => vec![self.option.clone()],
+        }
+    }
+
+    /// Whether an option on the command line sets the same thing as this flag. Options with a
+    /// `key=value` value like `--exec-opt` can be given repeatedly, those only conflict if the
+    /// key is the same
+    fn conflicts(&self, option: &str, value: Option<&str>) -> bool {
+        if option != self.option {
+            return false;
+        }
+
+        match self.value.as_deref().and_then(|v| v.split_once('=')) {
+            Some((key, _)) => value.and_then(|v| v.split_once('=')).is_some_and(|(k, _)| k == key),
+            None => true,
+        }
+    }
+}
+
+impl TryFrom<String> for Flag {
+    type Error = String;
+
+    fn try_from(flag: String) -> Result<Self, Self::Error> {
+        let args = split_command_line(&flag);
+
+        let (option, value, joined) = match args.as_slice() {
+            [arg] => match arg.split_once('=') {
+                Some((option, value)) => (option, Some(value), true),
+                None => (arg.as_str(), None, false),
+            },
+            [option, value] => (option.as_str(), Some(value.as_str()), false),
+            _ => return Err(format!("invalid flag \"{}\", expected \"--option value\"", flag)),
+        };
+
+        if !option.starts_with("--") || option.len() == 2 {
+            return Err(format!("invalid flag \"{}\", the option has to start with --", flag));
+        }
+
+        Ok(Flag { joined, ..Flag::new(option, value) })
+    }
+}
+
+impl fmt::Display for Flag {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&join_command_line(&self.args()))
+    }
+}
+
+/// Flags the patch enforces unless the config sets its own: process isolation on Windows, none
+/// elsewhere
+pub fn default_flags() -> Vec<Flag> {
+    if cfg!(windows) {
+        vec![Flag::new(EXEC_OPT, Some(ISOLATION_PROCESS))]
+    } else {
+        vec![]
+    }
+}
+
+/// Split a service command line (ImagePath) into arguments, the executable first. Quotes are
+/// removed
+pub fn split_command_line(command_line: &str) -> Vec<String> {
+    split_unquoted_whitespace(command_line)
+        .unwrap_quotes(true)
+        .filter(|arg| !arg.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+/// Inverse of split_command_line, quoting arguments which contain whitespace
+pub fn join_command_line(args: &[String]) -> String {
+    args.iter()
+        .map(|arg| {
+            if arg.is_empty() || arg.contains(char::is_whitespace) {
+                format!("\"{}\"", arg)
+            } else {
+                arg.clone()
+            }
+        })
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+/// Compare command lines argument by argument, ignoring quoting differences
+pub fn same_command_line(a: &str, b: &str) -> bool {
+    split_command_line(a) == split_command_line(b)
+}
+
+/// Values of every `--exec-opt` in the arguments, both `--exec-opt x` and `--exec-opt=x`
+pub fn exec_opts(args: &[String]) -> Vec<&str> {
+    options(args).into_iter().filter(|o| o.option == EXEC_OPT).filter_map(|o| o.value).collect()
+}
+
+/// An option on the command line, `len` arguments starting at `at`
+struct Occurrence<'a> {
+    at: usize,
+    len: usize,
+    option: &'a str,
+    value: Option<&'a str>,
+}
+
+/// Every `--option` in the arguments. dockerd takes no positional arguments, so an argument
+/// following an option is its value unless it's an option itself
+fn options(args: &[String]) -> Vec<Occurrence<'_>> {
+    let mut found = vec![];
+    let mut at = 0;
+
+    while at < args.len() {
+        let arg = &args[at];
+        if !arg.starts_with("--") {
+            at += 1;
+            continue;
+        }
+
+        let occurrence = match arg.split_once('=') {
+            Some((option, value)) => Occurrence { at, len: 1, option, value: Some(value) },
+            None => match args.get(at + 1).filter(|v| !v.starts_with('-')) {
+                Some(value) => Occurrence { at, len: 2, option: arg, value: Some(value) },
+                None => Occurrence { at, len: 1, option: arg, value: None },
+            },
+        };
+
+        at += occurrence.len;
+        found.push(occurrence);
+    }
+
+    found
+}
+
+/// Whether every flag is set, conflicting values may still be there, see has_drifted
+pub fn is_patched(args: &[String], flags: &[Flag]) -> bool {
+    let options = options(args);
+    flags.iter().all(|f| options.iter().any(|o| o.option == f.option && o.value == f.value.as_deref()))
+}
+
+/// is_patched for a service config
+pub fn is_patched_config(config: &ServiceConfig, flags: &[Flag]) -> bool {
+    is_patched(&split_command_line(&config.command_line), flags)
+}
+
+/// Whether the arguments differ from what the patch produces: every flag set, and nothing else
+/// setting the same option next to it
+pub fn has_drifted(args: &[String], flags: &[Flag]) -> bool {
+    let options = options(args);
+
+    flags.iter().any(|f| {
+        let set: Vec<Option<&str>> = options.iter().filter(|o| f.conflicts(o.option, o.value)).map(|o| o.value).collect();
+        set != [f.value.as_deref()]
+    })
+}
+
+/// Add the flags right after the executable, dropping whatever else sets the same options so
+/// dockerd doesn't get conflicting values
+pub fn patch_args(args: &[String], flags: &[Flag]) -> Vec<String> {
+    let mut patched = strip_flags(args, flags);
+
+    let at = patched.len().min(1);
+    patched.splice(at..at, flags.iter().flat_map(Flag::args));
+
+    patched
+}
+
+/// Arguments without anything setting the same options as the flags
+pub fn strip_flags(args: &[String], flags: &[Flag]) -> Vec<String> {
+    let mut stripped = args.to_vec();
+
+    for o in options(args).iter().rev() {
+        if flags.iter().any(|f| f.conflicts(o.option, o.value)) {
+            stripped.drain(o.at..o.at + o.len);
+        }
+    }
+
+    stripped
+}
+
+/// Config with the patch applied, everything besides the command line and display name is kept
+pub fn patch_config(config: &ServiceConfig, flags: &[Flag]) -> ServiceConfig {
+    let args = patch_args(&split_command_line(&config.command_line), flags);
+
+    ServiceConfig {
+        display_name: PATCHED_DISPLAY_NAME.to_string(),
+        command_line: join_command_line(&args),
+        ..config.clone()
+    }
+}
+
+/// Config with the options the flags set removed, for when the command line from before the patch
+/// wasn't recorded
+pub fn unpatch_config(config: &ServiceConfig, flags: &[Flag]) -> ServiceConfig {
+    let args = strip_flags(&split_command_line(&config.command_line), flags);
+
+    ServiceConfig {
+        display_name: UNPATCHED_DISPLAY_NAME.to_string(),
+        command_line: join_command_line(&args),
+        ..config.clone()
+    }
+}
+
+/// Value of `--config-file`, the daemon.json dockerd reads instead of the default one
+pub fn config_file_arg(args: &[String]) -> Option<&str> {
+    options(args).into_iter().find(|o| o.option == "--config-file").and_then(|o| o.value)
+}
+
+/// Write the patched docker config through `backend`. Returns the config from before, for
+/// `restore`, or None if docker didn't need patching. Docker runs the old command line until it's
+/// restarted
+pub fn apply<B: ServiceBackend>(backend: &B, flags: &[Flag]) -> BackendResult<Option<ServiceConfig>> {
+    let config = docker_config(backend)?;
+
+    if !has_drifted(&split_command_line(&config.command_line), flags) {
+        return Ok(None);
+    }
+
+    backend.change_config(DOCKER_SERVICE_NAME, &patch_config(&config, flags))?;
+    Ok(Some(config))
+}
+
+/// Write `original` back, as returned by `apply`. Without it everything setting the same options
+/// as the flags is removed instead
+pub fn restore<B: ServiceBackend>(backend: &B, original: Option<&ServiceConfig>, flags: &[Flag]) -> BackendResult<()> {
+    let restored = match original {
+        Some(original) => original.clone(),
+        None => unpatch_config(&docker_config(backend)?, flags),
+    };
+
+    backend.change_config(DOCKER_SERVICE_NAME, &restored)
+}
+
+fn docker_config<B: ServiceBackend>(backend: &B) -> BackendResult<ServiceConfig> {
+    backend
+        .query_config(DOCKER_SERVICE_NAME)?
+        .ok_or_else(|| BackendError::new(format!("the {} service isn't installed", DOCKER_SERVICE_NAME)))
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::convert::TryFrom;
+
+    use super::{has_drifted, is_patched, join_command_line, patch_args, split_command_line, strip_flags, Flag, EXEC_OPT, ISOLATION_PROCESS};
+
+    fn args(command_line: &str) -> Vec<String> {
+        split_command_line(command_line)
+    }
+
+    fn flag(flag: &str) -> Flag {
+        Flag::try_from(flag.to_string()).unwrap()
+    }
+
+    fn isolation() -> Vec<Flag> {
+        vec![Flag::new(EXEC_OPT, Some(ISOLATION_PROCESS))]
+    }
+
+    #[test]
+    fn split_and_join_round_trip() {
+        let command_line = r#""C:\Program Files\Docker\dockerd.exe" --run-service -G "docker users" --exec-opt isolation=process"#;
+
+        let split = args(command_line);
+        assert_eq!(split, vec![r"C:\Program Files\Docker\dockerd.exe", "--run-service", "-G", "docker users", "--exec-opt", "isolation=process"]);
+        assert_eq!(join_command_line(&split), command_line);
+
+        // extra whitespace goes, empty arguments stay
+        assert_eq!(args("  dockerd   -H  fd:// "), vec!["dockerd", "-H", "fd://"]);
+        assert_eq!(join_command_line(&["dockerd".to_string(), String::new()]), r#"dockerd """#);
+    }
+
+    #[test]
+    fn flags_from_the_config() {
+        assert_eq!(flag("--exec-opt isolation=process"), Flag::new(EXEC_OPT, Some(ISOLATION_PROCESS)));
+        assert_eq!(flag("--iptables=false"), Flag { joined: true, ..Flag::new("--iptables", Some("false")) });
+        assert_eq!(flag("--debug"), Flag::new("--debug", None));
+        assert_eq!(flag("--iptables=false").to_string(), "--iptables=false");
+        assert_eq!(flag(r#"--label "a b""#).to_string(), r#"--label "a b""#);
+
+        for invalid in ["iptables", "-D", "--", "--a b c", ""] {
+            assert!(Flag::try_from(invalid.to_string()).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn patched_when_every_flag_is_set() {
+        assert!(is_patched(&args("dockerd --exec-opt isolation=process -H fd://"), &isolation()));
+        assert!(is_patched(&args("dockerd --exec-opt=isolation=process"), &isolation()));
+        assert!(!is_patched(&args("dockerd -H fd://"), &isolation()));
+        assert!(!is_patched(&args("dockerd --exec-opt isolation=hyperv"), &isolation()));
+
+        let flags = vec![flag("--exec-opt isolation=process"), flag("--iptables=false")];
+        assert!(!is_patched(&args("dockerd --exec-opt isolation=process"), &flags));
+        assert!(is_patched(&args("dockerd --iptables=false --exec-opt isolation=process"), &flags));
+    }
+
+    #[test]
+    fn drift() {
+        let flags = isolation();
+
+        assert!(!has_drifted(&args("dockerd --exec-opt isolation=process"), &flags));
+        // other keys of the same option are left alone
+        assert!(!has_drifted(&args("dockerd --exec-opt native.cgroupdriver=systemd --exec-opt isolation=process"), &flags));
+
+        assert!(has_drifted(&args("dockerd"), &flags));
+        assert!(has_drifted(&args("dockerd --exec-opt isolation=hyperv"), &flags));
+        // set, but overridden by a later conflicting value
+        assert!(has_drifted(&args("dockerd --exec-opt isolation=process --exec-opt isolation=hyperv"), &flags));
+        assert!(has_drifted(&args("dockerd --exec-opt isolation=process --exec-opt=isolation=process"), &flags));
+    }
+
+    #[test]
+    fn patch_replaces_conflicting_options() {
+        let flags = vec![flag("--exec-opt isolation=process"), flag("--iptables=false")];
+
+        let patched = patch_args(&args("dockerd -H fd:// --exec-opt isolation=hyperv --exec-opt native.cgroupdriver=systemd --iptables true"), &flags);
+
+        assert_eq!(
+            join_command_line(&patched),
+            "dockerd --exec-opt isolation=process --iptables=false -H fd:// --exec-opt native.cgroupdriver=systemd"
+        );
+        assert!(!has_drifted(&patched, &flags));
+        // patching again changes nothing
+        assert_eq!(patch_args(&patched, &flags), patched);
+    }
+
+    #[test]
+    fn patch_keeps_quoting() {
+        let patched = patch_args(&args(r#""C:\Program Files\Docker\dockerd.exe" --run-service"#), &isolation());
+
+        assert_eq!(join_command_line(&patched), r#""C:\Program Files\Docker\dockerd.exe" --exec-opt isolation=process --run-service"#);
+    }
+
+    #[test]
+    fn strip_removes_what_the_flags_set() {
+        let stripped = strip_flags(&args("dockerd --exec-opt isolation=process -H fd://"), &isolation());
+
+        assert_eq!(join_command_line(&stripped), "dockerd -H fd://");
+    }
+}
@@ -0,0 +1,625 @@
+// File permission checks
+// The service runs as SYSTEM (root on Linux) and takes docker's flags, hook commands and the
+// command line to restore from files beside the exe, so whoever can change those files can run
+// commands as SYSTEM. Files and directories the patcher reads from have to be owned by
+// Administrators or SYSTEM and writable by nobody else (on Linux: owned by root and not group or
+// world writable). The account the patcher runs as counts as trusted too, its own files give it no
+// rights it didn't have. The directories above them, up to the root, need trusted owners too and
+// mustn't let anyone else replace what's in them: adding entries is fine, like Users creating
+// folders in C:\ or anyone in /tmp with its sticky bit, renaming or deleting them isn't. `secure`
+// sets files up that way, install does it for the exe directory.
+
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use super::audit::{AUDIT_FILE_NAME, AUDIT_HEAD_FILE_NAME};
+use super::config::CONFIG_FILE_NAME;
+
+#[cfg(windows)]
+const TRUSTED: &str = "Administrators or SYSTEM";
+#[cfg(not(windows))]
+const TRUSTED: &str = "root";
+
+/// A user, group or everyone, by id and display name
+struct Account {
+    id: String,
+    name: String,
+}
+
+/// Who owns a file and who besides the owner may change it
+struct Access {
+    owner: Account,
+    writers: Vec<Account>,
+}
+
+/// What someone mustn't be able to do to a path
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Rights {
+    /// change the file, or add to or replace what's in the directory
+    Write,
+    /// replace what's in the directory, or change who may
+    Replace,
+}
+
+#[derive(Debug)]
+pub enum PermissionError {
+    Io(PathBuf, io::Error),
+    Owner { path: PathBuf, owner: String },
+    Writable { path: PathBuf, by: String },
+}
+
+impl fmt::Display for PermissionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PermissionError::Io(path, e) => write!(f, "failed to read the permissions of {}: {}", path.display(), e),
+            PermissionError::Owner { path, owner } => {
+                write!(f, "{} is owned by {}, it has to be owned by {}", path.display(), owner, TRUSTED)
+            }
+            PermissionError::Writable { path, by } => {
+                write!(f, "{} can be changed by {}, only {} may change it", path.display(), by, TRUSTED)
+            }
+        }
+    }
+}
+
+impl std::error::Error for PermissionError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            PermissionError::Io(_, e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// Check a file and the directories holding it, which could replace it otherwise. A missing file
+/// only has its directories checked
+pub fn check_file(path: &Path) -> Result<(), PermissionError> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    check_dir(dir)?;
+
+    match path.symlink_metadata() {
+        Ok(_) => check(path, Rights::Write),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(PermissionError::Io(path.to_path_buf(), e)),
+    }
+}
+
+/// Check a directory and every directory above it up to the root
+pub fn check_dir(dir: &Path) -> Result<(), PermissionError> {
+    let dir = std::path::absolute(dir).map_err(|e| PermissionError::Io(dir.to_path_buf(), e))?;
+    check(&dir, Rights::Write)?;
+
+    for ancestor in dir.ancestors().skip(1) {
+        check(ancestor, Rights::Replace)?;
+    }
+
+    Ok(())
+}
+
+fn check(path: &Path, rights: Rights) -> Result<(), PermissionError> {
+    let access = platform::access(path, rights).map_err(|e| PermissionError::Io(path.to_path_buf(), e))?;
+    let me = platform::current_account().map_err(|e| PermissionError::Io(path.to_path_buf(), e))?;
+    let trusted = |account: &Account| platform::TRUSTED_IDS.contains(&account.id.as_str()) || account.id == me.id;
+
+    if !trusted(&access.owner) {
+        return Err(PermissionError::Owner { path: path.to_path_buf(), owner: access.owner.name });
+    }
+
+    match access.writers.into_iter().find(|w| !trusted(w)) {
+        Some(writer) => Err(PermissionError::Writable { path: path.to_path_buf(), by: writer.name }),
+        None => Ok(()),
+    }
+}
+
+/// The account this process runs as, `DOMAIN\user` on Windows. Taken from the process token
+/// rather than the environment, which whoever started the process controls
+pub fn current_user() -> io::Result<String> {
+    platform::current_account().map(|account| account.name)
+}
+
+/// The account of a user id
+#[cfg(not(windows))]
+pub fn user_name(uid: u32) -> String {
+    platform::account(uid).name
+}
+
+/// The account of an access token, `DOMAIN\user`
+///
+/// # Safety
+/// `token` has to be an access token opened with `TOKEN_QUERY`
+#[cfg(windows)]
+pub unsafe fn token_user(token: winapi::um::winnt::HANDLE) -> io::Result<String> {
+    platform::token_account(token).map(|account| account.name)
+}
+
+/// Make a file or directory owned by Administrators (root) and writable only by Administrators
+/// and SYSTEM, everyone else may read it. Needs administrator rights
+pub fn secure(path: &Path) -> io::Result<()> {
+    platform::secure(path)
+}
+
+/// Secure the exe directory and the files the patcher keeps in it: the exe, config, audit log and
+/// state files. Returns the paths secured
+pub fn secure_exe_dir(executable: &Path) -> io::Result<Vec<PathBuf>> {
+    let dir = executable.parent().unwrap_or_else(|| Path::new("."));
+    let mut paths = vec![dir.to_path_buf(), executable.to_path_buf()];
+
+    for entry in dir.read_dir()? {
+        let path = entry?.path();
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+
+        let ours = name == CONFIG_FILE_NAME || name == AUDIT_FILE_NAME || name == AUDIT_HEAD_FILE_NAME || (name.starts_with("state") && name.ends_with(".json"));
+        if ours && path.is_file() {
+            paths.push(path);
+        }
+    }
+
+    for path in &paths {
+        secure(path).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+    }
+
+    Ok(paths)
+}
+
+#[cfg(not(windows))]
+mod platform {
+    use std::fs;
+    use std::io;
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+    use std::path::Path;
+
+    use super::{Access, Account, Rights};
+
+    pub const TRUSTED_IDS: &[&str] = &["0"];
+
+    pub fn access(path: &Path, rights: Rights) -> io::Result<Access> {
+        // a link's own permissions don't matter, what it points to does
+        let metadata = fs::metadata(path)?;
+        let mode = metadata.mode();
+
+        // in a sticky directory only the owner of an entry may rename or delete it
+        let sticky = metadata.is_dir() && mode & 0o1000 != 0;
+        if rights == Rights::Replace && sticky {
+            return Ok(Access { owner: account(metadata.uid()), writers: vec![] });
+        }
+
+        let mut writers = vec![];
+        if mode & 0o020 != 0 {
+            writers.push(Account { id: format!("group:{}", metadata.gid()), name: format!("group {}", metadata.gid()) });
+        }
+        if mode & 0o002 != 0 {
+            writers.push(Account { id: "everyone".to_string(), name: "everyone".to_string() });
+        }
+
+        Ok(Access { owner: account(metadata.uid()), writers })
+    }
+
+    /// The user name from the user database, `user <uid>` for ids it doesn't know
+    pub fn account(uid: u32) -> Account {
+        let mut passwd: libc::passwd = unsafe { std::mem::zeroed() };
+        let mut buf = vec![0 as libc::c_char; 4096];
+        let mut found = std::ptr::null_mut();
+
+        let res = unsafe { libc::getpwuid_r(uid, &mut passwd, buf.as_mut_ptr(), buf.len(), &mut found) };
+        let name = if res == 0 && !found.is_null() {
+            unsafe { std::ffi::CStr::from_ptr(passwd.pw_name) }.to_string_lossy().into_owned()
+        } else {
+            format!("user {}", uid)
+        };
+
+        Account { id: uid.to_string(), name }
+    }
+
+    pub fn current_account() -> io::Result<Account> {
+        // /proc/self belongs to the effective user of the process
+        Ok(account(fs::metadata("/proc/self")?.uid()))
+    }
+
+    pub fn secure(path: &Path) -> io::Result<()> {
+        std::os::unix::fs::chown(path, Some(0), Some(0))?;
+
+        let mode = fs::metadata(path)?.mode() & 0o7777 & !0o022;
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))
+    }
+}
+
+#[cfg(windows)]
+mod platform {
+    use std::io;
+    use std::os::windows::ffi::OsStrExt;
+    use std::path::Path;
+    use std::ptr;
+
+    use winapi::ctypes::c_void;
+    use winapi::shared::minwindef::DWORD;
+    use winapi::shared::sddl::{ConvertSidToStringSidW, ConvertStringSecurityDescriptorToSecurityDescriptorW, SDDL_REVISION_1};
+    use winapi::shared::winerror::ERROR_SUCCESS;
+    use winapi::um::accctrl::SE_FILE_OBJECT;
+    use winapi::um::aclapi::{GetNamedSecurityInfoW, SetNamedSecurityInfoW};
+    use winapi::um::handleapi::CloseHandle;
+    use winapi::um::processthreadsapi::{GetCurrentProcess, OpenProcessToken};
+    use winapi::um::securitybaseapi::{GetAce, GetSecurityDescriptorDacl, GetSecurityDescriptorOwner, GetTokenInformation};
+    use winapi::um::winbase::{LocalFree, LookupAccountSidW};
+    use winapi::um::winnt::{
+        TokenUser, ACCESS_ALLOWED_ACE, ACCESS_ALLOWED_ACE_TYPE, ACE_HEADER, ACL, DACL_SECURITY_INFORMATION, DELETE, FILE_APPEND_DATA,
+        FILE_DELETE_CHILD, FILE_WRITE_DATA, GENERIC_ALL, GENERIC_WRITE, HANDLE, INHERIT_ONLY_ACE, OWNER_SECURITY_INFORMATION,
+        PROTECTED_DACL_SECURITY_INFORMATION, PSECURITY_DESCRIPTOR, PSID, SID_NAME_USE, TOKEN_QUERY, TOKEN_USER, WRITE_DAC, WRITE_OWNER,
+    };
+
+    use super::{Access, Account, Rights};
+
+    /// SYSTEM, Administrators and TrustedInstaller, which owns Program Files
+    pub const TRUSTED_IDS: &[&str] = &["S-1-5-18", "S-1-5-32-544", "S-1-5-80-956008885-3418522649-1831038044-1853292631-2271478464"];
+
+    /// rights which let someone change or replace a file, or its ACL
+    const WRITE_RIGHTS: DWORD =
+        FILE_WRITE_DATA | FILE_APPEND_DATA | FILE_DELETE_CHILD | DELETE | WRITE_DAC | WRITE_OWNER | GENERIC_WRITE | GENERIC_ALL;
+    /// rights which let someone replace what's in a directory, or the directory, or its ACL.
+    /// Adding files and folders isn't one
+    const REPLACE_RIGHTS: DWORD = FILE_DELETE_CHILD | DELETE | WRITE_DAC | WRITE_OWNER | GENERIC_WRITE | GENERIC_ALL;
+
+    /// owner Administrators, full control for SYSTEM and Administrators, read and execute for
+    /// Users, not inheriting from the parent. Directories pass it on to their contents
+    const FILE_SDDL: &str = "O:BAD:P(A;;FA;;;SY)(A;;FA;;;BA)(A;;0x1200a9;;;BU)";
+    const DIR_SDDL: &str = "O:BAD:P(A;OICI;FA;;;SY)(A;OICI;FA;;;BA)(A;OICI;0x1200a9;;;BU)";
+
+    fn wide(s: &std::ffi::OsStr) -> Vec<u16> {
+        s.encode_wide().chain(Some(0)).collect()
+    }
+
+    unsafe fn from_wide(s: *const u16) -> String {
+        let len = (0..).take_while(|&i| *s.offset(i) != 0).count();
+        String::from_utf16_lossy(std::slice::from_raw_parts(s, len))
+    }
+
+    unsafe fn account(sid: PSID) -> io::Result<Account> {
+        let mut string_sid = ptr::null_mut();
+        if ConvertSidToStringSidW(sid, &mut string_sid) == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let id = from_wide(string_sid);
+        LocalFree(string_sid as *mut c_void);
+
+        let mut name = [0u16; 256];
+        let mut domain = [0u16; 256];
+        let (mut name_len, mut domain_len) = (name.len() as DWORD, domain.len() as DWORD);
+        let mut kind: SID_NAME_USE = 0;
+
+        let name = if LookupAccountSidW(ptr::null(), sid, name.as_mut_ptr(), &mut name_len, domain.as_mut_ptr(), &mut domain_len, &mut kind) != 0 {
+            match (from_wide(domain.as_ptr()), from_wide(name.as_ptr())) {
+                (domain, name) if domain.is_empty() => name,
+                (domain, name) => format!(r"{}\{}", domain, name),
+            }
+        } else {
+            id.clone()
+        };
+
+        Ok(Account { id, name })
+    }
+
+    fn mask(rights: Rights) -> DWORD {
+        match rights {
+            Rights::Write => WRITE_RIGHTS,
+            Rights::Replace => REPLACE_RIGHTS,
+        }
+    }
+
+    pub fn access(path: &Path, rights: Rights) -> io::Result<Access> {
+        let path = wide(path.as_os_str());
+        let mask = mask(rights);
+
+        unsafe {
+            let mut owner: PSID = ptr::null_mut();
+            let mut dacl: *mut ACL = ptr::null_mut();
+            let mut descriptor: PSECURITY_DESCRIPTOR = ptr::null_mut();
+
+            let res = GetNamedSecurityInfoW(
+                path.as_ptr(),
+                SE_FILE_OBJECT,
+                OWNER_SECURITY_INFORMATION | DACL_SECURITY_INFORMATION,
+                &mut owner,
+                ptr::null_mut(),
+                &mut dacl,
+                ptr::null_mut(),
+                &mut descriptor,
+            );
+            if res != ERROR_SUCCESS {
+                return Err(io::Error::from_raw_os_error(res as i32));
+            }
+
+            let access = read_access(owner, dacl, mask);
+            LocalFree(descriptor);
+            access
+        }
+    }
+
+    unsafe fn read_access(owner: PSID, dacl: *mut ACL, mask: DWORD) -> io::Result<Access> {
+        let owner = account(owner)?;
+
+        // no DACL at all lets everyone do anything
+        if dacl.is_null() {
+            return Ok(Access { owner, writers: vec![Account { id: "S-1-1-0".to_string(), name: "Everyone".to_string() }] });
+        }
+
+        let mut writers = vec![];
+        for i in 0..(*dacl).AceCount {
+            let mut ace: *mut c_void = ptr::null_mut();
+            if GetAce(dacl, i as DWORD, &mut ace) == 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            let header = &*(ace as *const ACE_HEADER);
+            // deny entries only take rights away, inherit only ones apply to the contents
+            if header.AceType != ACCESS_ALLOWED_ACE_TYPE || header.AceFlags & INHERIT_ONLY_ACE != 0 {
+                continue;
+            }
+
+            let allowed = &*(ace as *const ACCESS_ALLOWED_ACE);
+            if allowed.Mask & mask != 0 {
+                writers.push(account(&allowed.SidStart as *const DWORD as PSID)?);
+            }
+        }
+
+        Ok(Access { owner, writers })
+    }
+
+    pub fn current_account() -> io::Result<Account> {
+        unsafe {
+            let mut token = ptr::null_mut();
+            if OpenProcessToken(GetCurrentProcess(), TOKEN_QUERY, &mut token) == 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            let account = token_account(token);
+            CloseHandle(token);
+            account
+        }
+    }
+
+    pub unsafe fn token_account(token: HANDLE) -> io::Result<Account> {
+        let mut len = 0;
+        GetTokenInformation(token, TokenUser, ptr::null_mut(), 0, &mut len);
+        let mut buf = vec![0u8; len as usize];
+        if GetTokenInformation(token, TokenUser, buf.as_mut_ptr() as *mut c_void, len, &mut len) == 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let user = &*(buf.as_ptr() as *const TOKEN_USER);
+        account(user.User.Sid)
+    }
+
+    pub fn secure(path: &Path) -> io::Result<()> {
+        let sddl = if path.is_dir() { DIR_SDDL } else { FILE_SDDL };
+        let sddl = wide(sddl.as_ref());
+        let mut path = wide(path.as_os_str());
+
+        unsafe {
+            let mut descriptor: PSECURITY_DESCRIPTOR = ptr::null_mut();
+            if ConvertStringSecurityDescriptorToSecurityDescriptorW(sddl.as_ptr(), SDDL_REVISION_1 as DWORD, &mut descriptor, ptr::null_mut()) == 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            let (mut present, mut defaulted) = (0, 0);
+            let mut dacl: *mut ACL = ptr::null_mut();
+            GetSecurityDescriptorDacl(descriptor, &mut present, &mut dacl, &mut defaulted);
+
+            // the owner from the descriptor, Administrators
+            let mut owner: PSID = ptr::null_mut();
+            GetSecurityDescriptorOwner(descriptor, &mut owner, &mut defaulted);
+
+            let res = SetNamedSecurityInfoW(
+                path.as_mut_ptr(),
+                SE_FILE_OBJECT,
+                OWNER_SECURITY_INFORMATION | DACL_SECURITY_INFORMATION | PROTECTED_DACL_SECURITY_INFORMATION,
+                owner,
+                ptr::null_mut(),
+                dacl,
+                ptr::null_mut(),
+            );
+            LocalFree(descriptor);
+
+            match res {
+                ERROR_SUCCESS => Ok(()),
+                res => Err(io::Error::from_raw_os_error(res as i32)),
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use std::ptr;
+
+        use winapi::shared::minwindef::DWORD;
+        use winapi::shared::sddl::{ConvertStringSecurityDescriptorToSecurityDescriptorW, SDDL_REVISION_1};
+        use winapi::um::securitybaseapi::{GetSecurityDescriptorDacl, GetSecurityDescriptorOwner};
+        use winapi::um::winbase::LocalFree;
+        use winapi::um::winnt::{ACL, PSECURITY_DESCRIPTOR, PSID};
+
+        use super::{current_account, mask, read_access, wide, DIR_SDDL, FILE_SDDL, TRUSTED_IDS};
+        use crate::permissions::{Access, Rights};
+
+        const SYSTEM: &str = "S-1-5-18";
+        const ADMINISTRATORS: &str = "S-1-5-32-544";
+        const USERS: &str = "S-1-5-32-545";
+        const EVERYONE: &str = "S-1-1-0";
+
+        /// Who owns and who may change a file with the security descriptor `sddl`
+        fn access(sddl: &str, rights: Rights) -> Access {
+            let sddl = wide(sddl.as_ref());
+
+            unsafe {
+                let mut descriptor: PSECURITY_DESCRIPTOR = ptr::null_mut();
+                let res = ConvertStringSecurityDescriptorToSecurityDescriptorW(sddl.as_ptr(), SDDL_REVISION_1 as DWORD, &mut descriptor, ptr::null_mut());
+                assert_ne!(res, 0, "{}", std::io::Error::last_os_error());
+
+                let (mut present, mut defaulted) = (0, 0);
+                let mut dacl: *mut ACL = ptr::null_mut();
+                GetSecurityDescriptorDacl(descriptor, &mut present, &mut dacl, &mut defaulted);
+                let mut owner: PSID = ptr::null_mut();
+                GetSecurityDescriptorOwner(descriptor, &mut owner, &mut defaulted);
+
+                let access = read_access(owner, dacl, mask(rights)).unwrap();
+                LocalFree(descriptor);
+                access
+            }
+        }
+
+        fn writers(access: &Access) -> Vec<&str> {
+            access.writers.iter().map(|w| w.id.as_str()).collect()
+        }
+
+        #[test]
+        fn secured() {
+            // what secure sets, Users may only read
+            for sddl in &[FILE_SDDL, DIR_SDDL] {
+                let access = access(sddl, Rights::Write);
+                assert_eq!(access.owner.id, ADMINISTRATORS);
+                assert_eq!(writers(&access), [SYSTEM, ADMINISTRATORS]);
+                assert!(access.writers.iter().all(|w| TRUSTED_IDS.contains(&w.id.as_str())));
+            }
+        }
+
+        #[test]
+        fn writable_by_others() {
+            let access = access("O:BAD:(A;;FA;;;SY)(A;;FA;;;WD)", Rights::Write);
+            assert_eq!(writers(&access), [SYSTEM, EVERYONE]);
+
+            // modify, without full control
+            assert_eq!(writers(&access("O:BAD:(A;;0x1301bf;;;BU)", Rights::Write)), [USERS]);
+
+            // no DACL at all lets everyone in
+            assert_eq!(writers(&access("O:BAD:NO_ACCESS_CONTROL", Rights::Write)), [EVERYONE]);
+        }
+
+        #[test]
+        fn adding_entries_doesnt_replace() {
+            // like Users on C:\, creating files and folders but not deleting them
+            let sddl = "O:SYD:(A;;FA;;;SY)(A;;0x1200af;;;BU)";
+            assert_eq!(writers(&access(sddl, Rights::Write)), [SYSTEM, USERS]);
+            assert_eq!(writers(&access(sddl, Rights::Replace)), [SYSTEM]);
+
+            // but deleting what's in a directory does
+            assert_eq!(writers(&access("O:SYD:(A;;0x40;;;BU)", Rights::Replace)), [USERS]);
+        }
+
+        #[test]
+        fn ignored_entries() {
+            // deny entries only take rights away, inherit only ones apply to the contents
+            let sddl = "O:BAD:(A;;FA;;;BA)(D;;FA;;;WD)(A;OICIIO;FA;;;BU)";
+            assert_eq!(writers(&access(sddl, Rights::Write)), [ADMINISTRATORS]);
+        }
+
+        #[test]
+        fn owner() {
+            let access = access("O:BUD:(A;;FA;;;SY)", Rights::Write);
+            assert_eq!(access.owner.id, USERS);
+            assert!(!TRUSTED_IDS.contains(&access.owner.id.as_str()));
+        }
+
+        #[test]
+        fn current_user() {
+            assert!(current_account().unwrap().id.starts_with("S-1-5-"));
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::fs;
+    use std::os::unix::fs::symlink;
+
+    use super::{check_dir, check_file, PermissionError};
+    use crate::testing::{set_mode, ScratchDir};
+
+    fn is_root() -> bool {
+        unsafe { libc::geteuid() == 0 }
+    }
+
+    #[test]
+    fn locked_down() {
+        let dir = ScratchDir::new("locked-down");
+        let file = dir.join("config.toml");
+        fs::write(&file, "").unwrap();
+        set_mode(&file, 0o644);
+
+        assert!(check_dir(&dir).is_ok());
+        assert!(check_file(&file).is_ok());
+        // only its directories are checked
+        assert!(check_file(&dir.join("missing.json")).is_ok());
+    }
+
+    #[test]
+    fn writable_by_others() {
+        let dir = ScratchDir::new("writable");
+        let file = dir.join("state.json");
+        fs::write(&file, "").unwrap();
+
+        for (mode, by) in [(0o664, "group"), (0o646, "everyone")] {
+            set_mode(&file, mode);
+            match check_file(&file) {
+                Err(PermissionError::Writable { path, by: found }) => {
+                    assert_eq!(path, file);
+                    assert!(found.starts_with(by), "{}", found);
+                }
+                res => panic!("{:o}: {:?}", mode, res),
+            }
+        }
+
+        set_mode(&file, 0o644);
+        set_mode(&dir, 0o777);
+        assert!(matches!(check_file(&file), Err(PermissionError::Writable { path, .. }) if path == *dir));
+        assert!(matches!(check_dir(&dir), Err(PermissionError::Writable { .. })));
+    }
+
+    #[test]
+    fn ancestors() {
+        let parent = ScratchDir::new("ancestors");
+        let dir = parent.join("logs");
+        fs::create_dir(&dir).unwrap();
+        set_mode(&dir, 0o755);
+
+        // anyone could rename logs and put a directory of their own in its place
+        set_mode(&parent, 0o777);
+        assert!(matches!(check_dir(&dir), Err(PermissionError::Writable { path, .. }) if path == *parent));
+
+        // but not with the sticky bit, like /tmp
+        set_mode(&parent, 0o1777);
+        assert!(check_dir(&dir).is_ok());
+
+        // the directory itself mustn't take new entries from others either way
+        set_mode(&dir, 0o1777);
+        assert!(check_dir(&dir).is_err());
+    }
+
+    #[test]
+    fn links_are_followed() {
+        let dir = ScratchDir::new("links");
+        let target = dir.join("target.toml");
+        fs::write(&target, "").unwrap();
+        set_mode(&target, 0o666);
+
+        let link = dir.join("config.toml");
+        symlink(&target, &link).unwrap();
+        assert!(matches!(check_file(&link), Err(PermissionError::Writable { .. })));
+    }
+
+    #[test]
+    fn owner() {
+        // files of the current user are trusted, only root can hand one to someone else
+        if !is_root() {
+            return;
+        }
+
+        let dir = ScratchDir::new("owner");
+        let file = dir.join("config.toml");
+        fs::write(&file, "").unwrap();
+        set_mode(&file, 0o644);
+        std::os::unix::fs::chown(&file, Some(65534), None).unwrap();
+
+        assert!(matches!(check_file(&file), Err(PermissionError::Owner { path, .. }) if path == file));
+    }
+}
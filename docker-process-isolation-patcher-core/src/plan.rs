@@ -0,0 +1,129 @@
+// `plan` report
+// What `patch-now` would do, step by step, without changing anything.
+
+use std::path::Path;
+
+use serde::Serialize;
+
+use super::backend::{BackendResult, ServiceBackend, ServiceState};
+use super::config::Config;
+use super::hooks::Stage;
+use super::patch;
+use super::shared::*;
+use super::state::State;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Plan {
+    /// None if docker isn't installed
+    pub docker: Option<ServiceState>,
+    pub command_line: Option<String>,
+    /// command line after patching, None if it wouldn't change
+    pub patched_command_line: Option<String>,
+    /// empty if there's nothing to do
+    pub steps: Vec<String>,
+}
+
+pub fn collect<B: ServiceBackend>(backend: &B, state_path: &Path, config: &Config) -> BackendResult<Plan> {
+    let docker = backend.query_state(DOCKER_SERVICE_NAME)?;
+    let docker_config = backend.query_config(DOCKER_SERVICE_NAME)?;
+
+    let mut plan = Plan {
+        docker,
+        command_line: docker_config.as_ref().map(|c| c.command_line.clone()),
+        patched_command_line: None,
+        steps: vec![],
+    };
+
+    let docker_config = match docker_config {
+        Some(docker_config) => docker_config,
+        None => return Ok(plan),
+    };
+
+    let running = docker == Some(ServiceState::Running);
+    let drifted = patch::has_drifted(&patch::split_command_line(&docker_config.command_line), &config.watcher.flags);
+    let pending = State::load_from(state_path).map(|s| s.pending_restart.is_some()).unwrap_or(false);
+
+    let hook = |steps: &mut Vec<String>, stage: Stage| {
+        if let Some(command) = config.hooks.command(stage) {
+            steps.push(format!("run the {} hook: {}", stage, command));
+        }
+    };
+
+    let graceful = config.watcher.graceful_restart;
+    let drain = |steps: &mut Vec<String>| {
+        if graceful {
+            steps.push(format!("stop the running containers, each gets {} seconds to exit", config.watcher.container_stop_timeout_secs));
+        }
+    };
+    let restore = |steps: &mut Vec<String>| {
+        if graceful {
+            steps.push("start the stopped containers again, docker starts those with restart policy always itself".to_string());
+        }
+    };
+
+    if drifted {
+        let patched = patch::patch_config(&docker_config, &config.watcher.flags);
+
+        if running {
+            hook(&mut plan.steps, Stage::PreStop);
+            drain(&mut plan.steps);
+            plan.steps.push(format!("stop {}", DOCKER_SERVICE_NAME));
+        }
+
+        plan.steps.push(format!("change the {} command line to {}", DOCKER_SERVICE_NAME, patched.command_line));
+        plan.steps.push(format!("change the {} display name to {}", DOCKER_SERVICE_NAME, patched.display_name));
+        hook(&mut plan.steps, Stage::PostPatch);
+
+        if running {
+            plan.steps.push(format!("start {}", DOCKER_SERVICE_NAME));
+            restore(&mut plan.steps);
+            hook(&mut plan.steps, Stage::PostStart);
+        } else {
+            plan.steps.push(format!("{} isn't running, it picks up the patch when it starts", DOCKER_SERVICE_NAME));
+        }
+
+        plan.patched_command_line = Some(patched.command_line);
+    } else if pending && running {
+        hook(&mut plan.steps, Stage::PreStop);
+        drain(&mut plan.steps);
+        plan.steps.push(format!("restart {} to apply the pending patch", DOCKER_SERVICE_NAME));
+        restore(&mut plan.steps);
+        hook(&mut plan.steps, Stage::PostStart);
+    }
+
+    Ok(plan)
+}
+
+pub fn render_text(plan: &Plan) -> String {
+    let command_line = match &plan.command_line {
+        Some(command_line) => command_line,
+        None => return format!("{} isn't installed, there's nothing to patch\n", DOCKER_SERVICE_NAME),
+    };
+
+    let docker = plan.docker.map_or("not installed".to_string(), |s| s.to_string());
+    let mut out = format!("docker service: {}\ncommand line: {}\n", docker, command_line);
+
+    if plan.steps.is_empty() {
+        out.push_str("nothing to do, docker is patched\n");
+        return out;
+    }
+
+    out.push_str("patch-now would:\n");
+    for (i, step) in plan.steps.iter().enumerate() {
+        out.push_str(&format!("  {}. {}\n", i + 1, step));
+    }
+
+    out
+}
+
+/// One line, for the batch table
+pub fn summary(plan: &Plan) -> String {
+    if plan.command_line.is_none() {
+        return format!("{} isn't installed", DOCKER_SERVICE_NAME);
+    }
+
+    match plan.steps.len() {
+        0 => "nothing to do, docker is patched".to_string(),
+        n => format!("patch-now would take {} steps: {}", n, plan.steps.join("; ")),
+    }
+}
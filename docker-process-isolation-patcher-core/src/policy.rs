@@ -0,0 +1,146 @@
+use serde::Deserialize;
+use serde_json::Value;
+
+use super::compat::Compatibility;
+
+pub const PROCESS_ISOLATION: &str = "process";
+pub const HYPERV_ISOLATION: &str = "hyperv";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PolicyMode {
+    /// every container and build is rewritten to process isolation
+    ForceProcess,
+    /// hyperv is kept for allow-listed images, everything else is rewritten to process isolation
+    AllowList,
+    /// explicit hyperv requests for images that aren't allow-listed are refused
+    Reject,
+}
+
+/// What the proxy enforces on `POST /containers/create` and `POST /build`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct IsolationPolicy {
+    pub mode: PolicyMode,
+    /// images which may run with hyperv isolation. A trailing `*` matches any suffix, and a
+    /// pattern without a tag matches every tag of that image
+    pub hyperv_images: Vec<String>,
+    /// use hyperv isolation for images whose windows build doesn't match the host, instead of
+    /// forcing process isolation on them
+    pub hyperv_fallback: bool,
+}
+
+impl Default for IsolationPolicy {
+    fn default() -> Self {
+        IsolationPolicy {
+            mode: PolicyMode::ForceProcess,
+            hyperv_images: vec![],
+            hyperv_fallback: true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Decision {
+    /// the request already asks for process isolation, or is allowed to keep what it asked for
+    Keep,
+    /// the requested isolation (None if it wasn't set) is replaced with `to`
+    Rewrite { from: Option<String>, to: &'static str },
+    /// the request must not reach docker
+    Reject(String),
+}
+
+impl IsolationPolicy {
+    /// Decide what to do with a request for `image` that asked for `requested` isolation.
+    /// `compat` is the image's compatibility with the host, if known
+    pub fn decide(&self, image: Option<&str>, requested: Option<&str>, compat: Option<&Compatibility>) -> Decision {
+        // "default" and an empty string both mean the daemon default, same as leaving it unset
+        let explicit = requested.filter(|r| !r.is_empty() && !r.eq_ignore_ascii_case("default"));
+        let incompatible = self.hyperv_fallback && compat.is_some_and(|c| !c.allows_process());
+
+        if let Some(isolation) = explicit {
+            if isolation.eq_ignore_ascii_case(PROCESS_ISOLATION) {
+                return Decision::Keep;
+            }
+
+            if self.mode != PolicyMode::ForceProcess && image.is_some_and(|i| self.allows_hyperv(i)) {
+                return Decision::Keep;
+            }
+
+            // the image can't run with process isolation anyway
+            if incompatible && isolation.eq_ignore_ascii_case(HYPERV_ISOLATION) {
+                return Decision::Keep;
+            }
+
+            if self.mode == PolicyMode::Reject {
+                return Decision::Reject(format!(
+                    "{} isolation is not allowed for image {} on this host, use process isolation instead",
+                    isolation,
+                    image.unwrap_or("<none>")
+                ));
+            }
+        }
+
+        let to = if incompatible { HYPERV_ISOLATION } else { PROCESS_ISOLATION };
+        Decision::Rewrite { from: requested.map(str::to_string), to }
+    }
+
+    /// Apply the policy to a `POST /containers/create` body, rewriting `HostConfig.Isolation`
+    /// in place if needed
+    pub fn apply_create(&self, body: &mut Value, compat: Option<&Compatibility>) -> Decision {
+        let image = body.get("Image").and_then(Value::as_str).map(str::to_string);
+        let requested = body
+            .pointer("/HostConfig/Isolation")
+            .and_then(Value::as_str)
+            .map(str::to_string);
+
+        let decision = self.decide(image.as_deref(), requested.as_deref(), compat);
+
+        if let Decision::Rewrite { to, .. } = decision {
+            if let Value::Object(root) = body {
+                let host_config = root
+                    .entry("HostConfig")
+                    .or_insert_with(|| Value::Object(Default::default()));
+
+                if !host_config.is_object() {
+                    *host_config = Value::Object(Default::default());
+                }
+
+                host_config["Isolation"] = Value::from(to);
+            }
+        }
+
+        decision
+    }
+
+    pub fn allows_hyperv(&self, image: &str) -> bool {
+        self.hyperv_images.iter().any(|pattern| image_matches(pattern, image))
+    }
+}
+
+/// `repo` matches every tag of `repo`, `repo:tag` only that tag, and `prefix*` anything starting
+/// with prefix
+pub fn image_matches(pattern: &str, image: &str) -> bool {
+    if let Some(prefix) = pattern.strip_suffix('*') {
+        return image.starts_with(prefix);
+    }
+
+    if image == pattern {
+        return true;
+    }
+
+    // pattern without tag or digest matches all tags of the same repository
+    let has_tag = pattern.contains('@') || pattern.rsplit('/').next().is_some_and(|last| last.contains(':'));
+    !has_tag && strip_tag(image) == pattern
+}
+
+fn strip_tag(image: &str) -> &str {
+    let image = image.split('@').next().unwrap_or(image);
+
+    // a colon before the last slash belongs to a registry port, not a tag
+    let last_slash = image.rfind('/').map_or(0, |i| i + 1);
+    match image[last_slash..].rfind(':') {
+        Some(colon) => &image[..last_slash + colon],
+        None => image,
+    }
+}
@@ -0,0 +1,1252 @@
+// Docker Engine API proxy
+// Listens on its own pipe/socket and forwards every request to dockerd. Container creation and
+// image builds have their isolation mode rewritten according to the configured policy before
+// they reach docker. Everything else, including hijacked attach/exec streams, is passed through.
+
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use log::{error, info, warn};
+use serde_json::Value;
+
+use super::compat::{self, Compatibility, OsVersion};
+use super::config::Config;
+use super::engine;
+use super::policy::{Decision, IsolationPolicy};
+
+const MAX_HEAD_SIZE: usize = 64 * 1024;
+// bodies read into memory are container create requests and api responses, anything larger
+// than this is not a real one
+const MAX_BODY_SIZE: u64 = 16 * 1024 * 1024;
+
+/// A bidirectional byte stream. It has to be splittable to tunnel hijacked connections,
+/// and one half has to be able to read while the other writes
+pub trait Stream: Read + Write + Send + Sized + 'static {
+    fn try_clone(&self) -> io::Result<Self>;
+    fn shutdown_write(&self) -> io::Result<()>;
+
+    /// The account on the other end, as the OS knows it. None for streams which can't tell
+    fn peer_user(&self) -> io::Result<Option<String>> {
+        Ok(None)
+    }
+}
+
+pub trait Listener {
+    type Stream: Stream;
+    fn accept(&mut self) -> io::Result<Self::Stream>;
+}
+
+/// Opens a new connection to dockerd
+pub trait Connector: Send + Sync + 'static {
+    type Stream: Stream;
+    fn connect(&self) -> io::Result<Self::Stream>;
+}
+
+pub fn spawn(config: Config) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        if let Err(e) = run(&config) {
+            error!("proxy stopped: {}", e);
+        }
+    })
+}
+
+/// Run the proxy on the configured pipe/socket, blocking forever
+pub fn run(config: &Config) -> io::Result<()> {
+    let host = match compat::host_os_version(&config.host) {
+        Ok(host) => host,
+        Err(e) => {
+            warn!("image compatibility checks disabled: {}", e);
+            None
+        }
+    };
+
+    let config = &config.proxy;
+
+    #[cfg(windows)]
+    let (listener, connector) = (
+        pipe::PipeListener::bind(&config.listen)?,
+        pipe::PipeConnector::new(&config.upstream),
+    );
+
+    #[cfg(unix)]
+    let (listener, connector) = (
+        unix::UnixSocketListener::bind(&config.listen)?,
+        unix::UnixSocketConnector::new(&config.upstream),
+    );
+
+    info!("listening on {} and forwarding to {}", config.listen, config.upstream);
+    serve(listener, connector, config.policy.clone(), host)
+}
+
+/// `host` enables image compatibility checks for the policy's hyperv fallback
+pub fn serve<L: Listener, C: Connector>(
+    mut listener: L,
+    connector: C,
+    policy: IsolationPolicy,
+    host: Option<OsVersion>,
+) -> io::Result<()> {
+    let proxy = Arc::new(Proxy { connector, policy, host });
+
+    loop {
+        let client = match listener.accept() {
+            Ok(client) => client,
+            Err(e) => {
+                warn!("failed to accept connection: {}", e);
+                thread::sleep(Duration::from_millis(100));
+                continue;
+            }
+        };
+
+        let proxy = proxy.clone();
+        thread::spawn(move || {
+            if let Err(e) = proxy.handle_connection(client) {
+                // clients hanging up mid request are normal
+                info!("connection closed: {}", e);
+            }
+        });
+    }
+}
+
+struct Proxy<C> {
+    connector: C,
+    policy: IsolationPolicy,
+    host: Option<OsVersion>,
+}
+
+enum Route {
+    Create,
+    Build,
+    Other,
+}
+
+struct Upstream<S> {
+    reader: BufReader<S>,
+    writer: S,
+}
+
+impl<C: Connector> Proxy<C> {
+    fn handle_connection<S: Stream>(&self, client: S) -> io::Result<()> {
+        let mut client_writer = client.try_clone()?;
+        let mut client_reader = BufReader::new(client);
+        let mut upstream: Option<Upstream<C::Stream>> = None;
+
+        loop {
+            let mut request = match Head::read(&mut client_reader)? {
+                Some(request) => request,
+                None => return Ok(()),
+            };
+
+            let (method, target) = request.request_line()?;
+            let request_body = request.body_length(false);
+            let mut rewritten_body = None;
+
+            match route(&method, &target) {
+                Route::Create => {
+                    let body = read_body(&request_body, &mut client_reader)?;
+
+                    match self.rewrite_create(&body) {
+                        Some(Err(message)) => {
+                            reject(&mut client_writer, &message)?;
+                            continue;
+                        }
+
+                        Some(Ok(new_body)) => rewritten_body = Some(new_body),
+                        None => rewritten_body = Some(body),
+                    }
+                }
+
+                Route::Build => {
+                    match self.rewrite_build(&target) {
+                        Ok(Some(new_target)) => request.set_request_target(&method, &new_target),
+                        Ok(None) => (),
+                        Err(message) => {
+                            // the build context has to be drained before answering
+                            relay_body(&request_body, &mut client_reader, &mut io::sink())?;
+                            reject(&mut client_writer, &message)?;
+                            continue;
+                        }
+                    }
+                }
+
+                Route::Other => (),
+            }
+
+            if upstream.is_none() {
+                let stream = self.connector.connect()?;
+                upstream = Some(Upstream {
+                    writer: stream.try_clone()?,
+                    reader: BufReader::new(stream),
+                });
+            }
+            let up = upstream.as_mut().unwrap();
+
+            match rewritten_body {
+                Some(body) => {
+                    request.remove_header("Transfer-Encoding");
+                    request.set_header("Content-Length", &body.len().to_string());
+                    request.write(&mut up.writer)?;
+                    up.writer.write_all(&body)?;
+                    up.writer.flush()?;
+                }
+
+                None => {
+                    request.write(&mut up.writer)?;
+                    relay_body(&request_body, &mut client_reader, &mut up.writer)?;
+                }
+            }
+
+            let response = Head::read(&mut up.reader)?
+                .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "docker closed the connection"))?;
+            response.write(&mut client_writer)?;
+
+            let status = response.status()?;
+            let response_body = if method == "HEAD" || status == 204 || status == 304 || ((100..200).contains(&status) && status != 101) {
+                BodyLength::None
+            } else {
+                response.body_length(true)
+            };
+
+            if status == 101 || response_body == BodyLength::UntilClose {
+                // hijacked stream (attach, exec), nothing but raw bytes from here on
+                let up = upstream.take().unwrap();
+                return tunnel(client_reader, client_writer, up);
+            }
+
+            relay_body(&response_body, &mut up.reader, &mut client_writer)?;
+
+            if request.is_close() || response.is_close() {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Some(Ok(body)) is the body to forward, Some(Err(message)) rejects the request, and None
+    /// forwards the body untouched
+    fn rewrite_create(&self, body: &[u8]) -> Option<Result<Vec<u8>, String>> {
+        let mut json: Value = match serde_json::from_slice(body) {
+            Ok(json) => json,
+            // let docker report malformed requests
+            Err(_) => return None,
+        };
+
+        let image = json.get("Image").and_then(Value::as_str).unwrap_or("<none>").to_string();
+        let compat = self.image_compat(&image);
+
+        match self.policy.apply_create(&mut json, compat.as_ref()) {
+            Decision::Keep => None,
+
+            Decision::Rewrite { from, to } => {
+                info!("rewrote isolation of {} from {:?} to {}", image, from, to);
+                Some(Ok(serde_json::to_vec(&json).unwrap()))
+            }
+
+            Decision::Reject(message) => {
+                info!("rejected container for {}: {}", image, message);
+                Some(Err(message))
+            }
+        }
+    }
+
+    /// Ok(Some(target)) is the rewritten request target, Ok(None) keeps the original one
+    fn rewrite_build(&self, target: &str) -> Result<Option<String>, String> {
+        let (path, query) = match target.find('?') {
+            Some(i) => (&target[..i], &target[i + 1..]),
+            None => (target, ""),
+        };
+
+        let mut params: Vec<(String, String)> = query
+            .split('&')
+            .filter(|p| !p.is_empty())
+            .map(|p| match p.find('=') {
+                Some(i) => (p[..i].to_string(), p[i + 1..].to_string()),
+                None => (p.to_string(), String::new()),
+            })
+            .collect();
+
+        let image = params.iter().find(|(k, _)| k == "t").map(|(_, v)| percent_decode(v));
+        let requested = params.iter().find(|(k, _)| k == "isolation").map(|(_, v)| percent_decode(v));
+
+        // the base image of a build isn't known here, so there's nothing to check compatibility of
+        match self.policy.decide(image.as_deref(), requested.as_deref(), None) {
+            Decision::Keep => Ok(None),
+
+            Decision::Rewrite { from, to } => {
+                info!("rewrote build isolation of {} from {:?} to {}", image.as_deref().unwrap_or("<untagged>"), from, to);
+
+                params.retain(|(k, _)| k != "isolation");
+                params.push(("isolation".to_string(), to.to_string()));
+
+                let query: Vec<String> = params.iter().map(|(k, v)| format!("{}={}", k, v)).collect();
+                Ok(Some(format!("{}?{}", path, query.join("&"))))
+            }
+
+            Decision::Reject(message) => {
+                info!("rejected build of {}: {}", image.as_deref().unwrap_or("<untagged>"), message);
+                Err(message)
+            }
+        }
+    }
+}
+
+impl<C: Connector> Proxy<C> {
+    /// None if the host build is unknown or docker doesn't have the image yet
+    fn image_compat(&self, image: &str) -> Option<Compatibility> {
+        let host = self.host.as_ref()?;
+
+        if !self.policy.hyperv_fallback {
+            return None;
+        }
+
+        match self.inspect_image(image) {
+            Ok(json) => compat::image_platforms(&json).ok().map(|platforms| compat::check_any(host, &platforms)),
+            Err(e) => {
+                info!("couldn't inspect {}: {}", image, e);
+                None
+            }
+        }
+    }
+
+    fn inspect_image(&self, image: &str) -> io::Result<Value> {
+        if !is_image_reference(image) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{:?} is not an image reference", image)));
+        }
+
+        engine::get_json(&self.connector, &format!("/images/{}/json", image))
+    }
+}
+
+/// Whether `image` can go into a request path as is: `name[:tag][@digest]`, no query, fragment,
+/// escapes or dot segments which would query another endpoint
+fn is_image_reference(image: &str) -> bool {
+    !image.is_empty()
+        && image.chars().all(|c| c.is_ascii_alphanumeric() || "._-/:@".contains(c))
+        && image.split('/').all(|segment| !segment.is_empty() && segment != "." && segment != "..")
+}
+
+fn route(method: &str, target: &str) -> Route {
+    if method != "POST" {
+        return Route::Other;
+    }
+
+    let path = target.split('?').next().unwrap_or(target);
+
+    // strip optional api version prefix, e.g. /v1.41/containers/create
+    let path = match path.strip_prefix("/v") {
+        Some(rest) => match rest.find('/') {
+            Some(i) if rest[..i].chars().all(|c| c.is_ascii_digit() || c == '.') => &rest[i..],
+            _ => path,
+        },
+        None => path,
+    };
+
+    match path.trim_end_matches('/') {
+        "/containers/create" => Route::Create,
+        "/build" => Route::Build,
+        _ => Route::Other,
+    }
+}
+
+fn reject<W: Write>(writer: &mut W, message: &str) -> io::Result<()> {
+    let body = serde_json::json!({ "message": message }).to_string();
+
+    write!(
+        writer,
+        "HTTP/1.1 403 Forbidden\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+        body.len(),
+        body
+    )?;
+    writer.flush()
+}
+
+fn tunnel<S: Stream, U: Stream>(client_reader: BufReader<S>, mut client_writer: S, upstream: Upstream<U>) -> io::Result<()> {
+    let Upstream { reader: mut upstream_reader, writer: mut upstream_writer } = upstream;
+    let mut client_reader = client_reader;
+
+    // the client side copy is left to finish on its own once either end hangs up
+    thread::spawn(move || {
+        io::copy(&mut client_reader, &mut upstream_writer).ok();
+        upstream_writer.shutdown_write().ok();
+    });
+
+    io::copy(&mut upstream_reader, &mut client_writer)?;
+    client_writer.shutdown_write()
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum BodyLength {
+    None,
+    Fixed(u64),
+    Chunked,
+    UntilClose,
+}
+
+/// Request or response line plus headers
+pub struct Head {
+    start_line: String,
+    headers: Vec<(String, String)>,
+}
+
+impl Head {
+    /// None if the peer closed the connection before sending anything
+    pub fn read<R: BufRead>(reader: &mut R) -> io::Result<Option<Head>> {
+        let mut start_line = String::new();
+        let mut headers = vec![];
+        let mut size = 0;
+
+        loop {
+            let mut line = vec![];
+            let read = reader.by_ref().take((MAX_HEAD_SIZE - size) as u64).read_until(b'\n', &mut line)?;
+            size += read;
+
+            if read == 0 {
+                if start_line.is_empty() && headers.is_empty() {
+                    return Ok(None);
+                }
+
+                return Err(invalid_data("connection closed mid header"));
+            }
+
+            if !line.ends_with(b"\n") {
+                return Err(invalid_data("header too large"));
+            }
+
+            let line = String::from_utf8(line).map_err(|_| invalid_data("header is not utf-8"))?;
+            let line = line.trim_end_matches(['\r', '\n']);
+
+            if start_line.is_empty() {
+                // tolerate stray empty lines between messages
+                start_line = line.to_string();
+                continue;
+            }
+
+            if line.is_empty() {
+                return Ok(Some(Head { start_line, headers }));
+            }
+
+            let colon = line.find(':').ok_or_else(|| invalid_data("malformed header"))?;
+            headers.push((line[..colon].trim().to_string(), line[colon + 1..].trim().to_string()));
+        }
+    }
+
+    fn write<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        let mut head = format!("{}\r\n", self.start_line);
+        for (name, value) in &self.headers {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        head.push_str("\r\n");
+
+        writer.write_all(head.as_bytes())?;
+        writer.flush()
+    }
+
+    fn request_line(&self) -> io::Result<(String, String)> {
+        let mut parts = self.start_line.split(' ');
+        match (parts.next(), parts.next()) {
+            (Some(method), Some(target)) => Ok((method.to_string(), target.to_string())),
+            _ => Err(invalid_data("malformed request line")),
+        }
+    }
+
+    fn set_request_target(&mut self, method: &str, target: &str) {
+        let version = self.start_line.rsplit(' ').next().unwrap_or("HTTP/1.1").to_string();
+        self.start_line = format!("{} {} {}", method, target, version);
+    }
+
+    pub fn status(&self) -> io::Result<u16> {
+        self.start_line
+            .split(' ')
+            .nth(1)
+            .and_then(|s| s.parse().ok())
+            .ok_or_else(|| invalid_data("malformed status line"))
+    }
+
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    fn set_header(&mut self, name: &str, value: &str) {
+        self.remove_header(name);
+        self.headers.push((name.to_string(), value.to_string()));
+    }
+
+    fn remove_header(&mut self, name: &str) {
+        self.headers.retain(|(n, _)| !n.eq_ignore_ascii_case(name));
+    }
+
+    fn is_close(&self) -> bool {
+        self.header("Connection").is_some_and(|v| v.eq_ignore_ascii_case("close"))
+    }
+
+    /// Requests without a length have no body, responses are read until the connection closes
+    pub fn body_length(&self, is_response: bool) -> BodyLength {
+        if self.header("Transfer-Encoding").is_some_and(|v| v.to_ascii_lowercase().contains("chunked")) {
+            return BodyLength::Chunked;
+        }
+
+        match self.header("Content-Length").and_then(|v| v.parse().ok()) {
+            Some(0) => BodyLength::None,
+            Some(length) => BodyLength::Fixed(length),
+            None if is_response => BodyLength::UntilClose,
+            None => BodyLength::None,
+        }
+    }
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+/// Copy a message body as is, flushing after every chunk so streamed output isn't held back
+fn relay_body<R: BufRead, W: Write>(length: &BodyLength, reader: &mut R, writer: &mut W) -> io::Result<()> {
+    match length {
+        BodyLength::None => (),
+
+        BodyLength::Fixed(length) => {
+            let copied = io::copy(&mut reader.by_ref().take(*length), writer)?;
+            if copied != *length {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "body ended early"));
+            }
+        }
+
+        BodyLength::Chunked => loop {
+            let size = read_chunk_size(reader, Some(writer))?;
+
+            if size == 0 {
+                // trailers, terminated by an empty line
+                loop {
+                    let mut line = vec![];
+                    reader.read_until(b'\n', &mut line)?;
+                    writer.write_all(&line)?;
+
+                    if line == b"\r\n" || line == b"\n" || line.is_empty() {
+                        break;
+                    }
+                }
+
+                break;
+            }
+
+            // chunk data plus its CRLF
+            let copied = io::copy(&mut reader.by_ref().take(size + 2), writer)?;
+            if copied != size + 2 {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "chunk ended early"));
+            }
+            writer.flush()?;
+        },
+
+        BodyLength::UntilClose => {
+            io::copy(reader, writer)?;
+        }
+    }
+
+    writer.flush()
+}
+
+/// Read a whole body into memory, decoding chunked encoding
+pub fn read_body<R: BufRead>(length: &BodyLength, reader: &mut R) -> io::Result<Vec<u8>> {
+    let mut body = vec![];
+
+    match length {
+        BodyLength::None => (),
+
+        BodyLength::Fixed(length) => {
+            if *length > MAX_BODY_SIZE {
+                return Err(invalid_data("body too large"));
+            }
+
+            body.resize(*length as usize, 0);
+            reader.read_exact(&mut body)?;
+        }
+
+        BodyLength::Chunked => loop {
+            let size = read_chunk_size::<_, io::Sink>(reader, None)?;
+
+            if size == 0 {
+                let mut line = vec![];
+                while reader.read_until(b'\n', &mut line)? > 2 {
+                    line.clear();
+                }
+                break;
+            }
+
+            if body.len() as u64 + size > MAX_BODY_SIZE {
+                return Err(invalid_data("body too large"));
+            }
+
+            let start = body.len();
+            body.resize(start + size as usize, 0);
+            reader.read_exact(&mut body[start..])?;
+
+            let mut crlf = [0; 2];
+            reader.read_exact(&mut crlf)?;
+        },
+
+        BodyLength::UntilClose => {
+            reader.take(MAX_BODY_SIZE).read_to_end(&mut body)?;
+        }
+    }
+
+    Ok(body)
+}
+
+/// Reads a chunk size line, echoing it to `echo` untouched if given
+fn read_chunk_size<R: BufRead, W: Write>(reader: &mut R, echo: Option<&mut W>) -> io::Result<u64> {
+    let mut line = vec![];
+    reader.by_ref().take(1024).read_until(b'\n', &mut line)?;
+
+    if let Some(echo) = echo {
+        echo.write_all(&line)?;
+    }
+
+    let line = String::from_utf8_lossy(&line);
+    let size = line.split(';').next().unwrap_or("").trim();
+    u64::from_str_radix(size, 16).map_err(|_| invalid_data("malformed chunk size"))
+}
+
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        let hex = bytes.get(i + 1..i + 3).and_then(|h| std::str::from_utf8(h).ok());
+
+        match (bytes[i], hex.and_then(|h| u8::from_str_radix(h, 16).ok())) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+
+            (b'+', _) => {
+                decoded.push(b' ');
+                i += 1;
+            }
+
+            (b, _) => {
+                decoded.push(b);
+                i += 1;
+            }
+        }
+    }
+
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+impl Stream for std::net::TcpStream {
+    fn try_clone(&self) -> io::Result<Self> {
+        std::net::TcpStream::try_clone(self)
+    }
+
+    fn shutdown_write(&self) -> io::Result<()> {
+        self.shutdown(std::net::Shutdown::Write)
+    }
+}
+
+#[cfg(unix)]
+pub mod unix {
+    use std::io;
+    use std::os::unix::net::{UnixListener, UnixStream};
+    use std::path::PathBuf;
+
+    impl super::Stream for UnixStream {
+        fn try_clone(&self) -> io::Result<Self> {
+            UnixStream::try_clone(self)
+        }
+
+        fn shutdown_write(&self) -> io::Result<()> {
+            self.shutdown(std::net::Shutdown::Write)
+        }
+
+        #[cfg(target_os = "linux")]
+        fn peer_user(&self) -> io::Result<Option<String>> {
+            use std::os::unix::io::AsRawFd;
+
+            let mut cred = libc::ucred { pid: 0, uid: 0, gid: 0 };
+            let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+            let res = unsafe {
+                libc::getsockopt(self.as_raw_fd(), libc::SOL_SOCKET, libc::SO_PEERCRED, &mut cred as *mut _ as *mut libc::c_void, &mut len)
+            };
+            if res != 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            Ok(Some(crate::permissions::user_name(cred.uid)))
+        }
+    }
+
+    pub struct UnixSocketListener(UnixListener);
+
+    impl UnixSocketListener {
+        pub fn bind(path: &str) -> io::Result<Self> {
+            // a stale socket from a previous run would make bind fail
+            match std::fs::remove_file(path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => (),
+            }
+
+            Ok(UnixSocketListener(UnixListener::bind(path)?))
+        }
+    }
+
+    impl super::Listener for UnixSocketListener {
+        type Stream = UnixStream;
+
+        fn accept(&mut self) -> io::Result<UnixStream> {
+            self.0.accept().map(|(stream, _)| stream)
+        }
+    }
+
+    pub struct UnixSocketConnector(PathBuf);
+
+    impl UnixSocketConnector {
+        pub fn new(path: &str) -> Self {
+            UnixSocketConnector(PathBuf::from(path))
+        }
+    }
+
+    impl super::Connector for UnixSocketConnector {
+        type Stream = UnixStream;
+
+        fn connect(&self) -> io::Result<UnixStream> {
+            UnixStream::connect(&self.0)
+        }
+    }
+}
+
+#[cfg(windows)]
+pub mod pipe {
+    use std::ffi::OsStr;
+    use std::fs::OpenOptions;
+    use std::io::{self, Read, Write};
+    use std::os::windows::ffi::OsStrExt;
+    use std::os::windows::fs::OpenOptionsExt;
+    use std::os::windows::io::IntoRawHandle;
+    use std::path::PathBuf;
+    use std::ptr;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use winapi::shared::minwindef::{BOOL, DWORD, FALSE, TRUE};
+    use winapi::shared::sddl::{ConvertStringSecurityDescriptorToSecurityDescriptorW, SDDL_REVISION_1};
+    use winapi::shared::winerror::{ERROR_BROKEN_PIPE, ERROR_IO_PENDING, ERROR_PIPE_BUSY, ERROR_PIPE_CONNECTED, ERROR_PIPE_NOT_CONNECTED};
+    use winapi::um::fileapi::{ReadFile, WriteFile};
+    use winapi::um::handleapi::{CloseHandle, INVALID_HANDLE_VALUE};
+    use winapi::um::ioapiset::GetOverlappedResult;
+    use winapi::um::minwinbase::{OVERLAPPED, SECURITY_ATTRIBUTES};
+    use winapi::um::namedpipeapi::{ConnectNamedPipe, CreateNamedPipeW, DisconnectNamedPipe, ImpersonateNamedPipeClient};
+    use winapi::um::processthreadsapi::{GetCurrentThread, OpenThreadToken};
+    use winapi::um::securitybaseapi::RevertToSelf;
+    use winapi::um::synchapi::CreateEventW;
+    use winapi::um::winbase::{
+        LocalFree, FILE_FLAG_FIRST_PIPE_INSTANCE, FILE_FLAG_OVERLAPPED, PIPE_ACCESS_DUPLEX, PIPE_READMODE_BYTE,
+        PIPE_REJECT_REMOTE_CLIENTS, PIPE_TYPE_MESSAGE, PIPE_UNLIMITED_INSTANCES, PIPE_WAIT,
+    };
+    use winapi::um::winnt::{HANDLE, PSECURITY_DESCRIPTOR, TOKEN_QUERY};
+
+    const PIPE_BUFFER_SIZE: u32 = 64 * 1024;
+
+    struct Handle(HANDLE);
+
+    // a pipe handle opened for overlapped io can be used from any thread, every read and write
+    // brings its own OVERLAPPED
+    unsafe impl Send for Handle {}
+    unsafe impl Sync for Handle {}
+
+    impl Drop for Handle {
+        fn drop(&mut self) {
+            unsafe { CloseHandle(self.0) };
+        }
+    }
+
+    /// Run `op` on `handle` opened with FILE_FLAG_OVERLAPPED and wait for it. Synchronous pipe
+    /// handles serialize reads and writes, a read waiting for docker's output would hold back
+    /// everything the client types
+    fn overlapped_io<F: FnOnce(*mut OVERLAPPED) -> BOOL>(handle: HANDLE, op: F) -> io::Result<usize> {
+        let event = unsafe { CreateEventW(ptr::null_mut(), TRUE, FALSE, ptr::null()) };
+        if event.is_null() {
+            return Err(io::Error::last_os_error());
+        }
+        let event = Handle(event);
+
+        let mut overlapped: OVERLAPPED = unsafe { std::mem::zeroed() };
+        overlapped.hEvent = event.0;
+
+        if op(&mut overlapped) == 0 {
+            let e = io::Error::last_os_error();
+            if e.raw_os_error() != Some(ERROR_IO_PENDING as i32) {
+                return Err(e);
+            }
+        }
+
+        // waited for even when it finished right away, `overlapped` must outlive the operation
+        let mut transferred: DWORD = 0;
+        if unsafe { GetOverlappedResult(handle, &mut overlapped, &mut transferred, TRUE) } == 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(transferred as usize)
+    }
+
+    /// A connected pipe. Clones share the handle, so one can read while another writes
+    #[derive(Clone)]
+    pub struct PipeStream(Arc<Handle>);
+
+    impl PipeStream {
+        fn from_raw(handle: HANDLE) -> Self {
+            PipeStream(Arc::new(Handle(handle)))
+        }
+
+        fn handle(&self) -> HANDLE {
+            self.0 .0
+        }
+    }
+
+    impl Read for PipeStream {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let len = buf.len().min(DWORD::MAX as usize) as DWORD;
+            let res = overlapped_io(self.handle(), |overlapped| unsafe {
+                ReadFile(self.handle(), buf.as_mut_ptr() as _, len, ptr::null_mut(), overlapped)
+            });
+
+            match res {
+                // the other end closed the pipe
+                Err(e) if [ERROR_BROKEN_PIPE, ERROR_PIPE_NOT_CONNECTED].iter().any(|&code| e.raw_os_error() == Some(code as i32)) => Ok(0),
+                res => res,
+            }
+        }
+    }
+
+    impl Write for PipeStream {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            // an empty write would tell the other end the stream is over, see shutdown_write
+            if buf.is_empty() {
+                return Ok(0);
+            }
+
+            let len = buf.len().min(DWORD::MAX as usize) as DWORD;
+            overlapped_io(self.handle(), |overlapped| unsafe {
+                WriteFile(self.handle(), buf.as_ptr() as _, len, ptr::null_mut(), overlapped)
+            })
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl super::Stream for PipeStream {
+        fn try_clone(&self) -> io::Result<Self> {
+            Ok(self.clone())
+        }
+
+        /// Pipes can't be half closed. On message type pipes, like docker's and the proxy's, an
+        /// empty message is read as the end of the stream instead
+        fn shutdown_write(&self) -> io::Result<()> {
+            overlapped_io(self.handle(), |overlapped| unsafe {
+                WriteFile(self.handle(), ptr::null(), 0, ptr::null_mut(), overlapped)
+            })
+            .map(|_| ())
+        }
+
+        /// The client of a pipe this process serves, by impersonating it for a moment
+        fn peer_user(&self) -> io::Result<Option<String>> {
+            unsafe {
+                if ImpersonateNamedPipeClient(self.handle()) == 0 {
+                    return Err(io::Error::last_os_error());
+                }
+
+                let mut token = ptr::null_mut();
+                let opened = OpenThreadToken(GetCurrentThread(), TOKEN_QUERY, TRUE, &mut token);
+                let open_error = io::Error::last_os_error();
+
+                // carrying on as the client would be far worse than stopping
+                if RevertToSelf() == 0 {
+                    std::process::abort();
+                }
+
+                if opened == 0 {
+                    return Err(open_error);
+                }
+
+                let user = crate::permissions::token_user(token);
+                CloseHandle(token);
+                user.map(Some)
+            }
+        }
+    }
+
+    pub struct PipeListener {
+        name: Vec<u16>,
+        // the next instance is created before handing out the connected one, so there is always
+        // an instance clients can connect to
+        next: HANDLE,
+        /// who may connect, null for the default: everyone may read, only administrators, SYSTEM
+        /// and the creator may write
+        security: PSECURITY_DESCRIPTOR,
+    }
+
+    fn create_instance(name: &[u16], security: PSECURITY_DESCRIPTOR, first: bool) -> io::Result<HANDLE> {
+        let mut attributes = SECURITY_ATTRIBUTES {
+            nLength: std::mem::size_of::<SECURITY_ATTRIBUTES>() as DWORD,
+            lpSecurityDescriptor: security,
+            bInheritHandle: FALSE,
+        };
+        let attributes = if security.is_null() { ptr::null_mut() } else { &mut attributes as *mut _ };
+
+        // the first instance fails if someone else already serves a pipe by that name, clients
+        // would be talking to them otherwise
+        let first = if first { FILE_FLAG_FIRST_PIPE_INSTANCE } else { 0 };
+
+        let handle = unsafe {
+            CreateNamedPipeW(
+                name.as_ptr(),
+                PIPE_ACCESS_DUPLEX | FILE_FLAG_OVERLAPPED | first,
+                // message type like docker's pipe, so the end of a hijacked stream can be sent as an
+                // empty message. Read as bytes all the same
+                PIPE_TYPE_MESSAGE | PIPE_READMODE_BYTE | PIPE_WAIT | PIPE_REJECT_REMOTE_CLIENTS,
+                PIPE_UNLIMITED_INSTANCES,
+                PIPE_BUFFER_SIZE,
+                PIPE_BUFFER_SIZE,
+                0,
+                attributes,
+            )
+        };
+
+        if handle == INVALID_HANDLE_VALUE {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(handle)
+        }
+    }
+
+    impl PipeListener {
+        pub fn bind(name: &str) -> io::Result<Self> {
+            PipeListener::bind_with(name, ptr::null_mut())
+        }
+
+        /// Bind with the security descriptor `sddl`, e.g. `D:P(A;;GA;;;SY)` for SYSTEM only
+        pub fn bind_with_sddl(name: &str, sddl: &str) -> io::Result<Self> {
+            let sddl: Vec<u16> = OsStr::new(sddl).encode_wide().chain(Some(0)).collect();
+            let mut security: PSECURITY_DESCRIPTOR = ptr::null_mut();
+
+            let res = unsafe {
+                ConvertStringSecurityDescriptorToSecurityDescriptorW(sddl.as_ptr(), SDDL_REVISION_1 as DWORD, &mut security, ptr::null_mut())
+            };
+            if res == 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            PipeListener::bind_with(name, security).inspect_err(|_| {
+                unsafe { LocalFree(security) };
+            })
+        }
+
+        fn bind_with(name: &str, security: PSECURITY_DESCRIPTOR) -> io::Result<Self> {
+            let name: Vec<u16> = OsStr::new(name).encode_wide().chain(Some(0)).collect();
+            let next = create_instance(&name, security, true)?;
+
+            Ok(PipeListener { name, next, security })
+        }
+    }
+
+    impl super::Listener for PipeListener {
+        type Stream = PipeStream;
+
+        fn accept(&mut self) -> io::Result<PipeStream> {
+            let pipe = self.next;
+            match overlapped_io(pipe, |overlapped| unsafe { ConnectNamedPipe(pipe, overlapped) }) {
+                Ok(_) => {}
+                // client connected between CreateNamedPipe and ConnectNamedPipe
+                Err(e) if e.raw_os_error() == Some(ERROR_PIPE_CONNECTED as i32) => {}
+                Err(e) => {
+                    unsafe { DisconnectNamedPipe(pipe) };
+                    return Err(e);
+                }
+            }
+
+            let next = create_instance(&self.name, self.security, false)?;
+            let connected = std::mem::replace(&mut self.next, next);
+
+            Ok(PipeStream::from_raw(connected))
+        }
+    }
+
+    impl Drop for PipeListener {
+        fn drop(&mut self) {
+            unsafe {
+                CloseHandle(self.next);
+                if !self.security.is_null() {
+                    LocalFree(self.security);
+                }
+            }
+        }
+    }
+
+    pub struct PipeConnector(PathBuf);
+
+    impl PipeConnector {
+        pub fn new(name: &str) -> Self {
+            PipeConnector(PathBuf::from(name))
+        }
+    }
+
+    impl super::Connector for PipeConnector {
+        type Stream = PipeStream;
+
+        fn connect(&self) -> io::Result<PipeStream> {
+            let mut attempts = 0;
+
+            loop {
+                match OpenOptions::new().read(true).write(true).custom_flags(FILE_FLAG_OVERLAPPED).open(&self.0) {
+                    // every pipe instance is in use, wait for one to free up
+                    Err(e) if e.raw_os_error() == Some(ERROR_PIPE_BUSY as i32) && attempts < 20 => {
+                        attempts += 1;
+                        std::thread::sleep(Duration::from_millis(50));
+                    }
+
+                    res => return res.map(|pipe| PipeStream::from_raw(pipe.into_raw_handle() as HANDLE)),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::io::{self, BufRead, BufReader, Read, Write};
+    use std::os::unix::net::UnixStream;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    use serde_json::Value;
+
+    use super::{read_body, Connector, Head, Proxy};
+    use crate::policy::{IsolationPolicy, PolicyMode};
+
+    /// Request line and decoded body of a request that reached docker
+    type Seen = Arc<Mutex<Vec<(String, Vec<u8>)>>>;
+
+    /// dockerd answering every request with `{}`. Hijacked streams get a prompt before docker
+    /// reads anything, then are echoed back
+    struct FakeDocker(Seen);
+
+    impl Connector for FakeDocker {
+        type Stream = UnixStream;
+
+        fn connect(&self) -> io::Result<UnixStream> {
+            let (proxy, docker) = UnixStream::pair()?;
+            let seen = self.0.clone();
+            thread::spawn(move || fake_docker(docker, seen));
+            Ok(proxy)
+        }
+    }
+
+    fn fake_docker(stream: UnixStream, seen: Seen) -> io::Result<()> {
+        let mut writer = stream.try_clone()?;
+        let mut reader = BufReader::new(stream);
+
+        while let Some(request) = Head::read(&mut reader)? {
+            let body = read_body(&request.body_length(false), &mut reader)?;
+            seen.lock().unwrap().push((request.start_line.clone(), body));
+
+            if request.header("Upgrade").is_some() {
+                writer.write_all(b"HTTP/1.1 101 UPGRADED\r\nConnection: Upgrade\r\nUpgrade: tcp\r\n\r\n$ ")?;
+                io::copy(&mut reader, &mut writer)?;
+                return writer.shutdown(std::net::Shutdown::Write);
+            }
+
+            writer.write_all(b"HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: 2\r\n\r\n{}")?;
+        }
+
+        Ok(())
+    }
+
+    fn proxy(policy: IsolationPolicy) -> (Arc<Proxy<FakeDocker>>, Seen) {
+        let seen = Seen::default();
+        let proxy = Proxy { connector: FakeDocker(seen.clone()), policy, host: None };
+        (Arc::new(proxy), seen)
+    }
+
+    /// A client connected to the proxy
+    fn connect(proxy: &Arc<Proxy<FakeDocker>>) -> (UnixStream, BufReader<UnixStream>) {
+        let (client, server) = UnixStream::pair().unwrap();
+        let proxy = proxy.clone();
+        thread::spawn(move || proxy.handle_connection(server));
+
+        let reader = BufReader::new(client.try_clone().unwrap());
+        (client, reader)
+    }
+
+    fn response(reader: &mut BufReader<UnixStream>) -> (u16, Value) {
+        let head = Head::read(reader).unwrap().unwrap();
+        let body = read_body(&head.body_length(true), reader).unwrap();
+        (head.status().unwrap(), serde_json::from_slice(&body).unwrap())
+    }
+
+    fn post(client: &mut UnixStream, target: &str, body: &str) {
+        write!(client, "POST {} HTTP/1.1\r\nHost: docker\r\nContent-Length: {}\r\n\r\n{}", target, body.len(), body).unwrap();
+    }
+
+    #[test]
+    fn create_isolation_is_rewritten() {
+        let (proxy, seen) = proxy(IsolationPolicy::default());
+        let (mut client, mut reader) = connect(&proxy);
+
+        post(&mut client, "/v1.41/containers/create?name=web", r#"{"Image":"nginx","HostConfig":{"Isolation":"hyperv","Memory":0}}"#);
+        assert_eq!(response(&mut reader).0, 200);
+
+        let seen = seen.lock().unwrap();
+        assert_eq!(seen[0].0, "POST /v1.41/containers/create?name=web HTTP/1.1");
+        let body: Value = serde_json::from_slice(&seen[0].1).unwrap();
+        assert_eq!(body["HostConfig"]["Isolation"], "process");
+        assert_eq!(body["HostConfig"]["Memory"], 0);
+    }
+
+    #[test]
+    fn create_is_rejected() {
+        let policy = IsolationPolicy { mode: PolicyMode::Reject, ..Default::default() };
+        let (proxy, seen) = proxy(policy);
+        let (mut client, mut reader) = connect(&proxy);
+
+        post(&mut client, "/containers/create", r#"{"Image":"nginx","HostConfig":{"Isolation":"hyperv"}}"#);
+        let (status, body) = response(&mut reader);
+        assert_eq!(status, 403);
+        assert!(body["message"].as_str().unwrap().contains("hyperv isolation is not allowed for image nginx"));
+
+        // the connection stays usable
+        post(&mut client, "/containers/create", r#"{"Image":"nginx"}"#);
+        assert_eq!(response(&mut reader).0, 200);
+        assert_eq!(seen.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn build_isolation_is_rewritten() {
+        let (proxy, seen) = proxy(IsolationPolicy::default());
+        let (mut client, mut reader) = connect(&proxy);
+
+        post(&mut client, "/v1.41/build?t=app%3A1&isolation=hyperv&q=1", "context");
+        assert_eq!(response(&mut reader).0, 200);
+
+        let seen = seen.lock().unwrap();
+        assert_eq!(seen[0].0, "POST /v1.41/build?t=app%3A1&q=1&isolation=process HTTP/1.1");
+        assert_eq!(seen[0].1, b"context");
+    }
+
+    #[test]
+    fn chunked_bodies() {
+        let (proxy, seen) = proxy(IsolationPolicy::default());
+        let (mut client, mut reader) = connect(&proxy);
+
+        let create = r#"{"Image":"nginx","HostConfig":{"Isolation":"hyperv"}}"#;
+        let (first, second) = create.split_at(20);
+        write!(
+            client,
+            "POST /containers/create HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n{:x}\r\n{}\r\n{:x}\r\n{}\r\n0\r\n\r\n",
+            first.len(),
+            first,
+            second.len(),
+            second
+        )
+        .unwrap();
+        assert_eq!(response(&mut reader).0, 200);
+
+        // passed through as is, docker decodes it
+        client
+            .write_all(b"POST /build?isolation=process HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n3;ext=1\r\nabc\r\n2\r\nde\r\n0\r\nX-Trailer: 1\r\n\r\n")
+            .unwrap();
+        assert_eq!(response(&mut reader).0, 200);
+
+        let seen = seen.lock().unwrap();
+        let body: Value = serde_json::from_slice(&seen[0].1).unwrap();
+        assert_eq!(body["HostConfig"]["Isolation"], "process");
+        assert_eq!(seen[1].1, b"abcde");
+    }
+
+    #[test]
+    fn attach_is_tunneled() {
+        let (proxy, _) = proxy(IsolationPolicy::default());
+        let (mut client, mut reader) = connect(&proxy);
+
+        client
+            .write_all(b"POST /containers/web/attach?stream=1&stdin=1 HTTP/1.1\r\nConnection: Upgrade\r\nUpgrade: tcp\r\n\r\n")
+            .unwrap();
+        let head = Head::read(&mut reader).unwrap().unwrap();
+        assert_eq!(head.status().unwrap(), 101);
+
+        // docker's output comes through while the client has nothing to send
+        let mut prompt = [0; 2];
+        reader.read_exact(&mut prompt).unwrap();
+        assert_eq!(&prompt, b"$ ");
+
+        client.write_all(b"ls\n").unwrap();
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        assert_eq!(line, "ls\n");
+
+        // hanging up ends the tunnel both ways
+        client.shutdown(std::net::Shutdown::Write).unwrap();
+        assert_eq!(reader.read(&mut [0; 16]).unwrap(), 0);
+    }
+
+    #[test]
+    fn run_is_tunneled_end_to_end() {
+        let (proxy, seen) = proxy(IsolationPolicy::default());
+        let (mut client, mut reader) = connect(&proxy);
+
+        // what `docker run -i` sends: create, attach, start, then stdin until it's closed
+        post(&mut client, "/v1.41/containers/create", r#"{"Image":"nanoserver","OpenStdin":true,"HostConfig":{"Isolation":"hyperv"}}"#);
+        assert_eq!(response(&mut reader).0, 200);
+
+        let (mut attach, mut attach_reader) = connect(&proxy);
+        attach
+            .write_all(b"POST /v1.41/containers/web/attach?stderr=1&stdin=1&stdout=1&stream=1 HTTP/1.1\r\nConnection: Upgrade\r\nUpgrade: tcp\r\n\r\n")
+            .unwrap();
+        assert_eq!(Head::read(&mut attach_reader).unwrap().unwrap().status().unwrap(), 101);
+
+        post(&mut client, "/v1.41/containers/web/start", "");
+        assert_eq!(response(&mut reader).0, 200);
+
+        let input = "dir\r\necho done\r\nexit\r\n";
+        let writer = thread::spawn(move || {
+            attach.write_all(input.as_bytes()).unwrap();
+            attach.shutdown(std::net::Shutdown::Write).unwrap();
+        });
+
+        let mut output = String::new();
+        attach_reader.read_to_string(&mut output).unwrap();
+        writer.join().unwrap();
+        assert_eq!(output, format!("$ {}", input));
+
+        let seen = seen.lock().unwrap();
+        let requests: Vec<&str> = seen.iter().map(|(line, _)| line.as_str()).collect();
+        assert_eq!(
+            requests,
+            [
+                "POST /v1.41/containers/create HTTP/1.1",
+                "POST /v1.41/containers/web/attach?stderr=1&stdin=1&stdout=1&stream=1 HTTP/1.1",
+                "POST /v1.41/containers/web/start HTTP/1.1",
+            ]
+        );
+        let create: Value = serde_json::from_slice(&seen[0].1).unwrap();
+        assert_eq!(create["HostConfig"]["Isolation"], "process");
+    }
+
+    #[test]
+    fn image_references_stay_in_the_path() {
+        let (proxy, seen) = proxy(IsolationPolicy::default());
+
+        assert!(proxy.inspect_image("mcr.microsoft.com/windows/nanoserver:ltsc2022").is_ok());
+        for image in ["nginx?all=1", "nginx#", "nginx%2F..", "../../containers/web", "a//b", ""] {
+            assert_eq!(proxy.inspect_image(image).unwrap_err().kind(), io::ErrorKind::InvalidInput, "{}", image);
+        }
+
+        let seen = seen.lock().unwrap();
+        assert_eq!(seen.len(), 1);
+        assert_eq!(seen[0].0, "GET /images/mcr.microsoft.com/windows/nanoserver:ltsc2022/json HTTP/1.1");
+    }
+}
@@ -0,0 +1,191 @@
+// Maintenance windows
+// Written as `[days] HH:MM-HH:MM` in local time, e.g. "Sat,Sun 02:00-06:00", "Mon-Fri 22:00-02:00"
+// or "01:00-03:00" for every day. A window ending before it starts runs past midnight, one ending
+// when it starts is refused rather than guessed to be empty or the whole day. The whole day is
+// 00:00-24:00, or `*` for the times, and `daily` or `*` alone is every day all day.
+
+use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
+
+use chrono::{Datelike, NaiveDateTime, NaiveTime, Weekday};
+use serde::Deserialize;
+
+const WHOLE_DAY: &str = "00:00-24:00";
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct MaintenanceWindow {
+    /// days the window starts on, indexed from monday
+    days: [bool; 7],
+    start: NaiveTime,
+    /// None for 24:00, the end of the day
+    end: Option<NaiveTime>,
+    text: String,
+}
+
+impl MaintenanceWindow {
+    pub fn contains(&self, now: NaiveDateTime) -> bool {
+        let time = now.time();
+        let today = self.days[now.weekday().num_days_from_monday() as usize];
+        let yesterday = self.days[now.weekday().pred().num_days_from_monday() as usize];
+
+        match self.end {
+            None => today && time >= self.start,
+            Some(end) if self.start < end => today && time >= self.start && time < end,
+            Some(end) => (today && time >= self.start) || (yesterday && time < end),
+        }
+    }
+}
+
+impl fmt::Display for MaintenanceWindow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.text)
+    }
+}
+
+impl FromStr for MaintenanceWindow {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        let s = s.trim();
+        let (days, times) = match s.rsplit_once(' ') {
+            Some((days, times)) => (parse_days(days.trim())?, times),
+            None if s == "*" || s.eq_ignore_ascii_case("daily") => ([true; 7], WHOLE_DAY),
+            None => ([true; 7], s),
+        };
+        let times = if times == "*" { WHOLE_DAY } else { times };
+
+        let (start, end) = times
+            .split_once('-')
+            .ok_or_else(|| format!("invalid maintenance window \"{}\", expected e.g. \"Sat 02:00-04:00\"", s))?;
+
+        let time = |t: &str| {
+            NaiveTime::parse_from_str(t, "%H:%M").map_err(|_| format!("invalid time \"{}\" in maintenance window \"{}\"", t, s))
+        };
+
+        let (start, end) = (time(start)?, if end == "24:00" { None } else { Some(time(end)?) });
+        if Some(start) == end {
+            return Err(format!("maintenance window \"{}\" ends when it starts, use {} for the whole day", s, WHOLE_DAY));
+        }
+
+        Ok(MaintenanceWindow { days, start, end, text: s.to_string() })
+    }
+}
+
+impl TryFrom<String> for MaintenanceWindow {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, String> {
+        s.parse()
+    }
+}
+
+/// `daily`, or a comma separated list of days and day ranges like `Mon-Fri`
+fn parse_days(s: &str) -> Result<[bool; 7], String> {
+    if s.eq_ignore_ascii_case("daily") {
+        return Ok([true; 7]);
+    }
+
+    let day = |d: &str| Weekday::from_str(d.trim()).map_err(|_| format!("invalid day \"{}\"", d));
+    let mut days = [false; 7];
+
+    for part in s.split(',') {
+        match part.split_once('-') {
+            Some((from, to)) => {
+                let (mut current, to) = (day(from)?, day(to)?);
+
+                // ranges may wrap around the week, e.g. Fri-Mon
+                loop {
+                    days[current.num_days_from_monday() as usize] = true;
+                    if current == to {
+                        break;
+                    }
+                    current = current.succ();
+                }
+            }
+
+            None => days[day(part)?.num_days_from_monday() as usize] = true,
+        }
+    }
+
+    Ok(days)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use chrono::NaiveDate;
+
+    use super::MaintenanceWindow;
+
+    fn window(s: &str) -> MaintenanceWindow {
+        s.parse().unwrap()
+    }
+
+    /// 2024-01-01 was a monday
+    fn at(day: u32, time: &str) -> chrono::NaiveDateTime {
+        NaiveDate::from_ymd_opt(2024, 1, day).unwrap().and_time(time.parse().unwrap())
+    }
+
+    #[test]
+    fn same_day_window() {
+        let window = window("Sat,Sun 02:00-06:00");
+
+        assert!(window.contains(at(6, "02:00:00")));
+        assert!(window.contains(at(7, "05:59:59")));
+        assert!(!window.contains(at(6, "06:00:00")));
+        assert!(!window.contains(at(5, "03:00:00")));
+    }
+
+    #[test]
+    fn overnight_window_belongs_to_the_day_it_starts() {
+        let window = window("Fri 22:00-02:00");
+
+        assert!(window.contains(at(5, "23:00:00")));
+        // saturday morning, the window started on friday
+        assert!(window.contains(at(6, "01:59:00")));
+        assert!(!window.contains(at(6, "02:00:00")));
+        assert!(!window.contains(at(6, "23:00:00")));
+        // friday morning belongs to a thursday window
+        assert!(!window.contains(at(5, "01:00:00")));
+    }
+
+    #[test]
+    fn day_ranges_wrap_around_the_week() {
+        let window = window("Fri-Mon 01:00-03:00");
+
+        for day in [5, 6, 7, 8] {
+            assert!(window.contains(at(day, "02:00:00")), "{}", day);
+        }
+        for day in [2, 3, 4] {
+            assert!(!window.contains(at(day, "02:00:00")), "{}", day);
+        }
+    }
+
+    #[test]
+    fn whole_day() {
+        for text in ["00:00-24:00", "daily", "*", "daily 00:00-24:00"] {
+            let window = window(text);
+            assert!(window.contains(at(1, "00:00:00")), "{}", text);
+            assert!(window.contains(at(3, "23:59:59")), "{}", text);
+        }
+
+        let weekend = window("Sat,Sun *");
+        assert!(weekend.contains(at(7, "23:59:59")));
+        assert!(!weekend.contains(at(8, "00:00:00")));
+
+        let evening = window("Mon 20:00-24:00");
+        assert!(evening.contains(at(1, "23:59:59")));
+        assert!(!evening.contains(at(2, "00:00:00")));
+    }
+
+    #[test]
+    fn invalid_windows() {
+        let err = "01:00-01:00".parse::<MaintenanceWindow>().unwrap_err();
+        assert!(err.contains("use 00:00-24:00 for the whole day"), "{}", err);
+
+        for text in ["Sat", "Sat 25:00-26:00", "Someday 01:00-02:00", "01:00", "Sat 01:00-24:30"] {
+            assert!(text.parse::<MaintenanceWindow>().is_err(), "{}", text);
+        }
+    }
+}
@@ -0,0 +1,267 @@
+// Windows service control manager backend
+
+use std::ffi::OsString;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use windows_service::service::{
+    self, Service, ServiceAccess, ServiceAction, ServiceActionType, ServiceDependency, ServiceErrorControl, ServiceFailureActions,
+    ServiceFailureResetPeriod, ServiceInfo, ServiceStartType, ServiceType,
+};
+use windows_service::service_manager::{ServiceManager, ServiceManagerAccess};
+
+use super::backend::{BackendError, BackendResult, NewService, ServiceBackend, ServiceConfig, ServiceState, Target};
+use super::patch;
+
+// ERROR_SERVICE_DOES_NOT_EXIST
+const ERROR_SERVICE_DOES_NOT_EXIST: i32 = 1060;
+/// waits before restarting a failed service, the last one repeats
+const RESTART_DELAYS: [Duration; 3] = [Duration::from_secs(10), Duration::from_secs(30), Duration::from_secs(60)];
+/// failures older than this don't count towards the next delay
+const FAILURE_RESET_PERIOD: Duration = Duration::from_secs(24 * 60 * 60);
+/// load order group for services which start early. The SCM starts the auto-start services of the
+/// groups in ServiceGroupOrder first and ungrouped ones like docker after them
+const EARLY_START_GROUP: &str = "Extended Base";
+
+pub struct ScmBackend {
+    manager: ServiceManager,
+    target: Target,
+}
+
+impl ScmBackend {
+    pub fn local() -> BackendResult<Self> {
+        ScmBackend::connect(&Target::Local)
+    }
+
+    /// Connect to the SCM of `target`, remote computers need the current user to be an
+    /// administrator there
+    pub fn connect(target: &Target) -> BackendResult<Self> {
+        let manager = ScmBackend::manager(target, ServiceManagerAccess::CONNECT)?;
+        Ok(ScmBackend { manager, target: target.clone() })
+    }
+
+    fn manager(target: &Target, access: ServiceManagerAccess) -> windows_service::Result<ServiceManager> {
+        match target.computer() {
+            None => ServiceManager::local_computer(None::<&str>, access),
+            Some(computer) => ServiceManager::remote_computer(computer, None::<&str>, access),
+        }
+    }
+
+    fn open(&self, name: &str, access: ServiceAccess) -> BackendResult<Option<Service>> {
+        match self.manager.open_service(name, access) {
+            Ok(service) => Ok(Some(service)),
+            Err(windows_service::Error::Winapi(e)) if e.raw_os_error() == Some(ERROR_SERVICE_DOES_NOT_EXIST) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn open_existing(&self, name: &str, access: ServiceAccess) -> BackendResult<Service> {
+        self.open(name, access)?.ok_or_else(|| BackendError {
+            code: Some(ERROR_SERVICE_DOES_NOT_EXIST),
+            message: format!("service {} does not exist", name),
+            refused: false,
+        })
+    }
+}
+
+impl From<windows_service::Error> for BackendError {
+    fn from(e: windows_service::Error) -> Self {
+        let code = match &e {
+            windows_service::Error::Winapi(io) => io.raw_os_error(),
+            _ => None,
+        };
+
+        BackendError { code, message: e.to_string(), refused: false }
+    }
+}
+
+impl From<service::ServiceState> for ServiceState {
+    fn from(state: service::ServiceState) -> Self {
+        match state {
+            service::ServiceState::Stopped => ServiceState::Stopped,
+            service::ServiceState::StartPending => ServiceState::StartPending,
+            service::ServiceState::StopPending => ServiceState::StopPending,
+            service::ServiceState::Running => ServiceState::Running,
+            service::ServiceState::ContinuePending => ServiceState::ContinuePending,
+            service::ServiceState::PausePending => ServiceState::PausePending,
+            service::ServiceState::Paused => ServiceState::Paused,
+        }
+    }
+}
+
+impl ServiceBackend for ScmBackend {
+    fn target(&self) -> &Target {
+        &self.target
+    }
+
+    fn query_state(&self, name: &str) -> BackendResult<Option<ServiceState>> {
+        match self.open(name, ServiceAccess::QUERY_STATUS)? {
+            Some(service) => Ok(Some(service.query_status()?.current_state.into())),
+            None => Ok(None),
+        }
+    }
+
+    fn query_process_id(&self, name: &str) -> BackendResult<Option<u32>> {
+        match self.open(name, ServiceAccess::QUERY_STATUS)? {
+            Some(service) => Ok(service.query_status()?.process_id.filter(|&pid| pid != 0)),
+            None => Ok(None),
+        }
+    }
+
+    fn query_config(&self, name: &str) -> BackendResult<Option<ServiceConfig>> {
+        let service = match self.open(name, ServiceAccess::QUERY_CONFIG)? {
+            Some(service) => service,
+            None => return Ok(None),
+        };
+
+        let config = service.query_config()?;
+
+        Ok(Some(ServiceConfig {
+            display_name: config.display_name.to_string_lossy().into_owned(),
+            command_line: config.executable_path.to_string_lossy().into_owned(),
+            dependencies: config
+                .dependencies
+                .iter()
+                .map(|d| d.to_system_identifier().to_string_lossy().into_owned())
+                .collect(),
+            account_name: config.account_name.map(|a| a.to_string_lossy().into_owned()),
+        }))
+    }
+
+    fn change_config(&self, name: &str, config: &ServiceConfig) -> BackendResult<()> {
+        let service = self.open_existing(name, ServiceAccess::QUERY_CONFIG | ServiceAccess::CHANGE_CONFIG)?;
+        let current = service.query_config()?;
+
+        let args = patch::split_command_line(&config.command_line);
+        if args.is_empty() {
+            return Err(BackendError::new(format!("empty command line for service {}", name)));
+        }
+
+        let new_config = ServiceInfo {
+            name: OsString::from(name),
+            display_name: OsString::from(&config.display_name),
+            service_type: current.service_type,
+            start_type: current.start_type,
+            error_control: current.error_control,
+            executable_path: PathBuf::from(&args[0]),
+            launch_arguments: args[1..].iter().map(OsString::from).collect(),
+            dependencies: config.dependencies.iter().map(ServiceDependency::from_system_identifier).collect(),
+            // None leaves the account unchanged
+            account_name: None,
+            account_password: None,
+        };
+
+        service.change_config(&new_config)?;
+        Ok(())
+    }
+
+    fn set_description(&self, name: &str, description: &str) -> BackendResult<()> {
+        let service = self.open_existing(name, ServiceAccess::CHANGE_CONFIG)?;
+        service.set_description(description)?;
+        Ok(())
+    }
+
+    fn start(&self, name: &str) -> BackendResult<()> {
+        let service = self.open_existing(name, ServiceAccess::START)?;
+        service.start(&[] as &[&str])?;
+        Ok(())
+    }
+
+    fn stop(&self, name: &str) -> BackendResult<()> {
+        let service = self.open_existing(name, ServiceAccess::STOP)?;
+        service.stop()?;
+        Ok(())
+    }
+
+    fn create(&self, service: &NewService) -> BackendResult<()> {
+        // only this needs CREATE_SERVICE, everything else gets by with CONNECT
+        let manager = ScmBackend::manager(&self.target, ServiceManagerAccess::CONNECT | ServiceManagerAccess::CREATE_SERVICE)?;
+
+        let info = ServiceInfo {
+            name: OsString::from(&service.name),
+            display_name: OsString::from(&service.display_name),
+            service_type: ServiceType::OWN_PROCESS,
+            start_type: ServiceStartType::AutoStart,
+            error_control: ServiceErrorControl::Normal,
+            executable_path: service.executable.clone(),
+            launch_arguments: service.arguments.iter().map(OsString::from).collect(),
+            dependencies: vec![],
+            account_name: None, // run as System
+            account_password: None,
+        };
+
+        // restart actions need START
+        let created = manager.create_service(&info, ServiceAccess::CHANGE_CONFIG | ServiceAccess::START)?;
+        created.set_description(&service.description)?;
+
+        // restarted when it crashes or stops with an exit code, like Restart=on-failure on Linux
+        created.update_failure_actions(ServiceFailureActions {
+            reset_period: ServiceFailureResetPeriod::After(FAILURE_RESET_PERIOD),
+            reboot_msg: None,
+            command: None,
+            actions: Some(RESTART_DELAYS.iter().map(|&delay| ServiceAction { action_type: ServiceActionType::Restart, delay }).collect()),
+        })?;
+        created.set_failure_actions_on_non_crash_failures(true)?;
+
+        if !service.start_before.is_empty() {
+            set_load_order_group(&self.target, &service.name, EARLY_START_GROUP)?;
+        }
+
+        Ok(())
+    }
+
+    fn delete(&self, name: &str) -> BackendResult<()> {
+        let service = self.open_existing(name, ServiceAccess::DELETE)?;
+        service.delete()?;
+        Ok(())
+    }
+}
+
+/// windows-service has no way to set the load order group, so it's set through the api directly
+fn set_load_order_group(target: &Target, name: &str, group: &str) -> BackendResult<()> {
+    use std::ffi::OsStr;
+    use std::io;
+    use std::os::windows::ffi::OsStrExt;
+    use std::ptr;
+
+    use winapi::um::winsvc::{
+        ChangeServiceConfigW, CloseServiceHandle, OpenSCManagerW, OpenServiceW, SC_MANAGER_CONNECT, SERVICE_CHANGE_CONFIG,
+        SERVICE_NO_CHANGE,
+    };
+
+    let wide = |s: &str| -> Vec<u16> { OsStr::new(s).encode_wide().chain(Some(0)).collect() };
+    let computer = target.computer().map(wide);
+    let (name, group) = (wide(name), wide(group));
+
+    unsafe {
+        let manager = OpenSCManagerW(computer.as_ref().map_or(ptr::null(), |c| c.as_ptr()), ptr::null(), SC_MANAGER_CONNECT);
+        if manager.is_null() {
+            return Err(io::Error::last_os_error().into());
+        }
+
+        let service = OpenServiceW(manager, name.as_ptr(), SERVICE_CHANGE_CONFIG);
+        let res = if service.is_null() {
+            Err(io::Error::last_os_error())
+        } else {
+            let changed = ChangeServiceConfigW(
+                service,
+                SERVICE_NO_CHANGE,
+                SERVICE_NO_CHANGE,
+                SERVICE_NO_CHANGE,
+                ptr::null(),
+                group.as_ptr(),
+                ptr::null_mut(),
+                ptr::null(),
+                ptr::null(),
+                ptr::null(),
+                ptr::null(),
+            );
+            let res = if changed == 0 { Err(io::Error::last_os_error()) } else { Ok(()) };
+            CloseServiceHandle(service);
+            res
+        };
+
+        CloseServiceHandle(manager);
+        res.map_err(Into::into)
+    }
+}
@@ -0,0 +1,5 @@
+pub const SERVICE_NAME: &str = "docker_process_isolation_patcher";
+pub const DOCKER_SERVICE_NAME: &str = "docker";
+pub const DOCKER_DESKTOP_SERVICE_NAME: &str = "com.docker.service";
+/// name of the binary, used for directories outside the exe directory
+pub const APP_NAME: &str = "docker-process-isolation-patcher";
@@ -0,0 +1,256 @@
+// Persistent patcher state, kept in `state.json` beside the exe
+// Commands run against a remote computer keep its state in `state-<computer>.json` instead, the
+// state of the patcher service on that computer isn't reachable from here. The command line to
+// restore comes from here, so a state file non-administrators could change isn't loaded.
+
+use std::fmt;
+use std::fs::OpenOptions;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::Level;
+use serde::{Deserialize, Serialize};
+
+use super::backend::Target;
+use super::event;
+use super::event::{Event, EventId};
+use super::permissions::{self, PermissionError};
+
+pub const STATE_FILE_NAME: &str = "state.json";
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct State {
+    /// docker command line as it was right before the last patch
+    pub original_command_line: Option<String>,
+    pub original_display_name: Option<String>,
+    /// command line the patcher last wrote
+    pub patched_command_line: Option<String>,
+    /// unix time of the last patch
+    pub patched_at: Option<u64>,
+    /// unpatched command line found while docker was running, until it's corrected
+    pub drift: Option<Drift>,
+    /// the patch was written but docker still runs the command line from before it
+    pub pending_restart: Option<PendingRestart>,
+    /// containers running before the last graceful restart which didn't come back after it
+    pub containers_not_restarted: Vec<NotRestarted>,
+    /// why the patcher service last stopped, until it's stopped without an error
+    pub service_failure: Option<ServiceFailure>,
+    pub metrics: Metrics,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Drift {
+    pub command_line: String,
+    pub detected_at: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PendingRestart {
+    /// unix time the patch was written
+    pub since: u64,
+    /// the watcher restarts docker once the restart policy allows it. Otherwise the patch waits
+    /// for docker to be restarted by someone else
+    pub deferred: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NotRestarted {
+    pub container: String,
+    pub reason: String,
+}
+
+impl fmt::Display for NotRestarted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({})", self.container, self.reason)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ServiceFailure {
+    /// service specific exit code, see exit
+    pub exit_code: u32,
+    pub message: String,
+    pub at: u64,
+}
+
+/// Counters over the lifetime of the state file
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Metrics {
+    pub patches_applied: u64,
+    pub patch_failures: u64,
+    pub drift_detected: u64,
+    pub drift_corrected: u64,
+    pub containers_restarted: u64,
+    pub containers_not_restarted: u64,
+    pub integrity_failures: u64,
+    /// service manager errors the service waited out and tried again
+    pub transient_errors: u64,
+    /// service manager errors which stopped the service
+    pub fatal_errors: u64,
+}
+
+#[derive(Debug)]
+pub enum StateError {
+    Io(PathBuf, std::io::Error),
+    /// the state couldn't be saved, everything else is about loading it
+    Write(PathBuf, std::io::Error),
+    Parse(PathBuf, serde_json::Error),
+    Insecure(PermissionError),
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StateError::Io(path, e) => write!(f, "failed to access {}: {}", path.display(), e),
+            StateError::Write(path, e) => write!(f, "failed to write {}: {}", path.display(), e),
+            StateError::Parse(path, e) => write!(f, "corrupt state file {}: {}", path.display(), e),
+            StateError::Insecure(e) => write!(f, "refusing to load the state: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for StateError {}
+
+impl StateError {
+    /// The event to log this with
+    pub fn event_id(&self) -> EventId {
+        match self {
+            StateError::Write(..) => EventId::StateSaveFailed,
+            _ => EventId::StateLoadFailed,
+        }
+    }
+}
+
+impl State {
+    pub fn default_path() -> PathBuf {
+        std::env::current_exe().unwrap().with_file_name(STATE_FILE_NAME)
+    }
+
+    pub fn path_for(target: &Target) -> PathBuf {
+        match target.computer() {
+            None => State::default_path(),
+            Some(computer) => {
+                let computer: String = computer.chars().map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '.' { c } else { '_' }).collect();
+                std::env::current_exe().unwrap().with_file_name(format!("state-{}.json", computer.to_lowercase()))
+            }
+        }
+    }
+
+    /// A missing file is an empty state
+    pub fn load_from(path: &Path) -> Result<State, StateError> {
+        match std::fs::read_to_string(path) {
+            Ok(contents) => {
+                permissions::check_file(path).map_err(StateError::Insecure)?;
+                serde_json::from_str(&contents).map_err(|e| StateError::Parse(path.to_path_buf(), e))
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(State::default()),
+            Err(e) => Err(StateError::Io(path.to_path_buf(), e)),
+        }
+    }
+
+    /// Written to a temporary file first, so a crash never leaves a half written state behind
+    pub fn save_to(&self, path: &Path) -> Result<(), StateError> {
+        let tmp = path.with_extension("json.tmp");
+        let contents = serde_json::to_string_pretty(self).unwrap();
+
+        std::fs::write(&tmp, contents).map_err(|e| StateError::Write(tmp.clone(), e))?;
+        std::fs::rename(&tmp, path).map_err(|e| StateError::Write(path.to_path_buf(), e))
+    }
+
+    /// Load, change and save. A corrupt state file is moved aside and started over, it may still
+    /// hold the command line to restore
+    pub fn update(path: &Path, f: impl FnOnce(&mut State)) -> Result<State, StateError> {
+        // the state file is replaced on save, so the lock is a file of its own. The service and
+        // the CLI updating at once would lose one of the changes otherwise
+        let lock_path = path.with_extension("json.lock");
+        let lock_error = |e| StateError::Io(lock_path.clone(), e);
+        let lock = OpenOptions::new().create(true).read(true).write(true).truncate(false).open(&lock_path).map_err(lock_error)?;
+        lock.lock().map_err(lock_error)?;
+
+        let mut state = match State::load_from(path) {
+            Ok(state) => state,
+            Err(e @ StateError::Parse(..)) => {
+                let aside = path.with_extension(format!("json.corrupt-{}", unix_time()));
+                std::fs::rename(path, &aside).map_err(|e| StateError::Io(path.to_path_buf(), e))?;
+                event!(
+                    Level::Error,
+                    Event::new(EventId::StateLoadFailed),
+                    "{}, moved it to {} and started over. Its original_command_line is what unpatch restores, copy it back if it's there",
+                    e,
+                    aside.display()
+                );
+                State::default()
+            }
+            Err(e) => return Err(e),
+        };
+
+        f(&mut state);
+        state.save_to(path)?;
+
+        // the lock goes with the file
+        Ok(state)
+    }
+
+    /// Remember a patch. Docker rewrites its service on updates, so the original is replaced
+    /// with whatever unpatched config was seen last
+    pub fn record_patch(&mut self, original_command_line: &str, original_display_name: &str, patched_command_line: &str) {
+        self.original_command_line = Some(original_command_line.to_string());
+        self.original_display_name = Some(original_display_name.to_string());
+        self.patched_command_line = Some(patched_command_line.to_string());
+        self.patched_at = Some(unix_time());
+    }
+}
+
+pub fn unix_time() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::thread;
+
+    use super::State;
+    use crate::testing::ScratchDir;
+
+    #[test]
+    fn concurrent_updates() {
+        let dir = ScratchDir::new("state-concurrent");
+        let path = dir.join("state.json");
+
+        // the service and CLI commands each counting at once, none of them may get lost
+        let threads: Vec<_> = (0..8)
+            .map(|_| {
+                let path = path.clone();
+                thread::spawn(move || {
+                    for _ in 0..25 {
+                        State::update(&path, |state| state.metrics.patches_applied += 1).unwrap();
+                    }
+                })
+            })
+            .collect();
+        threads.into_iter().for_each(|t| t.join().unwrap());
+
+        assert_eq!(State::load_from(&path).unwrap().metrics.patches_applied, 200);
+    }
+
+    #[test]
+    fn corrupt_state_is_moved_aside() {
+        let dir = ScratchDir::new("state-corrupt");
+        let path = dir.join("state.json");
+        std::fs::write(&path, "{\"original_command_line\": ").unwrap();
+
+        let state = State::update(&path, |state| state.metrics.patch_failures += 1).unwrap();
+        assert_eq!(state.metrics.patch_failures, 1);
+        assert_eq!(State::load_from(&path).unwrap(), state);
+
+        let aside: Vec<_> = std::fs::read_dir(&*dir)
+            .unwrap()
+            .flatten()
+            .filter(|e| e.file_name().to_string_lossy().starts_with("state.json.corrupt-"))
+            .collect();
+        assert_eq!(aside.len(), 1);
+        assert_eq!(std::fs::read_to_string(aside[0].path()).unwrap(), "{\"original_command_line\": ");
+    }
+}
@@ -0,0 +1,226 @@
+// `status` report
+// What the watcher did and is waiting for, read from the services and the state file.
+
+use std::path::Path;
+
+use serde::Serialize;
+
+use super::backend::{ServiceBackend, ServiceState};
+use super::config::{Config, CONFIG_FILE_NAME};
+use super::desktop::{self, Desktop};
+use super::exit;
+use super::patch;
+use super::shared::*;
+use super::state::{self, Drift, Metrics, NotRestarted, PendingRestart, ServiceFailure, State};
+
+#[derive(Debug, Clone, Serialize)]
+pub struct StatusReport {
+    /// None if the service isn't installed
+    pub patcher: Option<ServiceState>,
+    pub docker: Option<ServiceState>,
+    pub docker_command_line: Option<String>,
+    /// None if Docker Desktop isn't installed
+    pub desktop: Option<Desktop>,
+    pub patched: bool,
+    /// dockerd flags the patch enforces
+    pub flags: Vec<String>,
+    pub drift_policy: String,
+    pub restart_policy: String,
+    pub maintenance_windows: Vec<String>,
+    pub restart_when_idle: bool,
+    pub graceful_restart: bool,
+    /// unpatched command line found while docker was running and not corrected yet
+    pub drift: Option<Drift>,
+    /// patch written, docker still runs the old command line
+    pub pending_restart: Option<PendingRestart>,
+    pub patched_at: Option<u64>,
+    /// containers running before the last graceful restart which didn't come back
+    pub containers_not_restarted: Vec<NotRestarted>,
+    /// why the patcher service last stopped with an error
+    pub service_failure: Option<ServiceFailure>,
+    pub metrics: Metrics,
+    /// anything that couldn't be queried
+    pub errors: Vec<String>,
+}
+
+pub fn collect<B: ServiceBackend>(backend: &B, state_path: &Path, config: &Config) -> StatusReport {
+    let watcher = &config.watcher;
+    let mut errors = vec![];
+
+    let mut query_state = |name| {
+        backend.query_state(name).unwrap_or_else(|e| {
+            errors.push(format!("couldn't query {}: {}", name, e));
+            None
+        })
+    };
+    let patcher = query_state(SERVICE_NAME);
+    let docker = query_state(DOCKER_SERVICE_NAME);
+
+    let docker_command_line = match backend.query_config(DOCKER_SERVICE_NAME) {
+        Ok(config) => config.map(|c| c.command_line),
+        Err(e) => {
+            errors.push(format!("couldn't query the {} config: {}", DOCKER_SERVICE_NAME, e));
+            None
+        }
+    };
+
+    let desktop = desktop::detect(backend, &config.desktop).unwrap_or_else(|e| {
+        errors.push(format!("couldn't query {}: {}", DOCKER_DESKTOP_SERVICE_NAME, e));
+        None
+    });
+
+    let state = State::load_from(state_path).unwrap_or_else(|e| {
+        errors.push(e.to_string());
+        State::default()
+    });
+
+    StatusReport {
+        patcher,
+        docker,
+        patched: docker_command_line.as_deref().is_some_and(|c| !patch::has_drifted(&patch::split_command_line(c), &watcher.flags)),
+        flags: watcher.flags.iter().map(ToString::to_string).collect(),
+        docker_command_line,
+        desktop,
+        drift_policy: watcher.drift_policy.to_string(),
+        restart_policy: watcher.restart_policy.to_string(),
+        maintenance_windows: watcher.maintenance_windows.iter().map(ToString::to_string).collect(),
+        restart_when_idle: watcher.restart_when_idle,
+        graceful_restart: watcher.graceful_restart,
+        drift: state.drift,
+        pending_restart: state.pending_restart,
+        patched_at: state.patched_at,
+        containers_not_restarted: state.containers_not_restarted,
+        service_failure: state.service_failure,
+        metrics: state.metrics,
+        errors,
+    }
+}
+
+pub fn render_text(report: &StatusReport) -> String {
+    let service = |state: Option<ServiceState>| state.map_or("not installed".to_string(), |s| s.to_string());
+    let ago = |t: u64| format!("{} seconds ago", state::unix_time().saturating_sub(t));
+
+    let mut out = format!("patcher service: {}\n", service(report.patcher));
+
+    if let Some(failure) = &report.service_failure {
+        out.push_str(&format!(
+            "patcher failure: exit code {} {}: {}\n",
+            exit::describe(failure.exit_code),
+            ago(failure.at),
+            failure.message
+        ));
+    }
+
+    out.push_str(&format!("docker service:  {}\n", service(report.docker)));
+
+    if let Some(desktop) = &report.desktop {
+        out.push_str(&format!("docker desktop:  {}\n", desktop.mode));
+    }
+
+    if let Some(command_line) = &report.docker_command_line {
+        let patched = if report.patched { "patched" } else { "not patched" };
+        out.push_str(&format!("docker command line ({}): {}\n", patched, command_line));
+    }
+
+    if report.flags.is_empty() {
+        out.push_str(&format!("enforced flags: none, set watcher.flags in {}\n", CONFIG_FILE_NAME));
+    } else {
+        out.push_str(&format!("enforced flags: {}\n", report.flags.join(" ")));
+    }
+
+    out.push_str(&format!("last patch: {}\n", report.patched_at.map_or("never".to_string(), ago)));
+
+    if let Some(pending) = &report.pending_restart {
+        let waiting = if pending.deferred { deferred_until(report) } else { "docker's next restart".to_string() };
+        out.push_str(&format!("pending: patch written {}, docker runs unpatched until {}\n", ago(pending.since), waiting));
+    }
+
+    match &report.drift {
+        Some(drift) => out.push_str(&format!(
+            "drift: {} detected {} (policy {})\n",
+            drift.command_line,
+            ago(drift.detected_at),
+            report.drift_policy
+        )),
+        None => out.push_str(&format!("drift: none (policy {})\n", report.drift_policy)),
+    }
+
+    let graceful = if report.graceful_restart { ", stopping and restarting containers" } else { "" };
+    out.push_str(&format!("restart policy: {}{}\n", report.restart_policy, graceful));
+
+    if !report.containers_not_restarted.is_empty() {
+        let containers: Vec<String> = report.containers_not_restarted.iter().map(ToString::to_string).collect();
+        out.push_str(&format!("not restarted after the last restart: {}\n", containers.join(", ")));
+    }
+
+    let m = &report.metrics;
+    out.push_str(&format!(
+        "metrics: {} patches, {} failures, {} drifts detected, {} corrected, {} containers restarted, {} not, {} integrity failures, {} transient and {} fatal service errors\n",
+        m.patches_applied,
+        m.patch_failures,
+        m.drift_detected,
+        m.drift_corrected,
+        m.containers_restarted,
+        m.containers_not_restarted,
+        m.integrity_failures,
+        m.transient_errors,
+        m.fatal_errors
+    ));
+
+    for error in &report.errors {
+        out.push_str(&format!("error: {}\n", error));
+    }
+
+    out
+}
+
+/// One line, for the batch table
+pub fn summary(report: &StatusReport) -> String {
+    let service = |state: Option<ServiceState>| state.map_or("not installed".to_string(), |s| s.to_string());
+
+    let mut parts = vec![format!("patcher {}", service(report.patcher))];
+
+    if let Some(failure) = &report.service_failure {
+        parts.push(format!("patcher exit code {}", failure.exit_code));
+    }
+
+    parts.push(format!("docker {}", service(report.docker)));
+
+    if let Some(desktop) = &report.desktop {
+        parts.push(format!("desktop {}", desktop.mode));
+    }
+
+    if report.docker_command_line.is_some() {
+        parts.push(if report.patched { "patched" } else { "not patched" }.to_string());
+    }
+
+    if report.pending_restart.is_some() {
+        parts.push("restart pending".to_string());
+    }
+
+    if report.drift.is_some() {
+        parts.push("drifted".to_string());
+    }
+
+    if !report.containers_not_restarted.is_empty() {
+        parts.push(format!("{} containers not restarted", report.containers_not_restarted.len()));
+    }
+
+    parts.join(", ")
+}
+
+/// What a deferred restart waits for
+fn deferred_until(report: &StatusReport) -> String {
+    let mut until = vec![];
+
+    if !report.maintenance_windows.is_empty() {
+        until.push(format!("maintenance window {}", report.maintenance_windows.join(", ")));
+    }
+
+    if report.restart_when_idle {
+        until.push("no containers are running".to_string());
+    }
+
+    until.push("the patch-now command".to_string());
+    until.join(" or ")
+}
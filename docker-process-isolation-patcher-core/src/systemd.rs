@@ -0,0 +1,600 @@
+// systemd backend
+// The Linux counterpart of the SCM backend. docker.service belongs to the distribution's package,
+// so its unit file is never edited: a changed command line goes into a drop-in which replaces
+// ExecStart, and restoring the original removes the drop-in again. Units are read through
+// `systemctl show`. A unit file changed on disk is reloaded before it's read, so the watcher sees
+// edits without anyone running daemon-reload.
+
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use super::backend::{BackendError, BackendResult, NewService, ServiceBackend, ServiceConfig, ServiceState, Target};
+use super::patch;
+use super::shared::*;
+
+/// where units and drop-ins made by an administrator go
+pub const UNIT_DIR: &str = "/etc/systemd/system";
+
+const PROPERTIES: &[&str] = &["LoadState", "ActiveState", "MainPID", "NeedDaemonReload", "Description", "ExecStart", "Requires", "User"];
+
+// marks the lines of the drop-in holding the config from before it
+const ORIGINAL_DESCRIPTION: &str = "# original Description: ";
+const ORIGINAL_EXEC_START: &str = "# original ExecStart: ";
+const ORIGINAL_REQUIRES: &str = "# original Requires: ";
+
+/// The parts of systemd the backend uses, tests can pass a fake
+pub trait Systemd {
+    /// `systemctl show` properties of a unit. A unit which doesn't exist has LoadState not-found
+    fn show(&self, unit: &str, properties: &[&str]) -> BackendResult<HashMap<String, String>>;
+    fn daemon_reload(&self) -> BackendResult<()>;
+    /// Run a systemctl verb like start, stop, enable or disable on a unit, without waiting for
+    /// the job
+    fn unit_command(&self, verb: &str, unit: &str) -> BackendResult<()>;
+    /// directory unit files and drop-ins are written to
+    fn unit_dir(&self) -> &Path;
+}
+
+/// systemd of this computer, through the systemctl command
+pub struct Systemctl {
+    unit_dir: PathBuf,
+}
+
+impl Default for Systemctl {
+    fn default() -> Self {
+        Systemctl { unit_dir: PathBuf::from(UNIT_DIR) }
+    }
+}
+
+impl Systemctl {
+    fn run(&self, args: &[&str]) -> BackendResult<String> {
+        let output = Command::new("systemctl").args(args).output().map_err(|e| BackendError {
+            code: e.raw_os_error(),
+            message: format!("failed to run systemctl: {}", e),
+            refused: false,
+        })?;
+
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            return Err(BackendError::new(format!("systemctl {} failed: {}", args.join(" "), stderr.trim())));
+        }
+
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+}
+
+impl Systemd for Systemctl {
+    fn show(&self, unit: &str, properties: &[&str]) -> BackendResult<HashMap<String, String>> {
+        let property = format!("--property={}", properties.join(","));
+        self.run(&["show", unit, &property]).map(|output| parse_show(&output))
+    }
+
+    fn daemon_reload(&self) -> BackendResult<()> {
+        self.run(&["daemon-reload"]).map(|_| ())
+    }
+
+    fn unit_command(&self, verb: &str, unit: &str) -> BackendResult<()> {
+        self.run(&[verb, "--no-block", unit]).map(|_| ())
+    }
+
+    fn unit_dir(&self) -> &Path {
+        &self.unit_dir
+    }
+}
+
+pub struct SystemdBackend<S: Systemd = Systemctl> {
+    systemd: S,
+    target: Target,
+}
+
+impl SystemdBackend {
+    pub fn local() -> BackendResult<Self> {
+        SystemdBackend::connect(&Target::Local)
+    }
+
+    /// Only this computer is supported, remote computers need the Windows service manager
+    pub fn connect(target: &Target) -> BackendResult<Self> {
+        if !target.is_local() {
+            return Err(BackendError::new(format!("can't manage the services of {}, remote computers are only supported on Windows", target)));
+        }
+
+        Ok(SystemdBackend::with_systemd(Systemctl::default()))
+    }
+}
+
+impl<S: Systemd> SystemdBackend<S> {
+    pub fn with_systemd(systemd: S) -> Self {
+        SystemdBackend { systemd, target: Target::Local }
+    }
+
+    /// Drop-in the patched config of `name` is written to
+    pub fn drop_in_path(&self, name: &str) -> PathBuf {
+        self.systemd.unit_dir().join(format!("{}.d", unit_name(name))).join(format!("99-{}.conf", APP_NAME))
+    }
+
+    /// Properties of the unit, None if it doesn't exist
+    fn properties(&self, name: &str) -> BackendResult<Option<HashMap<String, String>>> {
+        let properties = self.systemd.show(&unit_name(name), PROPERTIES)?;
+
+        match properties.get("LoadState").map(String::as_str) {
+            Some("not-found") | None => Ok(None),
+            Some(_) => Ok(Some(properties)),
+        }
+    }
+
+    fn existing_properties(&self, name: &str) -> BackendResult<HashMap<String, String>> {
+        self.properties(name)?.ok_or_else(|| BackendError::new(format!("service {} does not exist", name)))
+    }
+
+    /// Config of the unit without the drop-in, as recorded in it
+    fn original_config(&self, name: &str) -> BackendResult<Option<ServiceConfig>> {
+        let contents = match fs::read_to_string(self.drop_in_path(name)) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+
+        let value = |prefix: &str| contents.lines().find_map(|l| l.strip_prefix(prefix)).map(str::to_string);
+
+        match (value(ORIGINAL_DESCRIPTION), value(ORIGINAL_EXEC_START)) {
+            (Some(display_name), Some(command_line)) => Ok(Some(ServiceConfig {
+                display_name,
+                command_line,
+                dependencies: value(ORIGINAL_REQUIRES).map(|r| split_units(&r)).unwrap_or_default(),
+                account_name: None,
+            })),
+            _ => Err(BackendError::new(format!(
+                "{} doesn't record the config from before the patch, delete it and run systemctl daemon-reload",
+                self.drop_in_path(name).display()
+            ))),
+        }
+    }
+
+    fn write_drop_in(&self, name: &str, original: &ServiceConfig, config: &ServiceConfig) -> BackendResult<()> {
+        let added: Vec<&String> = config.dependencies.iter().filter(|d| !original.dependencies.contains(d)).collect();
+        if let Some(removed) = original.dependencies.iter().find(|d| !config.dependencies.contains(d)) {
+            return Err(BackendError::new(format!("a drop-in can't remove the dependency of {} on {}", name, removed)));
+        }
+
+        let mut contents = format!("# Written by {}. Delete it and run systemctl daemon-reload to undo the patch\n", APP_NAME);
+        contents.push_str(&format!("{}{}\n", ORIGINAL_DESCRIPTION, original.display_name));
+        contents.push_str(&format!("{}{}\n", ORIGINAL_EXEC_START, original.command_line));
+        contents.push_str(&format!("{}{}\n", ORIGINAL_REQUIRES, original.dependencies.join(" ")));
+
+        contents.push_str(&format!("\n[Unit]\nDescription={}\n", config.display_name));
+        for dependency in added {
+            let unit = unit_name(dependency);
+            contents.push_str(&format!("Requires={}\nAfter={}\n", unit, unit));
+        }
+
+        // the empty ExecStart clears the one of the unit, otherwise both would be run
+        contents.push_str(&format!("\n[Service]\nExecStart=\nExecStart={}\n", exec_line(&config.command_line)));
+
+        let path = self.drop_in_path(name);
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(&path, contents)?;
+
+        Ok(())
+    }
+
+    fn remove_drop_in(&self, name: &str) -> BackendResult<()> {
+        let path = self.drop_in_path(name);
+        fs::remove_file(&path)?;
+
+        // the directory is left alone if something else put a drop-in there
+        if let Some(dir) = path.parent() {
+            fs::remove_dir(dir).ok();
+        }
+
+        Ok(())
+    }
+}
+
+impl<S: Systemd> ServiceBackend for SystemdBackend<S> {
+    fn target(&self) -> &Target {
+        &self.target
+    }
+
+    fn query_state(&self, name: &str) -> BackendResult<Option<ServiceState>> {
+        let properties = match self.properties(name)? {
+            Some(properties) => properties,
+            None => return Ok(None),
+        };
+
+        let state = match properties.get("ActiveState").map(String::as_str).unwrap_or("") {
+            "active" | "reloading" => ServiceState::Running,
+            "activating" => ServiceState::StartPending,
+            "deactivating" => ServiceState::StopPending,
+            // inactive and failed
+            _ => ServiceState::Stopped,
+        };
+
+        Ok(Some(state))
+    }
+
+    fn query_process_id(&self, name: &str) -> BackendResult<Option<u32>> {
+        let properties = match self.properties(name)? {
+            Some(properties) => properties,
+            None => return Ok(None),
+        };
+
+        // 0 until the main process is forked
+        Ok(properties.get("MainPID").and_then(|pid| pid.parse().ok()).filter(|&pid| pid != 0))
+    }
+
+    fn query_config(&self, name: &str) -> BackendResult<Option<ServiceConfig>> {
+        let mut properties = match self.properties(name)? {
+            Some(properties) => properties,
+            None => return Ok(None),
+        };
+
+        // the unit file or one of its drop-ins changed on disk, e.g. a package upgrade
+        if properties.get("NeedDaemonReload").is_some_and(|v| v == "yes") {
+            self.systemd.daemon_reload()?;
+            properties = self.existing_properties(name)?;
+        }
+
+        let property = |key: &str| properties.get(key).cloned().unwrap_or_default();
+
+        let exec_start = property("ExecStart");
+        let command_line = exec_start_argv(&exec_start)
+            .ok_or_else(|| BackendError::new(format!("service {} has no ExecStart: {}", name, exec_start)))?;
+
+        Ok(Some(ServiceConfig {
+            display_name: property("Description"),
+            command_line,
+            dependencies: split_units(&property("Requires")),
+            account_name: Some(property("User")).filter(|u| !u.is_empty()),
+        }))
+    }
+
+    fn change_config(&self, name: &str, config: &ServiceConfig) -> BackendResult<()> {
+        let current = self.query_config(name)?.ok_or_else(|| BackendError::new(format!("service {} does not exist", name)))?;
+
+        if patch::split_command_line(&config.command_line).is_empty() {
+            return Err(BackendError::new(format!("empty command line for service {}", name)));
+        }
+
+        let original = match self.original_config(name)? {
+            Some(original) => original,
+            None => ServiceConfig { account_name: None, ..current },
+        };
+
+        let unchanged = config.display_name == original.display_name
+            && patch::same_command_line(&config.command_line, &original.command_line)
+            && config.dependencies == original.dependencies;
+
+        if unchanged {
+            if self.drop_in_path(name).exists() {
+                self.remove_drop_in(name)?;
+            }
+        } else {
+            self.write_drop_in(name, &original, config)?;
+        }
+
+        self.systemd.daemon_reload()
+    }
+
+    /// Units only have the Description, which is the display name
+    fn set_description(&self, _name: &str, _description: &str) -> BackendResult<()> {
+        Ok(())
+    }
+
+    fn start(&self, name: &str) -> BackendResult<()> {
+        self.existing_properties(name)?;
+        self.systemd.unit_command("start", &unit_name(name))
+    }
+
+    fn stop(&self, name: &str) -> BackendResult<()> {
+        self.existing_properties(name)?;
+        self.systemd.unit_command("stop", &unit_name(name))
+    }
+
+    fn create(&self, service: &NewService) -> BackendResult<()> {
+        let mut command = vec![service.executable.to_string_lossy().into_owned()];
+        command.extend(service.arguments.iter().cloned());
+
+        let before: String = service.start_before.iter().map(|s| format!("Before={}\n", unit_name(s))).collect();
+
+        let contents = format!(
+            "# {}\n[Unit]\nDescription={}\nAfter=network.target\n{}\n[Service]\nExecStart={}\nRestart=on-failure\n\n[Install]\nWantedBy=multi-user.target\n",
+            service.description,
+            service.display_name,
+            before,
+            exec_line(&patch::join_command_line(&command))
+        );
+
+        let path = self.systemd.unit_dir().join(unit_name(&service.name));
+        OpenOptions::new().write(true).create_new(true).open(&path)?.write_all(contents.as_bytes())?;
+
+        // started automatically, like an AutoStart Windows service
+        self.systemd.daemon_reload()?;
+        self.systemd.unit_command("enable", &unit_name(&service.name))
+    }
+
+    fn delete(&self, name: &str) -> BackendResult<()> {
+        self.existing_properties(name)?;
+
+        self.systemd.unit_command("disable", &unit_name(name))?;
+        fs::remove_file(self.systemd.unit_dir().join(unit_name(name)))?;
+        self.systemd.daemon_reload()
+    }
+}
+
+/// `docker` is docker.service, names with a suffix are kept
+fn unit_name(name: &str) -> String {
+    if name.contains('.') {
+        name.to_string()
+    } else {
+        format!("{}.service", name)
+    }
+}
+
+/// `Key=value` lines of `systemctl show`
+fn parse_show(output: &str) -> HashMap<String, String> {
+    output
+        .lines()
+        .filter_map(|line| line.split_once('='))
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect()
+}
+
+/// Units of a `Requires` property, services without the .service suffix like the SCM names them
+fn split_units(units: &str) -> Vec<String> {
+    units.split_whitespace().map(|u| u.strip_suffix(".service").unwrap_or(u).to_string()).collect()
+}
+
+/// Command line of the first command in an ExecStart property, which systemctl shows as
+/// `{ path=/usr/bin/dockerd ; argv[]=/usr/bin/dockerd -H fd:// ; ignore_errors=no ; ... }`
+fn exec_start_argv(exec_start: &str) -> Option<String> {
+    let argv = exec_start.split_once("argv[]=")?.1;
+    let argv = argv.split_once(" ;").map_or(argv, |(argv, _)| argv);
+
+    Some(argv.trim().to_string()).filter(|a| !a.is_empty())
+}
+
+/// A command line as an ExecStart value. systemd expands `%` specifiers and `$` variables and
+/// unescapes backslashes, so those are escaped
+fn exec_line(command_line: &str) -> String {
+    patch::split_command_line(command_line)
+        .iter()
+        .map(|arg| {
+            let escaped = arg.replace('\\', "\\\\").replace('"', "\\\"").replace('%', "%%").replace('$', "$$");
+
+            if escaped.is_empty() || escaped.contains(char::is_whitespace) {
+                format!("\"{}\"", escaped)
+            } else {
+                escaped
+            }
+        })
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::cell::{Cell, RefCell};
+    use std::collections::HashMap;
+    use std::fs;
+    use std::path::Path;
+
+    use super::{exec_line, exec_start_argv, parse_show, Systemd, SystemdBackend, PROPERTIES};
+    use crate::backend::{BackendResult, ServiceBackend, ServiceConfig, ServiceState};
+    use crate::shared::*;
+    use crate::testing::ScratchDir;
+
+    const DOCKER_SHOW: &str = "\
+LoadState=loaded
+ActiveState=active
+MainPID=1234
+NeedDaemonReload=no
+Description=Docker Application Container Engine
+ExecStart={ path=/usr/bin/dockerd ; argv[]=/usr/bin/dockerd -H fd:// --containerd=/run/containerd/containerd.sock ; ignore_errors=no ; start_time=[n/a] ; stop_time=[n/a] ; pid=0 ; code=(null) ; status=0/0 }
+Requires=docker.socket containerd.service system.slice
+User=
+";
+
+    /// systemd with units shown as given, reloading applies the ExecStart and Description of the
+    /// patcher's drop-ins in `dir`
+    struct FakeSystemd {
+        dir: ScratchDir,
+        units: HashMap<String, HashMap<String, String>>,
+        shown: RefCell<HashMap<String, HashMap<String, String>>>,
+        reloads: Cell<usize>,
+        commands: RefCell<Vec<String>>,
+    }
+
+    impl FakeSystemd {
+        fn new(units: &[(&str, &str)]) -> Self {
+            let units: HashMap<String, HashMap<String, String>> = units.iter().map(|(unit, show)| (unit.to_string(), parse_show(show))).collect();
+
+            FakeSystemd {
+                dir: ScratchDir::new("systemd"),
+                shown: RefCell::new(units.clone()),
+                units,
+                reloads: Cell::new(0),
+                commands: RefCell::new(vec![]),
+            }
+        }
+
+        fn set(&self, unit: &str, key: &str, value: &str) {
+            self.shown.borrow_mut().get_mut(unit).unwrap().insert(key.to_string(), value.to_string());
+        }
+    }
+
+    impl Systemd for FakeSystemd {
+        fn show(&self, unit: &str, properties: &[&str]) -> BackendResult<HashMap<String, String>> {
+            assert_eq!(properties, PROPERTIES);
+
+            Ok(self.shown.borrow().get(unit).cloned().unwrap_or_else(|| parse_show("LoadState=not-found\nActiveState=inactive\n")))
+        }
+
+        fn daemon_reload(&self) -> BackendResult<()> {
+            self.reloads.set(self.reloads.get() + 1);
+
+            let mut shown = self.units.clone();
+            for (unit, properties) in shown.iter_mut() {
+                let drop_in = self.dir.join(format!("{}.d", unit)).join(format!("99-{}.conf", APP_NAME));
+                let contents = match fs::read_to_string(drop_in) {
+                    Ok(contents) => contents,
+                    Err(_) => continue,
+                };
+
+                for line in contents.lines() {
+                    match line.split_once('=') {
+                        Some(("ExecStart", argv)) if !argv.is_empty() => {
+                            properties.insert("ExecStart".to_string(), format!("{{ path=x ; argv[]={} ; ignore_errors=no }}", argv));
+                        }
+                        Some(("Description", description)) => {
+                            properties.insert("Description".to_string(), description.to_string());
+                        }
+                        _ => (),
+                    }
+                }
+            }
+            *self.shown.borrow_mut() = shown;
+
+            Ok(())
+        }
+
+        fn unit_command(&self, verb: &str, unit: &str) -> BackendResult<()> {
+            self.commands.borrow_mut().push(format!("{} {}", verb, unit));
+            Ok(())
+        }
+
+        fn unit_dir(&self) -> &Path {
+            &self.dir
+        }
+    }
+
+    fn docker() -> SystemdBackend<FakeSystemd> {
+        SystemdBackend::with_systemd(FakeSystemd::new(&[("docker.service", DOCKER_SHOW)]))
+    }
+
+    #[test]
+    fn config_from_systemctl_show() {
+        let backend = docker();
+
+        let config = backend.query_config(DOCKER_SERVICE_NAME).unwrap().unwrap();
+
+        assert_eq!(config.display_name, "Docker Application Container Engine");
+        assert_eq!(config.command_line, "/usr/bin/dockerd -H fd:// --containerd=/run/containerd/containerd.sock");
+        assert_eq!(config.dependencies, vec!["docker.socket", "containerd", "system.slice"]);
+        assert_eq!(config.account_name, None);
+        assert_eq!(backend.query_state(DOCKER_SERVICE_NAME).unwrap(), Some(ServiceState::Running));
+        assert_eq!(backend.query_process_id(DOCKER_SERVICE_NAME).unwrap(), Some(1234));
+        assert_eq!(backend.systemd.reloads.get(), 0);
+    }
+
+    #[test]
+    fn missing_unit() {
+        let backend = docker();
+
+        assert_eq!(backend.query_state("containerd").unwrap(), None);
+        assert!(backend.query_config("containerd").unwrap().is_none());
+        assert!(backend.start("containerd").is_err());
+    }
+
+    #[test]
+    fn states() {
+        let backend = docker();
+
+        for (active, state) in [
+            ("active", ServiceState::Running),
+            ("reloading", ServiceState::Running),
+            ("activating", ServiceState::StartPending),
+            ("deactivating", ServiceState::StopPending),
+            ("inactive", ServiceState::Stopped),
+            ("failed", ServiceState::Stopped),
+        ] {
+            backend.systemd.set("docker.service", "ActiveState", active);
+            assert_eq!(backend.query_state(DOCKER_SERVICE_NAME).unwrap(), Some(state), "{}", active);
+        }
+
+        // not forked yet
+        backend.systemd.set("docker.service", "MainPID", "0");
+        assert_eq!(backend.query_process_id(DOCKER_SERVICE_NAME).unwrap(), None);
+    }
+
+    #[test]
+    fn changed_unit_is_reloaded_before_its_read() {
+        let backend = docker();
+        backend.systemd.set("docker.service", "NeedDaemonReload", "yes");
+
+        backend.query_config(DOCKER_SERVICE_NAME).unwrap();
+
+        assert_eq!(backend.systemd.reloads.get(), 1);
+    }
+
+    #[test]
+    fn patch_goes_into_a_drop_in() {
+        let backend = docker();
+        let original = backend.query_config(DOCKER_SERVICE_NAME).unwrap().unwrap();
+        let patched = ServiceConfig {
+            display_name: "Docker - Patched".to_string(),
+            command_line: "/usr/bin/dockerd --exec-opt isolation=process -H fd:// --label \"a b\"".to_string(),
+            ..original.clone()
+        };
+
+        backend.change_config(DOCKER_SERVICE_NAME, &patched).unwrap();
+
+        let drop_in = backend.drop_in_path(DOCKER_SERVICE_NAME);
+        assert_eq!(drop_in, backend.systemd.dir.join("docker.service.d").join(format!("99-{}.conf", APP_NAME)));
+        let contents = fs::read_to_string(&drop_in).unwrap();
+        assert!(contents.contains(&format!("# original ExecStart: {}\n", original.command_line)), "{}", contents);
+        assert!(contents.contains("\n[Unit]\nDescription=Docker - Patched\n"), "{}", contents);
+        assert!(contents.contains("\n[Service]\nExecStart=\nExecStart=/usr/bin/dockerd --exec-opt isolation=process -H fd:// --label \"a b\"\n"), "{}", contents);
+        assert_eq!(backend.systemd.reloads.get(), 1);
+
+        let config = backend.query_config(DOCKER_SERVICE_NAME).unwrap().unwrap();
+        assert_eq!(config.command_line, patched.command_line);
+        assert_eq!(config.display_name, patched.display_name);
+
+        // patching again keeps the config from before the first patch
+        let repatched = ServiceConfig { command_line: "/usr/bin/dockerd --iptables=false".to_string(), ..patched };
+        backend.change_config(DOCKER_SERVICE_NAME, &repatched).unwrap();
+        assert!(fs::read_to_string(&drop_in).unwrap().contains(&format!("# original ExecStart: {}\n", original.command_line)));
+
+        // and writing that back removes the drop-in
+        backend.change_config(DOCKER_SERVICE_NAME, &original).unwrap();
+        assert!(!drop_in.exists());
+        assert!(!drop_in.parent().unwrap().exists());
+        assert_eq!(backend.query_config(DOCKER_SERVICE_NAME).unwrap().unwrap(), original);
+        assert_eq!(backend.systemd.reloads.get(), 3);
+    }
+
+    #[test]
+    fn drop_in_cant_remove_dependencies() {
+        let backend = docker();
+        let original = backend.query_config(DOCKER_SERVICE_NAME).unwrap().unwrap();
+
+        let config = ServiceConfig { dependencies: vec!["containerd".to_string()], ..original };
+
+        assert!(backend.change_config(DOCKER_SERVICE_NAME, &config).is_err());
+        assert!(!backend.drop_in_path(DOCKER_SERVICE_NAME).exists());
+    }
+
+    #[test]
+    fn start_and_stop_dont_wait() {
+        let backend = docker();
+
+        backend.stop(DOCKER_SERVICE_NAME).unwrap();
+        backend.start(DOCKER_SERVICE_NAME).unwrap();
+
+        assert_eq!(*backend.systemd.commands.borrow(), vec!["stop docker.service", "start docker.service"]);
+    }
+
+    #[test]
+    fn exec_start() {
+        assert_eq!(exec_start_argv("{ path=/usr/bin/dockerd ; argv[]=/usr/bin/dockerd ; ignore_errors=no }").as_deref(), Some("/usr/bin/dockerd"));
+        assert_eq!(exec_start_argv(""), None);
+        assert_eq!(exec_start_argv("{ path=/usr/bin/dockerd ; argv[]= ; ignore_errors=no }"), None);
+
+        assert_eq!(exec_line(r#"/usr/bin/dockerd --label "a b" --data-root /var/lib/100%"#), r#"/usr/bin/dockerd --label "a b" --data-root /var/lib/100%%"#);
+        assert_eq!(exec_line("/usr/bin/dockerd --label $HOME"), "/usr/bin/dockerd --label $$HOME");
+    }
+}
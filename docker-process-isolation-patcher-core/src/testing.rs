@@ -0,0 +1,175 @@
+// Test helpers
+// A service backend keeping its services in memory, and scratch directories.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::ops::Deref;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use super::backend::{BackendError, BackendResult, NewService, ServiceBackend, ServiceConfig, ServiceState, Target};
+
+/// Services of one computer. Clones share them, so a test can look at what code it handed one
+/// to did. Start and stop take effect right away
+#[derive(Clone)]
+pub struct FakeBackend {
+    target: Target,
+    services: Arc<Mutex<BTreeMap<String, (ServiceState, ServiceConfig)>>>,
+    /// stopped services stay stop pending
+    stop_hangs: bool,
+    /// error every call fails with
+    failure: Arc<Mutex<Option<BackendError>>>,
+}
+
+impl FakeBackend {
+    pub fn new(target: Target) -> Self {
+        FakeBackend { target, services: Default::default(), stop_hangs: false, failure: Default::default() }
+    }
+
+    /// Services asked to stop never get past stop pending
+    pub fn hanging_stop(mut self) -> Self {
+        self.stop_hangs = true;
+        self
+    }
+
+    pub fn with(self, name: &str, state: ServiceState, command_line: &str) -> Self {
+        let config = ServiceConfig {
+            display_name: name.to_string(),
+            command_line: command_line.to_string(),
+            dependencies: vec![],
+            account_name: None,
+        };
+        self.services.lock().unwrap().insert(name.to_string(), (state, config));
+        self
+    }
+
+    /// Fail every call with `error` from now on, or stop failing if it's None
+    pub fn fail(&self, error: Option<BackendError>) {
+        *self.failure.lock().unwrap() = error;
+    }
+
+    fn check(&self) -> BackendResult<()> {
+        self.failure.lock().unwrap().clone().map_or(Ok(()), Err)
+    }
+
+    pub fn state(&self, name: &str) -> Option<ServiceState> {
+        self.services.lock().unwrap().get(name).map(|(state, _)| *state)
+    }
+
+    pub fn config(&self, name: &str) -> Option<ServiceConfig> {
+        self.services.lock().unwrap().get(name).map(|(_, config)| config.clone())
+    }
+
+    fn set_state(&self, name: &str, state: ServiceState) -> BackendResult<()> {
+        self.check()?;
+        match self.services.lock().unwrap().get_mut(name) {
+            Some(service) => {
+                service.0 = state;
+                Ok(())
+            }
+            None => Err(not_found(name)),
+        }
+    }
+}
+
+fn not_found(name: &str) -> BackendError {
+    BackendError::new(format!("{} doesn't exist", name))
+}
+
+impl ServiceBackend for FakeBackend {
+    fn target(&self) -> &Target {
+        &self.target
+    }
+
+    fn query_state(&self, name: &str) -> BackendResult<Option<ServiceState>> {
+        self.check()?;
+        Ok(self.state(name))
+    }
+
+    fn query_config(&self, name: &str) -> BackendResult<Option<ServiceConfig>> {
+        self.check()?;
+        Ok(self.config(name))
+    }
+
+    fn change_config(&self, name: &str, config: &ServiceConfig) -> BackendResult<()> {
+        self.check()?;
+        match self.services.lock().unwrap().get_mut(name) {
+            Some(service) => {
+                service.1 = config.clone();
+                Ok(())
+            }
+            None => Err(not_found(name)),
+        }
+    }
+
+    fn set_description(&self, name: &str, _description: &str) -> BackendResult<()> {
+        self.query_config(name)?.map(|_| ()).ok_or_else(|| not_found(name))
+    }
+
+    fn start(&self, name: &str) -> BackendResult<()> {
+        self.set_state(name, ServiceState::Running)
+    }
+
+    fn stop(&self, name: &str) -> BackendResult<()> {
+        self.set_state(name, if self.stop_hangs { ServiceState::StopPending } else { ServiceState::Stopped })
+    }
+
+    fn create(&self, service: &NewService) -> BackendResult<()> {
+        self.check()?;
+        let mut command_line = vec![service.executable.display().to_string()];
+        command_line.extend(service.arguments.iter().cloned());
+
+        let config = ServiceConfig {
+            display_name: service.display_name.clone(),
+            command_line: command_line.join(" "),
+            dependencies: vec![],
+            account_name: None,
+        };
+        self.services.lock().unwrap().insert(service.name.clone(), (ServiceState::Stopped, config));
+        Ok(())
+    }
+
+    fn delete(&self, name: &str) -> BackendResult<()> {
+        self.check()?;
+        self.services.lock().unwrap().remove(name).map(|_| ()).ok_or_else(|| not_found(name))
+    }
+}
+
+/// A new empty directory in the temp directory, writable by its owner only. Removed when dropped
+pub struct ScratchDir(PathBuf);
+
+impl ScratchDir {
+    pub fn new(name: &str) -> Self {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+
+        let dir = std::env::temp_dir().join(format!(
+            "patcher-test-{}-{}-{}",
+            name,
+            std::process::id(),
+            COUNT.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        set_mode(&dir, 0o755);
+        ScratchDir(dir)
+    }
+}
+
+impl Deref for ScratchDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for ScratchDir {
+    fn drop(&mut self) {
+        fs::remove_dir_all(&self.0).ok();
+    }
+}
+
+pub fn set_mode(path: &Path, mode: u32) {
+    fs::set_permissions(path, fs::Permissions::from_mode(mode)).unwrap();
+}
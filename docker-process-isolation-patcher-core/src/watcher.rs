@@ -0,0 +1,1263 @@
+// Docker service watcher
+// Patches docker as soon as it's seen stopped or not launched yet, so it starts patched, and falls
+// back to restarting it when it's already running unpatched. Afterwards it reconciles docker's
+// config periodically so a command line rewritten while docker keeps running doesn't go unnoticed.
+// With the deferred restart policy the patch is written right away, but restarting docker waits
+// for a maintenance window, for docker to be idle, or for the patch-now command. Hooks run around
+// stopping, patching and starting docker, and a pre-stop hook can veto the attempt. Graceful
+// restarts stop the running containers before docker and start them again after, see drain. On
+// Docker Desktop machines docker is left alone while Desktop switches engines, see desktop. With
+// the integrity check enabled, dockerd is checked before its config is written or it's restarted.
+
+use std::cell::{Cell, RefCell};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use log::{debug, error, info, Level};
+
+use super::audit::{Action, AuditLog, Record, Trigger};
+use super::backend::{BackendError, BackendResult, ServiceBackend, ServiceConfig, ServiceState as DockerState, Target};
+use super::config::{DriftPolicy, RestartPolicy, WatcherConfig};
+use super::control::{Command, Reply, Request};
+use super::desktop::{self, DesktopConfig, EngineMode};
+use super::drain::{self, Drained};
+use super::engine::{self, EngineConnector};
+use super::event;
+use super::event::{Event, EventId};
+use super::hooks::{self, HooksConfig, Stage};
+use super::integrity::{self, IntegrityConfig};
+use super::patch;
+use super::shared::*;
+use super::state::{self, Drift, PendingRestart, State};
+
+// the engine api is asked for running containers at most this often
+const DEFERRED_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+// how long docker gets to stop on top of the containers' stop timeout, it stops leftover
+// containers itself
+const DOCKER_STOP_GRACE: Duration = Duration::from_secs(60);
+
+/// Who started a patch attempt, carried into events and the audit log
+struct Attempt {
+    correlation_id: String,
+    target: Target,
+    trigger: Trigger,
+    user: Option<String>,
+    /// command lines whose dockerd passed the integrity check, each is checked once per attempt
+    verified: RefCell<Vec<String>>,
+}
+
+impl Attempt {
+    fn new(target: &Target, trigger: Trigger, user: Option<String>) -> Self {
+        Attempt { correlation_id: event::correlation_id(), target: target.clone(), trigger, user, verified: RefCell::new(Vec::new()) }
+    }
+
+    fn event(&self, id: EventId) -> Event {
+        Event::new(id).service(DOCKER_SERVICE_NAME).target(&self.target).correlation(&self.correlation_id)
+    }
+
+    fn record(&self, action: Action) -> Record {
+        Record::new(action, DOCKER_SERVICE_NAME, self.trigger).target(&self.target).correlation(&self.correlation_id)
+    }
+
+    fn hook(&self, old_command_line: &str, new_command_line: &str) -> hooks::Context {
+        hooks::Context {
+            service: DOCKER_SERVICE_NAME.to_string(),
+            computer: self.target.computer().map(ToString::to_string),
+            old_command_line: old_command_line.to_string(),
+            new_command_line: new_command_line.to_string(),
+            attempt_id: self.correlation_id.clone(),
+            trigger: self.trigger.to_string(),
+            error: None,
+        }
+    }
+}
+
+pub struct Watcher<B: ServiceBackend> {
+    backend: B,
+    config: WatcherConfig,
+    hooks: HooksConfig,
+    engine: EngineConnector,
+    desktop: DesktopConfig,
+    integrity: IntegrityConfig,
+    last_desktop_mode: Option<EngineMode>,
+    /// when docker was last seen starting to run, a Desktop switch settles from there
+    docker_running_since: Option<Instant>,
+    state_path: PathBuf,
+    audit_log: AuditLog,
+    modified_docker: bool,
+    last_docker_state: Option<DockerState>,
+    last_reconcile: Instant,
+    last_deferred_check: Option<Instant>,
+    /// docker was launched while a patch was written before its start, it's restarted once running
+    restart_once_running: bool,
+    /// set when a pre-stop hook vetoed, attempts wait until then
+    vetoed_until: Cell<Option<Instant>>,
+    /// containers stopped before docker, started again once docker is back
+    drained: RefCell<Vec<Drained>>,
+    /// how long docker gets to stop on top of the containers' stop timeout
+    stop_grace: Duration,
+}
+
+impl<B: ServiceBackend> Watcher<B> {
+    /// `engine` is the pipe/socket of dockerd, used to check for running containers
+    pub fn new(backend: B, config: WatcherConfig, hooks: HooksConfig, engine: &str, state_path: PathBuf, audit_log: AuditLog) -> Self {
+        Watcher {
+            backend,
+            config,
+            hooks,
+            engine: engine::connector(engine),
+            desktop: DesktopConfig::default(),
+            integrity: IntegrityConfig::default(),
+            last_desktop_mode: None,
+            docker_running_since: None,
+            state_path,
+            audit_log,
+            modified_docker: false,
+            last_docker_state: None,
+            last_reconcile: Instant::now(),
+            last_deferred_check: None,
+            restart_once_running: false,
+            vetoed_until: Cell::new(None),
+            drained: RefCell::new(vec![]),
+            stop_grace: DOCKER_STOP_GRACE,
+        }
+    }
+
+    /// Docker Desktop detection settings, the defaults unless set
+    pub fn desktop(mut self, desktop: DesktopConfig) -> Self {
+        self.desktop = desktop;
+        self
+    }
+
+    /// dockerd integrity check settings, disabled unless set
+    pub fn integrity(mut self, integrity: IntegrityConfig) -> Self {
+        self.integrity = integrity;
+        self
+    }
+
+    /// One pass of the watcher loop, called about every second. Errors are the service manager's,
+    /// a change the watcher refused or gave up on is logged and tried again later
+    pub fn tick(&mut self) -> BackendResult<()> {
+        match self.watch() {
+            Err(e) if e.refused => {
+                info!("{}, trying again later", e);
+                Ok(())
+            }
+            res => res,
+        }
+    }
+
+    fn watch(&mut self) -> BackendResult<()> {
+        let mut docker_state = match self.backend.query_state(DOCKER_SERVICE_NAME)? {
+            Some(state) => state,
+            None => {
+                // docker deleted the service
+                if self.modified_docker {
+                    event!(Level::Info, Event::new(EventId::DockerDeleted).service(DOCKER_SERVICE_NAME), "docker deleted the service");
+                    self.modified_docker = false;
+                }
+                self.last_docker_state = None;
+                return Ok(());
+            }
+        };
+
+        let changed = self.last_docker_state != Some(docker_state);
+        if changed {
+            let old = self.last_docker_state.map_or("missing".to_string(), |s| s.to_string());
+            event!(
+                Level::Debug,
+                Event::new(EventId::DockerStateChanged).service(DOCKER_SERVICE_NAME).states(&old, docker_state),
+                "docker service {} -> {}", old, docker_state
+            );
+            self.last_docker_state = Some(docker_state);
+            self.docker_running_since = Some(Instant::now()).filter(|_| docker_state == DockerState::Running);
+        }
+
+        if self.modified_docker {
+            // docker stopped the process
+            if docker_state == DockerState::Stopped && changed {
+                info!("docker just stopped the service");
+                self.modified_docker = false;
+
+                // the next start picks up a patch written while docker was running
+                self.update_state(|state| state.pending_restart = None);
+            } else if docker_state == DockerState::Stopped {
+                // a config reset while docker is stopped is patched before docker starts again
+                if self.reconcile_due() {
+                    self.last_reconcile = Instant::now();
+                    self.modified_docker = !self.has_drifted()?;
+                }
+            } else if docker_state == DockerState::Running {
+                if self.restart_once_running && self.desktop_wait()?.is_none() {
+                    self.restart_once_running = false;
+                    self.restart_launched()?;
+                }
+
+                if self.reconcile_due() && self.desktop_wait()?.is_none() {
+                    self.last_reconcile = Instant::now();
+                    self.reconcile()?;
+                }
+
+                if self.deferred_check_due() && self.desktop_wait()?.is_none() {
+                    self.last_deferred_check = Some(Instant::now());
+                    self.restart_if_allowed()?;
+                }
+            }
+
+            return Ok(());
+        }
+
+        if self.vetoed() {
+            return Ok(());
+        }
+
+        match docker_state {
+            DockerState::Running => (),
+            // not launched yet, it's patched without a restart
+            DockerState::Stopped => return self.patch_stopped(docker_state),
+            DockerState::StartPending if self.backend.query_process_id(DOCKER_SERVICE_NAME)?.is_none() => {
+                return self.patch_stopped(docker_state);
+            }
+            // already launched unpatched, it's patched once it runs
+            _ => return Ok(()),
+        }
+
+        if let Some(reason) = self.desktop_wait()? {
+            debug!("waiting for docker desktop, {}", reason);
+            return Ok(());
+        }
+
+        // don't change service immediately once detected, otherwise it'll fail
+        std::thread::sleep(Duration::from_secs(2));
+        // requery again to make sure it's still up
+        docker_state = self.backend.query_state(DOCKER_SERVICE_NAME)?.unwrap_or(DockerState::Stopped);
+        if docker_state != DockerState::Running {
+            info!("docker state changed during 2 second wait: {:?}", docker_state);
+            return Ok(());
+        }
+
+        let config = match self.backend.query_config(DOCKER_SERVICE_NAME)? {
+            Some(config) => config,
+            None => return Ok(()),
+        };
+
+        // this service was already patched - update and exit
+        if patch::is_patched_config(&config, &self.config.flags) {
+            event!(
+                Level::Info,
+                Event::new(EventId::PatchAlreadyApplied).service(DOCKER_SERVICE_NAME),
+                "docker service already patched - updating status to modified"
+            );
+            self.modified_docker = true;
+            self.last_reconcile = Instant::now();
+            return Ok(());
+        }
+
+        info!("detected unmodified docker service");
+
+        // a failed attempt isn't retried until docker stops again, otherwise a broken docker
+        // service would be restarted every second
+        let attempt = Attempt::new(self.backend.target(), Trigger::Watcher, None);
+        if let Err(e) = self.apply_patch(&config, &attempt) {
+            error!("patch attempt {} failed: {}", attempt.correlation_id, e);
+        }
+
+        // a vetoed attempt didn't change anything and is tried again later
+        if self.vetoed() {
+            return Ok(());
+        }
+
+        self.modified_docker = true;
+        self.last_reconcile = Instant::now();
+
+        Ok(())
+    }
+
+    /// Patch docker while it isn't running, it starts with the patch and needs no restart. That's
+    /// the case for a service which was just created, is stopped, or whose start is pending but
+    /// which wasn't launched yet
+    fn patch_stopped(&mut self, docker_state: DockerState) -> BackendResult<()> {
+        // Desktop is recreating or starting docker, it's patched once the switch is done
+        if self.desktop_mode()? == Some(EngineMode::Switching) {
+            return Ok(());
+        }
+
+        let config = match self.backend.query_config(DOCKER_SERVICE_NAME)? {
+            Some(config) => config,
+            None => return Ok(()),
+        };
+
+        self.modified_docker = true;
+        self.last_reconcile = Instant::now();
+
+        if patch::is_patched_config(&config, &self.config.flags) {
+            debug!("docker service is {} and already patched", docker_state);
+            return Ok(());
+        }
+
+        info!("docker service is {}, patching it before it runs", docker_state);
+
+        // a failed attempt isn't retried every tick, the reconcile check picks it up again
+        let attempt = Attempt::new(self.backend.target(), Trigger::Watcher, None);
+        if let Err(e) = self.write_patch(&config, &attempt) {
+            error!("patch attempt {} failed: {}", attempt.correlation_id, e);
+            return Ok(());
+        }
+
+        // the SCM may have launched docker between checking and writing, then it can't be told
+        // which command line it got and it's restarted like one patched while running
+        if docker_state == DockerState::StartPending && self.backend.query_process_id(DOCKER_SERVICE_NAME)?.is_some() {
+            info!("docker was launched while it was patched, restarting it once it runs");
+            match self.config.restart_policy {
+                RestartPolicy::Immediate => self.restart_once_running = true,
+                RestartPolicy::Deferred => self.set_pending_restart(true),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Restart docker which may have been launched before its patch was written
+    fn restart_launched(&mut self) -> BackendResult<()> {
+        let config = match self.backend.query_config(DOCKER_SERVICE_NAME)? {
+            Some(config) => config,
+            None => return Ok(()),
+        };
+
+        let attempt = Attempt::new(self.backend.target(), Trigger::Watcher, None);
+        if let Err(e) = self.restart_docker(&config, &attempt) {
+            error!("restart {} failed: {}", attempt.correlation_id, e);
+        }
+
+        Ok(())
+    }
+
+    /// Whether docker's configured command line differs from the patch
+    fn has_drifted(&self) -> BackendResult<bool> {
+        Ok(match self.backend.query_config(DOCKER_SERVICE_NAME)? {
+            Some(config) => patch::has_drifted(&patch::split_command_line(&config.command_line), &self.config.flags),
+            None => false,
+        })
+    }
+
+    /// Where the watcher is, for panic reports
+    pub fn describe(&self) -> String {
+        let ago = |t: Instant| format!("{}s ago", t.elapsed().as_secs());
+        let vetoed = self.vetoed_until.get().filter(|t| Instant::now() < *t).map(|t| t.saturating_duration_since(Instant::now()));
+
+        format!(
+            "target: {}\ndocker: {}\nmodified docker: {}\nrestart once running: {}\ndocker running since: {}\n\
+             desktop mode: {}\nlast reconcile: {}\nlast deferred check: {}\nvetoed for: {}\ndrained containers: {}",
+            self.backend.target(),
+            self.last_docker_state.map_or("missing".to_string(), |s| s.to_string()),
+            self.modified_docker,
+            self.restart_once_running,
+            self.docker_running_since.map_or("not running".to_string(), ago),
+            self.last_desktop_mode.map_or("unknown".to_string(), |m| m.to_string()),
+            ago(self.last_reconcile),
+            self.last_deferred_check.map_or("never".to_string(), ago),
+            vetoed.map_or("not vetoed".to_string(), |d| format!("{}s more", d.as_secs())),
+            self.drained.borrow().len()
+        )
+    }
+
+    /// Handle a request from the control channel, sent by `user`
+    pub fn handle_request(&mut self, request: &Request, user: Option<String>) -> Reply {
+        event!(
+            Level::Info,
+            Event::new(EventId::ControlRequest),
+            "{:?} requested by {}", request.command, user.as_deref().unwrap_or("unknown user")
+        );
+
+        let res = match request.command {
+            Command::Repatch => self.patch_now(Trigger::ControlChannel, user),
+        };
+
+        match res {
+            Ok(message) => Reply::ok(message),
+            Err(e) => Reply::error(e.to_string()),
+        }
+    }
+
+    /// Patch and restart docker right away, regardless of the restart policy
+    pub fn patch_now(&mut self, trigger: Trigger, user: Option<String>) -> BackendResult<String> {
+        let attempt = Attempt::new(self.backend.target(), trigger, user);
+
+        self.repatch(&attempt).map_err(|e| {
+            error!("patch attempt {} failed: {}", attempt.correlation_id, e);
+            e
+        })
+    }
+
+    /// Put back docker's command line from before the patch, restarting docker if it's running and
+    /// `restart` is set
+    pub fn unpatch(&mut self, trigger: Trigger, user: Option<String>, restart: bool) -> BackendResult<String> {
+        let attempt = Attempt::new(self.backend.target(), trigger, user);
+
+        let patched = self
+            .backend
+            .query_config(DOCKER_SERVICE_NAME)?
+            .ok_or_else(|| BackendError::refused("the docker service isn't installed"))?;
+        if !patch::is_patched_config(&patched, &self.config.flags) {
+            return Ok("docker isn't patched".to_string());
+        }
+
+        let state = State::load_from(&self.state_path).unwrap_or_default();
+        let fallback = patch::unpatch_config(&patched, &self.config.flags);
+        let original = ServiceConfig {
+            command_line: state.original_command_line.unwrap_or(fallback.command_line),
+            display_name: state.original_display_name.unwrap_or(fallback.display_name),
+            ..patched.clone()
+        };
+
+        let restart = restart && self.backend.query_state(DOCKER_SERVICE_NAME)? == Some(DockerState::Running);
+        if restart {
+            self.check_dockerd(&original.command_line, &attempt)?;
+            self.stop_docker(&patched.command_line, &original.command_line, &attempt)?;
+        }
+
+        let res = self.backend.change_config(DOCKER_SERVICE_NAME, &original);
+        self.audit(&attempt, attempt.record(Action::Unpatch).image_paths(&patched.command_line, &original.command_line).result(&res));
+
+        if let Err(e) = res {
+            error!("failed to restore docker service: {}", e);
+            if restart {
+                let res = self.backend.start(DOCKER_SERVICE_NAME);
+                self.audit(&attempt, attempt.record(Action::Start).result(&res));
+                self.restore_containers(&attempt);
+            }
+            return Err(e);
+        }
+
+        event!(
+            Level::Info,
+            attempt.event(EventId::PatchRemoved),
+            "restored docker command line {}", original.command_line
+        );
+        self.update_state(|state| {
+            state.drift = None;
+            state.pending_restart = None;
+        });
+
+        if !restart {
+            return Ok(format!("restored docker command line {}, docker picks it up the next time it starts", original.command_line));
+        }
+
+        let res = self.backend.start(DOCKER_SERVICE_NAME);
+        self.audit(&attempt, attempt.record(Action::Start).result(&res));
+        self.restore_containers(&attempt);
+        res?;
+
+        Ok(format!("restored docker command line {} and restarted docker", original.command_line))
+    }
+
+    fn repatch(&mut self, attempt: &Attempt) -> BackendResult<String> {
+        if desktop::detect(&self.backend, &self.desktop)?.is_some_and(|d| d.mode == EngineMode::Switching) {
+            return Err(BackendError::refused("Docker Desktop is switching engines, try again once it's done"));
+        }
+
+        let config = self
+            .backend
+            .query_config(DOCKER_SERVICE_NAME)?
+            .ok_or_else(|| BackendError::refused("the docker service isn't installed"))?;
+        let running = self.backend.query_state(DOCKER_SERVICE_NAME)? == Some(DockerState::Running);
+        let drifted = patch::has_drifted(&patch::split_command_line(&config.command_line), &self.config.flags);
+
+        if !running {
+            if drifted {
+                self.write_patch(&config, attempt)?;
+                return Ok("patched docker, it isn't running so the patch applies when it starts".to_string());
+            }
+
+            return Ok("docker is already patched and isn't running".to_string());
+        }
+
+        let pending = State::load_from(&self.state_path).map(|s| s.pending_restart.is_some()).unwrap_or(false);
+        let res = if drifted {
+            self.patch_docker(&config, attempt).map(|_| "patched and restarted docker")
+        } else if pending {
+            self.restart_docker(&config, attempt).map(|_| "restarted docker to apply the pending patch")
+        } else {
+            Ok("docker is already patched and running with the patch")
+        };
+
+        if !self.vetoed() {
+            self.modified_docker = true;
+        }
+
+        res.map(ToString::to_string)
+    }
+
+    /// Why docker has to be left alone for Docker Desktop, None if it can be patched: Desktop
+    /// isn't installed, or it runs the Windows engine, which has been up for the settle time and
+    /// answers
+    fn desktop_wait(&mut self) -> BackendResult<Option<String>> {
+        let mode = match self.desktop_mode()? {
+            Some(mode) => mode,
+            None => return Ok(None),
+        };
+
+        if mode != EngineMode::Windows {
+            return Ok(Some(format!("it's {}", mode)));
+        }
+
+        let settle = Duration::from_secs(self.desktop.settle_secs);
+        if self.docker_running_since.is_none_or(|t| t.elapsed() < settle) {
+            return Ok(Some(format!("the windows engine has to run for {} seconds first", self.desktop.settle_secs)));
+        }
+
+        match engine::ping(&engine::connector(&self.desktop.windows_engine)) {
+            Ok(()) => Ok(None),
+            Err(e) => Ok(Some(format!("the windows engine doesn't answer yet: {}", e))),
+        }
+    }
+
+    /// Docker Desktop's engine mode, None if Desktop isn't installed. Mode changes are logged
+    fn desktop_mode(&mut self) -> BackendResult<Option<EngineMode>> {
+        let desktop = match desktop::detect(&self.backend, &self.desktop)? {
+            Some(desktop) => desktop,
+            None => return Ok(None),
+        };
+
+        if self.last_desktop_mode != Some(desktop.mode) {
+            event!(
+                Level::Info,
+                Event::new(EventId::DesktopModeChanged).service(DOCKER_DESKTOP_SERVICE_NAME),
+                "docker desktop: {}", desktop.mode
+            );
+            self.last_desktop_mode = Some(desktop.mode);
+        }
+
+        Ok(Some(desktop.mode))
+    }
+
+    fn reconcile_due(&self) -> bool {
+        self.config.reconcile_interval_secs > 0
+            && self.last_reconcile.elapsed() >= Duration::from_secs(self.config.reconcile_interval_secs)
+    }
+
+    /// A pre-stop hook vetoed and the retry delay hasn't passed yet
+    fn vetoed(&self) -> bool {
+        self.vetoed_until.get().is_some_and(|t| Instant::now() < t)
+    }
+
+    fn deferred_check_due(&self) -> bool {
+        self.config.restart_policy == RestartPolicy::Deferred
+            && self.last_deferred_check.is_none_or(|t| t.elapsed() >= DEFERRED_CHECK_INTERVAL)
+    }
+
+    /// Compare the live config with the patch and handle drift according to the drift policy
+    fn reconcile(&mut self) -> BackendResult<()> {
+        let config = match self.backend.query_config(DOCKER_SERVICE_NAME)? {
+            Some(config) => config,
+            None => return Ok(()),
+        };
+
+        let state = State::load_from(&self.state_path).unwrap_or_default();
+
+        if !patch::has_drifted(&patch::split_command_line(&config.command_line), &self.config.flags) {
+            if state.drift.is_some() {
+                event!(
+                    Level::Info,
+                    Event::new(EventId::DriftCorrected).service(DOCKER_SERVICE_NAME),
+                    "docker command line matches the patch again"
+                );
+                self.update_state(|state| {
+                    state.drift = None;
+                    state.metrics.drift_corrected += 1;
+                });
+            }
+
+            return Ok(());
+        }
+
+        // each drifted command line is reported and corrected once, a correction that failed isn't
+        // retried every interval. One that was vetoed is retried once the veto expired
+        let known = state.drift.as_ref().is_some_and(|d| d.command_line == config.command_line);
+        if known && (self.vetoed() || self.vetoed_until.get().is_none()) {
+            return Ok(());
+        }
+
+        if !known {
+            event!(
+                Level::Warn,
+                Event::new(EventId::DriftDetected).service(DOCKER_SERVICE_NAME),
+                "docker command line changed while running: {} (policy {})", config.command_line, self.config.drift_policy
+            );
+
+            let drift = Drift { command_line: config.command_line.clone(), detected_at: state::unix_time() };
+            self.update_state(|state| {
+                state.drift = Some(drift);
+                state.metrics.drift_detected += 1;
+            });
+        }
+
+        let attempt = Attempt::new(self.backend.target(), Trigger::Watcher, None);
+
+        let res = match self.config.drift_policy {
+            DriftPolicy::Report => return Ok(()),
+            DriftPolicy::Immediate => self.apply_patch(&config, &attempt),
+            DriftPolicy::NextRestart => self.write_patch(&config, &attempt).map(|_| {
+                event!(
+                    Level::Info,
+                    attempt.event(EventId::DriftPendingRestart),
+                    "patch written, docker runs unpatched until its next restart"
+                );
+                self.set_pending_restart(false);
+            }),
+        };
+
+        if let Err(e) = res {
+            error!("patch attempt {} failed: {}", attempt.correlation_id, e);
+            return Ok(());
+        }
+
+        self.update_state(|state| {
+            state.drift = None;
+            state.metrics.drift_corrected += 1;
+        });
+
+        Ok(())
+    }
+
+    /// Patch docker and restart it now or later, depending on the restart policy
+    fn apply_patch(&self, config: &ServiceConfig, attempt: &Attempt) -> BackendResult<()> {
+        match self.config.restart_policy {
+            RestartPolicy::Immediate => self.patch_docker(config, attempt),
+
+            RestartPolicy::Deferred => {
+                self.write_patch(config, attempt)?;
+                self.set_pending_restart(true);
+
+                event!(
+                    Level::Info,
+                    attempt.event(EventId::RestartDeferred),
+                    "docker restart deferred until a maintenance window{} or the patch-now command",
+                    if self.config.restart_when_idle { ", no running containers" } else { "" }
+                );
+
+                Ok(())
+            }
+        }
+    }
+
+    /// Restart docker for a deferred patch if a maintenance window is open or docker is idle
+    fn restart_if_allowed(&self) -> BackendResult<()> {
+        let state = State::load_from(&self.state_path).unwrap_or_default();
+        if !state.pending_restart.is_some_and(|p| p.deferred) || self.vetoed() {
+            return Ok(());
+        }
+
+        let reason = match self.restart_allowed() {
+            Some(reason) => reason,
+            None => return Ok(()),
+        };
+
+        let config = match self.backend.query_config(DOCKER_SERVICE_NAME)? {
+            Some(config) => config,
+            None => return Ok(()),
+        };
+
+        let attempt = Attempt::new(self.backend.target(), Trigger::Watcher, None);
+        event!(Level::Info, attempt.event(EventId::RestartPerformed), "restarting docker, {}", reason);
+
+        if let Err(e) = self.restart_docker(&config, &attempt) {
+            error!("restart {} failed: {}", attempt.correlation_id, e);
+        }
+
+        Ok(())
+    }
+
+    /// Why a deferred restart may happen now, None if it has to wait
+    fn restart_allowed(&self) -> Option<String> {
+        let now = chrono::Local::now().naive_local();
+
+        if let Some(window) = self.config.maintenance_windows.iter().find(|w| w.contains(now)) {
+            return Some(format!("inside maintenance window {}", window));
+        }
+
+        if self.config.restart_when_idle {
+            match engine::running_containers(&self.engine) {
+                Ok(containers) if containers.is_empty() => return Some("no containers are running".to_string()),
+                Ok(containers) => debug!("{} containers running", containers.len()),
+                Err(e) => debug!("couldn't list containers: {}", e),
+            }
+        }
+
+        None
+    }
+
+    /// Stop docker, patch its config and start it again
+    fn patch_docker(&self, config: &ServiceConfig, attempt: &Attempt) -> BackendResult<()> {
+        let patched = patch::patch_config(config, &self.config.flags);
+        self.verify_dockerd(&patched.command_line, config, &patched, attempt)?;
+
+        event!(Level::Info, attempt.event(EventId::PatchStarted), "stopping docker service");
+
+        self.stop_docker(&config.command_line, &patched.command_line, attempt)?;
+
+        let patched = match self.write_patch(config, attempt) {
+            Ok(patched) => patched,
+            Err(e) => {
+                let res = self.backend.start(DOCKER_SERVICE_NAME);
+                self.audit(attempt, attempt.record(Action::Start).result(&res));
+                self.restore_containers(attempt);
+                return Err(e);
+            }
+        };
+
+        self.start_docker(config, &patched, attempt)
+    }
+
+    /// Restart docker so it picks up a patch written earlier
+    fn restart_docker(&self, patched: &ServiceConfig, attempt: &Attempt) -> BackendResult<()> {
+        let state = State::load_from(&self.state_path).unwrap_or_default();
+        let original = ServiceConfig {
+            command_line: state.original_command_line.unwrap_or_else(|| patched.command_line.clone()),
+            display_name: state.original_display_name.unwrap_or_else(|| patched.display_name.clone()),
+            ..patched.clone()
+        };
+
+        self.verify_dockerd(&patched.command_line, &original, patched, attempt)?;
+        self.stop_docker(&original.command_line, &patched.command_line, attempt)?;
+        self.start_docker(&original, patched, attempt)
+    }
+
+    /// Run the pre-stop hook and stop docker, unless the hook vetoed
+    fn stop_docker(&self, old_command_line: &str, new_command_line: &str, attempt: &Attempt) -> BackendResult<()> {
+        self.vetoed_until.set(None);
+
+        if let Err(e) = hooks::run(&self.hooks, Stage::PreStop, &attempt.hook(old_command_line, new_command_line)) {
+            self.vetoed_until.set(Some(Instant::now() + Duration::from_secs(self.hooks.veto_retry_secs)));
+            event!(
+                Level::Warn,
+                attempt.event(EventId::HookVetoed),
+                "pre-stop hook {}, docker is left running until the next attempt in {} seconds", e, self.hooks.veto_retry_secs
+            );
+            return Err(BackendError::refused(format!("the pre-stop hook vetoed the patch, it {}", e)));
+        }
+
+        if self.config.graceful_restart {
+            self.drain_containers(attempt);
+        }
+
+        // stop service, failing is fine if docker stopped on its own in the meantime
+        let res = self.backend.stop(DOCKER_SERVICE_NAME);
+        self.audit(attempt, attempt.record(Action::Stop).result(&res));
+
+        // wait for service to stop, a docker hanging in stop pending mustn't block the watcher
+        let timeout = Duration::from_secs(self.config.container_stop_timeout_secs) + self.stop_grace;
+        let deadline = Instant::now() + timeout;
+        loop {
+            match self.backend.query_state(DOCKER_SERVICE_NAME)? {
+                Some(DockerState::Stopped) | None => {
+                    event!(
+                        Level::Info,
+                        attempt.event(EventId::PatchDockerStopped).states(DockerState::Running, DockerState::Stopped),
+                        "docker service stopped"
+                    );
+                    return Ok(());
+                }
+
+                Some(state) if Instant::now() >= deadline => {
+                    let error = format!("docker didn't stop within {} seconds, it's {}", timeout.as_secs(), state);
+                    event!(Level::Error, attempt.event(EventId::PatchFailed), "{}, leaving it alone", error);
+                    self.update_state(|state| state.metrics.patch_failures += 1);
+                    self.restore_containers(attempt);
+                    return Err(BackendError::refused(error));
+                }
+
+                _ => std::thread::sleep(Duration::from_millis(250)),
+            }
+        }
+    }
+
+    /// Start the patched docker
+    fn start_docker(&self, original: &ServiceConfig, patched: &ServiceConfig, attempt: &Attempt) -> BackendResult<()> {
+        info!("starting docker service");
+        let res = self.backend.start(DOCKER_SERVICE_NAME);
+        self.audit(attempt, attempt.record(Action::Start).result(&res));
+
+        if let Err(e) = res {
+            event!(Level::Error, attempt.event(EventId::PatchFailed), "patched docker service failed to start: {}", e);
+            self.update_state(|state| state.metrics.patch_failures += 1);
+            self.restore_containers(attempt);
+            self.run_hook(Stage::OnFailure, attempt, original, patched, Some(&e));
+            return Err(e);
+        }
+
+        event!(
+            Level::Info,
+            attempt.event(EventId::PatchDockerStarted).states(DockerState::Stopped, DockerState::Running),
+            "started docker service"
+        );
+        self.update_state(|state| state.pending_restart = None);
+        self.restore_containers(attempt);
+        self.run_hook(Stage::PostStart, attempt, original, patched, None);
+
+        Ok(())
+    }
+
+    /// Write the patched config without touching the running docker
+    fn write_patch(&self, config: &ServiceConfig, attempt: &Attempt) -> BackendResult<ServiceConfig> {
+        let patched = patch::patch_config(config, &self.config.flags);
+        self.verify_dockerd(&patched.command_line, config, &patched, attempt)?;
+
+        info!("patching docker service");
+
+        let res = self
+            .backend
+            .change_config(DOCKER_SERVICE_NAME, &patched)
+            .and_then(|_| self.backend.set_description(DOCKER_SERVICE_NAME, patch::PATCHED_DESCRIPTION));
+        self.audit(attempt, attempt.record(Action::ChangeConfig).image_paths(&config.command_line, &patched.command_line).result(&res));
+
+        if let Err(e) = res {
+            event!(Level::Error, attempt.event(EventId::PatchFailed), "failed to patch docker service: {}", e);
+            self.update_state(|state| state.metrics.patch_failures += 1);
+            self.run_hook(Stage::OnFailure, attempt, config, &patched, Some(&e));
+            return Err(e);
+        }
+
+        event!(
+            Level::Info,
+            attempt.event(EventId::PatchApplied),
+            "successfully patched docker service: {} -> {}", config.command_line, patched.command_line
+        );
+
+        self.update_state(|state| {
+            state.record_patch(&config.command_line, &config.display_name, &patched.command_line);
+            state.metrics.patches_applied += 1;
+        });
+        self.run_hook(Stage::PostPatch, attempt, config, &patched, None);
+
+        Ok(patched)
+    }
+
+    /// Refuse to patch or restart a dockerd failing the integrity check
+    fn verify_dockerd(&self, command_line: &str, original: &ServiceConfig, patched: &ServiceConfig, attempt: &Attempt) -> BackendResult<()> {
+        self.check_dockerd(command_line, attempt).inspect_err(|error| self.run_hook(Stage::OnFailure, attempt, original, patched, Some(error)))
+    }
+
+    /// Check the dockerd `command_line` starts before docker is started with it
+    fn check_dockerd(&self, command_line: &str, attempt: &Attempt) -> BackendResult<()> {
+        if attempt.verified.borrow().iter().any(|verified| verified == command_line) {
+            return Ok(());
+        }
+
+        match integrity::verify(&self.integrity, &attempt.target, command_line) {
+            Ok(verified) => {
+                if let Some(verified) = verified {
+                    debug!("{} passed, sha256 {}", verified.path.display(), verified.sha256);
+                }
+                attempt.verified.borrow_mut().push(command_line.to_string());
+                Ok(())
+            }
+
+            Err(e) => {
+                event!(Level::Error, attempt.event(EventId::IntegrityFailed), "refusing to start docker: {}", e);
+                self.update_state(|state| state.metrics.integrity_failures += 1);
+                Err(BackendError::refused(format!("dockerd failed the integrity check: {}", e)))
+            }
+        }
+    }
+
+    /// Stop the running containers before docker is stopped, restore_containers starts them again
+    fn drain_containers(&self, attempt: &Attempt) {
+        match drain::stop_running(&self.engine, self.config.container_stop_timeout_secs) {
+            Ok(drained) => {
+                if !drained.is_empty() {
+                    let names: Vec<&str> = drained.iter().map(|c| c.name.as_str()).collect();
+                    event!(
+                        Level::Info,
+                        attempt.event(EventId::ContainersStopped),
+                        "stopped {} containers: {}", drained.len(), names.join(", ")
+                    );
+                }
+
+                *self.drained.borrow_mut() = drained;
+            }
+
+            Err(e) => error!("couldn't list running containers, stopping docker stops them: {}", e),
+        }
+    }
+
+    /// Start the containers drained before docker was stopped, now that it was started again
+    fn restore_containers(&self, attempt: &Attempt) {
+        let drained = self.drained.take();
+        if drained.is_empty() {
+            return;
+        }
+
+        let not_restarted = drain::restore(&self.engine, &drained);
+        let restarted = drained.len() - not_restarted.len();
+
+        if not_restarted.is_empty() {
+            event!(Level::Info, attempt.event(EventId::ContainersRestarted), "restarted {} containers", restarted);
+        } else {
+            let list: Vec<String> = not_restarted.iter().map(ToString::to_string).collect();
+            event!(
+                Level::Warn,
+                attempt.event(EventId::ContainersNotRestarted),
+                "{} of {} containers didn't come back: {}", not_restarted.len(), drained.len(), list.join(", ")
+            );
+        }
+
+        self.update_state(|state| {
+            state.metrics.containers_restarted += restarted as u64;
+            state.metrics.containers_not_restarted += not_restarted.len() as u64;
+            state.containers_not_restarted = not_restarted;
+        });
+    }
+
+    /// Run a hook which can't veto, its failure is only reported
+    fn run_hook(&self, stage: Stage, attempt: &Attempt, original: &ServiceConfig, patched: &ServiceConfig, error: Option<&BackendError>) {
+        let context = hooks::Context {
+            error: error.map(ToString::to_string),
+            ..attempt.hook(&original.command_line, &patched.command_line)
+        };
+
+        if let Err(e) = hooks::run(&self.hooks, stage, &context) {
+            event!(Level::Warn, attempt.event(EventId::HookFailed), "{} hook {}", stage, e);
+        }
+    }
+
+    fn set_pending_restart(&self, deferred: bool) {
+        self.update_state(|state| state.pending_restart = Some(PendingRestart { since: state::unix_time(), deferred }));
+    }
+
+    /// Watcher actions run as SYSTEM, only control channel requests carry a user
+    fn audit(&self, attempt: &Attempt, record: Record) {
+        if let Err(e) = self.audit_log.append(record, attempt.user.clone()) {
+            error!("failed to write audit log: {}", e);
+        }
+    }
+
+    fn update_state(&self, f: impl FnOnce(&mut State)) {
+        if let Err(e) = State::update(&self.state_path, f) {
+            event!(Level::Error, Event::new(e.event_id()), "{}", e);
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::fs;
+    use std::path::Path;
+    use std::time::{Duration, Instant};
+
+    use super::Watcher;
+    use crate::audit::{Action, AuditLog, Trigger};
+    use crate::backend::{BackendError, ServiceBackend, ServiceState, Target};
+    use crate::config::{DriftPolicy, RestartPolicy, WatcherConfig};
+    use crate::hooks::HooksConfig;
+    use crate::integrity::{self, IntegrityConfig};
+    use crate::patch::{self, Flag};
+    use crate::schedule::MaintenanceWindow;
+    use crate::shared::*;
+    use crate::state::State;
+    use crate::testing::{FakeBackend, ScratchDir};
+
+    const DOCKERD: &str = "/usr/bin/dockerd -H fd://";
+
+    fn flags() -> Vec<Flag> {
+        vec![Flag::new("--exec-opt", Some("isolation=process"))]
+    }
+
+    fn patched(command_line: &str) -> String {
+        patch::join_command_line(&patch::patch_args(&patch::split_command_line(command_line), &flags()))
+    }
+
+    fn config() -> WatcherConfig {
+        WatcherConfig { flags: flags(), ..Default::default() }
+    }
+
+    /// A watcher keeping its state and audit log in `dir`
+    fn watcher(backend: &FakeBackend, config: WatcherConfig, dir: &Path) -> Watcher<FakeBackend> {
+        let engine = dir.join("docker.sock").display().to_string();
+        Watcher::new(backend.clone(), config, HooksConfig::default(), &engine, dir.join("state.json"), AuditLog::at(&dir.join("audit.log")))
+    }
+
+    /// A watcher which patched the running docker earlier and is due to reconcile
+    fn reconciling(backend: &FakeBackend, config: WatcherConfig, dir: &Path) -> Watcher<FakeBackend> {
+        let mut watcher = watcher(backend, config, dir);
+        watcher.modified_docker = true;
+        watcher.last_docker_state = Some(ServiceState::Running);
+        watcher.last_reconcile = Instant::now() - Duration::from_secs(watcher.config.reconcile_interval_secs);
+        watcher
+    }
+
+    /// Every day from `from` to `to` hours from now
+    fn window(from: i64, to: i64) -> MaintenanceWindow {
+        let now = chrono::Local::now();
+        let at = |hours| (now + chrono::Duration::hours(hours)).format("%H:%M").to_string();
+        format!("{}-{}", at(from), at(to)).parse().unwrap()
+    }
+
+    fn state(dir: &Path) -> State {
+        State::load_from(&dir.join("state.json")).unwrap_or_default()
+    }
+
+    fn actions(dir: &Path) -> Vec<Action> {
+        AuditLog::at(&dir.join("audit.log")).entries().unwrap().into_iter().map(|e| e.action).collect()
+    }
+
+    fn command_line(backend: &FakeBackend) -> String {
+        backend.config(DOCKER_SERVICE_NAME).unwrap().command_line
+    }
+
+    #[test]
+    fn drift_is_corrected_immediately() {
+        let dir = ScratchDir::new("watcher-drift");
+        let backend = FakeBackend::new(Target::Local).with(DOCKER_SERVICE_NAME, ServiceState::Running, DOCKERD);
+        let mut watcher = reconciling(&backend, config(), &dir);
+
+        watcher.tick().unwrap();
+
+        assert_eq!(command_line(&backend), patched(DOCKERD));
+        assert_eq!(backend.state(DOCKER_SERVICE_NAME), Some(ServiceState::Running));
+        assert_eq!(actions(&dir), vec![Action::Stop, Action::ChangeConfig, Action::Start]);
+
+        let state = state(&dir);
+        assert!(state.drift.is_none());
+        assert_eq!(state.metrics.drift_detected, 1);
+        assert_eq!(state.metrics.drift_corrected, 1);
+        assert_eq!(state.metrics.patches_applied, 1);
+    }
+
+    #[test]
+    fn drift_is_reported_once_until_corrected() {
+        let dir = ScratchDir::new("watcher-drift-report");
+        let backend = FakeBackend::new(Target::Local).with(DOCKER_SERVICE_NAME, ServiceState::Running, DOCKERD);
+        let mut watcher = reconciling(&backend, WatcherConfig { drift_policy: DriftPolicy::Report, ..config() }, &dir);
+
+        watcher.reconcile().unwrap();
+        watcher.reconcile().unwrap();
+
+        assert_eq!(command_line(&backend), DOCKERD);
+        assert!(actions(&dir).is_empty());
+        let reported = state(&dir);
+        assert_eq!(reported.drift.map(|d| d.command_line), Some(DOCKERD.to_string()));
+        assert_eq!(reported.metrics.drift_detected, 1);
+
+        // someone put the patch back by hand
+        let mut config = backend.config(DOCKER_SERVICE_NAME).unwrap();
+        config.command_line = patched(DOCKERD);
+        backend.change_config(DOCKER_SERVICE_NAME, &config).unwrap();
+        watcher.reconcile().unwrap();
+
+        let corrected = state(&dir);
+        assert!(corrected.drift.is_none());
+        assert_eq!(corrected.metrics.drift_corrected, 1);
+    }
+
+    #[test]
+    fn drift_waits_for_the_next_restart() {
+        let dir = ScratchDir::new("watcher-drift-next-restart");
+        let backend = FakeBackend::new(Target::Local).with(DOCKER_SERVICE_NAME, ServiceState::Running, DOCKERD);
+        let mut watcher = reconciling(&backend, WatcherConfig { drift_policy: DriftPolicy::NextRestart, ..config() }, &dir);
+
+        watcher.tick().unwrap();
+
+        // written, but docker keeps running with the old command line
+        assert_eq!(command_line(&backend), patched(DOCKERD));
+        assert_eq!(actions(&dir), vec![Action::ChangeConfig]);
+        let state = state(&dir);
+        assert!(state.pending_restart.is_some_and(|p| !p.deferred));
+        assert_eq!(state.metrics.drift_corrected, 1);
+    }
+
+    #[test]
+    fn deferred_restart_waits_for_the_maintenance_window() {
+        let dir = ScratchDir::new("watcher-deferred");
+        let backend = FakeBackend::new(Target::Local).with(DOCKER_SERVICE_NAME, ServiceState::Running, DOCKERD);
+        let config = WatcherConfig { restart_policy: RestartPolicy::Deferred, maintenance_windows: vec![window(2, 3)], ..config() };
+        let mut watcher = reconciling(&backend, config, &dir);
+
+        watcher.tick().unwrap();
+
+        // patched, but docker keeps running until the window opens
+        assert_eq!(command_line(&backend), patched(DOCKERD));
+        assert_eq!(actions(&dir), vec![Action::ChangeConfig]);
+        assert!(state(&dir).pending_restart.is_some_and(|p| p.deferred));
+
+        watcher.config.maintenance_windows = vec![window(-1, 1)];
+        watcher.last_deferred_check = None;
+        watcher.tick().unwrap();
+
+        assert_eq!(backend.state(DOCKER_SERVICE_NAME), Some(ServiceState::Running));
+        assert_eq!(actions(&dir), vec![Action::ChangeConfig, Action::Stop, Action::Start]);
+        assert!(state(&dir).pending_restart.is_none());
+    }
+
+    #[test]
+    fn docker_is_left_alone_while_desktop_switches() {
+        let dir = ScratchDir::new("watcher-desktop");
+        let backend = FakeBackend::new(Target::Local)
+            .with(DOCKER_SERVICE_NAME, ServiceState::Stopped, DOCKERD)
+            .with(DOCKER_DESKTOP_SERVICE_NAME, ServiceState::StartPending, "com.docker.service");
+        let mut watcher = watcher(&backend, config(), &dir);
+
+        watcher.tick().unwrap();
+        assert_eq!(command_line(&backend), DOCKERD);
+        assert!(!watcher.modified_docker);
+
+        // Desktop is up and runs linux containers, docker is patched before it's started
+        backend.start(DOCKER_DESKTOP_SERVICE_NAME).unwrap();
+        watcher.tick().unwrap();
+        assert_eq!(command_line(&backend), patched(DOCKERD));
+        assert_eq!(backend.state(DOCKER_SERVICE_NAME), Some(ServiceState::Stopped));
+    }
+
+    #[test]
+    fn running_windows_engine_settles_before_it_is_patched() {
+        let dir = ScratchDir::new("watcher-desktop-settle");
+        let backend = FakeBackend::new(Target::Local)
+            .with(DOCKER_SERVICE_NAME, ServiceState::Running, DOCKERD)
+            .with(DOCKER_DESKTOP_SERVICE_NAME, ServiceState::Running, "com.docker.service");
+        let mut watcher = watcher(&backend, config(), &dir);
+
+        watcher.tick().unwrap();
+
+        assert_eq!(command_line(&backend), DOCKERD);
+        assert!(actions(&dir).is_empty());
+        assert!(!watcher.modified_docker);
+    }
+
+    #[test]
+    fn stopped_docker_is_patched_without_starting_it() {
+        let dir = ScratchDir::new("watcher-stopped");
+        let backend = FakeBackend::new(Target::Local).with(DOCKER_SERVICE_NAME, ServiceState::Stopped, DOCKERD);
+        let mut watcher = watcher(&backend, config(), &dir);
+
+        watcher.tick().unwrap();
+
+        assert_eq!(command_line(&backend), patched(DOCKERD));
+        assert_eq!(backend.state(DOCKER_SERVICE_NAME), Some(ServiceState::Stopped));
+        assert_eq!(actions(&dir), vec![Action::ChangeConfig]);
+        assert_eq!(state(&dir).original_command_line.as_deref(), Some(DOCKERD));
+        assert!(watcher.modified_docker);
+    }
+
+    #[test]
+    fn pending_start_is_patched_before_docker_is_launched() {
+        let dir = ScratchDir::new("watcher-start-pending");
+        let backend = FakeBackend::new(Target::Local).with(DOCKER_SERVICE_NAME, ServiceState::StartPending, DOCKERD);
+        let mut watcher = watcher(&backend, config(), &dir);
+
+        watcher.tick().unwrap();
+
+        assert_eq!(command_line(&backend), patched(DOCKERD));
+        assert_eq!(actions(&dir), vec![Action::ChangeConfig]);
+        assert!(!watcher.restart_once_running);
+        assert!(state(&dir).pending_restart.is_none());
+    }
+
+    #[test]
+    fn config_reset_while_stopped_is_patched_again() {
+        let dir = ScratchDir::new("watcher-stopped-reset");
+        let backend = FakeBackend::new(Target::Local).with(DOCKER_SERVICE_NAME, ServiceState::Stopped, DOCKERD);
+        let mut watcher = reconciling(&backend, config(), &dir);
+        watcher.last_docker_state = Some(ServiceState::Stopped);
+
+        // the reconcile check notices the reset, the next pass patches it
+        watcher.tick().unwrap();
+        assert!(!watcher.modified_docker);
+        assert_eq!(command_line(&backend), DOCKERD);
+
+        watcher.tick().unwrap();
+        assert_eq!(command_line(&backend), patched(DOCKERD));
+        assert_eq!(backend.state(DOCKER_SERVICE_NAME), Some(ServiceState::Stopped));
+    }
+
+    #[test]
+    fn docker_hanging_in_stop_pending_is_given_up_on() {
+        let dir = ScratchDir::new("watcher-stop-timeout");
+        let backend = FakeBackend::new(Target::Local).with(DOCKER_SERVICE_NAME, ServiceState::Running, DOCKERD).hanging_stop();
+        let mut watcher = watcher(&backend, WatcherConfig { container_stop_timeout_secs: 0, ..config() }, &dir);
+        watcher.stop_grace = Duration::ZERO;
+
+        let e = watcher.patch_now(Trigger::Cli, None).unwrap_err();
+
+        assert!(e.refused);
+        assert_eq!(e.message, "docker didn't stop within 0 seconds, it's stop pending");
+        // the config isn't written under a docker which may still be running
+        assert_eq!(command_line(&backend), DOCKERD);
+        assert_eq!(actions(&dir), vec![Action::Stop]);
+        assert_eq!(state(&dir).metrics.patch_failures, 1);
+    }
+
+    /// A dockerd in `dir` and the command line starting it
+    fn dockerd(dir: &Path) -> String {
+        let path = dir.join("dockerd");
+        fs::write(&path, "#!/bin/sh\n").unwrap();
+        format!("{} -H fd://", path.display())
+    }
+
+    #[test]
+    fn dockerd_failing_the_integrity_check_is_not_patched() {
+        let dir = ScratchDir::new("watcher-integrity");
+        let dockerd = dockerd(&dir);
+        let backend = FakeBackend::new(Target::Local).with(DOCKER_SERVICE_NAME, ServiceState::Stopped, &dockerd);
+        let integrity = IntegrityConfig { enabled: true, sha256: vec!["0".repeat(64)], ..Default::default() };
+        let mut watcher = watcher(&backend, config(), &dir).integrity(integrity);
+
+        watcher.tick().unwrap();
+
+        assert_eq!(command_line(&backend), dockerd);
+        assert!(actions(&dir).is_empty());
+        let state = state(&dir);
+        assert_eq!(state.metrics.integrity_failures, 1);
+        assert_eq!(state.metrics.patches_applied, 0);
+
+        // once its hash is allowed it's patched
+        let sha256 = integrity::sha256_file(&dir.join("dockerd")).unwrap();
+        watcher.integrity.sha256.push(sha256);
+        watcher.patch_now(Trigger::Cli, None).unwrap();
+        assert_eq!(command_line(&backend), patched(&dockerd));
+    }
+
+    #[test]
+    fn running_dockerd_outside_the_allowed_dirs_is_not_restarted() {
+        let dir = ScratchDir::new("watcher-integrity-dirs");
+        let allowed = ScratchDir::new("watcher-integrity-allowed");
+        let dockerd = dockerd(&dir);
+        let backend = FakeBackend::new(Target::Local).with(DOCKER_SERVICE_NAME, ServiceState::Running, &dockerd);
+        let integrity = IntegrityConfig { enabled: true, allowed_dirs: vec![allowed.to_path_buf()], ..Default::default() };
+        let mut watcher = watcher(&backend, config(), &dir).integrity(integrity);
+
+        let e = watcher.patch_now(Trigger::Cli, None).unwrap_err();
+
+        assert!(e.refused);
+        assert!(e.message.starts_with("dockerd failed the integrity check"), "{}", e);
+        assert_eq!(backend.state(DOCKER_SERVICE_NAME), Some(ServiceState::Running));
+        assert_eq!(command_line(&backend), dockerd);
+        assert!(actions(&dir).is_empty());
+        assert_eq!(state(&dir).metrics.integrity_failures, 1);
+    }
+
+    #[test]
+    fn service_manager_errors_are_given_back_as_transient_or_fatal() {
+        let dir = ScratchDir::new("watcher-errors");
+        let backend = FakeBackend::new(Target::Local).with(DOCKER_SERVICE_NAME, ServiceState::Stopped, DOCKERD);
+        let mut watcher = watcher(&backend, config(), &dir);
+
+        // EBUSY, waiting may help
+        backend.fail(Some(std::io::Error::from_raw_os_error(16).into()));
+        assert!(watcher.tick().unwrap_err().is_transient());
+
+        // EACCES, it won't
+        backend.fail(Some(std::io::Error::from_raw_os_error(13).into()));
+        assert!(!watcher.tick().unwrap_err().is_transient());
+
+        backend.fail(Some(BackendError::new("the service manager is starting")));
+        assert!(watcher.tick().unwrap_err().is_transient());
+
+        // a change the watcher refused is tried again on its own
+        backend.fail(Some(BackendError::refused("the pre-stop hook vetoed")));
+        watcher.tick().unwrap();
+
+        backend.fail(None);
+        watcher.tick().unwrap();
+        assert_eq!(command_line(&backend), patched(DOCKERD));
+    }
+}
@@ -0,0 +1,236 @@
+// Command line definition
+// Kept apart from main so shell completions and the man page are generated from the same
+// definition the arguments are parsed with.
+
+use std::io::{self, Write};
+
+use clap::{App, AppSettings, Arg, ArgSettings};
+use clap_generate::generate;
+use clap_generate::generators::{Bash, PowerShell, Zsh};
+
+use super::batch;
+
+pub const BIN_NAME: &str = "docker-process-isolation-patcher";
+pub const SHELLS: [&str; 3] = ["powershell", "bash", "zsh"];
+
+pub fn app() -> App<'static> {
+    App::new(BIN_NAME)
+        .version("1.0")
+        .author("Cherryleafroad")
+        .about("Makes the docker service always run with the configured dockerd flags, process isolation mode by default on Windows (run with admin privileges)")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .arg(Arg::new("config")
+            .long("config")
+            .value_name("PATH")
+            .takes_value(true)
+            .global(true)
+            .about("Config file to use instead of config.toml beside the exe. The service always reads config.toml"))
+        .arg(Arg::new("json")
+            .long("json")
+            .global(true)
+            .about("Print the output of status, plan, batch, doctor, audit and compose as json"))
+        .arg(Arg::new("quiet")
+            .long("quiet")
+            .short('q')
+            .global(true)
+            .about("Only print errors and requested output"))
+        .arg(Arg::new("elevate")
+            .long("elevate")
+            .global(true)
+            .about("Run the command as administrator through a UAC prompt, if it needs it"))
+
+        .subcommand(App::new("install")
+            .visible_alias("install-service")
+            .about("Install the patcher service")
+            .arg(computer())
+            .arg(binary())
+            .arg(Arg::new("start")
+                .long("start")
+                .about("Start the service once it's installed")))
+        .subcommand(App::new("uninstall")
+            .visible_alias("uninstall-service")
+            .about("Stop and uninstall the patcher service")
+            .arg(computer()))
+        .subcommand(App::new("start")
+            .visible_alias("start-service")
+            .about("Start the patcher service")
+            .arg(computer()))
+        .subcommand(App::new("stop")
+            .visible_alias("stop-service")
+            .about("Stop the patcher service")
+            .arg(computer()))
+        .subcommand(App::new("run-service")
+            .setting(AppSettings::Hidden)
+            .about("Run the service, the service manager calls this. Don't run it directly"))
+
+        .subcommand(App::new("status")
+            .about("Show the patch state, pending restarts, drift and counters")
+            .arg(computer()))
+        .subcommand(App::new("plan")
+            .about("Show what patch-now would do, without changing anything")
+            .arg(computer()))
+        .subcommand(App::new("patch-now")
+            .visible_alias("repatch")
+            .about("Patch docker and restart it now, even if the restart is deferred")
+            .arg(computer())
+            .arg(Arg::new("no-service")
+                .long("no-service")
+                .about("Patch from this process instead of asking the running service. Always the case with --computer"))
+            .arg(wait()))
+        .subcommand(App::new("batch")
+            .about("Run a command on every computer of an inventory file and show the result per computer")
+            .arg(Arg::new("command")
+                .value_name("COMMAND")
+                .possible_values(&batch::Command::NAMES)
+                .required(true))
+            .arg(Arg::new("inventory")
+                .long("inventory")
+                .value_name("FILE")
+                .takes_value(true)
+                .required(true)
+                .about("Computer names, one per line. Blank lines and lines starting with # are skipped"))
+            .arg(binary()))
+        .subcommand(App::new("unpatch")
+            .about("Restore docker's command line from before the patch. Stop the patcher service first, or it patches docker again")
+            .arg(Arg::new("no-restart")
+                .long("no-restart")
+                .about("Don't restart docker, it picks up the change the next time it starts"))
+            .arg(wait()))
+
+        .subcommand(App::new("doctor")
+            .about("Diagnose why patching doesn't work"))
+        .subcommand(App::new("check-image")
+            .about("Check if an image can run in process isolation on this host")
+            .arg(Arg::new("path")
+                .value_name("PATH")
+                .required(true)
+                .about("Image manifest or config json, e.g. from docker manifest inspect -v")))
+        .subcommand(App::new("compose")
+            .about("Set isolation in docker-compose files the way the proxy policy in the config wants it, keeping comments and ordering")
+            .arg(Arg::new("files")
+                .value_name("FILE")
+                .multiple_values(true)
+                .required(true)
+                .about("Compose files to rewrite"))
+            .arg(Arg::new("check")
+                .long("check")
+                .about("Only show what would change, and exit with code 1 if anything would. For pre-commit hooks")))
+        .subcommand(App::new("audit")
+            .about("List the changes the patcher made to services, or verify the audit log wasn't tampered with")
+            .arg(Arg::new("action")
+                .value_name("ACTION")
+                .possible_values(&["list", "verify"])
+                .default_value("list")))
+        .subcommand(App::new("collect-diagnostics")
+            .about("Write logs, configs and doctor output into a zip for bug reports")
+            .arg(Arg::new("path")
+                .value_name("PATH")
+                .about("Zip to write, diagnostics-<time>.zip by default")))
+        .subcommand(App::new("run-proxy")
+            .about("Run the docker api proxy in the foreground"))
+
+        .subcommand(App::new("completions")
+            .about("Print shell completions")
+            .arg(Arg::new("shell")
+                .value_name("SHELL")
+                .possible_values(&SHELLS)
+                .required(true)))
+        .subcommand(App::new("man")
+            .about("Print the man page"))
+}
+
+fn computer() -> Arg<'static> {
+    Arg::new("computer")
+        .long("computer")
+        .value_name("NAME")
+        .takes_value(true)
+        .about("Manage the services of the named computer instead of this one. Needs administrator rights there, not here. Windows only")
+}
+
+fn binary() -> Arg<'static> {
+    Arg::new("binary")
+        .long("binary")
+        .value_name("PATH")
+        .takes_value(true)
+        .about("Path of the patcher exe on the target computer for install, this exe's path by default")
+}
+
+fn wait() -> Arg<'static> {
+    Arg::new("wait")
+        .long("wait")
+        .value_name("SECS")
+        .takes_value(true)
+        .default_value("60")
+        .validator(|v| v.parse::<u64>())
+        .about("How long to wait for another patcher process changing docker, like a running patch-now, before giving up")
+}
+
+/// Commands which change services or talk to the service, everything else works without admin.
+/// On a remote computer only the rights there count
+pub fn needs_admin(command: &str) -> bool {
+    matches!(command, "install" | "uninstall" | "start" | "stop" | "run-service" | "patch-now" | "unpatch")
+}
+
+pub fn write_completions(shell: &str, out: &mut dyn Write) {
+    let mut app = app();
+
+    match shell {
+        "powershell" => generate::<PowerShell, _>(&mut app, BIN_NAME, out),
+        "bash" => generate::<Bash, _>(&mut app, BIN_NAME, out),
+        "zsh" => generate::<Zsh, _>(&mut app, BIN_NAME, out),
+        _ => unreachable!("clap only accepts the shells in SHELLS"),
+    }
+}
+
+pub fn write_man_page(out: &mut dyn Write) -> io::Result<()> {
+    let app = app();
+
+    writeln!(out, ".TH {} 1", BIN_NAME.to_uppercase())?;
+    writeln!(out, ".SH NAME\n{} \\- {}", BIN_NAME, roff(app.get_about().unwrap_or("")))?;
+    writeln!(out, ".SH SYNOPSIS\n\\fB{}\\fR [OPTIONS] COMMAND [ARGS]", BIN_NAME)?;
+
+    writeln!(out, ".SH OPTIONS")?;
+    for arg in app.get_arguments().filter(|a| documented(a)) {
+        write_arg(out, arg)?;
+    }
+
+    writeln!(out, ".SH COMMANDS")?;
+    for command in app.get_subcommands().filter(|c| !c.is_set(AppSettings::Hidden)) {
+        writeln!(out, ".SS {}", command.get_name())?;
+        writeln!(out, "{}", roff(command.get_about().unwrap_or("")))?;
+
+        for arg in command.get_arguments().filter(|a| documented(a)) {
+            write_arg(out, arg)?;
+        }
+    }
+
+    writeln!(out, ".SH FILES\n.TP\nconfig.toml\nConfig, beside the exe\n.TP\nstate.json\nPatch state, beside the exe\n.TP\nstate\\-<computer>.json\nPatch state of a remote computer, beside the exe\n.TP\naudit.jsonl\nAudit log, beside the exe\n.TP\n/etc/systemd/system/docker.service.d/99\\-docker\\-process\\-isolation\\-patcher.conf\nPatched docker command line on Linux. Deleting it and running systemctl daemon\\-reload undoes the patch")
+}
+
+/// --help and --version are on every command, they'd only repeat in each section
+fn documented(arg: &Arg) -> bool {
+    arg.get_name() != "help" && arg.get_name() != "version"
+}
+
+fn write_arg(out: &mut dyn Write, arg: &Arg) -> io::Result<()> {
+    let mut name = match (arg.get_short(), arg.get_long()) {
+        (Some(short), Some(long)) => format!("\\fB\\-{}\\fR, \\fB\\-\\-{}\\fR", short, roff(long)),
+        (None, Some(long)) => format!("\\fB\\-\\-{}\\fR", roff(long)),
+        _ => format!("\\fI{}\\fR", roff(&arg.get_name().to_uppercase())),
+    };
+
+    if arg.get_long().is_some() && arg.is_set(ArgSettings::TakesValue) {
+        name.push_str(&format!(" \\fI{}\\fR", roff(&arg.get_name().to_uppercase())));
+    }
+
+    let mut about = roff(arg.get_about().unwrap_or(""));
+    if let Some(values) = arg.get_possible_values() {
+        about.push_str(&format!(". One of {}", roff(&values.join(", "))));
+    }
+
+    writeln!(out, ".TP\n{}\n{}", name, about)
+}
+
+fn roff(text: &str) -> String {
+    text.replace('\\', "\\e").replace('-', "\\-")
+}
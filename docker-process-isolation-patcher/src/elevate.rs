@@ -0,0 +1,115 @@
+// Relaunching elevated through UAC
+// `--elevate` runs the same command line again with the runas verb, which shows the UAC prompt.
+// The elevated process gets its own console window, its exit code is passed back. On Linux being
+// elevated means running as root, and there's no prompt to relaunch through.
+
+use std::io;
+
+/// Whether this process runs as administrator
+#[cfg(windows)]
+pub fn is_elevated() -> bool {
+    is_elevated::is_elevated()
+}
+
+/// Whether this process runs as root
+#[cfg(not(windows))]
+pub fn is_elevated() -> bool {
+    use std::os::unix::fs::MetadataExt;
+
+    // /proc/self belongs to the effective user of the process
+    std::fs::metadata("/proc/self").is_ok_and(|m| m.uid() == 0)
+}
+
+/// Run this exe elevated with `args`, wait for it and return its exit code
+#[cfg(windows)]
+pub fn relaunch(args: &[String]) -> io::Result<i32> {
+    use std::ffi::OsStr;
+    use std::os::windows::ffi::OsStrExt;
+    use winapi::shared::minwindef::DWORD;
+    use winapi::um::handleapi::CloseHandle;
+    use winapi::um::processthreadsapi::GetExitCodeProcess;
+    use winapi::um::shellapi::{ShellExecuteExW, SEE_MASK_NOCLOSEPROCESS, SHELLEXECUTEINFOW};
+    use winapi::um::synchapi::WaitForSingleObject;
+    use winapi::um::winbase::INFINITE;
+    use winapi::um::winuser::SW_SHOWNORMAL;
+
+    let wide = |s: &OsStr| s.encode_wide().chain(Some(0)).collect::<Vec<u16>>();
+    let verb = wide(OsStr::new("runas"));
+    let exe = wide(std::env::current_exe()?.as_os_str());
+    let params = wide(OsStr::new(&join_args(args)));
+    // elevated processes start in system32 otherwise, breaking relative paths
+    let dir = wide(std::env::current_dir()?.as_os_str());
+
+    let mut info: SHELLEXECUTEINFOW = unsafe { std::mem::zeroed() };
+    info.cbSize = std::mem::size_of::<SHELLEXECUTEINFOW>() as DWORD;
+    info.fMask = SEE_MASK_NOCLOSEPROCESS;
+    info.lpVerb = verb.as_ptr();
+    info.lpFile = exe.as_ptr();
+    info.lpParameters = params.as_ptr();
+    info.lpDirectory = dir.as_ptr();
+    info.nShow = SW_SHOWNORMAL;
+
+    if unsafe { ShellExecuteExW(&mut info) } == 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    if info.hProcess.is_null() {
+        return Err(io::Error::other("the elevated process wasn't started"));
+    }
+
+    let mut code: DWORD = 0;
+    let res = unsafe {
+        WaitForSingleObject(info.hProcess, INFINITE);
+        GetExitCodeProcess(info.hProcess, &mut code)
+    };
+    let err = io::Error::last_os_error();
+    unsafe { CloseHandle(info.hProcess) };
+
+    if res == 0 {
+        return Err(err);
+    }
+
+    Ok(code as i32)
+}
+
+#[cfg(not(windows))]
+pub fn relaunch(_args: &[String]) -> io::Result<i32> {
+    Err(io::Error::other("--elevate is only supported on Windows, use sudo"))
+}
+
+/// Whether `e` is ERROR_CANCELLED, the user declined the UAC prompt
+pub fn is_cancelled(e: &io::Error) -> bool {
+    e.raw_os_error() == Some(1223)
+}
+
+/// Join arguments so CommandLineToArgvW splits them back unchanged
+pub fn join_args(args: &[String]) -> String {
+    args.iter().map(|arg| quote(arg)).collect::<Vec<String>>().join(" ")
+}
+
+fn quote(arg: &str) -> String {
+    if !arg.is_empty() && !arg.contains([' ', '\t', '"']) {
+        return arg.to_string();
+    }
+
+    let mut quoted = String::from("\"");
+    let mut backslashes = 0;
+
+    for c in arg.chars() {
+        if c == '\\' {
+            backslashes += 1;
+            continue;
+        }
+
+        // backslashes are only special in front of a quote
+        let escaped = if c == '"' { backslashes * 2 + 1 } else { backslashes };
+        quoted.push_str(&"\\".repeat(escaped));
+        quoted.push(c);
+        backslashes = 0;
+    }
+
+    // the closing quote follows, so trailing backslashes are doubled
+    quoted.push_str(&"\\".repeat(backslashes * 2));
+    quoted.push('"');
+    quoted
+}
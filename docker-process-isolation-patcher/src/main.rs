@@ -1,28 +1,57 @@
-#[cfg(windows)]
-
 use std::error::Error;
-use std::ffi::OsString;
 use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::time::Duration;
 
-use clap::{App, Arg};
-use is_elevated::is_elevated;
-use log::{error, info};
-use windows_service::{
-    service::{ServiceAccess, ServiceErrorControl, ServiceInfo, ServiceStartType, ServiceState, ServiceType},
-    service_manager::{ServiceManager, ServiceManagerAccess}
-};
-use windows_service::service::Service;
-
+use clap::ArgMatches;
 use human_panic_logger::setup_panic_logger;
-use shared::*;
+use log::{error, info, warn, Level};
+
+use patcher::audit::{AuditLog, Trigger};
+use patcher::backend::{ServiceBackend, ServiceState, Target};
+use patcher::config::Config;
+use patcher::event::{Event, EventId};
+use patcher::lock::{LockError, PatchLock};
+use patcher::manage::{Outcome, PatcherService};
+use patcher::shared::*;
+use patcher::state::State;
+use patcher::watcher::Watcher;
+use patcher::PlatformBackend;
+use patcher::{
+    audit, backend, backoff, batch, compat, compose, config, control, crash, diagnostics, doctor, event, exit, lock, logging, patch, permissions, plan,
+    proxy, shared, state, status, watcher,
+};
 
+mod cli;
+mod elevate;
 mod service;
-mod shared;
+
+use elevate::is_elevated;
+
+/// same as ERROR_ELEVATION_REQUIRED, for commands needing admin run without it
+const EXIT_ELEVATION_REQUIRED: i32 = 740;
+
+#[cfg(windows)]
+const RUN_ELEVATED: &str = "Please run it from an elevated prompt, or add --elevate";
+#[cfg(not(windows))]
+const RUN_ELEVATED: &str = "Please run it as root";
+
+/// set by --quiet
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+/// println! for progress and success messages, which --quiet hides
+macro_rules! say {
+    ( $($t:tt)* ) => {
+        if !QUIET.load(Ordering::Relaxed) {
+            println!($($t)*);
+        }
+    }
+}
 
 macro_rules! print_flush {
     ( $($t:tt)* ) => {
-        {
+        if !QUIET.load(Ordering::Relaxed) {
             let mut h = std::io::stdout();
             write!(h, $($t)* ).unwrap();
             h.flush().unwrap();
@@ -31,272 +60,667 @@ macro_rules! print_flush {
 }
 
 fn main() {
-    let log_path = std::env::current_exe().unwrap().with_file_name("app.log");
-    setup_panic_logger!(log_path);
+    let matches = cli::app().get_matches();
+    let (command, args) = matches.subcommand().unwrap();
+
+    QUIET.store(args.is_present("quiet"), Ordering::Relaxed);
+    let config_path = args.value_of("config").map_or_else(Config::default_path, PathBuf::from);
+
+    // the logging section can't be applied from a broken config, the commands needing the rest of
+    // the config report the error themselves. Only the service refuses an insecure one here
+    let config = if command == "run-service" { Config::load_from(&config_path) } else { Config::read_from(&config_path) };
+    let logging_config = config.as_ref().map(|c| c.logging.clone()).unwrap_or_default();
+
+    // the service owns the log, it's the one process writing to it for long
+    let log_dir = logging::init(&logging_config, command == "run-service").unwrap_or_else(|e| {
+        eprintln!("Failed to set up logging: {}", e);
+        logging::log_dir(&logging_config)
+    });
+
+    let report_path = crash::report_path(&log_dir);
+    setup_panic_logger!(report_path.clone());
+    crash::add_context(report_path);
+
+    match &config {
+        Ok(config) => crash::set_config(config),
+        Err(e) => warn!("{}, using the default logging config", e),
+    }
+
+    // commands only reading the config go on with it, the ones changing docker refuse it
+    if command != "run-service" {
+        if let Err(e) = Config::check_permissions(&config_path) {
+            let message = format!("{}, the service and the commands changing docker refuse the config", e);
+            warn!("{}", message);
+            eprintln!("Warning: {}", message);
+        }
+    }
+
+    // docker counts as patched whatever its command line, the service refuses to start like that
+    if let Some(e) = config.as_ref().ok().and_then(|c| c.watcher.check_flags().err()) {
+        warn!("{}", e);
+        eprintln!("Warning: {}", e);
+    }
+
+    if let Err(e) = run(command, args, &config_path) {
+        event!(Level::Error, Event::new(EventId::CliError), "Caught error: {:?}", e);
+
+        if is_access_denied(e.as_ref()) {
+            // elevating here doesn't help with a remote computer
+            if let Target::Remote(computer) = Target::from_computer(args.value_of("computer")) {
+                eprintln!("Access denied on {}: {}. Your account needs administrator rights there", computer, e);
+                std::process::exit(1);
+            }
+
+            eprintln!("Access denied: {}. {}", e, RUN_ELEVATED);
+            std::process::exit(EXIT_ELEVATION_REQUIRED);
+        }
 
-    if let Err(e) = run() {
-        error!("Caught error: {:?}", e);
         std::process::exit(1);
     }
 }
 
-fn run() -> windows_service::Result<()> {
-    let matches = App::new("Docker Process Isolation Service")
-        .version("1.0")
-        .author("Cherryleafroad")
-        .about("Makes docker Windows service always run in process isolation mode (run with admin privileges)")
-        .arg(Arg::new("command")
-            .about("\"install-service\" to install the service.\n\"uninstall-service\" to uninstall the service.\n\"start-service\" to start the service.\n\"run-service\" to run the service (cannot be used directly)\n\"stop-service\" to stop the service.")
-            .required(true)
-            .index(1))
-        .get_matches();
+/// Whether the error is ERROR_ACCESS_DENIED (EACCES on Linux) from the service manager or a file
+fn is_access_denied(e: &(dyn Error + 'static)) -> bool {
+    // ERROR_ACCESS_DENIED
+    #[cfg(windows)]
+    const ACCESS_DENIED: i32 = 5;
+    // EACCES
+    #[cfg(not(windows))]
+    const ACCESS_DENIED: i32 = 13;
+
+    #[cfg(windows)]
+    if let Some(windows_service::Error::Winapi(e)) = e.downcast_ref::<windows_service::Error>() {
+        return e.raw_os_error() == Some(ACCESS_DENIED);
+    }
 
-    if !is_elevated() {
-        println!("Please run as administrator");
-        info!("main::run:: tried to run without administrator");
-        return Ok(())
+    if let Some(e) = e.downcast_ref::<backend::BackendError>() {
+        return e.code == Some(ACCESS_DENIED);
     }
 
-    let command = matches.value_of("command").unwrap();
+    e.downcast_ref::<std::io::Error>().is_some_and(|e| e.raw_os_error() == Some(ACCESS_DENIED))
+}
 
-    match command {
-        "install-service" => {
-            let manager_access = ServiceManagerAccess::CONNECT | ServiceManagerAccess::CREATE_SERVICE;
-            let service_manager = ServiceManager::local_computer(None::<&str>, manager_access)?;
-
-            let service_access = ServiceAccess::QUERY_STATUS;
-            let res = service_manager.open_service(SERVICE_NAME, service_access);
-
-            if let Err(e) = res {
-                if let Some(code) = std::io::Error::last_os_error().raw_os_error() {
-                    // ERROR_SERVICE_DOES_NOT_EXIST = 1060
-                    if code == 1060 {
-                        let service_binary_path = std::env::current_exe().unwrap();
-
-                        let service_info = ServiceInfo {
-                            name: OsString::from(SERVICE_NAME),
-                            display_name: OsString::from("Docker Process Isolation Patcher"),
-                            service_type: ServiceType::OWN_PROCESS,
-                            start_type: ServiceStartType::AutoStart,
-                            error_control: ServiceErrorControl::Normal,
-                            executable_path: service_binary_path,
-                            launch_arguments: vec![OsString::from("run-service")],
-                            dependencies: vec![],
-                            account_name: None, // run as System
-                            account_password: None
-                        };
-
-                        let service = service_manager.create_service(&service_info, ServiceAccess::CHANGE_CONFIG)?;
-                        service.set_description("Docker Process Isolation Manager will automatically set Windows docker service to run in process isolation mode")?;
-
-                        println!("Installed service");
-                        info!("main::run::install-service: installed service");
-                    } else {
-                        if let Some(s) = e.source() {
-                            error!("main::run::install-service: {}", s.to_string());
-                        }
-
-                        Result::Err(e)?
-                    }
-                }
+/// Exit with EXIT_ELEVATION_REQUIRED, or rerun elevated with --elevate
+fn require_elevation(command: &str, args: &ArgMatches) -> ! {
+    if !args.is_present("elevate") {
+        info!("{} needs administrator", command);
+        eprintln!("{} changes services and needs administrator rights. {}", command, RUN_ELEVATED);
+        std::process::exit(EXIT_ELEVATION_REQUIRED);
+    }
+
+    let relaunch_args: Vec<String> = std::env::args().skip(1).filter(|a| a != "--elevate").collect();
+    info!("relaunching elevated: {}", elevate::join_args(&relaunch_args));
+
+    match elevate::relaunch(&relaunch_args) {
+        Ok(code) => {
+            say!("The elevated {} finished with exit code {}", command, code);
+            std::process::exit(code);
+        }
+
+        Err(e) => {
+            if elevate::is_cancelled(&e) {
+                eprintln!("Elevation was cancelled");
             } else {
-                println!("Service already installed. Try the uninstall-service command");
-                info!("main::run::install-service: service already installed");
+                error!("failed to relaunch elevated: {}", e);
+                eprintln!("Failed to run elevated: {}", e);
             }
-        },
 
-        "start-service" => {
-            let manager_access = ServiceManagerAccess::CONNECT;
-            let service_manager = ServiceManager::local_computer(None::<&str>, manager_access)?;
-
-            let res = service_manager.open_service(SERVICE_NAME,
-                ServiceAccess::START | ServiceAccess::QUERY_STATUS
-            );
-
-            match res {
-                Ok(service) => {
-                    let status = service.query_status()?.current_state;
-                    match status {
-                        ServiceState::Stopped => {
-                            let res = service.start(&[] as &[&str]);
-
-                            if let Err(e) = res {
-                                error!("main::run::start-service: failed to start service: {:?}", e);
-                                println!("Failed to start service");
-                            } else {
-                                info!("main::run::start-service: started service");
-                                println!("Started service");
-                            }
-                        }
-
-                        ServiceState::Running => {
-                            info!("main::run::start-service: service already running");
-                            println!("Service already running");
-                        }
-
-                        _ => {
-                            info!("main::run::start-service: service not in running or stopped state - service state: {:?}", status);
-                            println!("Service neither stopped nor running. Please try again");
-                        }
-                    }
-                }
+            std::process::exit(EXIT_ELEVATION_REQUIRED);
+        }
+    }
+}
 
-                Err(_) => {
-                    info!("main::run::start-service: tried to start service, but it's missing");
-                    println!("Service not found. Is it installed?");
-                }
-            }
+fn run(command: &str, args: &ArgMatches, config_path: &Path) -> Result<(), Box<dyn Error>> {
+    let json = args.is_present("json");
+    let target = Target::from_computer(args.value_of("computer"));
+
+    match command {
+        // only reads a file, no need for admin
+        "check-image" => return check_image(args.value_of("path").unwrap(), &Config::read_from(config_path)?),
+
+        // only touches the files it's given
+        "compose" => return run_compose(config_path, args, json),
+
+        // read only, and reports missing elevation itself
+        "doctor" => return run_doctor(config_path, json),
+
+        // read only, services that can't be queried are reported as errors
+        "status" => return run_status(config_path, &target, json),
+        "plan" => return run_plan(config_path, &target, json),
+
+        // only needs rights on the computers of the inventory
+        "batch" => return run_batch(config_path, args, json),
+
+        // the log sits beside the exe, readable without admin
+        "audit" => return run_audit(args.value_of("action").unwrap(), json),
+
+        "collect-diagnostics" => {
+            let default_path = format!("diagnostics-{}.zip", state::unix_time());
+            return collect_diagnostics(config_path, args.value_of("path").unwrap_or(&default_path));
+        }
+
+        "completions" => {
+            cli::write_completions(args.value_of("shell").unwrap(), &mut std::io::stdout());
+            return Ok(());
         }
 
-        "stop-service" => {
-            let manager_access = ServiceManagerAccess::CONNECT;
-            let service_manager = ServiceManager::local_computer(None::<&str>, manager_access)?;
+        "man" => return Ok(cli::write_man_page(&mut std::io::stdout())?),
 
-            let service_access = ServiceAccess::QUERY_STATUS | ServiceAccess::STOP;
-            let res = service_manager.open_service(SERVICE_NAME, service_access);
-            if let Ok(service) = res {
-                stop_service(&service, true)?;
-            } else {
-                info!("main::run::stop-service: tried to stop service, but it's missing");
-                println!("Service not found. Is it installed?");
+        _ => (),
+    }
+
+    // a remote SCM checks the rights of the user over the network, elevating here doesn't help
+    if cli::needs_admin(command) && target.is_local() && !is_elevated() {
+        require_elevation(command, args);
+    }
+
+    match command {
+        "install" | "uninstall" | "start" | "stop" => manage_service(command, args, &target)?,
+
+        "run-service" => run_service()?,
+
+        "run-proxy" => {
+            let config = Config::load_from(config_path).map_err(|e| {
+                error!("{}", e);
+                println!("Failed to load config: {}", e);
+                e
+            })?;
+
+            info!("running docker api proxy in the foreground");
+            say!("Proxying {} on {}", config.proxy.upstream, config.proxy.listen);
+
+            proxy::run(&config)?;
+        }
+
+        "patch-now" => patch_now(config_path, &target, args.is_present("no-service"), lock_wait(args))?,
+
+        "unpatch" => unpatch(config_path, !args.is_present("no-restart"), lock_wait(args))?,
+
+        _ => unreachable!("clap only accepts the commands in cli::app"),
+    }
+
+    Ok(())
+}
+
+/// install, uninstall, start and stop of the patcher service
+fn manage_service(command: &str, args: &ArgMatches, target: &Target) -> Result<(), Box<dyn Error>> {
+    let backend = PlatformBackend::connect(target)?;
+    let audit_log = AuditLog::open();
+    let service = PatcherService::new(&backend, &audit_log, audit::current_user());
+
+    match command {
+        "install" => {
+            let binary = args.value_of("binary").map_or_else(|| std::env::current_exe().unwrap(), PathBuf::from);
+            if !target.is_local() && args.value_of("binary").is_none() {
+                say!("Installing with {}, the exe must be at the same path on {}. Pass --binary otherwise", binary.display(), target);
+            }
+
+            report(service.install(&binary)?);
+            secure_install(&binary, target)?;
+
+            if args.is_present("start") {
+                report(service.start()?);
             }
         }
 
-        "uninstall-service" => {
-            let manager_access = ServiceManagerAccess::CONNECT;
-            let service_manager = ServiceManager::local_computer(None::<&str>, manager_access)?;
-
-            let service_access = ServiceAccess::QUERY_STATUS | ServiceAccess::STOP | ServiceAccess::DELETE;
-            let res = service_manager.open_service(SERVICE_NAME, service_access);
-            if let Ok(service) = res {
-                stop_service(&service, false)?;
-
-                if let Some(e)  = service.delete().err() {
-                    if let Some(code) = std::io::Error::last_os_error().raw_os_error() {
-                        // bubble up error if not the right one
-                        // ERROR_SERVICE_MARKED_FOR_DELETE - it's already uninstalled
-                        if code == 1072 {
-                            info!("main::run::uninstall-service: tried to uninstall missing service - ERROR_SERVICE_MARKED_FOR_DELETE");
-                            println!("Service not found. Is it installed? If Windows services manager is open, please close it to let the service delete");
-                        } else {
-                            error!("main::run::uninstall-service: {:?}", e);
-                            println!("Failed to uninstall service");
-                            Result::Err(e)?
-                        }
-                    }
-                } else {
-                    info!("main::run::uninstall-service: uninstalled service");
-                    println!("Uninstalled service");
+        "uninstall" => report(service.uninstall()?),
+
+        "start" => report(service.start()?),
+
+        "stop" => {
+            let mut printed = false;
+            let outcome = service.stop(|| {
+                if !printed {
+                    print_flush!("Stopping service");
+                    printed = true;
                 }
-            } else {
-                info!("main::run::uninstall-service: tried to uninstall missing service");
-                println!("Service not found. Is it installed?");
+                print_flush!(".");
+            })?;
+
+            if printed {
+                say!();
             }
+            report(outcome);
         }
 
-        "run-service" => {
-            let manager_access = ServiceManagerAccess::CONNECT;
-            let service_manager = ServiceManager::local_computer(None::<&str>, manager_access)?;
-
-            let service_access = ServiceAccess::QUERY_STATUS;
-            let res = service_manager.open_service(SERVICE_NAME, service_access);
-
-            if let Ok(service) = res {
-                let service_status = service.query_status()?.current_state;
-
-                match service_status {
-                    ServiceState::Stopped | ServiceState::StartPending => {
-                        info!("main::run::run-service: running service");
-                        service::run()?;
-
-                        if let Some(code) = std::io::Error::last_os_error().raw_os_error() {
-                            // this was run directly
-                            // ERROR_FAILED_SERVICE_CONTROLLER_CONNECT
-                            if code == 1063 {
-                                info!("main::run::run-service: tried to run service directly - ERROR_FAILED_SERVICE_CONTROLLER_CONNECT");
-                                println!("Do not run directly. Please use the start-service command");
-                            }
-                        }
-                    }
-
-                    _ => {
-                        info!("main::run::run-service: tried to run service, but its status is {:?}", service_status);
-                        println!("Service already running");
-                    }
+        _ => unreachable!("only called for the service commands"),
+    }
+
+    Ok(())
+}
+
+/// Let only administrators change the files the service reads, it runs as SYSTEM
+fn secure_install(binary: &Path, target: &Target) -> Result<(), Box<dyn Error>> {
+    let dir = binary.parent().unwrap();
+
+    if !target.is_local() {
+        say!("Make sure only administrators can change {} on {}, the service trusts the files in it", dir.display(), target);
+        return Ok(());
+    }
+
+    let secured = permissions::secure_exe_dir(binary).map_err(|e| {
+        println!("Failed to lock down {}: {}", dir.display(), e);
+        e
+    })?;
+    info!("secured {} paths in {}", secured.len(), dir.display());
+
+    // the service logs to a configured directory as SYSTEM too
+    if let Ok(Some(log_dir)) = Config::load_from(&binary.with_file_name(config::CONFIG_FILE_NAME)).map(|c| c.logging.directory) {
+        std::fs::create_dir_all(&log_dir).and_then(|_| permissions::secure(&log_dir)).map_err(|e| {
+            println!("Failed to lock down {}: {}", log_dir.display(), e);
+            e
+        })?;
+    }
+
+    say!("Only administrators can change {} now", dir.display());
+    Ok(())
+}
+
+/// Print what a service command did, failures even with --quiet
+fn report(outcome: Outcome) {
+    if outcome.failed() {
+        println!("{}", outcome);
+    } else {
+        say!("{}", outcome);
+    }
+}
+
+#[cfg(windows)]
+fn run_service() -> Result<(), Box<dyn Error>> {
+    let backend = PlatformBackend::local()?;
+
+    match backend.query_state(SERVICE_NAME)? {
+        Some(ServiceState::Stopped) | Some(ServiceState::StartPending) => {
+            info!("running service");
+            service::run()?;
+
+            if let Some(code) = std::io::Error::last_os_error().raw_os_error() {
+                // this was run directly
+                // ERROR_FAILED_SERVICE_CONTROLLER_CONNECT
+                if code == 1063 {
+                    info!("tried to run service directly - ERROR_FAILED_SERVICE_CONTROLLER_CONNECT");
+                    println!("Do not run directly. Please use the start command");
                 }
-            } else {
-                info!("main::run::run-service: tried to run service, but it wasn't found");
-                println!("Service not found. Is it installed?");
             }
         }
 
-        _ => {
-            info!("main::run::_: invalid command {}", command);
-            println!("Invalid command. Please see help for commands");
+        Some(service_status) => {
+            info!("tried to run service, but its status is {:?}", service_status);
+            println!("Service already running");
+        }
+
+        None => {
+            info!("tried to run service, but it wasn't found");
+            println!("Service not found. Is it installed?");
         }
     }
 
     Ok(())
 }
 
-fn stop_service(service: &Service, is_stop_command: bool) -> windows_service::Result<()> {
-    let mut service_status = service.query_status()?;
+/// systemd runs the service in the foreground, there's no dispatcher to hand it to
+#[cfg(not(windows))]
+fn run_service() -> Result<(), Box<dyn Error>> {
+    info!("running service");
+    service::run()
+}
+
+/// Ask the running service to patch, or patch from this process if it isn't running. The control
+/// channel only takes local clients, remote computers are always patched from here
+fn patch_now(config_path: &Path, target: &Target, no_service: bool, wait: Duration) -> Result<(), Box<dyn Error>> {
+    let user = audit::current_user();
 
-    if service_status.current_state != ServiceState::Stopped {
-        info!("main::stop_service: stopping service");
+    if !no_service && target.is_local() {
+        let request = control::Request { command: control::Command::Repatch };
 
-        if let Ok(_) = service.stop() {
-            // Wait for service to stop
-            let mut elapsed_time = 0;
-            let mut printed = false;
-            loop {
-                service_status = service.query_status()?;
-
-                if service_status.current_state != ServiceState::Stopped {
-                    if !printed {
-                        print_flush!("Stopping service.");
-                        printed = true;
-                    }
-
-                    if elapsed_time >= 10000 {
-                        // really, should've been long enough..
-                        error!("main::stop_service: service timed out");
-                        print_flush!("failed");
-
-                        if is_stop_command {
-                            println!();
-                        } else {
-                            print_flush!("...");
-                        }
-                        break;
-                    }
-
-                    print_flush!(".");
-
-                    std::thread::sleep(Duration::from_millis(250));
-                    elapsed_time += 250;
+        match control::send(&request) {
+            Ok(reply) if reply.ok => {
+                info!("{}", reply.message);
+                say!("{}", reply.message);
+                return Ok(());
+            }
+
+            Ok(reply) => {
+                error!("{}", reply.message);
+                println!("Patching failed: {}", reply.message);
+                std::process::exit(1);
+            }
+
+            Err(e) => {
+                info!("couldn't reach the service, patching from the cli: {}", e);
+                say!("The patcher service isn't running, patching from here");
+            }
+        }
+    }
+
+    let _lock = take_lock(target, "patch-now", wait);
+    let config = Config::load_from(config_path)?;
+    let mut watcher = Watcher::new(PlatformBackend::connect(target)?, config.watcher, config.hooks, &config.proxy.upstream, State::path_for(target), AuditLog::open())
+        .desktop(config.desktop)
+        .integrity(config.integrity);
+
+    match watcher.patch_now(Trigger::Cli, user) {
+        Ok(message) => say!("{}", message),
+        Err(e) => {
+            println!("Patching failed: {}", e);
+            std::process::exit(1);
+        }
+    }
+
+    Ok(())
+}
+
+fn unpatch(config_path: &Path, restart: bool, wait: Duration) -> Result<(), Box<dyn Error>> {
+    let backend = PlatformBackend::local()?;
+
+    if backend.query_state(SERVICE_NAME)? == Some(ServiceState::Running) {
+        info!("refused, the patcher service is running");
+        println!("The patcher service is running and would patch docker again. Please stop it first");
+        std::process::exit(1);
+    }
+
+    let _lock = take_lock(&Target::Local, "unpatch", wait);
+    let config = Config::load_from(config_path)?;
+    let mut watcher = Watcher::new(backend, config.watcher, config.hooks, &config.proxy.upstream, State::default_path(), AuditLog::open())
+        .desktop(config.desktop)
+        .integrity(config.integrity);
+
+    match watcher.unpatch(Trigger::Cli, audit::current_user(), restart) {
+        Ok(message) => say!("{}", message),
+        Err(e) => {
+            println!("Unpatching failed: {}", e);
+            std::process::exit(1);
+        }
+    }
+
+    Ok(())
+}
+
+/// --wait of patch-now and unpatch, validated by clap
+fn lock_wait(args: &ArgMatches) -> Duration {
+    Duration::from_secs(args.value_of("wait").unwrap().parse().unwrap())
+}
+
+/// Wait up to `wait` for other patcher processes changing docker on `target`, exit if they don't
+/// finish in time
+fn take_lock(target: &Target, holder: &str, wait: Duration) -> PatchLock {
+    let e = match PatchLock::try_acquire(target, holder) {
+        Ok(lock) => return lock,
+        Err(e) => e,
+    };
+
+    if let LockError::Busy { .. } = e {
+        info!("{}, waiting up to {} seconds", e, wait.as_secs());
+        say!("Waiting up to {} seconds: {}", wait.as_secs(), e);
+    }
+
+    match PatchLock::acquire(target, holder, wait) {
+        Ok(lock) => lock,
+        Err(e) => {
+            error!("{}", e);
+            println!("Giving up: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn doctor_environment(config_path: &Path) -> doctor::Environment {
+    // the permissions check reports an insecure config
+    let config = Config::read_from(config_path);
+    let host_version = config
+        .as_ref()
+        .map_err(|e| e.to_string())
+        .and_then(|config| compat::host_os_version(&config.host).map_err(|e| e.to_string()));
+    let logging_config = config.as_ref().map(|c| c.logging.clone()).unwrap_or_default();
+    let integrity = config.as_ref().map(|c| c.integrity.clone()).unwrap_or_default();
+    let hooks = config.as_ref().map(|c| c.hooks.clone()).unwrap_or_default();
+
+    doctor::Environment {
+        elevated: is_elevated(),
+        config_path: config_path.to_path_buf(),
+        state_path: State::default_path(),
+        log_dir: logging::log_dir(&logging_config),
+        default_daemon_json: doctor::default_daemon_json(),
+        host_version,
+        flags: config.map(|c| c.watcher.flags).unwrap_or_else(|_| patch::default_flags()),
+        integrity,
+        hooks,
+    }
+}
+
+fn run_doctor(config_path: &Path, json: bool) -> Result<(), Box<dyn Error>> {
+    let backend = PlatformBackend::local()?;
+    let env = doctor_environment(config_path);
+
+    let checks = doctor::run(&backend, &env);
+    let overall = doctor::overall(&checks);
+    info!("doctor finished with {}", overall);
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&checks)?);
+    } else {
+        print!("{}", doctor::render_text(&checks));
+    }
+
+    if overall == doctor::Status::Fail {
+        std::process::exit(1);
+    }
+
+    Ok(())
+}
+
+fn collect_diagnostics(config_path: &Path, path: &str) -> Result<(), Box<dyn Error>> {
+    let backend = PlatformBackend::local()?;
+    let env = doctor_environment(config_path);
+
+    let entries = diagnostics::collect(&backend, &env, config_path);
+    diagnostics::write_zip(Path::new(path), &entries)?;
+
+    info!("wrote {} entries to {}", entries.len(), path);
+    say!("Wrote diagnostics to {}. Please check it doesn't contain anything private before attaching it to an issue", path);
+
+    Ok(())
+}
+
+fn run_status(config_path: &Path, target: &Target, json: bool) -> Result<(), Box<dyn Error>> {
+    let config = Config::read_from(config_path).unwrap_or_else(|e| {
+        eprintln!("Failed to load config, showing the default drift policy: {}", e);
+        Config::default()
+    });
+
+    let backend = PlatformBackend::connect(target)?;
+    let report = status::collect(&backend, &State::path_for(target), &config);
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        print!("{}", status::render_text(&report));
+    }
+
+    Ok(())
+}
+
+fn run_plan(config_path: &Path, target: &Target, json: bool) -> Result<(), Box<dyn Error>> {
+    let config = Config::read_from(config_path)?;
+
+    let backend = PlatformBackend::connect(target)?;
+    let plan = plan::collect(&backend, &State::path_for(target), &config)?;
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&plan)?);
+    } else {
+        print!("{}", plan::render_text(&plan));
+    }
+
+    Ok(())
+}
+
+fn run_batch(config_path: &Path, args: &ArgMatches, json: bool) -> Result<(), Box<dyn Error>> {
+    let command = batch::Command::from_name(args.value_of("command").unwrap()).expect("clap only accepts batch::Command::NAMES");
+    let targets = batch::read_inventory(Path::new(args.value_of("inventory").unwrap()))?;
+    let config = match command {
+        batch::Command::Status | batch::Command::Plan => Config::read_from(config_path)?,
+        _ => Config::load_from(config_path)?,
+    };
+    let binary = args.value_of("binary").map_or_else(|| std::env::current_exe().unwrap(), PathBuf::from);
+    let audit_log = AuditLog::open();
+
+    let batch = batch::Batch { command, config: &config, binary: &binary, audit_log: &audit_log, user: audit::current_user() };
+    let results = batch.run(&targets, PlatformBackend::connect);
+
+    let failed = results.iter().filter(|r| !r.ok).count();
+    info!("ran {:?} on {} computers, {} failed", command, results.len(), failed);
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&results)?);
+    } else {
+        print!("{}", batch::render_table(&results));
+    }
+
+    if failed > 0 {
+        std::process::exit(1);
+    }
+
+    Ok(())
+}
+
+fn run_audit(action: &str, json: bool) -> Result<(), Box<dyn Error>> {
+    let audit_log = AuditLog::open();
+
+    match action {
+        "list" => {
+            let entries = audit_log.entries()?;
+
+            if json {
+                println!("{}", serde_json::to_string_pretty(&entries)?);
+                return Ok(());
+            }
+
+            if entries.is_empty() {
+                println!("No entries in {}", audit_log.path().display());
+            }
+
+            for entry in &entries {
+                let user = entry.user.as_deref().unwrap_or("SYSTEM");
+                let result = entry.error.as_deref().map_or("ok".to_string(), |e| format!("failed: {}", e));
+                println!("#{} {} {} by {} ({}): {} {}", entry.seq, entry.timestamp, entry.action, user, entry.trigger, entry.service, result);
+
+                if let (Some(before), Some(after)) = (&entry.before, &entry.after) {
+                    println!("    before: {}\n    after:  {}", before, after);
+                }
+            }
+        }
+
+        "verify" => match audit_log.verify()? {
+            None => say!("Audit log {} is intact", audit_log.path().display()),
+            Some(broken) => {
+                error!("audit log verification failed: {}", broken);
+                println!("Audit log {} was tampered with: {}", audit_log.path().display(), broken);
+                std::process::exit(1);
+            }
+        },
+
+        _ => unreachable!("clap only accepts list and verify"),
+    }
+
+    Ok(())
+}
+
+/// Rewrite the isolation of compose files, or with --check only report what would change
+fn run_compose(config_path: &Path, args: &ArgMatches, json: bool) -> Result<(), Box<dyn Error>> {
+    let policy = Config::read_from(config_path)?.proxy.policy;
+    let check = args.is_present("check");
+
+    let mut reports = vec![];
+    let mut failed = false;
+    let mut needs_changes = false;
+
+    for path in args.values_of("files").unwrap() {
+        let res = std::fs::read_to_string(path)
+            .map_err(|e| e.to_string())
+            .and_then(|contents| compose::rewrite(&contents, &policy).map_err(|e| e.to_string()));
+
+        let rewrite = match res {
+            Ok(rewrite) => rewrite,
+            Err(e) => {
+                error!("{}: {}", path, e);
+                if !json {
+                    println!("{}: {}", path, e);
+                }
+                reports.push(serde_json::json!({ "path": path, "changes": [], "error": e }));
+                failed = true;
+                continue;
+            }
+        };
+
+        needs_changes |= !rewrite.changes.is_empty();
+
+        let written = !check && !rewrite.changes.is_empty();
+        if written {
+            if let Err(e) = std::fs::write(path, &rewrite.contents) {
+                error!("failed to write {}: {}", path, e);
+                println!("Failed to write {}: {}", path, e);
+                failed = true;
+                continue;
+            }
+            info!("set isolation of {} services in {}", rewrite.changes.len(), path);
+        }
+
+        if !json {
+            for change in &rewrite.changes {
+                if check {
+                    println!("{}: {}", path, change);
                 } else {
-                    info!("main::stop_service: stopped service");
-
-                    if printed == true {
-                        print_flush!("stopped");
-                        if is_stop_command {
-                            println!();
-                        } else {
-                            print_flush!("...");
-                        }
-                    } else if is_stop_command {
-                        println!("Stopped service")
-                    }
-                    break;
+                    say!("{}: {}", path, change);
                 }
             }
+
+            if rewrite.changes.is_empty() {
+                say!("{}: nothing to change", path);
+            }
         }
-    } else {
-        info!("main::stop_service: tried to stop service, but it was already stopped");
-        if is_stop_command {
-            println!("Service already stopped");
+
+        reports.push(serde_json::json!({ "path": path, "changes": rewrite.changes, "written": written }));
+    }
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&reports)?);
+    } else if check && needs_changes {
+        println!("Isolation doesn't match the policy, run compose without --check to fix it");
+    }
+
+    if failed || (check && needs_changes) {
+        std::process::exit(1);
+    }
+
+    Ok(())
+}
+
+fn check_image(path: &str, config: &Config) -> Result<(), Box<dyn Error>> {
+    let contents = std::fs::read_to_string(path)?;
+    let json: serde_json::Value = serde_json::from_str(&contents)?;
+    let platforms = compat::image_platforms(&json)?;
+
+    let host = match compat::host_os_version(&config.host)? {
+        Some(host) => host,
+        None => {
+            println!("Host windows build is unknown. Please set host.os_version in {}", config::CONFIG_FILE_NAME);
+            return Ok(());
         }
+    };
+
+    println!("Host: {}", host);
+    for platform in &platforms {
+        let version = platform.os_version.map_or("unknown version".to_string(), |v| v.to_string());
+        println!("  {} {} {}: {}", platform.os, platform.architecture.as_deref().unwrap_or(""), version, compat::check(&host, platform));
+    }
+
+    let result = compat::check_any(&host, &platforms);
+    info!("{} is {}", path, result);
+    println!("Result: {}", result);
+
+    if !result.allows_process() {
+        std::process::exit(1);
     }
 
     Ok(())
@@ -1,204 +1,334 @@
-#[cfg(windows)]
-
+// The patcher service
+// On Windows the SCM starts `run-service` through the service dispatcher and stops it with a
+// control event, on Linux systemd runs it in the foreground and stops it with SIGTERM. Either way
+// `serve` runs the watcher until the stop arrives. The service holds the patch lock of this computer
+// while the watcher runs, see lock. Service manager errors which may go away, like the SCM being
+// busy or systemd reloading, are waited out with a growing delay instead of stopping enforcement,
+// only fatal ones like missing rights stop the service. A failed service reports its exit code, see
+// exit. A panic in the watcher doesn't unwind into the service manager, it stops the service with
+// an exit code like any other error and leaves a panic report, see crash.
+
+use super::audit::AuditLog;
+use super::backend::{BackendError, Target};
+use super::backoff::Backoff;
+use super::config::Config;
+use super::control;
+use super::crash::{self, Panicked};
+use super::event;
+use super::event::{Event, EventId};
+use super::exit::{self, ServiceExit};
+use super::lock::{LockError, PatchLock};
+use super::proxy;
 use super::shared::*;
-use log::info;
-
-use windows_service::{
-    define_windows_service,
-    service::{
-        ServiceControl, ServiceControlAccept, ServiceExitCode, ServiceState, ServiceStatus,
-        ServiceType,
-    },
-    service_control_handler::{self, ServiceControlHandlerResult},
-    service_dispatcher, Result,
-};
+use super::state::{self, ServiceFailure, State};
+use super::watcher::Watcher;
+use super::PlatformBackend;
+use log::{error, info, Level};
+
+use std::error::Error;
+use std::panic::{self, AssertUnwindSafe};
 use std::sync::mpsc;
-use std::time::Duration;
-use std::ffi::OsString;
-use windows_service::service_manager::{ServiceManagerAccess, ServiceManager};
-use windows_service::service::{ServiceAccess, ServiceInfo};
+use std::time::{Duration, Instant};
 
-use splitty::*;
-use std::path::PathBuf;
+/// wait after the first transient service manager error, doubled for each one in a row
+const RETRY_INITIAL: Duration = Duration::from_secs(1);
+const RETRY_MAX: Duration = Duration::from_secs(60);
 
-const SERVICE_TYPE: ServiceType = ServiceType::OWN_PROCESS;
+#[cfg(windows)]
+pub use self::windows::run;
 
-pub fn run() -> Result<()> {
-    // Register generated `ffi_service_main` with the system and start the service, blocking
-    // this thread until the service is stopped.
-    service_dispatcher::start(SERVICE_NAME, ffi_service_main)
-}
+/// Run the proxy, the control channel and the watcher loop until `stop_requested` returns true.
+/// It's called every loop and may wait up to the timeout for a stop
+fn serve(mut stop_requested: impl FnMut(Duration) -> bool) -> Result<(), Box<dyn Error>> {
+    event!(Level::Info, Event::new(EventId::ServiceStarted).service(SERVICE_NAME), "service started");
 
-// Generate the windows service boilerplate.
-// The boilerplate contains the low-level service entry function (ffi_service_main) that parses
-// incoming service arguments into Vec<OsString> and passes them to user defined service
-// entry (my_service_main).
-define_windows_service!(ffi_service_main, service_main);
-
-// Service entry function which is called on background thread by the system with service
-// parameters. There is no stdout or stderr at this point so make sure to configure the log
-// output to file if needed.
-pub fn service_main(_arguments: Vec<OsString>) {
-    if let Err(_e) = run_service() {
-        // Handle the error, by logging or something.
+    let config = Config::load().unwrap_or_else(|e| {
+        event!(Level::Error, Event::new(EventId::ConfigInvalid), "{}, using the default config", e);
+        Config::default()
+    });
+    crash::set_config(&config);
+
+    // docker would count as patched whatever its command line
+    config.watcher.check_flags()?;
+
+    if config.proxy.enabled {
+        event!(Level::Info, Event::new(EventId::ProxyStarted), "starting docker api proxy on {}", config.proxy.listen);
+        proxy::spawn(config.clone());
+    }
+
+    // requests from the CLI, answered between watcher ticks
+    let (request_tx, request_rx) = mpsc::channel::<control::Pending>();
+    if let Err(e) = control::spawn(request_tx) {
+        error!("failed to start the control channel: {}", e);
     }
-}
 
-pub fn run_service() -> Result<()> {
-    // Create a channel to be able to poll a stop event from the service worker loop.
-    let (shutdown_tx, shutdown_rx) = mpsc::channel();
-
-    // Define system service event handler that will be receiving service events.
-    let event_handler = move |control_event| -> ServiceControlHandlerResult {
-        match control_event {
-            // Notifies a service to report its current status information to the service
-            // control manager. Always return NoError even if not implemented.
-            ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
-
-            // Handle stop
-            ServiceControl::Stop => {
-                shutdown_tx.send(()).unwrap();
-                ServiceControlHandlerResult::NoError
+    // a patch-now or unpatch from the CLI finishes first
+    let mut waiting = false;
+    let _lock = loop {
+        match PatchLock::try_acquire(&Target::Local, "the patcher service") {
+            Ok(lock) => break lock,
+            Err(e @ LockError::Busy { .. }) => {
+                if !waiting {
+                    info!("waiting for the patch lock, {}", e);
+                    waiting = true;
+                }
             }
+            Err(e) => return Err(e.into()),
+        }
 
-            _ => ServiceControlHandlerResult::NotImplemented,
+        if stop_requested(Duration::from_secs(1)) {
+            stopping();
+            return Ok(());
         }
     };
 
-    // Register system service event handler.
-    // The returned status handle should be used to report service status changes to the system.
-    let status_handle = service_control_handler::register(SERVICE_NAME, event_handler)?;
-
-    // Tell the system that service is running
-    status_handle.set_service_status(ServiceStatus {
-        service_type: SERVICE_TYPE,
-        current_state: ServiceState::Running,
-        controls_accepted: ServiceControlAccept::STOP,
-        exit_code: ServiceExitCode::Win32(0),
-        checkpoint: 0,
-        wait_hint: Duration::default(),
-        process_id: None,
-    })?;
-
-    let manager_access = ServiceManagerAccess::CONNECT;
-    let service_manager = ServiceManager::local_computer(None::<&str>, manager_access)?;
-    let service_access = ServiceAccess::QUERY_STATUS | ServiceAccess::STOP | ServiceAccess::START | ServiceAccess::QUERY_CONFIG | ServiceAccess::CHANGE_CONFIG;
-
-    let mut modified_docker = false;
+    let mut backoff = Backoff::new(RETRY_INITIAL, RETRY_MAX);
+    let backend = loop {
+        match PlatformBackend::local() {
+            Ok(backend) => break backend,
+            Err(e) => {
+                if retry(e, &mut backoff, &mut stop_requested)? {
+                    stopping();
+                    return Ok(());
+                }
+            }
+        }
+    };
+    backoff.reset();
+
+    let mut watcher = Watcher::new(backend, config.watcher, config.hooks, &config.proxy.upstream, State::default_path(), AuditLog::open())
+        .desktop(config.desktop)
+        .integrity(config.integrity);
+
     loop {
-        // Poll shutdown event.
-        match shutdown_rx.recv_timeout(Duration::from_secs(1)) {
-            // Break the loop either upon stop or channel disconnect
-            Ok(_) | Err(mpsc::RecvTimeoutError::Disconnected) => {
-                info!("service::run_service: stopping service");
-                break
-            },
-
-            // Continue work if no events were received within the timeout
-            Err(mpsc::RecvTimeoutError::Timeout) => (),
-        };
+        if stop_requested(Duration::from_secs(1)) {
+            stopping();
+            break;
+        }
 
-        let res = service_manager.open_service(DOCKER_SERVICE_NAME, service_access);
-        if let Ok(service) = res {
-            let mut service_state = service.query_status()?;
-
-            if !modified_docker {
-                if service_state.current_state == ServiceState::Running {
-                    // don't change service immediately once detected, otherwise it'll fail
-                    std::thread::sleep(Duration::from_secs(2));
-                    // requery again to make sure it's still up
-                    service_state = service.query_status()?;
-                    if service_state.current_state != ServiceState::Running {
-                        info!("service::run_service: docker state changed during 2 second wait: {:?}", service_state.current_state);
-                        continue;
-                    }
-
-                    let config = service.query_config()?;
-
-                    // this service was already patched - update and exit
-                    if config.display_name == "Docker Engine - Patched Process Isolation" {
-                        info!("service::run_service: docker service already patched - updating status to modified");
-                        modified_docker = true;
-                        continue;
-                    }
-
-                    info!("service::run_service: detected unmodified docker service");
-                    info!("service::run_service: stopping docker service");
-
-                    // stop service
-                    service.stop().ok();
-
-                    // wait for service to stop
-                    loop {
-                        service_state = service.query_status()?;
-
-                        if service_state.current_state == ServiceState::Stopped {
-                            info!("service::run_service: docker service stopped");
-                            break;
-                        }
-
-                        std::thread::sleep(Duration::from_millis(250));
-                    }
-
-                    info!("service::run_service: patching docker service");
-
-                    let path = config.executable_path.to_str().unwrap();
-
-                    let mut split_path: Vec<&str> = split_unquoted_whitespace(path).unwrap_quotes(true).collect();
-                    split_path.insert(1, "--exec-opt");
-                    split_path.insert(2, "isolation=process");
-                    let buffer: Vec<OsString> = split_path.iter().map(|x| OsString::from(x)).collect();
-
-                    let new_config = ServiceInfo {
-                        name: OsString::from(DOCKER_SERVICE_NAME),
-                        display_name: OsString::from("Docker Engine - Patched Process Isolation"),
-                        service_type: config.service_type,
-                        start_type: config.start_type,
-                        error_control: config.error_control,
-                        executable_path: PathBuf::from(&buffer[0]),
-                        launch_arguments: buffer[1..].to_vec(),
-                        dependencies: config.dependencies,
-                        account_name: None,
-                        account_password: None
-                    };
-
-                    service.change_config(&new_config).unwrap();
-                    service.set_description("Patched docker process isolated service").unwrap();
-
-                    info!("service::run_service: successfully patched docker service");
-
-                    info!("service::run_service: starting docker service");
-                    service.start(&[] as &[&str])?;
-                    info!("service::run_service: started docker service");
-
-                    modified_docker = true;
-                }
-            } else {
-                // docker stopped the process
-                if service_state.current_state == ServiceState::Stopped {
-                    info!("service::run_service: docker just stopped the service");
-                    modified_docker = false;
+        while let Ok(pending) = request_rx.try_recv() {
+            let _ = pending.reply.send(watcher.handle_request(&pending.request, pending.user));
+        }
+
+        crash::set_state(watcher.describe());
+        match watcher.tick() {
+            Ok(()) => backoff.reset(),
+            Err(e) => {
+                if retry(e, &mut backoff, &mut stop_requested)? {
+                    stopping();
+                    break;
                 }
             }
-        } else {
-            // docker deleted the service
-            if modified_docker {
-                info!("service::run_service: docker deleted the service");
-                modified_docker = false;
-            }
         }
     }
 
-    // Tell the system that service has stopped.
-    status_handle.set_service_status(ServiceStatus {
-        service_type: SERVICE_TYPE,
-        current_state: ServiceState::Stopped,
-        controls_accepted: ServiceControlAccept::empty(),
-        exit_code: ServiceExitCode::Win32(0),
-        checkpoint: 0,
-        wait_hint: Duration::default(),
-        process_id: None,
-    })?;
+    Ok(())
+}
+
+/// Run `serve` with a panic in it turned into an error, so the service reports it before stopping
+fn contain(serve: impl FnOnce() -> Result<(), Box<dyn Error>>) -> Result<(), Box<dyn Error>> {
+    panic::catch_unwind(AssertUnwindSafe(serve)).unwrap_or_else(|payload| Err(Box::new(Panicked::from_payload(payload.as_ref()))))
+}
+
+/// Count a service manager error and wait before trying again if it's transient, or give it back
+/// if it's fatal. True if a stop was requested while waiting
+fn retry(e: BackendError, backoff: &mut Backoff, stop_requested: &mut impl FnMut(Duration) -> bool) -> Result<bool, BackendError> {
+    if !e.is_transient() {
+        update_state(|state| state.metrics.fatal_errors += 1);
+        return Err(e);
+    }
+
+    let delay = backoff.fail();
+    event!(
+        Level::Warn,
+        Event::new(EventId::ServiceRetrying).service(SERVICE_NAME),
+        "{}, trying again in {}s ({} in a row)", e, delay.as_secs(), backoff.failures()
+    );
+    update_state(|state| state.metrics.transient_errors += 1);
+
+    // a second at a time, so a stop doesn't wait for the whole delay
+    let deadline = Instant::now() + delay;
+    loop {
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return Ok(false);
+        }
+
+        if stop_requested(left.min(Duration::from_secs(1))) {
+            return Ok(true);
+        }
+    }
+}
+
+/// The service stops as asked, its last failure is over
+fn stopping() {
+    event!(Level::Info, Event::new(EventId::ServiceStopped).service(SERVICE_NAME), "stopping service");
+    update_state(|state| state.service_failure = None);
+}
+
+/// Log why the service stops with `e` and keep it for status and doctor. The exit code to report
+fn failed(e: &(dyn Error + 'static)) -> ServiceExit {
+    let exit = ServiceExit::of(e);
+    let message = exit::error_chain(e);
+
+    event!(
+        Level::Error,
+        Event::new(EventId::ServiceFailed).service(SERVICE_NAME),
+        "stopping with exit code {}: {}", exit::describe(exit.code()), message
+    );
+    update_state(|state| state.service_failure = Some(ServiceFailure { exit_code: exit.code(), message, at: state::unix_time() }));
+
+    exit
+}
+
+fn update_state(f: impl FnOnce(&mut State)) {
+    if let Err(e) = State::update(&State::default_path(), f) {
+        event!(Level::Error, Event::new(e.event_id()), "{}", e);
+    }
+}
+
+#[cfg(windows)]
+mod windows {
+    use super::crash::Panicked;
+    use super::SERVICE_NAME;
+    use log::error;
+    use windows_service::{
+        define_windows_service,
+        service::{
+            ServiceControl, ServiceControlAccept, ServiceExitCode, ServiceState, ServiceStatus,
+            ServiceType,
+        },
+        service_control_handler::{self, ServiceControlHandlerResult},
+        service_dispatcher, Result,
+    };
+    use std::error::Error;
+    use std::panic;
+    use std::sync::mpsc;
+    use std::time::Duration;
+    use std::ffi::OsString;
+
+    const SERVICE_TYPE: ServiceType = ServiceType::OWN_PROCESS;
+
+    pub fn run() -> Result<()> {
+        // Register generated `ffi_service_main` with the system and start the service, blocking
+        // this thread until the service is stopped.
+        service_dispatcher::start(SERVICE_NAME, ffi_service_main)
+    }
+
+    // Generate the windows service boilerplate.
+    // The boilerplate contains the low-level service entry function (ffi_service_main) that parses
+    // incoming service arguments into Vec<OsString> and passes them to user defined service
+    // entry (my_service_main).
+    define_windows_service!(ffi_service_main, service_main);
+
+    // Service entry function which is called on background thread by the system with service
+    // parameters. There is no stdout or stderr at this point so make sure to configure the log
+    // output to file if needed.
+    pub fn service_main(_arguments: Vec<OsString>) {
+        // the watcher's errors and panics are reported by run_service, these are the SCM's. Nothing
+        // may unwind into the SCM's thread
+        match panic::catch_unwind(run_service) {
+            Ok(Ok(())) => (),
+            Ok(Err(e)) => error!("{}", e),
+            Err(payload) => error!("{}", Panicked::from_payload(payload.as_ref())),
+        }
+    }
+
+    pub fn run_service() -> std::result::Result<(), Box<dyn Error>> {
+        // Create a channel to be able to poll a stop event from the service worker loop.
+        let (shutdown_tx, shutdown_rx) = mpsc::channel();
+
+        // Define system service event handler that will be receiving service events.
+        let event_handler = move |control_event| -> ServiceControlHandlerResult {
+            match control_event {
+                // Notifies a service to report its current status information to the service
+                // control manager. Always return NoError even if not implemented.
+                ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+
+                // Handle stop
+                ServiceControl::Stop => {
+                    // the worker may be gone already
+                    let _ = shutdown_tx.send(());
+                    ServiceControlHandlerResult::NoError
+                }
+
+                _ => ServiceControlHandlerResult::NotImplemented,
+            }
+        };
+
+        // Register system service event handler.
+        // The returned status handle should be used to report service status changes to the system.
+        let status_handle = service_control_handler::register(SERVICE_NAME, event_handler)?;
+
+        // Tell the system that service is running
+        status_handle.set_service_status(ServiceStatus {
+            service_type: SERVICE_TYPE,
+            current_state: ServiceState::Running,
+            controls_accepted: ServiceControlAccept::STOP,
+            exit_code: ServiceExitCode::Win32(0),
+            checkpoint: 0,
+            wait_hint: Duration::default(),
+            process_id: None,
+        })?;
+
+        // Poll shutdown event.
+        let served = super::contain(|| {
+            super::serve(|timeout| match shutdown_rx.recv_timeout(timeout) {
+                // Break the loop either upon stop or channel disconnect
+                Ok(_) | Err(mpsc::RecvTimeoutError::Disconnected) => true,
+
+                // Continue work if no events were received within the timeout
+                Err(mpsc::RecvTimeoutError::Timeout) => false,
+            })
+        });
+
+        // a service specific code makes the SCM run the recovery actions
+        let exit_code = match served {
+            Ok(()) => ServiceExitCode::Win32(0),
+            Err(e) => ServiceExitCode::ServiceSpecific(super::failed(e.as_ref()).code()),
+        };
+
+        // Tell the system that service has stopped.
+        status_handle.set_service_status(ServiceStatus {
+            service_type: SERVICE_TYPE,
+            current_state: ServiceState::Stopped,
+            controls_accepted: ServiceControlAccept::empty(),
+            exit_code,
+            checkpoint: 0,
+            wait_hint: Duration::default(),
+            process_id: None,
+        })?;
+
+        Ok(())
+    }
+}
+
+/// Run in the foreground until SIGTERM, which is how systemd stops a service, or SIGINT
+#[cfg(not(windows))]
+pub fn run() -> Result<(), Box<dyn Error>> {
+    use signal_hook::consts::{SIGINT, SIGTERM};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    let stop = Arc::new(AtomicBool::new(false));
+    for signal in &[SIGTERM, SIGINT] {
+        signal_hook::flag::register(*signal, Arc::clone(&stop))?;
+    }
+
+    let served = contain(|| {
+        serve(|timeout| {
+            if !stop.load(Ordering::Relaxed) {
+                std::thread::sleep(timeout);
+            }
+
+            stop.load(Ordering::Relaxed)
+        })
+    });
+
+    // the same codes as on Windows, systemd restarts the service on any of them
+    if let Err(e) = served {
+        std::process::exit(failed(e.as_ref()).code() as i32);
+    }
 
     Ok(())
 }
@@ -1,2 +0,0 @@
-pub const SERVICE_NAME: &str = "docker_process_isolation_patcher";
-pub const DOCKER_SERVICE_NAME: &str = "docker";
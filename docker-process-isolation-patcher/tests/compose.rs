@@ -0,0 +1,95 @@
+// compose command
+// Runs the built exe, --check has to fail a pre-commit hook when a file doesn't match the policy.
+#![cfg(unix)]
+
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+/// A compose file with `contents` in the temp directory, removed when dropped
+struct ComposeFile(PathBuf);
+
+impl ComposeFile {
+    fn new(name: &str, contents: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("patcher-test-{}-{}.yml", name, std::process::id()));
+        fs::write(&path, contents).unwrap();
+        ComposeFile(path)
+    }
+
+    fn contents(&self) -> String {
+        fs::read_to_string(&self.0).unwrap()
+    }
+}
+
+impl Drop for ComposeFile {
+    fn drop(&mut self) {
+        fs::remove_file(&self.0).ok();
+    }
+}
+
+fn compose(args: &[&str], file: &ComposeFile) -> Output {
+    // a config which doesn't exist is the default config
+    let config = std::env::temp_dir().join(format!("patcher-test-missing-{}.toml", std::process::id()));
+    compose_with(&config, args, file)
+}
+
+fn compose_with(config: &Path, args: &[&str], file: &ComposeFile) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_docker-process-isolation-patcher"))
+        .arg("--config")
+        .arg(config)
+        .arg("compose")
+        .args(args)
+        .arg(&file.0)
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn check_fails_when_isolation_doesnt_match() {
+    let file = ComposeFile::new("check-mismatch", "services:\n  web:\n    image: nginx\n");
+
+    let output = compose(&["--check"], &file);
+
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stdout).contains("service web: isolation unset -> process"));
+    assert_eq!(file.contents(), "services:\n  web:\n    image: nginx\n");
+}
+
+#[test]
+fn check_passes_when_isolation_matches() {
+    let file = ComposeFile::new("check-match", "services:\n  web:\n    image: nginx\n    isolation: process\n");
+
+    assert_eq!(compose(&["--check"], &file).status.code(), Some(0));
+}
+
+#[test]
+fn files_are_rewritten_without_check() {
+    let file = ComposeFile::new("rewrite", "services:\n  web:\n    image: nginx\n");
+
+    assert_eq!(compose(&[], &file).status.code(), Some(0));
+    assert_eq!(file.contents(), "services:\n  web:\n    image: nginx\n    isolation: process\n");
+}
+
+#[test]
+fn flow_style_fails() {
+    let file = ComposeFile::new("flow-style", "services:\n  web: {image: nginx}\n");
+
+    let output = compose(&["--check"], &file);
+
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stdout).contains("web isn't written in block style"));
+}
+
+#[test]
+fn insecure_config_is_only_warned_about() {
+    let file = ComposeFile::new("insecure-config", "services:\n  web:\n    image: nginx\n    isolation: process\n");
+    let config = ComposeFile::new("insecure-config-toml", "[proxy]\nenabled = false\n");
+    fs::set_permissions(&config.0, fs::Permissions::from_mode(0o646)).unwrap();
+
+    let output = compose_with(&config.0, &["--check"], &file);
+
+    assert_eq!(output.status.code(), Some(0));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("can be changed by") && stderr.contains("the commands changing docker refuse the config"), "{}", stderr);
+}